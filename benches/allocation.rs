@@ -0,0 +1,70 @@
+// Benchmarks of the block allocation and filenode table paths over a large in-memory
+// filesystem. FS_BENCH_SIZE sets the filesystem size in MiB (default 256) and
+// FS_BENCH_FILES the number of filenodes (default 8192). Run with
+// `cargo bench --bench allocation`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use filesystem::fs_ops::{FileSystemManager, InitOptions, UploadOptions};
+use filesystem::fs_structs::MEGABYTE;
+use filesystem::storage::MemStorage;
+
+fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Builds a filesystem whose filenode table is full and whose free blocks are scattered:
+/// files of one to eight blocks fill it, then every other file is deleted.
+fn fragmented_manager() -> FileSystemManager {
+    let num_files = env_or("FS_BENCH_FILES", 8192);
+    let options = InitOptions {
+        num_filenodes: num_files,
+        total_size: env_or("FS_BENCH_SIZE", 256) * MEGABYTE,
+        ..InitOptions::default()
+    };
+    let mut manager = FileSystemManager::init_in_storage(Box::new(MemStorage::new()), &options)
+        .expect("failed to create benchmark filesystem");
+    let block = options.block_size / 2;
+    for i in 0..num_files {
+        let data = vec![i as u8; block * (1 + i % 8)];
+        if manager
+            .upload_reader(
+                &mut data.as_slice(),
+                &format!("f{}", i),
+                &UploadOptions::default(),
+            )
+            .is_err()
+        {
+            break; // out of blocks
+        }
+    }
+    for i in (0..num_files).step_by(2) {
+        let _ = manager.delete_file(&format!("f{}", i));
+    }
+    manager
+}
+
+fn allocation(c: &mut Criterion) {
+    let mut manager = fragmented_manager();
+    let free_blocks = manager.usage().free_blocks;
+
+    let mut group = c.benchmark_group("find_free_blocks");
+    for num_blocks in [1, 16, 256] {
+        group.bench_function(num_blocks.to_string(), |b| {
+            b.iter(|| manager.bench_find_free_blocks(black_box(num_blocks)))
+        });
+    }
+    group.bench_function("all_free", |b| {
+        b.iter(|| manager.bench_find_free_blocks(black_box(free_blocks)))
+    });
+    group.finish();
+
+    c.bench_function("save_filenodes", |b| {
+        b.iter(|| manager.bench_save_filenodes().unwrap())
+    });
+}
+
+criterion_group!(benches, allocation);
+criterion_main!(benches);
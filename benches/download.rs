@@ -0,0 +1,53 @@
+// Benchmarks of downloading a contiguous file from a filesystem stored in a file, with
+// `sendfile` against the userspace copy loop used where it is unavailable. FS_BENCH_SIZE
+// sets the size of the file in MiB (default 64). Run with `cargo bench --bench download`.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use filesystem::fs_ops::{FileSystemManager, InitOptions, UploadOptions};
+use filesystem::fs_structs::MEGABYTE;
+
+fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn download(c: &mut Criterion) {
+    let file_size = env_or("FS_BENCH_SIZE", 64) * MEGABYTE;
+    let dir = std::env::temp_dir().join(format!("fs-bench-download-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("failed to create benchmark directory");
+    let options = InitOptions {
+        total_size: file_size + file_size / 8 + MEGABYTE,
+        ..InitOptions::default()
+    };
+    let mut manager = FileSystemManager::init_filesystem_at(&dir.join("bench.dat"), 0, &options)
+        .expect("failed to create benchmark filesystem");
+    let data: Vec<u8> = (0..file_size).map(|i| (i * 7 + i / 251) as u8).collect();
+    manager
+        .upload_reader(&mut data.as_slice(), "file", &UploadOptions::default())
+        .expect("failed to store benchmark file");
+    let target = dir.join("download");
+    let target = target.to_str().expect("temporary path is not UTF-8");
+
+    let mut group = c.benchmark_group("download");
+    group.throughput(Throughput::Bytes(file_size as u64));
+    group.sample_size(20);
+    group.bench_function("sendfile", |b| {
+        b.iter(|| manager.download_file("file", target).unwrap())
+    });
+    group.bench_function("copy_loop", |b| {
+        b.iter(|| {
+            manager
+                .bench_download_with_copy_loop("file", target)
+                .unwrap()
+        })
+    });
+    group.finish();
+
+    drop(manager);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+criterion_group!(benches, download);
+criterion_main!(benches);
@@ -0,0 +1,96 @@
+// Error type returned by the filesystem operations.
+
+use std::fmt;
+use std::io;
+
+/// Errors returned by `FileSystemManager` operations.
+#[derive(Debug)]
+pub enum FsError {
+    /// No stored file has the given alias.
+    AliasNotFound(String),
+    /// A stored file already uses the given alias.
+    AliasExists(String),
+    /// The alias cannot be used, e.g. because of its length.
+    InvalidAlias(String),
+    /// There are not enough free data blocks.
+    NotEnoughSpace { needed: usize, available: usize },
+    /// Every filenode is in use.
+    NoFreeFilenodes,
+    /// The image was created with a layout this build cannot read.
+    Incompatible {
+        field: &'static str,
+        expected: usize,
+        found: usize,
+    },
+    /// The on-disk structures are inconsistent.
+    Corrupt(String),
+    /// A structure could not be serialized or deserialized.
+    Serialization(String),
+    /// An argument is out of range or otherwise unusable.
+    InvalidArgument(String),
+    /// An encrypted file could not be authenticated with the given password.
+    BadPassword(String),
+    /// Reading or writing a file failed.
+    Io(io::Error),
+}
+
+impl FsError {
+    /// Wraps `error` with a description of the operation that failed, keeping its kind.
+    pub(crate) fn io(context: impl fmt::Display, error: io::Error) -> Self {
+        FsError::Io(io::Error::new(
+            error.kind(),
+            format!("{}: {}", context, error),
+        ))
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::AliasNotFound(alias) => write!(f, "File with alias '{}' not found.", alias),
+            FsError::AliasExists(alias) => {
+                write!(f, "File with alias '{}' already exists.", alias)
+            }
+            FsError::InvalidAlias(message) => write!(f, "{}", message),
+            FsError::NotEnoughSpace { needed, available } => write!(
+                f,
+                "Not enough free blocks. Needed: {}, Available: {}.",
+                needed, available
+            ),
+            FsError::NoFreeFilenodes => write!(f, "No free filenodes available."),
+            FsError::Incompatible {
+                field,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Incompatible filesystem {}: expected {}, found {}.",
+                field, expected, found
+            ),
+            FsError::Corrupt(message) => write!(f, "{}", message),
+            FsError::Serialization(message) => write!(f, "{}", message),
+            FsError::InvalidArgument(message) => write!(f, "{}", message),
+            FsError::BadPassword(alias) => write!(
+                f,
+                "Wrong password for file '{}', or its contents were tampered with.",
+                alias
+            ),
+            FsError::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for FsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FsError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FsError {
+    fn from(error: io::Error) -> Self {
+        FsError::Io(error)
+    }
+}
@@ -1,584 +1,8371 @@
 // Core logic for the filesystem operations.
 
+use crate::fs_error::FsError;
 use crate::fs_structs::{
-    FileNode, Header, BLOCK_SIZE, FILESYSTEM_SIZE, MAX_FILENAME_LENGTH, NEXT_BLOCK_POINTER_SIZE,
-    USABLE_BLOCK_SIZE,
+    Archive, ArchivedFile, BaselineHeader, Encryption, FileNode, Header, NodeType, Snapshot,
+    BASELINE_VERSION, BLOCK_SIZE, DEFAULT_CONTENT_TYPE, DEFAULT_FILENODE_COUNT, FILESYSTEM_SIZE,
+    FILESYSTEM_VERSION, MAX_CONTENT_TYPE_LEN, MAX_DESCRIPTION_LEN, MAX_FILENAME_LENGTH, MEGABYTE,
+    NEXT_BLOCK_POINTER_SIZE, OLDEST_UPGRADABLE_VERSION,
 };
-use bincode;
+use crate::journal::{self, JournalWrite};
+use crate::storage::{FileStorage, MmapStorage, Storage, StorageReader};
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{AeadCore, AeadInPlace, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce, Tag};
+use argon2::Argon2;
+use crc32fast::Hasher as Crc32;
+use log::{debug, info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::io::{self, Read, Seek, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub const FILESYSTEM_FILENAME: &str = "myfs.dat";
 
+/// Result of running `FileSystemManager::doctor`.
+#[derive(Debug, Default)]
+pub struct DoctorReport {
+    /// Informational results of the checks.
+    pub findings: Vec<String>,
+    /// Serious problems that were found (and not fixed).
+    pub problems: Vec<String>,
+    /// Actionable suggestions for the user.
+    pub suggestions: Vec<String>,
+}
+
+/// Result of running `FileSystemManager::repair`.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Orphaned blocks that were marked free again.
+    pub blocks_reclaimed: usize,
+    /// Blocks in use by a file that had been marked free.
+    pub blocks_marked_used: usize,
+    /// Files cut short at a broken chain, as (alias, old size, new size).
+    pub truncated: Vec<(String, usize, usize)>,
+}
+
+/// Outcome of `FileSystemManager::defragment`.
+#[derive(Debug, Default)]
+pub struct DefragReport {
+    /// Blocks whose contents were moved to another block.
+    pub blocks_relocated: usize,
+    /// Files with at least one relocated block.
+    pub files_moved: usize,
+    /// False if defragmentation stopped early because no spare block was free.
+    pub complete: bool,
+}
+
+/// What deleting a file would do, as returned by `FileSystemManager::plan_delete`.
+#[derive(Debug, Clone)]
+pub struct DeletePlan {
+    /// Data blocks that would be marked free, in chain order.
+    pub blocks_freed: Vec<usize>,
+    /// Aliases of deduplicated files sharing the blocks, which keep them.
+    pub shared_with: Vec<String>,
+    filenode_index: usize,
+    other_sharers: Vec<usize>,
+}
+
+/// What importing a tarball would do, as returned by `FileSystemManager::plan_import_tar`.
+#[derive(Debug, Clone, Default)]
+pub struct ImportPlan {
+    /// Files that would be created, as (alias, size).
+    pub created: Vec<(String, usize)>,
+    /// Existing files that would be overwritten, as (alias, new size).
+    pub overwritten: Vec<(String, usize)>,
+    /// Entries that would stop the import, with the reason.
+    pub rejected: Vec<(String, String)>,
+    /// Data blocks the imported contents would take up.
+    pub blocks_needed: usize,
+}
+
+/// A byte range within the filesystem file.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Region {
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// Physical location of each on-disk region, as returned by `FileSystemManager::layout`.
+/// The regions are contiguous and together cover the whole filesystem file.
+#[derive(Debug, Serialize)]
+pub struct Layout {
+    pub header: Region,
+    /// Journal recording metadata updates before they are applied.
+    pub journal: Region,
+    /// Space reserved for the filenode table.
+    pub filenode_table: Region,
+    /// Number of bytes the serialized filenode table currently occupies.
+    pub filenode_table_serialized_len: usize,
+    pub free_block_bitmap: Region,
+    pub data_blocks: Region,
+    /// Space after the last data block that is too small to hold another block.
+    pub unused_tail: Region,
+}
+
+/// Metadata of a single stored file, as returned by `FileSystemManager::get_file_info`.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileInfo {
+    pub alias: String,
+    /// Size of the file contents in bytes.
+    pub size: usize,
+    /// Number of data blocks holding the contents.
+    pub num_blocks: usize,
+    pub first_block_index: Option<usize>,
+    /// Unix time at which the file expires, if it has a time to live.
+    pub expires_at: Option<u64>,
+    /// Unix time at which the file was first stored.
+    pub created_at: u64,
+    /// Unix time at which the contents were last written.
+    pub modified_at: u64,
+    /// Whether the contents are stored compressed, and how many bytes they occupy.
+    pub compressed: bool,
+    pub stored_size: usize,
+    /// Whether the contents are encrypted and need a password to be read.
+    pub encrypted: bool,
+    /// Number of deduplicated files sharing the contents' blocks, this one included.
+    pub refcount: u32,
+    /// Whether the entry is a directory rather than a file.
+    pub is_directory: bool,
+    /// Whether the alias is a symbolic link. `get_file_info` describes the file it points
+    /// to instead; listings describe the link itself.
+    pub is_symlink: bool,
+    /// MIME type of the contents. Directories have none.
+    pub content_type: Option<String>,
+    /// Note about the file, if one was added.
+    pub description: Option<String>,
+}
+
+/// Size bounds for `FileSystemManager::find_files`. Both bounds are exclusive, and
+/// directories never match once a bound is set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeFilter {
+    pub larger_than: Option<usize>,
+    pub smaller_than: Option<usize>,
+}
+
+impl SizeFilter {
+    /// Returns true if the entry described by `info` is within the bounds.
+    pub fn matches(&self, info: &FileInfo) -> bool {
+        if self.larger_than.is_none() && self.smaller_than.is_none() {
+            return true;
+        }
+        !info.is_directory
+            && self.larger_than.is_none_or(|min| info.size > min)
+            && self.smaller_than.is_none_or(|max| info.size < max)
+    }
+}
+
+/// What `FileSystemManager::grep_file` looks for in each line.
+#[derive(Debug, Clone)]
+pub enum LinePattern {
+    /// Lines containing these bytes.
+    Substring(Vec<u8>),
+    /// Lines matching a regular expression. Lines need not be valid UTF-8.
+    Regex(regex::bytes::Regex),
+}
+
+impl LinePattern {
+    /// Compiles `pattern` as a regular expression.
+    pub fn regex(pattern: &str) -> Result<Self, FsError> {
+        regex::bytes::Regex::new(pattern)
+            .map(LinePattern::Regex)
+            .map_err(|e| FsError::InvalidArgument(format!("Invalid regular expression: {}", e)))
+    }
+
+    fn is_match(&self, line: &[u8]) -> bool {
+        match self {
+            LinePattern::Substring(text) => line
+                .windows(text.len())
+                .any(|candidate| candidate == text.as_slice()),
+            LinePattern::Regex(regex) => regex.is_match(line),
+        }
+    }
+}
+
+/// Splits the bytes written to it into lines and keeps the ones matching a pattern, so a
+/// file can be searched as it is streamed.
+struct LineGrep<'a> {
+    pattern: &'a LinePattern,
+    line: Vec<u8>,      // Bytes of the line read so far
+    line_number: usize, // Number of complete lines seen
+    matches: Vec<(usize, Vec<u8>)>,
+}
+
+impl LineGrep<'_> {
+    fn end_line(&mut self) {
+        self.line_number += 1;
+        if self.pattern.is_match(&self.line) {
+            self.matches
+                .push((self.line_number, std::mem::take(&mut self.line)));
+        } else {
+            self.line.clear();
+        }
+    }
+}
+
+impl Write for LineGrep<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+            self.line.extend_from_slice(&rest[..end]);
+            self.end_line();
+            rest = &rest[end + 1..];
+        }
+        self.line.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Order in which `FileSystemManager::list_files_sorted` returns files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortBy {
+    Name,
+    Size,
+    /// Filenode table order.
+    #[default]
+    None,
+}
+
+impl std::str::FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(SortBy::Name),
+            "size" => Ok(SortBy::Size),
+            "none" => Ok(SortBy::None),
+            _ => Err(format!(
+                "Unknown sort order '{}'. Expected name, size or none.",
+                s
+            )),
+        }
+    }
+}
+
+/// How `FileSystemManager` picks the data blocks of a new file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocStrategy {
+    /// Take the lowest-numbered free blocks, wherever they are.
+    #[default]
+    FirstFit,
+    /// Take the smallest run of contiguous free blocks the file fits in, falling back to
+    /// first fit when no run is large enough.
+    Contiguous,
+}
+
+impl std::str::FromStr for AllocStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first-fit" => Ok(AllocStrategy::FirstFit),
+            "contiguous" => Ok(AllocStrategy::Contiguous),
+            _ => Err(format!(
+                "Unknown allocation strategy '{}'. Expected first-fit or contiguous.",
+                s
+            )),
+        }
+    }
+}
+
+/// Space statistics of a filesystem, as returned by `FileSystemManager::usage`.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct UsageStats {
+    pub total_blocks: usize,
+    pub used_blocks: usize,
+    pub free_blocks: usize,
+    /// Bytes of file contents the data blocks can hold in total.
+    pub total_usable_bytes: usize,
+    /// Sum of the sizes of all unexpired files.
+    pub used_bytes: usize,
+    pub num_files: usize,
+    /// Filenodes that can still take a new file, including reusable tombstones.
+    pub free_filenodes: usize,
+}
+
+/// Space a stored file takes up in data blocks, as returned by
+/// `FileSystemManager::disk_usage`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskUsage {
+    pub alias: String,
+    /// Size of the file contents in bytes.
+    pub size: usize,
+    pub num_blocks: usize,
+    /// Bytes of the blocks holding the file, next pointers included.
+    pub disk_bytes: usize,
+    /// Bytes of those blocks not holding stored contents: the next pointers and the unused
+    /// tail of the last block.
+    pub slack: usize,
+    /// Whether deduplicated files share the blocks, which are counted for each of them.
+    pub shared: bool,
+}
+
+/// How scattered files and free space are, as returned by
+/// `FileSystemManager::fragmentation_report`.
+#[derive(Debug, Clone, Default)]
+pub struct FragReport {
+    /// Per file: alias, number of block transitions and how many of them are jumps.
+    pub files: Vec<(String, usize, usize)>,
+    /// Block transitions across all files, and how many of them are jumps.
+    pub total_transitions: usize,
+    pub non_contiguous_transitions: usize,
+    /// Number of separate runs of free blocks, and the length of the longest one.
+    pub free_runs: usize,
+    pub largest_free_run: usize,
+}
+
+impl FragReport {
+    /// Share of block transitions that are jumps, as a percentage.
+    pub fn percentage(&self) -> f64 {
+        self.non_contiguous_transitions as f64 * 100.0 / self.total_transitions.max(1) as f64
+    }
+
+    /// Number of files with at least one jump in their chain.
+    pub fn fragmented_files(&self) -> usize {
+        self.files.iter().filter(|(_, _, jumps)| *jumps > 0).count()
+    }
+}
+
+/// Usage of the alias space reserved in filenode slots, as returned by
+/// `FileSystemManager::alias_space_stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct AliasSpaceStats {
+    /// Average alias length of the stored files, in bytes.
+    pub average_alias_len: f64,
+    /// Reserved alias bytes across the whole filenode table not holding a stored alias.
+    pub wasted_bytes: usize,
+}
+
+/// Options used when uploading a file.
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+    /// Number of seconds after which the file expires.
+    pub ttl: Option<u64>,
+    /// Replace the contents of an existing file with the same alias instead of failing.
+    pub overwrite: bool,
+    /// Store the contents zstd-compressed if that makes them smaller.
+    pub compress: bool,
+    /// Encrypt the (possibly compressed) contents with a key derived from this password.
+    pub password: Option<String>,
+    /// Share the blocks of an existing file with identical stored contents instead of
+    /// writing them again.
+    pub dedup: bool,
+    /// MIME type to record for the file. Guessed from the alias's extension if not set.
+    pub content_type: Option<String>,
+    /// Note to attach to the file. An overwritten file keeps its note if not set.
+    pub description: Option<String>,
+}
+
+/// Options for `FileSystemManager::benchmark`.
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Settings of the throwaway filesystem the benchmark runs in.
+    pub init: InitOptions,
+    /// Number of files uploaded and then deleted.
+    pub num_files: usize,
+    /// Size of each file in bytes.
+    pub file_size: usize,
+    /// How blocks are picked for each file.
+    pub alloc_strategy: AllocStrategy,
+}
+
+/// Timings measured by `FileSystemManager::benchmark`.
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub num_files: usize,
+    pub bytes: usize,
+    /// Wall time of all the uploads.
+    pub upload_time: Duration,
+    /// Wall time of all the deletes.
+    pub delete_time: Duration,
+    /// Part of the upload time spent finding free blocks.
+    pub alloc_time: Duration,
+}
+
+impl BenchReport {
+    /// Upload time not spent allocating, i.e. writing blocks and metadata.
+    pub fn io_time(&self) -> Duration {
+        self.upload_time.saturating_sub(self.alloc_time)
+    }
+}
+
+/// Options used when creating a new filesystem.
+#[derive(Debug, Clone)]
+pub struct InitOptions {
+    /// Maximum number of files the filesystem can hold.
+    pub num_filenodes: usize,
+    /// Byte used to fill the unused tail of data blocks.
+    pub fill_byte: u8,
+    /// Total size of the filesystem in bytes.
+    pub total_size: usize,
+    /// Size of each data block in bytes, including its next pointer.
+    pub block_size: usize,
+    /// Longest alias allowed, in bytes of UTF-8.
+    pub max_alias_len: usize,
+}
+
+impl Default for InitOptions {
+    fn default() -> Self {
+        InitOptions {
+            num_filenodes: DEFAULT_FILENODE_COUNT,
+            fill_byte: 0,
+            total_size: FILESYSTEM_SIZE,
+            block_size: BLOCK_SIZE,
+            max_alias_len: MAX_FILENAME_LENGTH,
+        }
+    }
+}
+
 /// FileSystemManager handles the filesystem operations.
 pub struct FileSystemManager {
-    pub file: File,
+    storage: Box<dyn Storage>,
+    path: Option<PathBuf>, // File holding the filesystem, if it is stored in one
+    base_offset: u64,      // Byte offset of the filesystem within `storage`
     header: Header,
     filenodes: Vec<FileNode>,
-    free_block_bitmap: Vec<bool>, // In-memory: true = FREE, false = USED
+    slot_checksums: Vec<u32>, // CRC32 of each filenode slot as last written
+    free_block_bitmap: Vec<u64>, // In-memory, 64 blocks per word: set bit = FREE
+    free_block_count: usize,  // Number of FREE bits in `free_block_bitmap`
+    io_retries: u32,          // Extra attempts for block I/O hitting transient errors
+    alloc_strategy: AllocStrategy,
+    password: Option<String>, // Password used to read encrypted files
+    growable: bool,           // Whether the volume grows instead of running out of blocks
+    journal: Option<Vec<JournalWrite>>, // Metadata writes of the open transaction, if any
 }
 
-impl FileSystemManager {
-    pub fn init_filesystem() -> Result<Self, String> {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(FILESYSTEM_FILENAME)
-            .map_err(|e| format!("Failed to open/create {}: {}", FILESYSTEM_FILENAME, e))?;
+impl FileSystemManager {
+    pub fn init_filesystem() -> Result<Self, FsError> {
+        Self::init_filesystem_with(&InitOptions::default())
+    }
+
+    /// Returns the number of content bytes each data block holds.
+    fn usable_block_size(&self) -> usize {
+        self.header.block_size - NEXT_BLOCK_POINTER_SIZE
+    }
+
+    /// Returns the path of the file holding the filesystem, if it is stored in a file.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Calculates the on-disk layout for a filesystem created with `options`.
+    fn compute_header(options: &InitOptions) -> Result<Header, FsError> {
+        let num_filenodes = options.num_filenodes;
+        let total_size = options.total_size;
+        let block_size = options.block_size;
+        if num_filenodes == 0 {
+            return Err(FsError::InvalidArgument(
+                "Filesystem must have at least one filenode.".to_string(),
+            ));
+        }
+        if options.max_alias_len == 0 {
+            return Err(FsError::InvalidArgument(
+                "Maximum alias length must be at least one byte.".to_string(),
+            ));
+        }
+        if block_size <= NEXT_BLOCK_POINTER_SIZE {
+            return Err(FsError::InvalidArgument(format!(
+                "Block size must be larger than the {}-byte next block pointer.",
+                NEXT_BLOCK_POINTER_SIZE
+            )));
+        }
+        let header_size: usize = Header::max_serialized_size();
+
+        // The table is a u64 node count followed by a fixed-size slot per node
+        let slot_size = FileNode::slot_size(options.max_alias_len);
+        let serialized_filenode_table_bytes: usize =
+            std::mem::size_of::<u64>() + (num_filenodes * slot_size);
+
+        // Calculate tentative offsets to determine the number of data blocks and bitmap size.
+        let tentative_data_blocks_offset_for_calc: usize =
+            header_size + serialized_filenode_table_bytes;
+        let tentative_num_data_blocks_for_calc: usize =
+            (total_size.saturating_sub(tentative_data_blocks_offset_for_calc)) / block_size;
+        let bitmap_size_bytes: usize = tentative_num_data_blocks_for_calc.div_ceil(8);
+        let journal_size = journal::journal_size(
+            header_size,
+            serialized_filenode_table_bytes,
+            bitmap_size_bytes,
+        );
+
+        // Calculate actual offsets based on the above calculations.
+        let actual_filenode_table_offset: usize = header_size + journal_size;
+        let actual_free_block_bitmap_offset: usize =
+            actual_filenode_table_offset + serialized_filenode_table_bytes;
+        let actual_data_blocks_offset: usize = actual_free_block_bitmap_offset + bitmap_size_bytes;
+
+        let actual_num_data_blocks: usize = if total_size > actual_data_blocks_offset {
+            (total_size - actual_data_blocks_offset) / block_size
+        } else {
+            0
+        };
+
+        if actual_num_data_blocks == 0 {
+            return Err(FsError::InvalidArgument(format!(
+                "A {}-byte filesystem with {}-byte blocks and {} filenodes has no room for data blocks.",
+                total_size, block_size, num_filenodes
+            )));
+        }
+
+        // Creates the header with the calculated offsets and sizes.
+        Ok(Header {
+            version: FILESYSTEM_VERSION,
+            total_size,
+            block_size,
+            filenode_table_offset: actual_filenode_table_offset,
+            filenode_table_size: num_filenodes,
+            free_block_bitmap_offset: actual_free_block_bitmap_offset,
+            data_blocks_offset: actual_data_blocks_offset,
+            num_data_blocks: actual_num_data_blocks,
+            fill_byte: options.fill_byte,
+            generation: 0,
+            tombstone_floor: 0,
+            merkle_root: None,
+            merkle_generation: 0,
+            max_alias_len: options.max_alias_len,
+            max_data_blocks: actual_num_data_blocks,
+            journal_offset: header_size,
+            journal_size,
+            // Filled in when the new table and bitmap are first written
+            filenode_table_checksum: 0,
+            bitmap_checksum: 0,
+        })
+    }
+
+    /// Initialises a fresh filesystem in `myfs.dat` using the given options.
+    pub fn init_filesystem_with(options: &InitOptions) -> Result<Self, FsError> {
+        Self::init_filesystem_at(Path::new(FILESYSTEM_FILENAME), 0, options)
+    }
+
+    /// Initialises a fresh filesystem stored at byte `base_offset` of the file at `path`.
+    /// Bytes before the volume are left untouched. The file is locked exclusively until
+    /// the manager is dropped.
+    pub fn init_filesystem_at(
+        path: &Path,
+        base_offset: u64,
+        options: &InitOptions,
+    ) -> Result<Self, FsError> {
+        // Options without room for a data block are rejected before the file is created
+        Self::compute_header(options)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| FsError::io(format!("Failed to open/create {}", path.display()), e))?;
+        lock_exclusive(&file, path)?;
+        Self::init_volume(
+            Box::new(FileStorage::new(file)),
+            Some(path.to_path_buf()),
+            base_offset,
+            options,
+        )
+    }
+
+    /// Initialises a fresh filesystem at the start of `storage`, e.g. a `MemStorage` to
+    /// keep the whole filesystem in memory.
+    pub fn init_in_storage(
+        storage: Box<dyn Storage>,
+        options: &InitOptions,
+    ) -> Result<Self, FsError> {
+        Self::init_volume(storage, None, 0, options)
+    }
+
+    fn init_volume(
+        storage: Box<dyn Storage>,
+        path: Option<PathBuf>,
+        base_offset: u64,
+        options: &InitOptions,
+    ) -> Result<Self, FsError> {
+        let mut manager = FileSystemManager {
+            storage,
+            path,
+            base_offset,
+            header: Self::compute_header(options)?,
+            filenodes: Vec::new(),
+            slot_checksums: Vec::new(),
+            free_block_bitmap: Vec::new(),
+            free_block_count: 0,
+            io_retries: 0,
+            alloc_strategy: AllocStrategy::default(),
+            password: None,
+            growable: false,
+            journal: None,
+        };
+        manager.reset(options)?;
+        Ok(manager)
+    }
+
+    /// Replaces the filesystem with an empty one laid out for `options`, in the same storage
+    /// and at the same offset, keeping the lock on its file.
+    pub fn reinitialise(&mut self, options: &InitOptions) -> Result<(), FsError> {
+        self.reset(options)
+    }
+
+    /// Replaces the filesystem with an empty one laid out for `options` and writes it out,
+    /// extending the storage if the new volume does not fit.
+    fn reset(&mut self, options: &InitOptions) -> Result<(), FsError> {
+        let header = Self::compute_header(options)?;
+        let volume_end = self.base_offset + header.total_size as u64;
+        let storage_len = self
+            .storage
+            .len()
+            .map_err(|e| FsError::io("Failed to get storage length", e))?;
+        if storage_len < volume_end {
+            self.storage
+                .set_len(volume_end)
+                .map_err(|e| FsError::io("Failed to set storage length", e))?;
+        }
+
+        // All filenodes are empty and every block is free
+        self.filenodes = vec![FileNode::new(); options.num_filenodes];
+        self.free_block_bitmap = all_free_bitmap(header.num_data_blocks);
+        self.free_block_count = header.num_data_blocks;
+        self.header = header;
+        journal::clear(
+            self.storage.as_mut(),
+            self.base_offset,
+            self.header.journal_offset,
+        )?;
+        self.save_header()?;
+        self.save_filenodes()?;
+        self.write_bitmap_to_disk()
+    }
+
+    /// Opens an existing filesystem stored at byte `base_offset` of the file at `path`,
+    /// holding an exclusive lock on the file until the manager is dropped. Opening fails
+    /// while another manager, read-only or not, has the file open.
+    pub fn open_at(path: &Path, base_offset: u64) -> Result<Self, FsError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| FsError::io(format!("Failed to open {}", path.display()), e))?;
+        lock_exclusive(&file, path)?;
+        Self::open_volume(
+            Box::new(FileStorage::new(file)),
+            Some(path.to_path_buf()),
+            base_offset,
+            true,
+        )
+    }
+
+    /// Opens the filesystem in the file at `path` for reading only, holding a shared lock
+    /// on the file until the manager is dropped, so other readers can open it but no
+    /// writer. Anything that changes the filesystem
+    /// fails, as does opening one with an interrupted update left in its journal. A volume
+    /// in an older layout is read as it is rather than upgraded.
+    pub fn open_read_only(path: &Path) -> Result<Self, FsError> {
+        let file = File::open(path)
+            .map_err(|e| FsError::io(format!("Failed to open {}", path.display()), e))?;
+        file.try_lock_shared().map_err(|e| {
+            FsError::io(
+                format!("Failed to lock {}", path.display()),
+                io::Error::from(e),
+            )
+        })?;
+        Self::open_volume(
+            Box::new(FileStorage::new(file)),
+            Some(path.to_path_buf()),
+            0,
+            false,
+        )
+    }
+
+    /// Opens an existing filesystem stored at the start of `storage`.
+    pub fn open_storage(storage: Box<dyn Storage>) -> Result<Self, FsError> {
+        Self::open_volume(storage, None, 0, true)
+    }
+
+    /// Loads the volume at `base_offset` of `storage`. Volumes in an older layout are
+    /// upgraded if `writable`, and otherwise kept in that layout on disk.
+    fn open_volume(
+        mut storage: Box<dyn Storage>,
+        path: Option<PathBuf>,
+        base_offset: u64,
+        writable: bool,
+    ) -> Result<Self, FsError> {
+        let mut version = [0u8; std::mem::size_of::<u32>()];
+        storage
+            .read_at(base_offset, &mut version)
+            .map_err(|e| FsError::io("Read failed (header)", e))?;
+        if u32::from_le_bytes(version) == BASELINE_VERSION {
+            let mut manager = Self::open_baseline_volume(storage, path, base_offset)?;
+            if writable {
+                manager.upgrade()?;
+            }
+            return Ok(manager);
+        }
+
+        // The header is read field by field, so its encoded size does not need to be known
+        let mut header_reader = StorageReader::new(storage.as_mut(), base_offset);
+        let header: Header = bincode::deserialize_from(&mut header_reader)
+            .map_err(|e| FsError::Serialization(format!("Failed to deserialize header: {}", e)))?;
+        let header_len = header_reader.position() - base_offset;
+
+        // Volumes in an older layout that can still be read are upgraded once loaded
+        if !(OLDEST_UPGRADABLE_VERSION..=FILESYSTEM_VERSION).contains(&header.version) {
+            return Err(FsError::Incompatible {
+                field: "version",
+                expected: FILESYSTEM_VERSION as usize,
+                found: header.version as usize,
+            });
+        }
+        // Sizes come from the header, so they only need to be plausible
+        if header.block_size <= NEXT_BLOCK_POINTER_SIZE
+            || (header.journal_offset as u64) < header_len
+            || header.journal_offset + header.journal_size > header.filenode_table_offset
+            || header.filenode_table_offset > header.free_block_bitmap_offset
+            || header.data_blocks_offset + header.num_data_blocks * header.block_size
+                > header.total_size
+        {
+            return Err(FsError::Corrupt(format!(
+                "Header describes an impossible layout (size {}, block size {}, {} data blocks).",
+                header.total_size, header.block_size, header.num_data_blocks
+            )));
+        }
+
+        // An update interrupted after it was journaled is finished before anything is
+        // loaded, and may have changed the header itself
+        if journal::recover(
+            storage.as_mut(),
+            base_offset,
+            header.journal_offset,
+            header.journal_size,
+        )? {
+            return Self::open_volume(storage, path, base_offset, writable);
+        }
+
+        let tables = Self::read_tables(storage.as_mut(), base_offset, &header)?;
+        let mut manager = FileSystemManager {
+            storage,
+            path,
+            base_offset,
+            header,
+            filenodes: tables.filenodes,
+            slot_checksums: tables.slot_checksums,
+            free_block_bitmap: tables.free_block_bitmap,
+            free_block_count: tables.free_block_count,
+            io_retries: 0,
+            alloc_strategy: AllocStrategy::default(),
+            password: None,
+            growable: false,
+            journal: None,
+        };
+        if writable && manager.header.version < FILESYSTEM_VERSION {
+            manager.upgrade()?;
+        }
+        Ok(manager)
+    }
+
+    /// Reads and checks the filenode table and bitmap of the volume at `base_offset`, laid
+    /// out as `header` describes.
+    fn read_tables(
+        storage: &mut dyn Storage,
+        base_offset: u64,
+        header: &Header,
+    ) -> Result<Tables, FsError> {
+        // The table is followed by unused reserved space up to the bitmap
+        let mut table_data =
+            vec![0u8; header.free_block_bitmap_offset - header.filenode_table_offset];
+        storage
+            .read_at(
+                base_offset + header.filenode_table_offset as u64,
+                &mut table_data,
+            )
+            .map_err(|e| FsError::io("Read failed (load filenodes)", e))?;
+        let mut count_bytes = [0u8; std::mem::size_of::<u64>()];
+        if let Some(prefix) = table_data.get(..count_bytes.len()) {
+            count_bytes.copy_from_slice(prefix);
+        }
+        let node_count = u64::from_le_bytes(count_bytes) as usize;
+        if node_count != header.filenode_table_size {
+            return Err(FsError::Corrupt(format!(
+                "Filenode count mismatch after deserialize. Header: {}, Actual: {}.",
+                header.filenode_table_size, node_count
+            )));
+        }
+        let slot_size = FileNode::slot_size_for_version(header.version, header.max_alias_len);
+        let slots = table_data
+            .get(count_bytes.len()..)
+            .filter(|slots| {
+                node_count
+                    .checked_mul(slot_size)
+                    .is_some_and(|len| len <= slots.len())
+            })
+            .ok_or_else(|| {
+                FsError::Corrupt(format!(
+                    "Filenode table is too small for {} filenodes with {}-byte aliases.",
+                    node_count, header.max_alias_len
+                ))
+            })?;
+        // Damaged metadata is refused rather than decoded into nonsense
+        let slots = &slots[..node_count * slot_size];
+        let slot_checksums: Vec<u32> = slots.chunks_exact(slot_size).map(crc32fast::hash).collect();
+        if filenode_table_checksum(&slot_checksums) != header.filenode_table_checksum {
+            return Err(FsError::Corrupt(
+                "Filenode table does not match its checksum in the header.".to_string(),
+            ));
+        }
+        let filenodes = slots
+            .chunks_exact(slot_size)
+            .map(|slot| FileNode::from_slot(slot, header.version))
+            .collect::<Result<Vec<FileNode>, _>>()
+            .map_err(|e| {
+                FsError::Serialization(format!("Deserialize failed (load filenodes): {}", e))
+            })?;
+
+        let bitmap_size_bytes = header.num_data_blocks.div_ceil(8);
+        let mut disk_bitmap_bytes = vec![0u8; bitmap_size_bytes];
+        storage
+            .read_at(
+                base_offset + header.free_block_bitmap_offset as u64,
+                &mut disk_bitmap_bytes,
+            )
+            .map_err(|e| FsError::io("Read failed (load bitmap)", e))?;
+        if crc32fast::hash(&disk_bitmap_bytes) != header.bitmap_checksum {
+            return Err(FsError::Corrupt(
+                "Free block bitmap does not match its checksum in the header.".to_string(),
+            ));
+        }
+
+        let (free_block_bitmap, free_block_count) =
+            bitmap_from_disk(&disk_bitmap_bytes, header.num_data_blocks);
+        Ok(Tables {
+            filenodes,
+            slot_checksums,
+            free_block_bitmap,
+            free_block_count,
+        })
+    }
+
+    /// Reads the header, filenode table and bitmap back from disk, dropping any changes
+    /// made in memory since they were last written. Nothing changes if they cannot be read.
+    fn reload_metadata(&mut self) -> Result<(), FsError> {
+        let header: Header =
+            bincode::deserialize_from(StorageReader::new(self.storage.as_mut(), self.base_offset))
+                .map_err(|e| {
+                    FsError::Serialization(format!("Failed to deserialize header: {}", e))
+                })?;
+        let tables = Self::read_tables(self.storage.as_mut(), self.base_offset, &header)?;
+        self.header = header;
+        self.filenodes = tables.filenodes;
+        self.slot_checksums = tables.slot_checksums;
+        self.free_block_bitmap = tables.free_block_bitmap;
+        self.free_block_count = tables.free_block_count;
+        Ok(())
+    }
+
+    /// Loads a volume in the baseline layout, which has no journal, no checksums and no
+    /// file metadata beyond aliases and sizes. Every file is read once as it is loaded to
+    /// fill in its checksum and content hash, so that it can be verified like any other.
+    fn open_baseline_volume(
+        mut storage: Box<dyn Storage>,
+        path: Option<PathBuf>,
+        base_offset: u64,
+    ) -> Result<Self, FsError> {
+        let old: BaselineHeader =
+            bincode::deserialize_from(StorageReader::new(storage.as_mut(), base_offset)).map_err(
+                |e| FsError::Serialization(format!("Failed to deserialize header: {}", e)),
+            )?;
+        let bitmap_size_bytes = old.num_data_blocks.div_ceil(8);
+        if old.block_size <= NEXT_BLOCK_POINTER_SIZE
+            || old.filenode_table_offset > old.free_block_bitmap_offset
+            || old.free_block_bitmap_offset + bitmap_size_bytes > old.data_blocks_offset
+            || old.data_blocks_offset + old.num_data_blocks * old.block_size > old.total_size
+        {
+            return Err(FsError::Corrupt(format!(
+                "Header describes an impossible layout (size {}, block size {}, {} data blocks).",
+                old.total_size, old.block_size, old.num_data_blocks
+            )));
+        }
+
+        // Nodes vary in length, from the 266 bytes of one without a first block
+        let mut table_reader = StorageReader::new(
+            storage.as_mut(),
+            base_offset + old.filenode_table_offset as u64,
+        );
+        let mut count_bytes = [0u8; std::mem::size_of::<u64>()];
+        table_reader
+            .read_exact(&mut count_bytes)
+            .map_err(|e| FsError::io("Read failed (load filenodes)", e))?;
+        let node_count = u64::from_le_bytes(count_bytes) as usize;
+        let table_room = old.free_block_bitmap_offset - old.filenode_table_offset;
+        if node_count != old.filenode_table_size
+            || node_count.saturating_mul(MAX_FILENAME_LENGTH + 11) > table_room
+        {
+            return Err(FsError::Corrupt(format!(
+                "Filenode count mismatch after deserialize. Header: {}, Actual: {}.",
+                old.filenode_table_size, node_count
+            )));
+        }
+        let filenodes = (0..node_count)
+            .map(|_| FileNode::read_baseline(&mut table_reader))
+            .collect::<io::Result<Vec<FileNode>>>()
+            .map_err(|e| {
+                FsError::Serialization(format!("Deserialize failed (load filenodes): {}", e))
+            })?;
+
+        let mut disk_bitmap_bytes = vec![0u8; bitmap_size_bytes];
+        storage
+            .read_at(
+                base_offset + old.free_block_bitmap_offset as u64,
+                &mut disk_bitmap_bytes,
+            )
+            .map_err(|e| FsError::io("Read failed (load bitmap)", e))?;
+        let (free_block_bitmap, free_block_count) =
+            bitmap_from_disk(&disk_bitmap_bytes, old.num_data_blocks);
+
+        let mut manager = FileSystemManager {
+            storage,
+            path,
+            base_offset,
+            header: Header {
+                version: BASELINE_VERSION,
+                total_size: old.total_size,
+                block_size: old.block_size,
+                filenode_table_offset: old.filenode_table_offset,
+                filenode_table_size: old.filenode_table_size,
+                free_block_bitmap_offset: old.free_block_bitmap_offset,
+                data_blocks_offset: old.data_blocks_offset,
+                num_data_blocks: old.num_data_blocks,
+                fill_byte: 0,
+                generation: 0,
+                tombstone_floor: 0,
+                merkle_root: None,
+                merkle_generation: 0,
+                max_alias_len: MAX_FILENAME_LENGTH,
+                max_data_blocks: old.num_data_blocks,
+                journal_offset: old.filenode_table_offset,
+                journal_size: 0,
+                filenode_table_checksum: 0,
+                bitmap_checksum: 0,
+            },
+            slot_checksums: vec![0; filenodes.len()],
+            filenodes,
+            free_block_bitmap,
+            free_block_count,
+            io_retries: 0,
+            alloc_strategy: AllocStrategy::default(),
+            password: None,
+            growable: false,
+            journal: None,
+        };
+        for index in 0..manager.filenodes.len() {
+            if !manager.filenodes[index].is_used {
+                continue;
+            }
+            let node = manager.filenodes[index].clone();
+            let mut stored = Vec::with_capacity(node.size);
+            manager.filenodes[index].checksum = manager.stream_chain(&node, &mut stored)?;
+            manager.filenodes[index].content_hash = Sha256::digest(&stored).into();
+        }
+        Ok(manager)
+    }
+
+    /// Returns the options that lay the volume out again in the current layout with as many
+    /// data blocks as it has now, growing it if the new layout needs more room for metadata.
+    fn upgrade_options(&self) -> Result<InitOptions, FsError> {
+        let mut options = InitOptions {
+            num_filenodes: self.header.filenode_table_size,
+            fill_byte: self.header.fill_byte,
+            total_size: self.header.total_size,
+            block_size: self.header.block_size,
+            max_alias_len: self.header.max_alias_len,
+        };
+        while Self::compute_header(&options)?.num_data_blocks < self.header.num_data_blocks {
+            options.total_size += self.header.block_size;
+        }
+        Ok(options)
+    }
+
+    /// Rewrites a volume loaded from an older layout in the current one, keeping its files,
+    /// their metadata and the deletion history. The volume grows if the new layout needs
+    /// more room for metadata, so that it keeps as many data blocks as before; one that is
+    /// followed by other data in its storage is refused instead.
+    ///
+    /// Like `grow_filenode_table`, this holds every file in memory while the volume is
+    /// rewritten, and an interruption part way through loses the files not yet written.
+    fn upgrade(&mut self) -> Result<(), FsError> {
+        let old_version = self.header.version;
+        let options = self.upgrade_options()?;
+        if options.total_size > self.header.total_size && !self.volume_ends_storage()? {
+            return Err(FsError::InvalidArgument(format!(
+                "Filesystem in layout version {} needs {} more bytes to be upgraded, but other \
+                 data follows it.",
+                old_version,
+                options.total_size - self.header.total_size
+            )));
+        }
+        self.rebuild(&options)?;
+        info!(
+            "Upgraded filesystem from layout version {} to {}",
+            old_version, FILESYSTEM_VERSION
+        );
+        Ok(())
+    }
+
+    /// Returns the index of a filenode that can hold a new file. Empty nodes are preferred;
+    /// otherwise the oldest tombstone is reused.
+    fn find_free_filenode_index(&self) -> Option<usize> {
+        self.filenodes
+            .iter()
+            .position(|node| !node.is_used && !node.is_tombstone())
+            .or_else(|| {
+                self.filenodes
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, node)| node.is_tombstone())
+                    .min_by_key(|(_, node)| node.generation)
+                    .map(|(index, _)| index)
+            })
+    }
+
+    /// Resets the filenode at `index` so it can hold a new file, remembering when a
+    /// tombstone is overwritten so incremental exports know which deletions are lost.
+    fn claim_filenode(&mut self, index: usize) {
+        let node = &self.filenodes[index];
+        if node.is_tombstone() {
+            self.header.tombstone_floor = self.header.tombstone_floor.max(node.generation);
+        }
+        self.filenodes[index] = FileNode::new();
+    }
+
+    /// Advances the filesystem generation and stamps the filenode at `index` with it.
+    /// The header is written immediately; the caller saves the filenode table.
+    fn record_change(&mut self, index: usize) -> Result<(), FsError> {
+        self.header.generation += 1;
+        self.filenodes[index].generation = self.header.generation;
+        self.save_header()
+    }
+
+    /// Writes the header to the beginning of the file.
+    fn save_header(&mut self) -> Result<(), FsError> {
+        let header_bytes = bincode::serialize(&self.header).map_err(|e| {
+            FsError::Serialization(format!("Serialize failed (write_header): {}", e))
+        })?;
+        if header_bytes.len() > self.header.journal_offset {
+            return Err(FsError::Serialization(format!(
+                "Header takes {} bytes but only {} are reserved for it.",
+                header_bytes.len(),
+                self.header.journal_offset
+            )));
+        }
+        self.write_metadata(0, header_bytes)
+            .map_err(|e| FsError::io("Write failed (write_header)", e))
+    }
+
+    /// Writes `bytes` of metadata at `offset` within the volume, or adds them to the open
+    /// transaction. A transaction only keeps the last write to each place.
+    fn write_metadata(&mut self, offset: usize, bytes: Vec<u8>) -> io::Result<()> {
+        match &mut self.journal {
+            Some(writes) => {
+                writes.retain(|(o, b)| (*o, b.len()) != (offset, bytes.len()));
+                writes.push((offset, bytes));
+                Ok(())
+            }
+            None => self
+                .storage
+                .write_at(self.base_offset + offset as u64, &bytes),
+        }
+    }
+
+    /// Runs `f` as a transaction: the header, filenode and bitmap writes it makes are
+    /// recorded in the journal before any of them is applied, so an interruption leaves
+    /// either all or none of them on disk. If `f` fails, or the record cannot be written,
+    /// none of them is and the metadata is read back from disk, dropping the changes made
+    /// in memory. Nested transactions join the outer one.
+    fn transaction<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        if self.journal.is_some() {
+            return f(self);
+        }
+        self.journal = Some(Vec::new());
+        let result = f(self);
+        let writes = self.journal.take().unwrap_or_default();
+        let committed = match result {
+            Ok(value) => self.commit(&writes).map(|_| value),
+            Err(e) => Err(e),
+        };
+        if committed.is_err() {
+            if let Err(e) = self.reload_metadata() {
+                warn!("Failed to roll back an update that did not complete: {}", e);
+            }
+        }
+        committed
+    }
+
+    /// Writes the journal record for `writes`, applies them and clears the journal. The
+    /// journal has room for any transaction once its writes are merged, except on volumes
+    /// laid out before it was sized for that; a record too large for it is refused before
+    /// anything is written.
+    fn commit(&mut self, writes: &[JournalWrite]) -> Result<(), FsError> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let writes = journal::coalesce(self.storage.as_mut(), self.base_offset, writes)
+            .map_err(|e| FsError::io("Read failed (journal)", e))?;
+        let record = journal::encode_record(&writes)?;
+        if record.len() > self.header.journal_size {
+            return Err(FsError::InvalidArgument(format!(
+                "The update takes {} bytes of metadata, more than the {}-byte journal holds. \
+                 Nothing was changed.",
+                record.len(),
+                self.header.journal_size
+            )));
+        }
+        debug!("Journaling {} metadata writes", writes.len());
+        self.storage
+            .write_at(
+                self.base_offset + self.header.journal_offset as u64,
+                &record,
+            )
+            .and_then(|_| self.storage.flush())
+            .map_err(|e| FsError::io("Write failed (journal)", e))?;
+        journal::apply(self.storage.as_mut(), self.base_offset, &writes)?;
+        journal::clear(
+            self.storage.as_mut(),
+            self.base_offset,
+            self.header.journal_offset,
+        )
+    }
+
+    /// Returns `num_blocks_needed` free blocks without marking them used. With
+    /// `prefer_contiguous`, the smallest free run they fit in is picked if there is one;
+    /// otherwise the lowest-numbered free blocks are.
+    ///
+    /// Returns None without scanning if the free block count is too low. Any free blocks
+    /// will do once the contiguous search fails, so with a correct count the scan below
+    /// always succeeds.
+    fn find_free_blocks(
+        &self,
+        num_blocks_needed: usize,
+        prefer_contiguous: bool,
+    ) -> Option<Vec<usize>> {
+        if num_blocks_needed > self.free_block_count {
+            return None;
+        }
+        if prefer_contiguous && num_blocks_needed > 0 {
+            if let Some(start) = self.find_best_fit_run(num_blocks_needed) {
+                return Some((start..start + num_blocks_needed).collect());
+            }
+        }
+        let mut free_blocks_indices = Vec::new();
+        if num_blocks_needed == 0 {
+            return Some(free_blocks_indices);
+        }
+        // Fully used words are zero and skipped without looking at their bits
+        for (word_index, &word) in self.free_block_bitmap.iter().enumerate() {
+            let mut free_bits = word;
+            while free_bits != 0 {
+                free_blocks_indices.push(word_index * 64 + free_bits.trailing_zeros() as usize);
+                if free_blocks_indices.len() == num_blocks_needed {
+                    return Some(free_blocks_indices);
+                }
+                free_bits &= free_bits - 1;
+            }
+        }
+        None
+    }
+
+    /// Explains why `find_free_blocks` found no `num_blocks` free blocks. Either there are
+    /// not enough, or the free block count has drifted from the bitmap, which is a bug;
+    /// debug builds stop on the latter.
+    fn allocation_failure(&self, num_blocks: usize) -> FsError {
+        if num_blocks > self.free_block_count {
+            return FsError::NotEnoughSpace {
+                needed: num_blocks,
+                available: self.free_block_count,
+            };
+        }
+        let free_in_bitmap: usize = self
+            .free_block_bitmap
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum();
+        debug_assert!(
+            false,
+            "free block count ({}) drifted from the bitmap ({} free) while allocating {} blocks",
+            self.free_block_count, free_in_bitmap, num_blocks
+        );
+        FsError::Corrupt(format!(
+            "{} free blocks were counted but the bitmap only has {}, so {} blocks could not be \
+             allocated. Reopen the filesystem or run fsck --repair.",
+            self.free_block_count, free_in_bitmap, num_blocks
+        ))
+    }
+
+    /// Finds free blocks for a whole chain of `num_blocks` blocks in one scan and marks
+    /// them used in memory, so nothing else is handed them while the chain is written.
+    /// Blocks of a chain that is not stored after all must be given back with
+    /// `release_blocks`.
+    fn reserve_blocks(
+        &mut self,
+        num_blocks: usize,
+        prefer_contiguous: bool,
+    ) -> Result<Vec<usize>, FsError> {
+        let Some(blocks) = self.find_free_blocks(num_blocks, prefer_contiguous) else {
+            return Err(self.allocation_failure(num_blocks));
+        };
+        for &block_index in &blocks {
+            self.set_block_free(block_index, false);
+        }
+        Ok(blocks)
+    }
+
+    /// Marks blocks reserved for a chain that was not stored as free again.
+    fn release_blocks(&mut self, blocks: &[usize]) {
+        for &block_index in blocks {
+            self.set_block_free(block_index, true);
+        }
+    }
+
+    /// Returns true if new chains should be placed in contiguous runs where possible.
+    fn prefers_contiguous(&self) -> bool {
+        self.alloc_strategy == AllocStrategy::Contiguous
+    }
+
+    /// Returns the start of the smallest run of contiguous free blocks holding at least
+    /// `num_blocks_needed` blocks. Ties go to the lowest-numbered run.
+    fn find_best_fit_run(&self, num_blocks_needed: usize) -> Option<usize> {
+        let mut best: Option<(usize, usize)> = None; // (start, length)
+        let mut run_start = 0;
+        for index in 0..=self.header.num_data_blocks {
+            if index < self.header.num_data_blocks && self.is_block_free(index) {
+                continue;
+            }
+            let run_len = index - run_start;
+            if run_len >= num_blocks_needed && best.is_none_or(|(_, len)| run_len < len) {
+                best = Some((run_start, run_len));
+            }
+            run_start = index + 1;
+        }
+        best.map(|(start, _)| start)
+    }
+
+    /// Returns true if data block `index` is marked free in the in-memory bitmap.
+    fn is_block_free(&self, index: usize) -> bool {
+        (self.free_block_bitmap[index / 64] >> (index % 64)) & 1 != 0
+    }
+
+    /// Marks data block `index` as free or used in the in-memory bitmap.
+    fn set_block_free(&mut self, index: usize, free: bool) {
+        if self.is_block_free(index) != free {
+            self.free_block_bitmap[index / 64] ^= 1 << (index % 64);
+            if free {
+                self.free_block_count += 1;
+            } else {
+                self.free_block_count -= 1;
+            }
+        }
+    }
+
+    /// Returns the number of free data blocks.
+    fn free_block_count(&self) -> usize {
+        debug_assert_eq!(
+            self.free_block_count,
+            self.free_block_bitmap
+                .iter()
+                .map(|word| word.count_ones() as usize)
+                .sum::<usize>(),
+            "cached free block count drifted from the bitmap"
+        );
+        self.free_block_count
+    }
+
+    /// Writes only the filenode at `index` to disk. Every filenode has the same encoded
+    /// size, so the node is rewritten in place after the table's length prefix. The
+    /// header is written with it for the new table checksum.
+    fn save_single_filenode(&mut self, index: usize) -> Result<(), FsError> {
+        let node_bytes = self.filenode_slot(index)?;
+        let node_offset = self.header.filenode_table_offset
+            + std::mem::size_of::<u64>()
+            + index * node_bytes.len();
+        self.slot_checksums[index] = crc32fast::hash(&node_bytes);
+        self.header.filenode_table_checksum = filenode_table_checksum(&self.slot_checksums);
+        self.transaction(|fs| {
+            fs.write_metadata(node_offset, node_bytes)
+                .map_err(|e| FsError::io(format!("Write failed (write_filenode {})", index), e))?;
+            fs.save_header()
+        })?;
+        self.storage
+            .flush()
+            .map_err(|e| FsError::io(format!("Flush failed (write_filenode {})", index), e))
+    }
+
+    /// Serializes filenode `index` into a slot-sized buffer, zero-padded after the node so
+    /// that no bytes of a longer previous alias are left behind.
+    fn filenode_slot(&self, index: usize) -> Result<Vec<u8>, FsError> {
+        let slot_size = FileNode::slot_size(self.header.max_alias_len);
+        let mut node_bytes = bincode::serialize(&self.filenodes[index]).map_err(|e| {
+            FsError::Serialization(format!(
+                "Serialize failed (write_filenode {}): {}",
+                index, e
+            ))
+        })?;
+        if node_bytes.len() > slot_size {
+            return Err(FsError::Serialization(format!(
+                "Filenode {} takes {} bytes but its slot holds {}.",
+                index,
+                node_bytes.len(),
+                slot_size
+            )));
+        }
+        node_bytes.resize(slot_size, 0);
+        Ok(node_bytes)
+    }
+
+    /// Writes the entire filenode table to disk, along with the header for its checksum.
+    fn save_filenodes(&mut self) -> Result<(), FsError> {
+        // The node count comes first, as bincode would write the length of a Vec
+        let mut table_bytes = (self.filenodes.len() as u64).to_le_bytes().to_vec();
+        self.slot_checksums.clear();
+        for index in 0..self.filenodes.len() {
+            let slot = self.filenode_slot(index)?;
+            self.slot_checksums.push(crc32fast::hash(&slot));
+            table_bytes.extend_from_slice(&slot);
+        }
+        self.header.filenode_table_checksum = filenode_table_checksum(&self.slot_checksums);
+        self.transaction(|fs| {
+            fs.write_metadata(fs.header.filenode_table_offset, table_bytes)
+                .map_err(|e| FsError::io("Write failed (write_all_filenodes)", e))?;
+            fs.save_header()
+        })?;
+
+        // Flush the storage to ensure all data is written.
+        self.storage
+            .flush()
+            .map_err(|e| FsError::io("Flush failed (write_all_filenodes)", e))
+    }
+
+    /// Writes the free block bitmap to disk, along with the header for its checksum.
+    fn write_bitmap_to_disk(&mut self) -> Result<(), FsError> {
+        self.write_bitmap_range(0..self.header.num_data_blocks)
+    }
+
+    /// Writes only the bitmap bytes holding the bits of `blocks`, from the lowest to the
+    /// highest of them, for updates that change a few blocks of a large bitmap.
+    fn write_bitmap_blocks(&mut self, blocks: &[usize]) -> Result<(), FsError> {
+        match (blocks.iter().min(), blocks.iter().max()) {
+            (Some(&first), Some(&last)) => self.write_bitmap_range(first..last + 1),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the bitmap bytes holding the bits of the blocks in `blocks` to disk, along
+    /// with the header. The checksum in the header still covers the whole bitmap.
+    fn write_bitmap_range(&mut self, blocks: Range<usize>) -> Result<(), FsError> {
+        // Calculate the size of the bitmap in bytes.
+        let bitmap_size_bytes: usize = self.header.num_data_blocks.div_ceil(8);
+
+        // Create a byte array to represent the bitmap.
+        let mut disk_bitmap_bytes: Vec<u8> = vec![0; bitmap_size_bytes];
+
+        // Set the bits in the byte array based on the free block bitmap.
+        for i in 0..self.header.num_data_blocks {
+            if !self.is_block_free(i) {
+                disk_bitmap_bytes[i / 8] |= 1 << (i % 8);
+            }
+        }
+
+        // Write the bytes covering `blocks` at their offset in the storage.
+        let start = (blocks.start / 8).min(bitmap_size_bytes);
+        let end = blocks.end.div_ceil(8).clamp(start, bitmap_size_bytes);
+        debug!(
+            "Writing {} of {} bitmap bytes",
+            end - start,
+            bitmap_size_bytes
+        );
+        self.header.bitmap_checksum = crc32fast::hash(&disk_bitmap_bytes);
+        let dirty_bytes = disk_bitmap_bytes[start..end].to_vec();
+        self.transaction(|fs| {
+            fs.write_metadata(fs.header.free_block_bitmap_offset + start, dirty_bytes)
+                .map_err(|e| FsError::io("Write failed (write_bitmap)", e))?;
+            fs.save_header()
+        })?;
+
+        // Flush the storage to ensure all data is written.
+        self.storage
+            .flush()
+            .map_err(|e| FsError::io("Flush failed (write_bitmap)", e))
+    }
+
+    /// Returns the index of the used filenode with the given alias, if any.
+    fn find_filenode_index(&self, alias: &str) -> Option<usize> {
+        self.filenodes
+            .iter()
+            .position(|node| node.is_used && node.get_alias_str().is_ok_and(|a| a == alias))
+    }
+
+    /// Returns true if a file is stored under `alias`.
+    pub fn exists(&self, alias: &str) -> bool {
+        self.find_filenode_index(alias).is_some()
+    }
+
+    /// Returns the position in the filenode table of the file stored as `alias`.
+    pub fn filenode_index(&self, alias: &str) -> Option<usize> {
+        self.find_filenode_index(alias)
+    }
+
+    /// Checks that `alias` is a valid, not yet used alias for a new file.
+    fn validate_new_alias(&self, alias: &str) -> Result<(), FsError> {
+        // Check if the alias is valid
+        if alias.is_empty() {
+            return Err(FsError::InvalidAlias(
+                "Alias must not be empty.".to_string(),
+            ));
+        }
+        if alias.len() > self.header.max_alias_len {
+            return Err(FsError::InvalidAlias(format!(
+                "Alias is {} bytes ({} characters) in UTF-8, but at most {} bytes are allowed.",
+                alias.len(),
+                alias.chars().count(),
+                self.header.max_alias_len
+            )));
+        }
+        // NULs, newlines and other control characters break listings and make different
+        // aliases look alike in a terminal
+        if let Some(c) = alias.chars().find(|c| c.is_control()) {
+            return Err(FsError::InvalidAlias(format!(
+                "Alias {:?} contains the control character {:?}, which is not allowed.",
+                alias, c
+            )));
+        }
+        if alias
+            .split('/')
+            .any(|component| component.is_empty() || component == "." || component == "..")
+        {
+            return Err(FsError::InvalidAlias(format!(
+                "Alias '{}' is not a valid path: components must not be empty, '.' or '..'.",
+                alias
+            )));
+        }
+
+        // Check if the alias already exists
+        if self.exists(alias) {
+            return Err(FsError::AliasExists(alias.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Gets `alias` ready to receive a file's contents. With `overwrite`, an existing file
+    /// of that name is returned to be replaced; otherwise the alias must be new. The caller
+    /// creates the missing parent directories of a new alias once it has made room.
+    fn prepare_alias(&self, alias: &str, overwrite: bool) -> Result<Option<usize>, FsError> {
+        let existing = overwrite.then(|| self.find_filenode_index(alias)).flatten();
+        match existing {
+            Some(index) if self.filenodes[index].is_dir() => {
+                return Err(FsError::InvalidArgument(format!(
+                    "'{}' is a directory.",
+                    alias
+                )));
+            }
+            Some(_) => {}
+            None => self.validate_new_alias(alias)?,
+        }
+        Ok(existing)
+    }
+
+    /// Creates every missing directory above `alias`, failing if a file is in the way.
+    fn create_parent_dirs(&mut self, alias: &str) -> Result<(), FsError> {
+        let mut end = 0;
+        while let Some(offset) = alias[end..].find('/') {
+            end += offset;
+            let dir = &alias[..end];
+            match self.find_filenode_index(dir) {
+                Some(index) if !self.filenodes[index].is_dir() => {
+                    return Err(FsError::InvalidAlias(format!(
+                        "'{}' is a file, not a directory.",
+                        dir
+                    )));
+                }
+                Some(_) => {}
+                None => self.create_dir_node(dir)?,
+            }
+            end += 1;
+        }
+        Ok(())
+    }
+
+    /// Stores an empty directory called `path`. The caller checks the alias and parents.
+    fn create_dir_node(&mut self, path: &str) -> Result<(), FsError> {
+        let index = self
+            .find_free_filenode_index()
+            .ok_or(FsError::NoFreeFilenodes)?;
+        self.claim_filenode(index);
+        let filenode = &mut self.filenodes[index];
+        filenode.set_alias(path);
+        filenode.is_used = true;
+        filenode.node_type = NodeType::Dir;
+        filenode.refcount = 1;
+        filenode.created_at = unix_now();
+        filenode.modified_at = filenode.created_at;
+        self.transaction(|fs| {
+            fs.record_change(index)?;
+            fs.save_single_filenode(index)
+        })
+    }
+
+    /// Creates the directory `path`, along with any missing directories above it.
+    pub fn mkdir(&mut self, path: &str) -> Result<(), FsError> {
+        self.validate_new_alias(path)?;
+        self.create_parent_dirs(path)?;
+        self.create_dir_node(path)
+    }
+
+    /// Returns true if any entry lies inside the directory `path`.
+    fn has_children(&self, path: &str) -> bool {
+        let prefix = format!("{}/", path);
+        self.filenodes.iter().any(|node| {
+            node.is_used
+                && node
+                    .get_alias_str()
+                    .is_ok_and(|alias| alias.starts_with(&prefix))
+        })
+    }
+
+    /// Uploads a file from the local filesystem to the virtual filesystem and returns the
+    /// number of bytes read from it. Directories missing from the alias's path are created.
+    pub fn upload_file(
+        &mut self,
+        local_path_str: &str,
+        alias: &str,
+        options: &UploadOptions,
+    ) -> Result<usize, FsError> {
+        self.upload_file_with_progress(local_path_str, alias, options, |_, _| {})
+    }
+
+    /// Uploads a file like `upload_file`, calling `progress` with `(bytes_done, total_bytes)`
+    /// after each block is written. The bytes counted are those stored, so for compressed
+    /// or encrypted files they are the encoded bytes. A deduplicated upload writes no
+    /// blocks and reports once.
+    pub fn upload_file_with_progress(
+        &mut self,
+        local_path_str: &str,
+        alias: &str,
+        options: &UploadOptions,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<usize, FsError> {
+        // Check if the local file exists and is a file
+        let local_path = Path::new(local_path_str);
+        if !local_path.exists() {
+            return Err(FsError::InvalidArgument(format!(
+                "Local file '{}' does not exist.",
+                local_path_str
+            )));
+        }
+        if !local_path.is_file() {
+            return Err(FsError::InvalidArgument(format!(
+                "'{}' is not a file.",
+                local_path_str
+            )));
+        }
+
+        // Empty files are stored without any data blocks
+        let file_size: usize = local_path
+            .metadata()
+            .map_err(|e| FsError::io(format!("Metadata failed for '{}'", local_path_str), e))?
+            .len() as usize;
+
+        let mut local_file = File::open(local_path).map_err(|e| {
+            FsError::io(format!("Failed to open local file '{}'", local_path_str), e)
+        })?;
+        self.transaction(|fs| {
+            fs.upload_source(
+                &mut local_file,
+                file_size,
+                local_path_str,
+                alias,
+                options,
+                &mut progress,
+            )
+        })?;
+        Ok(file_size)
+    }
+
+    /// Uploads everything `reader` yields, e.g. standard input, as `alias` and returns the
+    /// number of bytes read. The size is not known up front, so the data is buffered in
+    /// memory before it is stored.
+    pub fn upload_reader<R: Read>(
+        &mut self,
+        reader: &mut R,
+        alias: &str,
+        options: &UploadOptions,
+    ) -> Result<usize, FsError> {
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .map_err(|e| FsError::io(format!("Failed to read data for '{}'", alias), e))?;
+        let size = data.len();
+        self.transaction(|fs| {
+            fs.upload_source(
+                &mut io::Cursor::new(data),
+                size,
+                "standard input",
+                alias,
+                options,
+                &mut |_, _| {},
+            )
+        })?;
+        Ok(size)
+    }
+
+    /// Stores `file_size` bytes from `local_file` as `alias`, applying the upload options.
+    /// `local_path_str` names the source in error messages.
+    fn upload_source<R: Read + Seek>(
+        &mut self,
+        local_file: &mut R,
+        file_size: usize,
+        local_path_str: &str,
+        alias: &str,
+        options: &UploadOptions,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<(), FsError> {
+        let content_type = match &options.content_type {
+            Some(content_type) => validate_content_type(content_type)?,
+            None => guess_content_type(alias),
+        };
+        if let Some(description) = &options.description {
+            validate_description(description)?;
+        }
+        let existing = self.prepare_alias(alias, options.overwrite)?;
+
+        // Compressed contents are only kept if they actually save space
+        let mut original_size = None;
+        let mut payload = None;
+        if options.compress && file_size > 0 {
+            let compressed = zstd::encode_all(&mut *local_file, 0)
+                .map_err(|e| FsError::io(format!("Failed to compress '{}'", local_path_str), e))?;
+            if compressed.len() < file_size {
+                original_size = Some(file_size);
+                payload = Some(compressed);
+            } else {
+                local_file
+                    .rewind()
+                    .map_err(|e| FsError::io(format!("Seek failed for '{}'", local_path_str), e))?;
+            }
+        }
+
+        // Encryption covers the whole payload at once, so it is read into memory first
+        let mut encryption = None;
+        if let Some(password) = &options.password {
+            let mut data = match payload.take() {
+                Some(data) => data,
+                None => {
+                    let mut data = Vec::with_capacity(file_size);
+                    local_file.read_to_end(&mut data).map_err(|e| {
+                        FsError::io(format!("Failed to read local file '{}'", local_path_str), e)
+                    })?;
+                    data
+                }
+            };
+            encryption = Some(encrypt_contents(password, &mut data)?);
+            payload = Some(data);
+        }
+
+        // Identical stored bytes are found by their hash, which is computed up front
+        let duplicate = if options.dedup {
+            let content_hash: [u8; 32] = match &payload {
+                Some(data) => Sha256::digest(data).into(),
+                None => {
+                    let mut hasher = Sha256::new();
+                    io::copy(local_file, &mut hasher).map_err(|e| {
+                        FsError::io(format!("Failed to read local file '{}'", local_path_str), e)
+                    })?;
+                    local_file.rewind().map_err(|e| {
+                        FsError::io(format!("Seek failed for '{}'", local_path_str), e)
+                    })?;
+                    hasher.finalize().into()
+                }
+            };
+            let stored_size = payload.as_ref().map_or(file_size, Vec::len);
+            self.find_duplicate(
+                &content_hash,
+                stored_size,
+                file_size,
+                original_size.is_some(),
+                encryption,
+            )
+        } else {
+            None
+        };
+
+        // Room is made before the parent directories are created, as growing moves metadata
+        let block_size = self.usable_block_size();
+        if duplicate.is_none() {
+            let stored_size = payload.as_ref().map_or(file_size, Vec::len);
+            self.make_room(stored_size.div_ceil(block_size))?;
+        }
+        if existing.is_none() {
+            self.create_parent_dirs(alias)?;
+        }
+        let filenode_index = match (duplicate, payload) {
+            (Some(source), _) => {
+                let index = self.link_file(alias, source, existing)?;
+                let stored_size = self.filenodes[index].stored_len();
+                progress(stored_size, stored_size);
+                index
+            }
+            (None, Some(data)) => self.write_from_reader(
+                alias,
+                &mut Progress::new(data.as_slice(), data.len(), block_size, progress),
+                data.len(),
+                original_size,
+                encryption,
+                existing,
+            )?,
+            (None, None) => self.write_from_reader(
+                alias,
+                &mut Progress::new(local_file, file_size, block_size, progress),
+                file_size,
+                None,
+                None,
+                existing,
+            )?,
+        };
+
+        // A deduplicated file starts out with the type of the file it shares blocks with
+        let filenode = &mut self.filenodes[filenode_index];
+        filenode.content_type = content_type.as_bytes().to_vec();
+        if let Some(description) = &options.description {
+            filenode.description = description.as_bytes().to_vec();
+        }
+        if let Some(ttl) = options.ttl {
+            filenode.expires_at = Some(unix_now().saturating_add(ttl));
+        }
+        self.save_single_filenode(filenode_index)
+    }
+
+    /// Stores `size` bytes streamed from `reader` as a new file called `alias`, one block at
+    /// a time. Bytes after the first `size` are not read. If the reader ends early, the
+    /// upload fails and every block allocated for it is released.
+    pub fn write_file<R: Read>(
+        &mut self,
+        alias: &str,
+        reader: &mut R,
+        size: usize,
+    ) -> Result<(), FsError> {
+        self.validate_new_alias(alias)?;
+        // Room is made before the transaction writes any metadata, as growing moves it
+        self.make_room(size.div_ceil(self.usable_block_size()))?;
+        self.transaction(|fs| {
+            fs.create_parent_dirs(alias)?;
+            fs.write_from_reader(alias, reader, size, None, None, None)
+        })?;
+        Ok(())
+    }
+
+    /// Appends everything `reader` yields to the file stored as `alias`. The free space in
+    /// the file's last block is filled first, then new blocks are linked on for the rest.
+    ///
+    /// New blocks are written and marked used on disk before the chain is linked to them,
+    /// and the filenode, with the new size, is saved last. An interruption can at worst
+    /// leave blocks orphaned, which `fsck --repair` reclaims.
+    pub fn append_file<R: Read>(&mut self, alias: &str, reader: &mut R) -> Result<(), FsError> {
+        let index = self.find_editable_file(alias, "append to")?;
+        let filenode = self.filenodes[index].clone();
+
+        // Fill the unused tail of the last block, if the file has one and it is not full
+        let usable_block_size = self.usable_block_size();
+        let chain = self.collect_block_chain(&filenode)?;
+        let mut last_block_buffer = vec![0u8; self.header.block_size];
+        let mut appended = 0;
+        if let Some(&last_block) = chain.last() {
+            self.read_block(last_block, &mut last_block_buffer)?;
+            // A size short of the chain's earlier blocks means the filenode is damaged
+            let used = (chain.len() - 1)
+                .checked_mul(usable_block_size)
+                .and_then(|full_blocks| filenode.size.checked_sub(full_blocks))
+                .filter(|&used| used <= usable_block_size)
+                .ok_or_else(|| {
+                    FsError::Corrupt(format!(
+                        "File '{}' is {} bytes long, which does not fit its {}-block chain.",
+                        alias,
+                        filenode.size,
+                        chain.len()
+                    ))
+                })?;
+            let tail = read_chunk(reader, usable_block_size - used)?;
+            last_block_buffer[used..used + tail.len()].copy_from_slice(&tail);
+            appended += tail.len();
+        }
+
+        // Write the overflow to new blocks. Each block's successor is allocated before the
+        // block is written, so its next pointer is final from the start.
+        let mut new_blocks = Vec::new();
+        let written = self.append_new_blocks(reader, &mut new_blocks);
+        let committed = written.and_then(|new_bytes| {
+            appended += new_bytes;
+            self.write_bitmap_blocks(&new_blocks)?;
+            if let Some(&last_block) = chain.last() {
+                let next_block_index = new_blocks.first().copied().unwrap_or(usize::MAX);
+                last_block_buffer[usable_block_size..self.header.block_size]
+                    .copy_from_slice(&next_block_index.to_le_bytes());
+                self.write_block(last_block, &last_block_buffer)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = committed {
+            for &block_index in &new_blocks {
+                self.set_block_free(block_index, true);
+            }
+            let _ = self.write_bitmap_to_disk();
+            return Err(e);
+        }
+        if appended == 0 {
+            return Ok(());
+        }
+
+        // Update the filenode, rehashing the whole contents
+        let filenode = &mut self.filenodes[index];
+        if filenode.first_block_index.is_none() {
+            filenode.first_block_index = new_blocks.first().copied();
+        }
+        filenode.size += appended;
+        self.finish_edit(index)?;
+        self.storage
+            .flush()
+            .map_err(|e| FsError::io("Final flush failed (append)", e))
+    }
+
+    /// Shrinks the file stored as `alias` to its first `new_size` bytes. Blocks no longer
+    /// needed are freed; truncating to the current size does nothing.
+    ///
+    /// The next pointer ending the chain is journaled together with the filenode and the
+    /// freed blocks, so an interruption leaves the file either whole or truncated.
+    pub fn truncate_file(&mut self, alias: &str, new_size: usize) -> Result<(), FsError> {
+        let index = self.find_editable_file(alias, "truncate")?;
+        let filenode = self.filenodes[index].clone();
+        if new_size > filenode.size {
+            return Err(FsError::InvalidArgument(format!(
+                "Cannot truncate '{}' to {} bytes: it only holds {}. Use append to grow it.",
+                alias, new_size, filenode.size
+            )));
+        }
+        if new_size == filenode.size {
+            return Ok(());
+        }
+
+        let usable_block_size = self.usable_block_size();
+        let chain = self.collect_block_chain(&filenode)?;
+        let blocks_kept = new_size.div_ceil(usable_block_size);
+        let last_block = blocks_kept.checked_sub(1).map(|last| chain[last]);
+        if last_block.is_none() {
+            self.filenodes[index].first_block_index = None;
+        }
+        self.filenodes[index].size = new_size;
+        self.transaction(|fs| {
+            // End the chain at the last block still needed
+            if let Some(last_block) = last_block {
+                let pointer_offset = fs.header.data_blocks_offset
+                    + last_block * fs.header.block_size
+                    + usable_block_size;
+                fs.write_metadata(pointer_offset, usize::MAX.to_le_bytes().to_vec())
+                    .map_err(|e| FsError::io(format!("Write failed (block {})", last_block), e))?;
+            }
+            fs.finish_edit(index)?;
+            for &block_index in &chain[blocks_kept..] {
+                fs.set_block_free(block_index, true);
+            }
+            fs.write_bitmap_blocks(&chain[blocks_kept..])
+        })?;
+
+        // Pad what follows the new end, which is no longer part of the file
+        if let Some(last_block) = last_block {
+            let mut block_data_buffer = vec![0u8; self.header.block_size];
+            self.read_block(last_block, &mut block_data_buffer)?;
+            let used = new_size - (blocks_kept - 1) * usable_block_size;
+            block_data_buffer[used..usable_block_size].fill(self.header.fill_byte);
+            self.write_block(last_block, &block_data_buffer)?;
+        }
+        self.storage
+            .flush()
+            .map_err(|e| FsError::io("Final flush failed (truncate)", e))
+    }
+
+    /// Returns the filenode index of the file stored as `alias` if its blocks can be edited
+    /// in place: it must be a plain file that is neither compressed, encrypted nor shared.
+    /// Like `resolve_node`, an expired file is treated as if it no longer existed.
+    /// `action` describes the edit in error messages.
+    fn find_editable_file(&self, alias: &str, action: &str) -> Result<usize, FsError> {
+        let index = self
+            .find_filenode_index(alias)
+            .filter(|&index| !self.filenodes[index].is_expired(unix_now()))
+            .ok_or_else(|| FsError::AliasNotFound(alias.to_string()))?;
+        let filenode = &self.filenodes[index];
+        if filenode.is_dir() {
+            return Err(FsError::InvalidArgument(format!(
+                "'{}' is a directory.",
+                alias
+            )));
+        }
+        if filenode.is_symlink() {
+            return Err(FsError::InvalidArgument(format!(
+                "Cannot {} '{}': it is a symlink.",
+                action, alias
+            )));
+        }
+        if filenode.compressed || filenode.encryption.is_some() {
+            return Err(FsError::InvalidArgument(format!(
+                "Cannot {} '{}': it is stored compressed or encrypted.",
+                action, alias
+            )));
+        }
+        if filenode.refcount > 1 {
+            return Err(FsError::InvalidArgument(format!(
+                "Cannot {} '{}': its blocks are shared with {} deduplicated files.",
+                action,
+                alias,
+                filenode.refcount - 1
+            )));
+        }
+        Ok(index)
+    }
+
+    /// Completes an in-place edit of the filenode at `index`, whose size and chain are
+    /// already updated: the contents are rehashed and the filenode is saved.
+    fn finish_edit(&mut self, index: usize) -> Result<(), FsError> {
+        let filenode = &mut self.filenodes[index];
+        filenode.stored_size = filenode.size;
+        filenode.modified_at = unix_now();
+        let edited_node = filenode.clone();
+        let mut content_hasher = Sha256::new();
+        let checksum = self.stream_chain(&edited_node, &mut content_hasher)?;
+        self.filenodes[index].checksum = checksum;
+        self.filenodes[index].content_hash = content_hasher.finalize().into();
+        self.transaction(|fs| {
+            fs.record_change(index)?;
+            fs.save_single_filenode(index)
+        })
+    }
+
+    /// Writes what remains in `reader` to newly allocated blocks chained together, marking
+    /// them used in memory and recording them in `new_blocks`. Returns the number of bytes
+    /// written.
+    fn append_new_blocks<R: Read>(
+        &mut self,
+        reader: &mut R,
+        new_blocks: &mut Vec<usize>,
+    ) -> Result<usize, FsError> {
+        let usable_block_size = self.usable_block_size();
+        let mut written = 0;
+        let mut chunk = read_chunk(reader, usable_block_size)?;
+        let mut block_opt = self.allocate_block_for(&chunk, new_blocks)?;
+        while let Some(block_index) = block_opt {
+            let next_chunk = read_chunk(reader, usable_block_size)?;
+            let next_block_opt = self.allocate_block_for(&next_chunk, new_blocks)?;
+
+            let mut block_data_buffer = vec![self.header.fill_byte; self.header.block_size];
+            block_data_buffer[..chunk.len()].copy_from_slice(&chunk);
+            block_data_buffer[usable_block_size..self.header.block_size]
+                .copy_from_slice(&next_block_opt.unwrap_or(usize::MAX).to_le_bytes());
+            self.write_block(block_index, &block_data_buffer)?;
+            written += chunk.len();
+
+            chunk = next_chunk;
+            block_opt = next_block_opt;
+        }
+        Ok(written)
+    }
+
+    /// Picks a free block to hold `chunk`, unless it is empty, and marks it used in memory.
+    fn allocate_block_for(
+        &mut self,
+        chunk: &[u8],
+        new_blocks: &mut Vec<usize>,
+    ) -> Result<Option<usize>, FsError> {
+        if chunk.is_empty() {
+            return Ok(None);
+        }
+        self.make_room(1)?;
+        let block_index = self.reserve_blocks(1, self.prefers_contiguous())?[0];
+        new_blocks.push(block_index);
+        debug!("Allocated block {} to extend a file", block_index);
+        Ok(Some(block_index))
+    }
+
+    /// Stores exactly `file_size` bytes from `reader` as a new file called `alias`.
+    /// Returns the index of the filenode that now holds the file. With `original_size`,
+    /// the bytes are zstd-compressed contents of that many bytes. With `encryption`, they
+    /// are ciphertext that decrypts with those parameters.
+    ///
+    /// With `replace`, the file in that filenode is overwritten instead. Its old blocks are
+    /// only released once the new content is written and committed, so a failed overwrite
+    /// leaves the old version intact; the upload therefore needs room for both versions.
+    /// Old blocks still shared with deduplicated files are kept for those files.
+    fn write_from_reader<R: Read>(
+        &mut self,
+        alias: &str,
+        reader: &mut R,
+        file_size: usize,
+        original_size: Option<usize>,
+        encryption: Option<Encryption>,
+        replace: Option<usize>,
+    ) -> Result<usize, FsError> {
+        // Walk the old chain up front so a corrupt file is reported before anything changes
+        let mut old_chain = Vec::new();
+        let mut other_sharers = Vec::new();
+        if let Some(index) = replace {
+            let old_filenode = self.filenodes[index].clone();
+            other_sharers = self.chain_sharers(old_filenode.first_block_index);
+            other_sharers.retain(|&sharer| sharer != index);
+            if other_sharers.is_empty() {
+                old_chain = self.collect_block_chain(&old_filenode)?;
+            }
+        }
+
+        // Check if there is enough space in the filesystem
+        let num_blocks_needed = file_size.div_ceil(self.usable_block_size());
+        self.make_room(num_blocks_needed)?;
+
+        // Find a free filenode (unless overwriting) and reserve the whole chain up front
+        let filenode_index = match replace {
+            Some(index) => index,
+            None => self
+                .find_free_filenode_index()
+                .ok_or(FsError::NoFreeFilenodes)?,
+        };
+        let block_indices = self.reserve_blocks(num_blocks_needed, self.prefers_contiguous())?;
+        if let Some(first_block) = block_indices.first() {
+            debug!(
+                "Allocated {} blocks for '{}' starting at block {}",
+                block_indices.len(),
+                alias,
+                first_block
+            );
+        }
+
+        // Write the data, releasing the reservation if anything goes wrong so that a
+        // failed upload does not leak blocks. The bitmap is only persisted after a
+        // successful write, so the on-disk bitmap stays clean as well.
+        let (checksum, content_hash) = match self.write_blocks(reader, &block_indices, file_size) {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                self.release_blocks(&block_indices);
+                return Err(e);
+            }
+        };
+
+        // Update the filenode with the alias and size
+        let previous_filenode = self.filenodes[filenode_index].clone();
+        self.claim_filenode(filenode_index);
+        let filenode = &mut self.filenodes[filenode_index];
+        filenode.set_alias(alias);
+        filenode.size = original_size.unwrap_or(file_size);
+        filenode.compressed = original_size.is_some();
+        filenode.stored_size = file_size;
+        filenode.encryption = encryption;
+        filenode.first_block_index = block_indices.first().copied();
+        filenode.is_used = true;
+        filenode.checksum = checksum;
+        filenode.content_hash = content_hash;
+        filenode.refcount = 1;
+        filenode.content_type = guess_content_type(alias).as_bytes().to_vec();
+        let now = unix_now();
+        filenode.created_at = match replace {
+            Some(_) => previous_filenode.created_at,
+            None => now,
+        };
+        filenode.modified_at = now;
+        // The note is about the file rather than its contents, so it outlives them
+        if replace.is_some() {
+            filenode.description = previous_filenode.description.clone();
+        }
+        for &block_index in &old_chain {
+            self.set_block_free(block_index, true);
+        }
+
+        // Save the filenode and bitmap to disk in one transaction. If that fails, the new
+        // file is forgotten again so neither its filenode nor its blocks leak, and any
+        // file it was replacing is restored.
+        let changed_blocks: Vec<usize> = block_indices.iter().chain(&old_chain).copied().collect();
+        let committed = self.transaction(|fs| {
+            fs.record_change(filenode_index)?;
+            fs.save_single_filenode(filenode_index)?;
+            fs.write_bitmap_blocks(&changed_blocks)
+        });
+        if let Err(e) = committed {
+            self.filenodes[filenode_index] = previous_filenode;
+            self.release_blocks(&block_indices);
+            for &block_index in &old_chain {
+                self.set_block_free(block_index, false);
+            }
+            let _ = self.save_single_filenode(filenode_index);
+            return Err(e);
+        }
+        self.set_refcounts(&other_sharers)?;
+        Ok(filenode_index)
+    }
+
+    /// Returns the index of a used filenode whose chain holds exactly the stored bytes
+    /// described, so a new file with those contents can share it.
+    fn find_duplicate(
+        &self,
+        content_hash: &[u8; 32],
+        stored_size: usize,
+        size: usize,
+        compressed: bool,
+        encryption: Option<Encryption>,
+    ) -> Option<usize> {
+        self.filenodes.iter().position(|node| {
+            node.is_used
+                && node.node_type == NodeType::File
+                && node.first_block_index.is_some()
+                && node.content_hash == *content_hash
+                && node.stored_len() == stored_size
+                && node.size == size
+                && node.compressed == compressed
+                && node.encryption == encryption
+        })
+    }
+
+    /// Stores `alias` as one more reference to the block chain of the file at `source`
+    /// instead of writing the same contents again. With `replace`, the file in that
+    /// filenode is overwritten and its old chain released. Returns the index of the
+    /// filenode that now holds the file.
+    fn link_file(
+        &mut self,
+        alias: &str,
+        source: usize,
+        replace: Option<usize>,
+    ) -> Result<usize, FsError> {
+        let filenode_index = match replace {
+            Some(index) => index,
+            None => self
+                .find_free_filenode_index()
+                .ok_or(FsError::NoFreeFilenodes)?,
+        };
+        let mut sharers = self.chain_sharers(self.filenodes[source].first_block_index);
+        let previous_filenode = self.filenodes[filenode_index].clone();
+        let now = unix_now();
+
+        // Overwriting a file with the contents it already shares only touches its time
+        if replace.is_some() && sharers.contains(&filenode_index) {
+            self.filenodes[filenode_index].modified_at = now;
+            self.record_change(filenode_index)?;
+            return self
+                .save_single_filenode(filenode_index)
+                .map(|_| filenode_index);
+        }
+
+        let mut filenode = self.filenodes[source].clone();
+        filenode.set_alias(alias);
+        filenode.expires_at = None;
+        filenode.created_at = match replace {
+            Some(_) => previous_filenode.created_at,
+            None => now,
+        };
+        filenode.modified_at = now;
+        filenode.description = match replace {
+            Some(_) => previous_filenode.description.clone(),
+            None => Vec::new(),
+        };
+        self.claim_filenode(filenode_index);
+        self.filenodes[filenode_index] = filenode;
+        self.record_change(filenode_index)?;
+        sharers.push(filenode_index);
+        self.set_refcounts(&sharers)?;
+
+        if replace.is_some() {
+            self.release_chain(&previous_filenode)?;
+        }
+        Ok(filenode_index)
+    }
+
+    /// Releases the block chain of `filenode`, which no filenode refers to any more. The
+    /// blocks are freed unless deduplicated files still share them, in which case only
+    /// their refcounts drop.
+    fn release_chain(&mut self, filenode: &FileNode) -> Result<(), FsError> {
+        let other_sharers = self.chain_sharers(filenode.first_block_index);
+        if !other_sharers.is_empty() {
+            return self.set_refcounts(&other_sharers);
+        }
+        let chain = self.collect_block_chain(filenode)?;
+        for &block_index in &chain {
+            self.set_block_free(block_index, true);
+        }
+        debug!(
+            "Freed {} blocks of '{}'",
+            chain.len(),
+            filenode.alias_lossy()
+        );
+        self.write_bitmap_blocks(&chain)?;
+        self.storage
+            .flush()
+            .map_err(|e| FsError::io("Final flush failed (release)", e))
+    }
+
+    /// Returns the indices of the used filenodes whose chain starts at `first_block`.
+    fn chain_sharers(&self, first_block: Option<usize>) -> Vec<usize> {
+        if first_block.is_none() {
+            return Vec::new();
+        }
+        (0..self.filenodes.len())
+            .filter(|&index| {
+                self.filenodes[index].is_used
+                    && self.filenodes[index].first_block_index == first_block
+            })
+            .collect()
+    }
+
+    /// Sets the refcount of each filenode in `sharers`, which share one chain, to their
+    /// number and saves them.
+    fn set_refcounts(&mut self, sharers: &[usize]) -> Result<(), FsError> {
+        for &index in sharers {
+            self.filenodes[index].refcount = sharers.len() as u32;
+            self.save_single_filenode(index)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `file_size` bytes from `reader` into the given chain of reserved blocks.
+    /// Returns the CRC32 and SHA-256 of the bytes written.
+    fn write_blocks<R: Read>(
+        &mut self,
+        reader: &mut R,
+        block_indices: &[usize],
+        file_size: usize,
+    ) -> Result<(u32, [u8; 32]), FsError> {
+        let mut hasher = Crc32::new();
+        let mut content_hasher = Sha256::new();
+        let num_blocks_needed = block_indices.len();
+        let mut read_buffer = vec![0u8; self.usable_block_size()];
+        let mut bytes_remaining_to_write = file_size;
+
+        // Read from the source and write to the filesystem
+        for i in 0..num_blocks_needed {
+            // Read data for the current block
+            let current_fs_block_index = block_indices[i];
+            let bytes_to_read_this_iteration =
+                std::cmp::min(bytes_remaining_to_write, self.usable_block_size());
+            let mut block_data_buffer = vec![self.header.fill_byte; self.header.block_size];
+            reader
+                .read_exact(&mut read_buffer[0..bytes_to_read_this_iteration])
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::UnexpectedEof => FsError::InvalidArgument(format!(
+                        "Source ended before the expected {} bytes were read.",
+                        file_size
+                    )),
+                    _ => FsError::io("Read failed from source", e),
+                })?;
+            block_data_buffer[0..bytes_to_read_this_iteration]
+                .copy_from_slice(&read_buffer[0..bytes_to_read_this_iteration]);
+            hasher.update(&read_buffer[0..bytes_to_read_this_iteration]);
+            content_hasher.update(&read_buffer[0..bytes_to_read_this_iteration]);
+
+            // If this is not the last block, set the next block pointer to the next block index
+            if i < num_blocks_needed - 1 {
+                let next_fs_block_index = block_indices[i + 1];
+                block_data_buffer[self.usable_block_size()..self.header.block_size]
+                    .copy_from_slice(&next_fs_block_index.to_le_bytes());
+            } else {
+                block_data_buffer[self.usable_block_size()..self.header.block_size]
+                    .copy_from_slice(&usize::MAX.to_le_bytes());
+            }
+
+            // Write the block data to the filesystem
+            self.write_block(current_fs_block_index, &block_data_buffer)?;
+            bytes_remaining_to_write -= bytes_to_read_this_iteration;
+        }
+
+        if bytes_remaining_to_write != 0 {
+            return Err(FsError::Corrupt(format!(
+                "Write error: {} bytes remaining unexpectedly.",
+                bytes_remaining_to_write
+            )));
+        }
+        Ok((hasher.finalize(), content_hasher.finalize().into()))
+    }
+
+    /// Downloads a file from the virtual filesystem to the local filesystem and returns the
+    /// number of bytes written to it.
+    pub fn download_file(&mut self, alias: &str, local_path_str: &str) -> Result<usize, FsError> {
+        self.download_file_with_progress(alias, local_path_str, |_, _| {})
+    }
+
+    /// Downloads a file like `download_file`, calling `progress` with
+    /// `(bytes_done, total_bytes)` after each block is written to the local file.
+    pub fn download_file_with_progress(
+        &mut self,
+        alias: &str,
+        local_path_str: &str,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<usize, FsError> {
+        self.download(alias, local_path_str, true, &mut progress)
+    }
+
+    /// Downloads a file, copying contiguous files with `sendfile` where the platform and
+    /// storage allow it and `allow_sendfile` is set. A download that fails part way, e.g.
+    /// on a checksum mismatch, removes the local file rather than leave a partial or
+    /// corrupt copy behind.
+    fn download(
+        &mut self,
+        alias: &str,
+        local_path_str: &str,
+        allow_sendfile: bool,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<usize, FsError> {
+        // Find the filenode by alias and clone it to avoid borrowing issues with self.storage.
+        // Expired files are treated as if they no longer exist, and symlinks are followed.
+        let filenode = self.resolve_node(alias)?;
+        if filenode.is_dir() {
+            return Err(FsError::InvalidArgument(format!(
+                "'{}' is a directory.",
+                alias
+            )));
+        }
+
+        // Check if the local path is valid
+        let mut local_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(local_path_str)
+            .map_err(|e| {
+                FsError::io(
+                    format!("Failed to open/create local file '{}'", local_path_str),
+                    e,
+                )
+            })?;
+
+        // Empty files have no blocks to read
+        if filenode.stored_len() == 0 {
+            return Ok(0);
+        }
+        let copied = self
+            .copy_to_local(&filenode, alias, &mut local_file, allow_sendfile, progress)
+            .and_then(|_| {
+                local_file.flush().map_err(|e| {
+                    FsError::io(
+                        format!("Flush failed for local file '{}'", local_path_str),
+                        e,
+                    )
+                })
+            });
+        if let Err(e) = copied {
+            drop(local_file);
+            if let Err(remove_error) = std::fs::remove_file(local_path_str) {
+                warn!(
+                    "Failed to remove incomplete download '{}': {}",
+                    local_path_str, remove_error
+                );
+            }
+            return Err(e);
+        }
+        Ok(filenode.size)
+    }
+
+    /// Writes the contents of `filenode`, stored as `alias`, to `local_file`.
+    fn copy_to_local(
+        &mut self,
+        filenode: &FileNode,
+        alias: &str,
+        local_file: &mut File,
+        allow_sendfile: bool,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<(), FsError> {
+        // Contiguous files can be copied by the kernel without going through userspace.
+        // The kernel copy never shows us the bytes, so the checksum is computed over what
+        // landed in the local file, which is still in the page cache.
+        #[cfg(target_os = "linux")]
+        let copied_with_sendfile = match self.collect_block_chain(filenode) {
+            Ok(chain)
+                if allow_sendfile
+                    && self.storage.as_file().is_some()
+                    && !filenode.compressed
+                    && filenode.encryption.is_none()
+                    && chain.windows(2).all(|pair| pair[1] == pair[0] + 1) =>
+            {
+                let checksum = self.sendfile_blocks(&chain, filenode.size, local_file, progress)?;
+                if checksum != filenode.checksum {
+                    warn!("Checksum mismatch for '{}'", alias);
+                    return Err(FsError::Corrupt(format!(
+                        "Checksum mismatch for file '{}': stored {:08x}, computed {:08x}. Corrupt.",
+                        alias, filenode.checksum, checksum
+                    )));
+                }
+                true
+            }
+            _ => false,
+        };
+        #[cfg(not(target_os = "linux"))]
+        let copied_with_sendfile = {
+            let _ = allow_sendfile;
+            false
+        };
+
+        if !copied_with_sendfile {
+            let block_size = self.usable_block_size();
+            let mut out = Progress::new(local_file, filenode.size, block_size, progress);
+            self.read_into_writer(filenode, &mut out)?;
+        }
+        Ok(())
+    }
+
+    /// Streams the contents of the file stored as `alias` to `out`, following a symlink.
+    /// Returns the number of bytes written.
+    pub fn read_file<W: Write>(&mut self, alias: &str, out: &mut W) -> Result<usize, FsError> {
+        let filenode = self.resolve_node(alias)?;
+        self.read_node(&filenode, out)
+    }
+
+    /// Writes the contents of `filenode`, as returned by `resolve_node`, to `out` and
+    /// returns their size. Reading a node resolved earlier does not look the alias up
+    /// again, so the file cannot expire in between.
+    pub fn read_node<W: Write>(
+        &mut self,
+        filenode: &FileNode,
+        out: &mut W,
+    ) -> Result<usize, FsError> {
+        if filenode.is_dir() {
+            return Err(FsError::InvalidArgument(format!(
+                "'{}' is a directory.",
+                filenode.alias_lossy()
+            )));
+        }
+        self.read_into_writer(filenode, out)?;
+        Ok(filenode.size)
+    }
+
+    /// Returns up to `len` bytes of the file stored as `alias`, starting at byte `offset`.
+    /// The range is clamped to the end of the file; an offset past the end is an error.
+    ///
+    /// Blocks before the range are skipped by reading only their next pointers, and
+    /// reading stops after the last block the range covers. As the whole file is not read,
+    /// its checksum is not verified. Compressed and encrypted files are decoded in full.
+    pub fn read_range(
+        &mut self,
+        alias: &str,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, FsError> {
+        let filenode = self.resolve_node(alias)?;
+        if filenode.is_dir() {
+            return Err(FsError::InvalidArgument(format!(
+                "'{}' is a directory.",
+                alias
+            )));
+        }
+        if offset > filenode.size {
+            return Err(FsError::InvalidArgument(format!(
+                "Offset {} is past the end of '{}' ({} bytes).",
+                offset, alias, filenode.size
+            )));
+        }
+        let len = std::cmp::min(len, filenode.size - offset);
+        if filenode.compressed || filenode.encryption.is_some() {
+            let mut contents = Vec::with_capacity(filenode.size);
+            self.read_into_writer(&filenode, &mut contents)?;
+            return Ok(contents[offset..offset + len].to_vec());
+        }
+
+        let usable_block_size = self.usable_block_size();
+        let mut range = Vec::with_capacity(len);
+        let mut block_data_buffer = vec![0u8; self.header.block_size];
+        let mut current_block_opt = filenode.first_block_index;
+        let mut block_start = 0; // Offset in the file of the current block's first byte
+        let mut blocks_walked = 0;
+        while range.len() < len {
+            let current_block_index = match current_block_opt {
+                Some(block_index)
+                    if block_index < self.header.num_data_blocks
+                        && blocks_walked < self.header.num_data_blocks =>
+                {
+                    block_index
+                }
+                _ => {
+                    return Err(FsError::Corrupt(format!(
+                        "Block chain of file '{}' is broken before byte {}. Corrupt.",
+                        alias,
+                        offset + range.len()
+                    )))
+                }
+            };
+            blocks_walked += 1;
+
+            if block_start + usable_block_size <= offset {
+                current_block_opt = self.read_next_pointer(current_block_index)?;
+            } else {
+                self.read_block(current_block_index, &mut block_data_buffer)?;
+                let start = offset + range.len() - block_start;
+                let end = std::cmp::min(usable_block_size, offset + len - block_start);
+                range.extend_from_slice(&block_data_buffer[start..end]);
+
+                let mut next_block_ptr_bytes = [0u8; NEXT_BLOCK_POINTER_SIZE];
+                next_block_ptr_bytes
+                    .copy_from_slice(&block_data_buffer[usable_block_size..self.header.block_size]);
+                let next_block_index = usize::from_le_bytes(next_block_ptr_bytes);
+                current_block_opt = if next_block_index == usize::MAX {
+                    None
+                } else {
+                    Some(next_block_index)
+                };
+            }
+            block_start += usable_block_size;
+        }
+        Ok(range)
+    }
+
+    /// Checks the file stored as `alias` without writing it anywhere: its block chain must
+    /// stay in range, hold exactly the blocks its size needs and match the stored checksum.
+    /// Encrypted files are also authenticated if a password is set.
+    pub fn verify_file(&mut self, alias: &str) -> Result<(), FsError> {
+        let filenode = self
+            .find_filenode_index(alias)
+            .map(|index| self.filenodes[index].clone())
+            .ok_or_else(|| FsError::AliasNotFound(alias.to_string()))?;
+        let chain = self.collect_block_chain(&filenode)?;
+        self.verify_node(&filenode, chain.len())
+    }
+
+    /// Checks `filenode`, whose chain is `chain_len` blocks long, like `verify_file`.
+    fn verify_node(&mut self, filenode: &FileNode, chain_len: usize) -> Result<(), FsError> {
+        let alias = filenode.alias_lossy();
+        let blocks_needed = filenode.stored_len().div_ceil(self.usable_block_size());
+        if chain_len != blocks_needed {
+            return Err(FsError::Corrupt(format!(
+                "File '{}' has {} blocks in its chain but its size of {} bytes needs {}.",
+                alias,
+                chain_len,
+                filenode.stored_len(),
+                blocks_needed
+            )));
+        }
+
+        // Without a password, encrypted contents can still be checked against the checksum
+        if filenode.encryption.is_some() && self.password.is_none() {
+            let checksum = self.stream_chain(filenode, &mut io::sink())?;
+            if checksum != filenode.checksum {
+                warn!("Checksum mismatch for '{}'", alias);
+                return Err(FsError::Corrupt(format!(
+                    "Checksum mismatch for file '{}': stored {:08x}, computed {:08x}. Corrupt.",
+                    alias, filenode.checksum, checksum
+                )));
+            }
+            return Ok(());
+        }
+        self.read_into_writer(filenode, &mut io::sink())
+    }
+
+    /// Reads the next block pointer stored at the end of data block `block_index`.
+    fn read_next_pointer(&mut self, block_index: usize) -> Result<Option<usize>, FsError> {
+        let mut next_block_ptr_bytes = [0u8; NEXT_BLOCK_POINTER_SIZE];
+        let pointer_offset = self.base_offset
+            + (self.header.data_blocks_offset
+                + block_index * self.header.block_size
+                + self.usable_block_size()) as u64;
+        let storage = &mut self.storage;
+        retry_io(self.io_retries, || {
+            storage.read_at(pointer_offset, &mut next_block_ptr_bytes)
+        })
+        .map_err(|e| FsError::io(format!("Read failed (chain block {})", block_index), e))?;
+        let next_block_index = usize::from_le_bytes(next_block_ptr_bytes);
+        Ok(if next_block_index == usize::MAX {
+            None
+        } else {
+            Some(next_block_index)
+        })
+    }
+
+    /// Returns the block indices of `filenode`'s chain in order, reading only the next pointers.
+    fn collect_block_chain(&mut self, filenode: &FileNode) -> Result<Vec<usize>, FsError> {
+        BlockChainIter::indices_only(self, filenode.first_block_index)
+            .map(|block| block.map(|(block_index, _)| block_index))
+            .collect()
+    }
+
+    /// Copies the usable payload of each block in `chain` straight from the filesystem
+    /// file to `out` using `sendfile`, avoiding a userspace buffer. The storage must be a
+    /// file and `out` must be readable. Returns the CRC32 of the bytes written, read back
+    /// from `out`.
+    #[cfg(target_os = "linux")]
+    fn sendfile_blocks(
+        &mut self,
+        chain: &[usize],
+        size: usize,
+        out: &File,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<u32, FsError> {
+        use std::os::unix::fs::FileExt;
+        use std::os::unix::io::AsRawFd;
+
+        let in_fd = self
+            .storage
+            .as_file()
+            .ok_or_else(|| FsError::InvalidArgument("Storage is not a file.".to_string()))?
+            .as_raw_fd();
+        let out_fd = out.as_raw_fd();
+        let mut bytes_remaining = size;
+        let mut hasher = Crc32::new();
+        let mut written = vec![0u8; self.usable_block_size()];
+
+        for &block_index in chain {
+            if bytes_remaining == 0 {
+                break;
+            }
+            let mut offset = (self.base_offset as usize
+                + self.header.data_blocks_offset
+                + block_index * self.header.block_size) as libc::off_t;
+            let block_len = std::cmp::min(bytes_remaining, self.usable_block_size());
+            let mut bytes_in_this_block = block_len;
+
+            // sendfile may transfer fewer bytes than requested, so loop until the block is done
+            while bytes_in_this_block > 0 {
+                // SAFETY: both descriptors are owned by open `File`s for the duration of the call
+                // and `offset` is a valid pointer to a local variable.
+                let sent =
+                    unsafe { libc::sendfile(out_fd, in_fd, &mut offset, bytes_in_this_block) };
+                if sent < 0 {
+                    let err = std::io::Error::last_os_error();
+                    if err.kind() == std::io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(FsError::io(
+                        format!("sendfile failed (block {})", block_index),
+                        err,
+                    ));
+                }
+                if sent == 0 {
+                    return Err(FsError::Corrupt(format!(
+                        "sendfile hit end of file (block {}).",
+                        block_index
+                    )));
+                }
+                bytes_in_this_block -= sent as usize;
+                bytes_remaining -= sent as usize;
+            }
+            let block_start = (size - bytes_remaining - block_len) as u64;
+            out.read_exact_at(&mut written[..block_len], block_start)
+                .map_err(|e| FsError::io("Read back failed from output", e))?;
+            hasher.update(&written[..block_len]);
+            progress(size - bytes_remaining, size);
+        }
+
+        if bytes_remaining != 0 {
+            return Err(FsError::Corrupt(format!(
+                "File download incomplete. {} bytes remaining. Corrupt.",
+                bytes_remaining
+            )));
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// Walks the block chain of `filenode` and writes its contents to `out`.
+    fn read_into_writer<W: Write>(
+        &mut self,
+        filenode: &FileNode,
+        out: &mut W,
+    ) -> Result<(), FsError> {
+        let alias = filenode.alias_lossy();
+        let check_checksum = |checksum: u32| {
+            if checksum != filenode.checksum {
+                warn!("Checksum mismatch for '{}'", alias);
+                return Err(FsError::Corrupt(format!(
+                    "Checksum mismatch for file '{}': stored {:08x}, computed {:08x}. Corrupt.",
+                    alias, filenode.checksum, checksum
+                )));
+            }
+            Ok(())
+        };
+        if !filenode.compressed && filenode.encryption.is_none() {
+            return check_checksum(self.stream_chain(filenode, out)?);
+        }
+
+        // Stored bytes are checked before they are decrypted and decompressed
+        let mut stored = Vec::with_capacity(filenode.stored_len());
+        check_checksum(self.stream_chain(filenode, &mut stored)?)?;
+        if let Some(encryption) = &filenode.encryption {
+            let password = self.password.as_deref().ok_or_else(|| {
+                FsError::InvalidArgument(format!(
+                    "File '{}' is encrypted. A password is required to read it.",
+                    alias
+                ))
+            })?;
+            if !decrypt_contents(password, encryption, &mut stored)? {
+                return Err(FsError::BadPassword(alias.clone()));
+            }
+        }
+        if !filenode.compressed {
+            return out
+                .write_all(&stored)
+                .map_err(|e| FsError::io("Write failed to output", e));
+        }
+        let contents = zstd::decode_all(stored.as_slice()).map_err(|e| {
+            FsError::Corrupt(format!("Failed to decompress file '{}': {}", alias, e))
+        })?;
+        if contents.len() != filenode.size {
+            return Err(FsError::Corrupt(format!(
+                "File '{}' decompressed to {} bytes instead of {}. Corrupt.",
+                alias,
+                contents.len(),
+                filenode.size
+            )));
+        }
+        out.write_all(&contents)
+            .map_err(|e| FsError::io("Write failed to output", e))
+    }
+
+    /// Writes the stored bytes of `filenode`'s chain to `out` without checking them against
+    /// the stored checksum, decrypting or decompressing them. Returns the CRC32 of the
+    /// bytes written.
+    fn stream_chain<W: Write>(&mut self, filenode: &FileNode, out: &mut W) -> Result<u32, FsError> {
+        let alias = filenode.alias_lossy();
+        let mut hasher = Crc32::new();
+        let usable_block_size = self.usable_block_size();
+
+        // Read the blocks from the filesystem and write to the output, stopping as soon as
+        // the file's bytes are done so the last next pointer is never followed
+        let mut bytes_to_download = filenode.stored_len();
+        let mut chain = BlockChainIter::new(self, filenode.first_block_index);
+        while bytes_to_download > 0 {
+            let Some(block) = chain.next() else {
+                break;
+            };
+            let (_, block_data) = block?;
+            let bytes_in_this_block = std::cmp::min(bytes_to_download, usable_block_size);
+            out.write_all(&block_data[0..bytes_in_this_block])
+                .map_err(|e| FsError::io("Write failed to output", e))?;
+            hasher.update(&block_data[0..bytes_in_this_block]);
+            bytes_to_download -= bytes_in_this_block;
+        }
+
+        // Check if the download was incomplete
+        if bytes_to_download != 0 {
+            return Err(FsError::Corrupt(format!(
+                "File download incomplete for '{}'. {} bytes remaining. Corrupt.",
+                alias, bytes_to_download
+            )));
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// Searches every live file for `pattern`, returning each matching alias with its
+    /// number of matches. With `first_match_only`, a file's chain is abandoned at the
+    /// first hit and the count reported is 1.
+    pub fn grep(
+        &mut self,
+        pattern: &[u8],
+        first_match_only: bool,
+    ) -> Result<Vec<(String, usize)>, FsError> {
+        if pattern.is_empty() {
+            return Err(FsError::InvalidArgument(
+                "Search pattern must not be empty.".to_string(),
+            ));
+        }
+        let now = unix_now();
+        let candidates: Vec<FileNode> = self
+            .filenodes
+            .iter()
+            .filter(|node| {
+                node.is_used && node.node_type == NodeType::File && !node.is_expired(now)
+            })
+            .filter(|node| node.encryption.is_none() || self.password.is_some())
+            .cloned()
+            .collect();
+
+        let mut matches = Vec::new();
+        for filenode in &candidates {
+            let alias = filenode
+                .get_alias_str()
+                .map_err(|e| FsError::Corrupt(format!("Invalid alias while searching: {}", e)))?;
+            let count = self.count_matches(filenode, pattern, first_match_only)?;
+            if count > 0 {
+                matches.push((alias, count));
+            }
+        }
+        Ok(matches)
+    }
+
+    /// Counts the occurrences of `pattern` in `filenode`'s contents, one block at a time.
+    /// The tail of each block is carried over so matches spanning two blocks are found.
+    fn count_matches(
+        &mut self,
+        filenode: &FileNode,
+        pattern: &[u8],
+        first_match_only: bool,
+    ) -> Result<usize, FsError> {
+        // Compressed or encrypted contents can only be searched once fully decoded
+        if filenode.compressed || filenode.encryption.is_some() {
+            let mut contents = Vec::with_capacity(filenode.size);
+            self.read_into_writer(filenode, &mut contents)?;
+            let count = contents
+                .windows(pattern.len())
+                .filter(|candidate| *candidate == pattern)
+                .count();
+            return Ok(if first_match_only {
+                count.min(1)
+            } else {
+                count
+            });
+        }
+
+        let mut count = 0;
+        let usable_block_size = self.usable_block_size();
+        let mut window: Vec<u8> = Vec::with_capacity(pattern.len() - 1 + usable_block_size);
+        let mut bytes_remaining = filenode.size;
+        let mut chain = BlockChainIter::new(self, filenode.first_block_index);
+
+        while bytes_remaining > 0 {
+            let Some(block) = chain.next() else {
+                break;
+            };
+            let (_, block_data) = block?;
+            let bytes_in_this_block = std::cmp::min(bytes_remaining, usable_block_size);
+            window.extend_from_slice(&block_data[0..bytes_in_this_block]);
+            bytes_remaining -= bytes_in_this_block;
+
+            // Every match in the window ends inside this block, as the carried-over
+            // tail is shorter than the pattern
+            count += window
+                .windows(pattern.len())
+                .filter(|candidate| *candidate == pattern)
+                .count();
+            if first_match_only && count > 0 {
+                return Ok(1);
+            }
+            let carry = std::cmp::min(window.len(), pattern.len() - 1);
+            window.drain(..window.len() - carry);
+        }
+        Ok(count)
+    }
+
+    /// Returns groups of aliases whose files have byte-identical contents, each sorted and
+    /// holding at least two aliases. Only files of equal size are read and hashed, one
+    /// block at a time. Encrypted files are left out unless a password is set.
+    pub fn find_duplicates(&mut self) -> Result<Vec<Vec<String>>, FsError> {
+        let now = unix_now();
+        let mut by_size: HashMap<usize, Vec<FileNode>> = HashMap::new();
+        for node in &self.filenodes {
+            if node.is_used
+                && node.node_type == NodeType::File
+                && !node.is_expired(now)
+                && (node.encryption.is_none() || self.password.is_some())
+            {
+                by_size.entry(node.size).or_default().push(node.clone());
+            }
+        }
+
+        let mut by_hash: HashMap<[u8; 32], Vec<String>> = HashMap::new();
+        for nodes in by_size.into_values().filter(|nodes| nodes.len() > 1) {
+            for node in nodes {
+                let mut hasher = Sha256::new();
+                self.read_into_writer(&node, &mut hasher)?;
+                let alias = node.get_alias_str().map_err(|e| {
+                    FsError::Corrupt(format!("Invalid alias while finding duplicates: {}", e))
+                })?;
+                by_hash
+                    .entry(hasher.finalize().into())
+                    .or_default()
+                    .push(alias);
+            }
+        }
+
+        let mut groups: Vec<Vec<String>> = by_hash
+            .into_values()
+            .filter(|aliases| aliases.len() > 1)
+            .collect();
+        for group in &mut groups {
+            group.sort();
+        }
+        groups.sort();
+        Ok(groups)
+    }
+
+    /// Returns the lines of the file stored as `alias` that match `pattern`, with their
+    /// line numbers counted from 1 and without their line endings. The file is streamed,
+    /// so only the matching lines are held in memory.
+    pub fn grep_file(
+        &mut self,
+        alias: &str,
+        pattern: &LinePattern,
+    ) -> Result<Vec<(usize, Vec<u8>)>, FsError> {
+        if matches!(pattern, LinePattern::Substring(text) if text.is_empty()) {
+            return Err(FsError::InvalidArgument(
+                "Search pattern must not be empty.".to_string(),
+            ));
+        }
+        let mut grep = LineGrep {
+            pattern,
+            line: Vec::new(),
+            line_number: 0,
+            matches: Vec::new(),
+        };
+        self.read_file(alias, &mut grep)?;
+        // A last line without a line ending still counts
+        if !grep.line.is_empty() {
+            grep.end_line();
+        }
+        Ok(grep.matches)
+    }
+
+    /// Returns the metadata of the unexpired file stored as `alias`. For a symlink, this is
+    /// the metadata of the file it points to.
+    pub fn get_file_info(&mut self, alias: &str) -> Result<FileInfo, FsError> {
+        let filenode = self
+            .find_filenode_index(alias)
+            .map(|index| self.filenodes[index].clone())
+            .filter(|node| !node.is_expired(unix_now()))
+            .ok_or_else(|| FsError::AliasNotFound(alias.to_string()))?;
+        if filenode.is_symlink() {
+            let target_node = self.resolve_node(alias)?;
+            let mut info = self.file_info(alias.to_string(), &target_node);
+            info.is_symlink = true;
+            return Ok(info);
+        }
+        Ok(self.file_info(alias.to_string(), &filenode))
+    }
+
+    /// Creates `link_alias` as a symbolic link to the file stored as `target_alias`. The
+    /// link holds the target's alias rather than its contents, so it is left dangling if
+    /// the target is deleted or renamed. Links to directories and to other links are not
+    /// allowed.
+    pub fn link(&mut self, target_alias: &str, link_alias: &str) -> Result<(), FsError> {
+        let target = self
+            .find_filenode_index(target_alias)
+            .ok_or_else(|| FsError::AliasNotFound(target_alias.to_string()))?;
+        if self.filenodes[target].node_type != NodeType::File {
+            return Err(FsError::InvalidArgument(format!(
+                "'{}' is not a file, so it cannot be linked to.",
+                target_alias
+            )));
+        }
+        self.validate_new_alias(link_alias)?;
+        let target_bytes = target_alias.as_bytes();
+        self.make_room(target_bytes.len().div_ceil(self.usable_block_size()))?;
+        self.transaction(|fs| {
+            fs.create_parent_dirs(link_alias)?;
+            let index = fs.write_from_reader(
+                link_alias,
+                &mut &target_bytes[..],
+                target_bytes.len(),
+                None,
+                None,
+                None,
+            )?;
+            fs.filenodes[index].node_type = NodeType::Symlink;
+            fs.save_single_filenode(index)
+        })
+    }
+
+    /// Returns the alias the symlink stored as `alias` points to.
+    pub fn link_target(&mut self, alias: &str) -> Result<String, FsError> {
+        let filenode = self
+            .find_filenode_index(alias)
+            .map(|index| self.filenodes[index].clone())
+            .ok_or_else(|| FsError::AliasNotFound(alias.to_string()))?;
+        if !filenode.is_symlink() {
+            return Err(FsError::InvalidArgument(format!(
+                "'{}' is not a symlink.",
+                alias
+            )));
+        }
+        self.read_link_target(&filenode)
+    }
+
+    /// Reads the target alias held in the chain of the symlink `filenode`.
+    fn read_link_target(&mut self, filenode: &FileNode) -> Result<String, FsError> {
+        let mut target = Vec::with_capacity(filenode.size);
+        self.read_into_writer(filenode, &mut target)?;
+        String::from_utf8(target).map_err(|_| {
+            FsError::Corrupt(format!(
+                "Symlink '{}' points to an alias that is not valid UTF-8.",
+                filenode.alias_lossy()
+            ))
+        })
+    }
+
+    /// Returns the unexpired filenode stored as `alias`, or the one it points to if it is
+    /// a symlink. Only one level of links is followed.
+    pub fn resolve_node(&mut self, alias: &str) -> Result<FileNode, FsError> {
+        let now = unix_now();
+        let filenode = self
+            .find_filenode_index(alias)
+            .map(|index| self.filenodes[index].clone())
+            .filter(|node| !node.is_expired(now))
+            .ok_or_else(|| FsError::AliasNotFound(alias.to_string()))?;
+        if !filenode.is_symlink() {
+            return Ok(filenode);
+        }
+        let target = self.read_link_target(&filenode)?;
+        let target_node = self
+            .find_filenode_index(&target)
+            .map(|index| self.filenodes[index].clone())
+            .filter(|node| !node.is_expired(now))
+            .ok_or_else(|| {
+                FsError::InvalidArgument(format!(
+                    "Symlink '{}' is dangling: '{}' does not exist.",
+                    alias, target
+                ))
+            })?;
+        if target_node.is_symlink() {
+            let loops = target == alias || self.read_link_target(&target_node)? == alias;
+            return Err(FsError::InvalidArgument(if loops {
+                format!("Symlink '{}' is part of a loop.", alias)
+            } else {
+                format!(
+                    "Symlink '{}' points to another symlink, '{}'; only one level is followed.",
+                    alias, target
+                )
+            }));
+        }
+        Ok(target_node)
+    }
+
+    /// Returns the indices of the data blocks holding the file stored as `alias`, in chain
+    /// order, by following the next pointers on disk. A chain that loops or leaves the data
+    /// area is reported as corrupt.
+    pub fn block_chain(&mut self, alias: &str) -> Result<Vec<usize>, FsError> {
+        let filenode = self
+            .find_filenode_index(alias)
+            .map(|index| self.filenodes[index].clone())
+            .ok_or_else(|| FsError::AliasNotFound(alias.to_string()))?;
+        self.collect_block_chain(&filenode)
+    }
+
+    /// Yields the metadata of every unexpired file, in filenode order, without collecting
+    /// them first.
+    pub fn files(&self) -> impl Iterator<Item = FileInfo> + '_ {
+        let now = unix_now();
+        self.filenodes
+            .iter()
+            .filter(move |node| node.is_used && !node.is_expired(now))
+            .map(|node| self.file_info(node.alias_lossy(), node))
+    }
+
+    /// Returns the metadata of every unexpired file, in filenode order.
+    pub fn list_file_infos(&self) -> Vec<FileInfo> {
+        self.files().collect()
+    }
+
+    /// Returns the metadata of every stored file ordered by `sort`. Files that compare equal
+    /// are ordered by alias.
+    pub fn list_files_sorted(
+        &self,
+        sort: SortBy,
+        descending: bool,
+    ) -> Result<Vec<FileInfo>, FsError> {
+        let mut files = self.list_file_infos();
+        match sort {
+            SortBy::Name => files.sort_by(|a, b| a.alias.cmp(&b.alias)),
+            SortBy::Size => files.sort_by(|a, b| a.size.cmp(&b.size).then(a.alias.cmp(&b.alias))),
+            SortBy::None => {}
+        }
+        if descending {
+            files.reverse();
+        }
+        Ok(files)
+    }
+
+    /// Returns the metadata of the entries directly inside the directory `path`, ordered
+    /// by `sort`. An empty path lists the top level.
+    pub fn list_dir(
+        &self,
+        path: &str,
+        sort: SortBy,
+        descending: bool,
+    ) -> Result<Vec<FileInfo>, FsError> {
+        let path = path.trim_matches('/');
+        if !path.is_empty() {
+            let index = self
+                .find_filenode_index(path)
+                .ok_or_else(|| FsError::AliasNotFound(path.to_string()))?;
+            if !self.filenodes[index].is_dir() {
+                return Err(FsError::InvalidArgument(format!(
+                    "'{}' is not a directory.",
+                    path
+                )));
+            }
+        }
+        let mut files = self.list_files_sorted(sort, descending)?;
+        files.retain(|info| info.alias.rsplit_once('/').map_or("", |(parent, _)| parent) == path);
+        Ok(files)
+    }
+
+    /// Returns the metadata of every unexpired file whose alias matches the glob `pattern`,
+    /// where `*` matches any run of characters and `?` matches exactly one, and whose size
+    /// is within `size`.
+    pub fn find_files(
+        &self,
+        pattern: &str,
+        ignore_case: bool,
+        size: SizeFilter,
+    ) -> Result<Vec<FileInfo>, FsError> {
+        let fold = |text: &str| -> Vec<char> {
+            if ignore_case {
+                text.to_lowercase().chars().collect()
+            } else {
+                text.chars().collect()
+            }
+        };
+        let pattern = fold(pattern);
+        Ok(self
+            .files()
+            .filter(|info| size.matches(info) && glob_match(&pattern, &fold(&info.alias)))
+            .collect())
+    }
+
+    fn file_info(&self, alias: String, filenode: &FileNode) -> FileInfo {
+        FileInfo {
+            alias,
+            size: filenode.size,
+            num_blocks: filenode.stored_len().div_ceil(self.usable_block_size()),
+            first_block_index: filenode.first_block_index,
+            expires_at: filenode.expires_at,
+            created_at: filenode.created_at,
+            modified_at: filenode.modified_at,
+            compressed: filenode.compressed,
+            stored_size: filenode.stored_len(),
+            encrypted: filenode.encryption.is_some(),
+            refcount: filenode.refcount,
+            is_directory: filenode.is_dir(),
+            is_symlink: filenode.is_symlink(),
+            content_type: (!filenode.is_dir()).then(|| filenode.content_type()),
+            description: filenode.description(),
+        }
+    }
+
+    /// Lists the entries directly inside the directory `dir` (the top level if empty),
+    /// with the size of each file. Directory names end in a slash.
+    pub fn list_files(&self, dir: &str) -> Result<Vec<String>, FsError> {
+        Ok(self
+            .list_dir(dir, SortBy::None, false)?
+            .into_iter()
+            .map(|info| {
+                if info.is_directory {
+                    format!("{}/", info.alias)
+                } else {
+                    format!("{} ({} bytes)", info.alias, info.size)
+                }
+            })
+            .collect())
+    }
+
+    /// Deletes a file from the filesystem.
+    pub fn delete_file(&mut self, alias: &str) -> Result<(), FsError> {
+        self.transaction(|fs| fs.remove_file(alias, false))
+    }
+
+    /// Deletes a file after overwriting each of its blocks with zeros, so its old contents
+    /// cannot be recovered from the filesystem file. Blocks still shared with deduplicated
+    /// files are left untouched.
+    pub fn shred_file(&mut self, alias: &str) -> Result<(), FsError> {
+        self.transaction(|fs| fs.remove_file(alias, true))
+    }
+
+    /// Works out what deleting the file stored as `alias` would do without changing
+    /// anything: the blocks that would be freed and the deduplicated files that would
+    /// keep them. Fails where deleting would fail.
+    pub fn plan_delete(&mut self, alias: &str) -> Result<DeletePlan, FsError> {
+        // Check if the alias is valid
+        let filenode_index = self
+            .find_filenode_index(alias)
+            .ok_or_else(|| FsError::AliasNotFound(alias.to_string()))?;
+        if self.filenodes[filenode_index].is_dir() && self.has_children(alias) {
+            return Err(FsError::InvalidArgument(format!(
+                "Directory '{}' is not empty.",
+                alias
+            )));
+        }
+
+        // Calculate the number of blocks to free; shared blocks stay with the other files
+        let first_block_index = self.filenodes[filenode_index].first_block_index;
+        let mut other_sharers = self.chain_sharers(first_block_index);
+        other_sharers.retain(|&sharer| sharer != filenode_index);
+        let mut blocks_to_free = Vec::new();
+
+        // Traverse the linked list of blocks. A chain that leaves the data area is freed
+        // as far as it goes, but a looping one may run into another file's blocks.
+        let mut chain = BlockChainIter::indices_only(
+            self,
+            first_block_index.filter(|_| other_sharers.is_empty()),
+        );
+        while let Some(block) = chain.next() {
+            match block {
+                Ok((block_index, _)) => blocks_to_free.push(block_index),
+                Err(e) if chain.looped() => {
+                    return Err(FsError::Corrupt(format!(
+                        "File '{}': {} Run `fsck --repair` first.",
+                        alias, e
+                    )));
+                }
+                Err(e) => {
+                    warn!("Deleting '{}' despite its broken block chain: {}", alias, e);
+                    break;
+                }
+            }
+        }
+        Ok(DeletePlan {
+            blocks_freed: blocks_to_free,
+            shared_with: other_sharers
+                .iter()
+                .map(|&sharer| self.filenodes[sharer].alias_lossy())
+                .collect(),
+            filenode_index,
+            other_sharers,
+        })
+    }
+
+    /// Deletes the file stored as `alias`, zeroing its blocks first if `shred` is set. The
+    /// blocks are only marked free on disk after they have been zeroed. If deduplicated
+    /// files share the blocks, they are kept and only the refcount drops.
+    fn remove_file(&mut self, alias: &str, shred: bool) -> Result<(), FsError> {
+        let DeletePlan {
+            blocks_freed: blocks_to_free,
+            filenode_index,
+            other_sharers,
+            ..
+        } = self.plan_delete(alias)?;
+        if shred {
+            let zeros = vec![0u8; self.header.block_size];
+            for &block_index in &blocks_to_free {
+                self.write_block(block_index, &zeros)?;
+            }
+        }
+
+        // Mark the blocks as free in the bitmap
+        for block_idx in &blocks_to_free {
+            if *block_idx < self.header.num_data_blocks {
+                self.set_block_free(*block_idx, true);
+            } else {
+                warn!(
+                    "Tried to free out-of-bounds block {} for '{}'.",
+                    block_idx, alias
+                );
+            }
+        }
+        debug!("Freed {} blocks of '{}'", blocks_to_free.len(), alias);
+
+        // Clear the filenode data, keeping the alias as a tombstone for incremental exports
+        let filenode = &mut self.filenodes[filenode_index];
+        filenode.is_used = false;
+        filenode.size = 0;
+        filenode.first_block_index = None;
+        filenode.expires_at = None;
+        filenode.refcount = 0;
+        filenode.node_type = NodeType::File;
+        self.record_change(filenode_index)?;
+
+        // Save the updated filenode and bitmap to disk and flush the file
+        self.save_single_filenode(filenode_index)?;
+        self.set_refcounts(&other_sharers)?;
+        self.write_bitmap_blocks(&blocks_to_free)?;
+        self.storage
+            .flush()
+            .map_err(|e| FsError::io("Final flush failed (delete)", e))?;
+        Ok(())
+    }
+
+    /// Deletes every file and directory, keeping the volume's size and layout. Deletions
+    /// are not recorded as tombstones, so the next incremental export must be a full one.
+    /// Returns the number of entries deleted.
+    pub fn clear_all(&mut self) -> Result<usize, FsError> {
+        self.clear(false)
+    }
+
+    /// Like `clear_all`, but overwrites every block in use with zeros first, so no old
+    /// contents can be recovered from the filesystem file.
+    pub fn shred_all(&mut self) -> Result<usize, FsError> {
+        self.clear(true)
+    }
+
+    fn clear(&mut self, shred: bool) -> Result<usize, FsError> {
+        if shred {
+            let zeros = vec![0u8; self.header.block_size];
+            for block_index in 0..self.header.num_data_blocks {
+                if !self.is_block_free(block_index) {
+                    self.write_block(block_index, &zeros)?;
+                }
+            }
+        }
+
+        let removed = self.filenodes.iter().filter(|node| node.is_used).count();
+        self.filenodes.fill(FileNode::new());
+        self.free_block_bitmap = all_free_bitmap(self.header.num_data_blocks);
+        self.free_block_count = self.header.num_data_blocks;
+        // Dropping the tombstones forgets every deletion up to now
+        self.header.generation += 1;
+        self.header.tombstone_floor = self.header.generation;
+        self.transaction(|fs| {
+            fs.save_filenodes()?;
+            fs.write_bitmap_to_disk()
+        })?;
+        info!("Cleared {} entries from the filesystem", removed);
+        Ok(removed)
+    }
+
+    /// Copies the file stored as `src_alias` to a new, independent file `dst_alias`.
+    /// Expired files cannot be copied, matching `download_file`.
+    ///
+    /// The destination chain is allocated up front and the data is streamed one block at a
+    /// time: each source block is read, its next pointer rewritten to the destination chain
+    /// and written to the matching destination block, so at most one block is held in memory.
+    pub fn copy_file(&mut self, src_alias: &str, dst_alias: &str) -> Result<(), FsError> {
+        self.validate_new_alias(dst_alias)?;
+        let src_node = self
+            .find_filenode_index(src_alias)
+            .map(|index| self.filenodes[index].clone())
+            .filter(|node| !node.is_expired(unix_now()))
+            .ok_or_else(|| FsError::AliasNotFound(src_alias.to_string()))?;
+        if src_node.is_dir() {
+            return Err(FsError::InvalidArgument(format!(
+                "'{}' is a directory.",
+                src_alias
+            )));
+        }
+
+        let src_chain = self.collect_block_chain(&src_node)?;
+        self.make_room(src_chain.len())?;
+        let dst_chain = self.reserve_blocks(src_chain.len(), self.prefers_contiguous())?;
+        if let Some(first_block) = dst_chain.first() {
+            debug!(
+                "Allocated {} blocks for '{}' starting at block {}",
+                dst_chain.len(),
+                dst_alias,
+                first_block
+            );
+        }
+
+        // Source and destination blocks are distinct: the destination blocks were free.
+        let mut block_data_buffer = vec![0u8; self.header.block_size];
+        for (i, (&src_block, &dst_block)) in src_chain.iter().zip(&dst_chain).enumerate() {
+            let next_block_index = dst_chain.get(i + 1).copied().unwrap_or(usize::MAX);
+            let result = self
+                .read_block(src_block, &mut block_data_buffer)
+                .and_then(|_| {
+                    block_data_buffer[self.usable_block_size()..self.header.block_size]
+                        .copy_from_slice(&next_block_index.to_le_bytes());
+                    self.write_block(dst_block, &block_data_buffer)
+                });
+            if let Err(e) = result {
+                self.release_blocks(&dst_chain);
+                return Err(e);
+            }
+        }
+
+        // The copy shares the content metadata of the source but is a new file. Its parent
+        // directories are created with it, so a copy that fails leaves none behind, and the
+        // reserved blocks are freed again when the transaction rolls back.
+        let mut dst_node = src_node;
+        dst_node.set_alias(dst_alias);
+        dst_node.first_block_index = dst_chain.first().copied();
+        dst_node.expires_at = None;
+        dst_node.refcount = 1;
+        dst_node.created_at = unix_now();
+        dst_node.modified_at = dst_node.created_at;
+        self.transaction(|fs| {
+            fs.create_parent_dirs(dst_alias)?;
+            let dst_index = fs
+                .find_free_filenode_index()
+                .ok_or(FsError::NoFreeFilenodes)?;
+            fs.claim_filenode(dst_index);
+            fs.filenodes[dst_index] = dst_node;
+            fs.record_change(dst_index)?;
+            fs.save_single_filenode(dst_index)?;
+            fs.write_bitmap_blocks(&dst_chain)
+        })
+    }
+
+    /// Copies the file stored as `alias` into the filesystem `other` under the same alias,
+    /// then deletes it here if `remove_source` is set. The stored bytes are copied as they
+    /// are, so compression and encryption carry over, as do the expiry and timestamps.
+    ///
+    /// The source is read and checked, and `other` checked for space, before `other` is
+    /// changed. The source is only deleted once `other` has saved the file, so a failure
+    /// leaves both filesystems consistent.
+    pub fn transfer_to(
+        &mut self,
+        other: &mut FileSystemManager,
+        alias: &str,
+        remove_source: bool,
+    ) -> Result<(), FsError> {
+        if let (Some(source_path), Some(target_path)) = (self.path(), other.path()) {
+            if source_path.canonicalize().ok() == target_path.canonicalize().ok() {
+                return Err(FsError::InvalidArgument(
+                    "Cannot transfer a file to the filesystem it is stored in.".to_string(),
+                ));
+            }
+        }
+        let node = self
+            .find_filenode_index(alias)
+            .map(|index| self.filenodes[index].clone())
+            .filter(|node| !node.is_expired(unix_now()))
+            .ok_or_else(|| FsError::AliasNotFound(alias.to_string()))?;
+        if node.is_dir() {
+            return Err(FsError::InvalidArgument(format!(
+                "'{}' is a directory.",
+                alias
+            )));
+        }
+        if node.is_symlink() {
+            return Err(FsError::InvalidArgument(format!(
+                "'{}' is a symlink and cannot be transferred.",
+                alias
+            )));
+        }
+        other.validate_new_alias(alias)?;
+
+        let mut stored = Vec::with_capacity(node.stored_len());
+        if self.stream_chain(&node, &mut stored)? != node.checksum {
+            return Err(FsError::Corrupt(format!(
+                "Checksum mismatch for file '{}'. File is corrupt.",
+                alias
+            )));
+        }
+        other.make_room(stored.len().div_ceil(other.usable_block_size()))?;
+
+        other.transaction(|other| {
+            other.create_parent_dirs(alias)?;
+            let original_size = node.compressed.then_some(node.size);
+            let index = other.write_from_reader(
+                alias,
+                &mut stored.as_slice(),
+                stored.len(),
+                original_size,
+                node.encryption,
+                None,
+            )?;
+            let transferred = &mut other.filenodes[index];
+            transferred.expires_at = node.expires_at;
+            transferred.created_at = node.created_at;
+            transferred.modified_at = node.modified_at;
+            transferred.content_type = node.content_type.clone();
+            transferred.description = node.description.clone();
+            other.save_single_filenode(index)
+        })?;
+
+        if remove_source {
+            self.delete_file(alias)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the raw contents of physical data block `index`, including the next pointer.
+    ///
+    /// Expert-only: intended for recovery tooling.
+    pub fn read_block_raw(&mut self, index: usize) -> Result<Vec<u8>, FsError> {
+        if index >= self.header.num_data_blocks {
+            return Err(FsError::InvalidArgument(format!(
+                "Block index {} out of range (filesystem has {} data blocks).",
+                index, self.header.num_data_blocks
+            )));
+        }
+        let mut block_data_buffer = vec![0u8; self.header.block_size];
+        self.read_block(index, &mut block_data_buffer)?;
+        Ok(block_data_buffer)
+    }
+
+    /// Overwrites the start of physical data block `index` with `data`, bypassing all
+    /// filesystem metadata. Writing over the last bytes of a block changes its next pointer.
+    ///
+    /// Expert-only: this can corrupt files and is meant for manual repair and for
+    /// reproducing corruption.
+    pub fn write_block_raw(&mut self, index: usize, data: &[u8]) -> Result<(), FsError> {
+        let mut block_data_buffer = self.read_block_raw(index)?;
+        if data.len() > self.header.block_size {
+            return Err(FsError::InvalidArgument(format!(
+                "Raw data is {} bytes but a block holds only {} bytes.",
+                data.len(),
+                self.header.block_size
+            )));
+        }
+        block_data_buffer[..data.len()].copy_from_slice(data);
+        self.write_block(index, &block_data_buffer)?;
+
+        // File contents may have changed behind the filesystem's back.
+        self.header.merkle_root = None;
+        self.save_header()?;
+        self.storage
+            .flush()
+            .map_err(|e| FsError::io("Flush failed (raw write)", e))
+    }
+
+    /// Reads the full contents of data block `block_index` into `buffer`.
+    fn read_block(&mut self, block_index: usize, buffer: &mut [u8]) -> Result<(), FsError> {
+        let disk_offset = self.base_offset
+            + (self.header.data_blocks_offset + block_index * self.header.block_size) as u64;
+        let storage = &mut self.storage;
+        retry_io(self.io_retries, || storage.read_at(disk_offset, buffer))
+            .map_err(|e| FsError::io(format!("Read failed (block {})", block_index), e))
+    }
+
+    /// Writes `buffer` as the full contents of data block `block_index`.
+    fn write_block(&mut self, block_index: usize, buffer: &[u8]) -> Result<(), FsError> {
+        let disk_offset = self.base_offset
+            + (self.header.data_blocks_offset + block_index * self.header.block_size) as u64;
+        let storage = &mut self.storage;
+        retry_io(self.io_retries, || storage.write_at(disk_offset, buffer))
+            .map_err(|e| FsError::io(format!("Write failed (block {})", block_index), e))
+    }
+
+    /// Sets how many times block reads and writes are retried after a transient I/O error.
+    pub fn set_io_retries(&mut self, retries: u32) {
+        self.io_retries = retries;
+    }
+
+    /// Sets how the data blocks of new files are chosen.
+    pub fn set_alloc_strategy(&mut self, strategy: AllocStrategy) {
+        self.alloc_strategy = strategy;
+    }
+
+    /// Sets whether the volume grows its file with more data blocks when it runs out of
+    /// space, rather than failing with `NotEnoughSpace`. Off by default.
+    ///
+    /// Growing past the room the bitmap was laid out with moves the data area to make the
+    /// bitmap larger, which, like `grow_filenode_table`, is not safe against interruption.
+    /// A volume followed by other data in its file is never grown.
+    pub fn set_growable(&mut self, growable: bool) {
+        self.growable = growable;
+    }
+
+    /// Sets the password used to decrypt encrypted files when they are read.
+    pub fn set_password(&mut self, password: Option<String>) {
+        self.password = password;
+    }
+
+    /// Switches to reading and writing the filesystem's file through a memory mapping,
+    /// which makes bulk block operations cheaper. Fails if the filesystem is not stored in
+    /// a file or the file cannot be mapped, in which case the current storage is kept.
+    pub fn use_mmap(&mut self) -> Result<(), FsError> {
+        let path = self.path.clone().ok_or_else(|| {
+            FsError::InvalidArgument(
+                "Only a filesystem stored in a file can be mapped.".to_string(),
+            )
+        })?;
+        self.storage
+            .flush()
+            .map_err(|e| FsError::io("Flush failed (map file)", e))?;
+        // A handle to the same open file shares its lock, which a new one would conflict with
+        let file = self
+            .storage
+            .as_file()
+            .ok_or_else(|| {
+                FsError::InvalidArgument(
+                    "Only a filesystem stored in a file can be mapped.".to_string(),
+                )
+            })?
+            .try_clone()
+            .map_err(|e| FsError::io(format!("Failed to open {}", path.display()), e))?;
+        let storage = MmapStorage::new(file)
+            .map_err(|e| FsError::io(format!("Failed to map {}", path.display()), e))?;
+        self.storage = Box::new(storage);
+        Ok(())
+    }
+
+    /// Returns the password set with `set_password`, if any.
+    pub fn password(&self) -> Option<&str> {
+        self.password.as_deref()
+    }
+
+    /// Sets the note about the file or directory stored as `alias`, or removes it with
+    /// `None` or an empty note.
+    pub fn set_description(
+        &mut self,
+        alias: &str,
+        description: Option<&str>,
+    ) -> Result<(), FsError> {
+        let description = description.unwrap_or_default();
+        validate_description(description)?;
+        let index = self
+            .find_filenode_index(alias)
+            .ok_or_else(|| FsError::AliasNotFound(alias.to_string()))?;
+        self.filenodes[index].description = description.as_bytes().to_vec();
+        self.transaction(|fs| {
+            fs.record_change(index)?;
+            fs.save_single_filenode(index)
+        })
+    }
+
+    /// Renames the file stored as `old_alias` to `new_alias` without touching its data.
+    /// Renaming a file to its current alias is a no-op.
+    pub fn rename_file(&mut self, old_alias: &str, new_alias: &str) -> Result<(), FsError> {
+        let index = self
+            .find_filenode_index(old_alias)
+            .ok_or_else(|| FsError::AliasNotFound(old_alias.to_string()))?;
+        if old_alias == new_alias {
+            return Ok(());
+        }
+        self.validate_new_alias(new_alias)?;
+        if self.filenodes[index].is_dir() && self.has_children(old_alias) {
+            return Err(FsError::InvalidArgument(format!(
+                "Directory '{}' is not empty.",
+                old_alias
+            )));
+        }
+        self.transaction(|fs| {
+            fs.create_parent_dirs(new_alias)?;
+            fs.filenodes[index].set_alias(new_alias);
+            fs.record_change(index)?;
+            fs.save_single_filenode(index)?;
+
+            // Leave a tombstone so incremental exports drop the old alias. Without a free
+            // filenode to hold it, earlier generations can no longer be exported from.
+            match fs.find_free_filenode_index() {
+                Some(tombstone_index) => {
+                    fs.claim_filenode(tombstone_index);
+                    fs.filenodes[tombstone_index].set_alias(old_alias);
+                    fs.record_change(tombstone_index)?;
+                    fs.save_single_filenode(tombstone_index)
+                }
+                None => {
+                    fs.header.tombstone_floor = fs.header.generation;
+                    fs.save_header()
+                }
+            }
+        })
+    }
+
+    /// Moves the file stored as `src` to `dst` without copying its data. If `dst` exists
+    /// and `overwrite` is set, the file there is deleted first and its blocks freed;
+    /// otherwise this is `rename_file`. Moving a file onto itself is a no-op.
+    ///
+    /// A move that would be refused is refused before `dst` is deleted. Both steps share
+    /// one transaction, but it commits the writes made before an I/O error, so such an
+    /// error partway through can leave `dst` deleted and `src` not yet renamed.
+    pub fn move_file(&mut self, src: &str, dst: &str, overwrite: bool) -> Result<(), FsError> {
+        let src_index = self
+            .find_filenode_index(src)
+            .ok_or_else(|| FsError::AliasNotFound(src.to_string()))?;
+        if src == dst {
+            return Ok(());
+        }
+        let Some(dst_index) = self.find_filenode_index(dst).filter(|_| overwrite) else {
+            return self.rename_file(src, dst);
+        };
+        if self.filenodes[src_index].is_dir() || self.filenodes[dst_index].is_dir() {
+            return Err(FsError::InvalidArgument(
+                "Directories cannot be moved over another entry.".to_string(),
+            ));
+        }
+        if self.filenodes[src_index].is_symlink()
+            && self.link_target(src).is_ok_and(|target| target == dst)
+        {
+            return Err(FsError::InvalidArgument(format!(
+                "'{}' is a symlink to '{}' and cannot replace it.",
+                src, dst
+            )));
+        }
+
+        info!("Moving '{}' over '{}'", src, dst);
+        self.transaction(|fs| {
+            fs.remove_file(dst, false)?;
+            fs.rename_file(src, dst)
+        })
+    }
+
+    /// Exchanges the contents of the files stored as `a` and `b`. Each alias keeps its own
+    /// note, creation time and expiry; the blocks and everything describing them move.
+    /// Only the two filenodes change, in one transaction, so readers see either the old
+    /// or the new pairing. Directories and symlinks cannot be swapped.
+    pub fn swap_aliases(&mut self, a: &str, b: &str) -> Result<(), FsError> {
+        let index_a = self
+            .find_filenode_index(a)
+            .ok_or_else(|| FsError::AliasNotFound(a.to_string()))?;
+        let index_b = self
+            .find_filenode_index(b)
+            .ok_or_else(|| FsError::AliasNotFound(b.to_string()))?;
+        if index_a == index_b {
+            return Ok(());
+        }
+        for (alias, index) in [(a, index_a), (b, index_b)] {
+            if self.filenodes[index].node_type != NodeType::File {
+                return Err(FsError::InvalidArgument(format!(
+                    "'{}' is not a file. Only files can be swapped.",
+                    alias
+                )));
+            }
+        }
+
+        let (low, high) = (index_a.min(index_b), index_a.max(index_b));
+        let (head, tail) = self.filenodes.split_at_mut(high);
+        swap_contents(&mut head[low], &mut tail[0]);
+        self.transaction(|fs| {
+            fs.record_change(index_a)?;
+            fs.record_change(index_b)?;
+            fs.save_single_filenode(index_a)?;
+            fs.save_single_filenode(index_b)
+        })
+    }
+
+    /// Returns the stored alias closest to `alias` when `alias` itself does not exist,
+    /// to help users spot typos. Only reasonably close matches are suggested.
+    pub fn suggest_alias(&self, alias: &str) -> Option<String> {
+        if self.find_filenode_index(alias).is_some() {
+            return None;
+        }
+        let max_distance = std::cmp::max(1, alias.chars().count() / 3);
+        self.filenodes
+            .iter()
+            .filter(|node| node.is_used)
+            .filter_map(|node| node.get_alias_str().ok())
+            .map(|candidate| (levenshtein_distance(alias, &candidate), candidate))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .min_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)))
+            .map(|(_, candidate)| candidate)
+    }
+
+    /// Deletes every file whose expiry time has passed and returns their aliases.
+    pub fn purge_expired(&mut self) -> Result<Vec<String>, FsError> {
+        let now = unix_now();
+        let expired_aliases: Vec<String> = self
+            .filenodes
+            .iter()
+            .filter(|node| node.is_used && node.is_expired(now))
+            .filter_map(|node| node.get_alias_str().ok())
+            .collect();
+        for alias in &expired_aliases {
+            self.delete_file(alias)?;
+        }
+        Ok(expired_aliases)
+    }
+
+    /// Returns a Merkle root summarising the content of every stored file.
+    ///
+    /// Each leaf hashes a file's alias together with the SHA-256 of its content; leaves are
+    /// ordered by alias, so volumes holding the same files produce the same root. The root
+    /// is cached in the header and recomputed once the generation has moved on.
+    pub fn merkle_root(&mut self) -> Result<[u8; 32], FsError> {
+        if let Some(root) = self.header.merkle_root {
+            if self.header.merkle_generation == self.header.generation {
+                return Ok(root);
+            }
+        }
+
+        let mut used_nodes: Vec<(String, FileNode)> = Vec::new();
+        for node in self.filenodes.iter().filter(|n| n.is_used) {
+            let alias = node
+                .get_alias_str()
+                .map_err(|e| FsError::Corrupt(format!("Invalid alias while hashing: {}", e)))?;
+            used_nodes.push((alias, node.clone()));
+        }
+        used_nodes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut level: Vec<[u8; 32]> = Vec::with_capacity(used_nodes.len());
+        for (alias, node) in &used_nodes {
+            // Encrypted files are hashed as stored, so no password is needed
+            let mut content_hasher = Sha256::new();
+            if node.encryption.is_some() {
+                self.stream_chain(node, &mut content_hasher)?;
+            } else {
+                self.read_into_writer(node, &mut content_hasher)?;
+            }
+            let content_hash = content_hasher.finalize();
+
+            let mut leaf_hasher = Sha256::new();
+            leaf_hasher.update([0u8]);
+            leaf_hasher.update((alias.len() as u64).to_le_bytes());
+            leaf_hasher.update(alias.as_bytes());
+            leaf_hasher.update(content_hash);
+            level.push(leaf_hasher.finalize().into());
+        }
+
+        // Hash pairs of nodes until one remains; an odd node is carried up unchanged.
+        let root: [u8; 32] = if level.is_empty() {
+            Sha256::digest([]).into()
+        } else {
+            while level.len() > 1 {
+                level = level
+                    .chunks(2)
+                    .map(|pair| match pair {
+                        [left, right] => {
+                            let mut hasher = Sha256::new();
+                            hasher.update([1u8]);
+                            hasher.update(left);
+                            hasher.update(right);
+                            hasher.finalize().into()
+                        }
+                        [single] => *single,
+                        _ => unreachable!(),
+                    })
+                    .collect();
+            }
+            level[0]
+        };
+
+        self.header.merkle_root = Some(root);
+        self.header.merkle_generation = self.header.generation;
+        self.save_header()?;
+        Ok(root)
+    }
+
+    /// Returns the byte ranges of the header, journal, filenode table, bitmap and data region.
+    pub fn layout(&self) -> Result<Layout, FsError> {
+        let header = &self.header;
+        let data_blocks_len = header.num_data_blocks * header.block_size;
+        let data_blocks_end = header.data_blocks_offset + data_blocks_len;
+        let filenode_table_serialized_len = bincode::serialized_size(&self.filenodes)
+            .map_err(|e| FsError::Serialization(format!("Failed to size filenode table: {}", e)))?
+            as usize;
+
+        Ok(Layout {
+            header: Region {
+                offset: 0,
+                len: header.journal_offset,
+            },
+            journal: Region {
+                offset: header.journal_offset,
+                len: header.filenode_table_offset - header.journal_offset,
+            },
+            filenode_table: Region {
+                offset: header.filenode_table_offset,
+                len: header.free_block_bitmap_offset - header.filenode_table_offset,
+            },
+            filenode_table_serialized_len,
+            free_block_bitmap: Region {
+                offset: header.free_block_bitmap_offset,
+                len: header.data_blocks_offset - header.free_block_bitmap_offset,
+            },
+            data_blocks: Region {
+                offset: header.data_blocks_offset,
+                len: data_blocks_len,
+            },
+            unused_tail: Region {
+                offset: data_blocks_end,
+                len: header.total_size.saturating_sub(data_blocks_end),
+            },
+        })
+    }
+
+    /// Walks every used file's block chain, reading the next pointers from disk, and
+    /// compares it with the file sizes and the free block bitmap. Returns a description of
+    /// each problem found; an empty list means the filesystem is intact. Nothing is modified.
+    pub fn check_integrity(&mut self) -> Result<Vec<String>, FsError> {
+        let mut issues = Vec::new();
+        let reachable = self.reachable_blocks(&mut issues)?;
+        self.bitmap_issues(&reachable, &mut issues);
+        self.symlink_issues(&mut issues);
+        Ok(issues)
+    }
+
+    /// Adds a description of each block whose bitmap entry disagrees with `reachable`.
+    fn bitmap_issues(&self, reachable: &[bool], issues: &mut Vec<String>) {
+        let (orphaned, marked_free) = self.bitmap_mismatches(reachable);
+        for block_index in marked_free {
+            issues.push(format!(
+                "Block {} is used by a file but marked free.",
+                block_index
+            ));
+        }
+        for block_index in orphaned {
+            issues.push(format!(
+                "Block {} is marked used but not reachable from any file (orphaned).",
+                block_index
+            ));
+        }
+    }
+
+    /// Adds a description of each symlink whose target does not exist. Symlinks whose
+    /// chain cannot be read are left to be reported as broken chains.
+    fn symlink_issues(&mut self, issues: &mut Vec<String>) {
+        let symlinks: Vec<FileNode> = self
+            .filenodes
+            .iter()
+            .filter(|node| node.is_used && node.is_symlink())
+            .cloned()
+            .collect();
+        for node in symlinks {
+            if let Ok(target) = self.read_link_target(&node) {
+                if self.find_filenode_index(&target).is_none() {
+                    issues.push(format!(
+                        "Symlink '{}' is dangling: '{}' does not exist.",
+                        node.alias_lossy(),
+                        target
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Compares the bitmap with the set of reachable blocks. Returns the orphaned blocks
+    /// (used but unreachable) and the blocks that are reachable but marked free.
+    fn bitmap_mismatches(&self, reachable: &[bool]) -> (Vec<usize>, Vec<usize>) {
+        let mut orphaned = Vec::new();
+        let mut marked_free = Vec::new();
+        for (block_index, is_reachable) in reachable.iter().enumerate() {
+            let is_free = self.is_block_free(block_index);
+            if *is_reachable && is_free {
+                marked_free.push(block_index);
+            } else if !*is_reachable && !is_free {
+                orphaned.push(block_index);
+            }
+        }
+        (orphaned, marked_free)
+    }
+
+    /// Returns, for each data block, whether it is reachable from a used filenode.
+    /// Broken chains are followed as far as possible and reported in `issues`, as are
+    /// chains whose length does not match the file size, wrong refcounts and blocks
+    /// reachable from more than one file other than through a shared (deduplicated) chain.
+    fn reachable_blocks(&mut self, issues: &mut Vec<String>) -> Result<Vec<bool>, FsError> {
+        self.walk_chains(issues).map(|(reachable, _)| reachable)
+    }
+
+    /// Like `reachable_blocks`, but also returns every intact chain, keyed by its first
+    /// block, for callers that need the chains as well.
+    #[allow(clippy::type_complexity)]
+    fn walk_chains(
+        &mut self,
+        issues: &mut Vec<String>,
+    ) -> Result<(Vec<bool>, HashMap<usize, Vec<usize>>), FsError> {
+        let mut chains = HashMap::new();
+        let mut reachable = vec![false; self.header.num_data_blocks];
+        let mut owners: Vec<Option<String>> = vec![None; self.header.num_data_blocks];
+        let mut walked_chains = HashSet::new();
+        let used_nodes: Vec<FileNode> = self
+            .filenodes
+            .iter()
+            .filter(|n| n.is_used)
+            .cloned()
+            .collect();
+
+        for node in used_nodes {
+            let alias = node.alias_lossy();
+            if node.get_alias_str().is_err() {
+                issues.push(format!(
+                    "File '{}' has an alias that is not valid UTF-8.",
+                    alias
+                ));
+            }
+
+            // Deduplicated files share one chain, which is only walked once
+            if let Some(first_block_index) = node.first_block_index {
+                let sharers = self.chain_sharers(Some(first_block_index)).len();
+                if node.refcount as usize != sharers {
+                    issues.push(format!(
+                        "File '{}' records a refcount of {} but its chain is shared by {} files.",
+                        alias, node.refcount, sharers
+                    ));
+                }
+                if !walked_chains.insert(first_block_index) {
+                    continue;
+                }
+            }
+
+            let mut visited = HashSet::new();
+            let mut chain = Vec::new();
+            let mut current_block_opt = node.first_block_index;
+            let mut chain_broken = false;
+
+            while let Some(current_block_index) = current_block_opt {
+                if current_block_index >= self.header.num_data_blocks {
+                    issues.push(format!(
+                        "File '{}' points to out-of-range block {}.",
+                        alias, current_block_index
+                    ));
+                    chain_broken = true;
+                    break;
+                }
+                if !visited.insert(current_block_index) {
+                    issues.push(format!(
+                        "File '{}' has a cycle in its block chain at block {}.",
+                        alias, current_block_index
+                    ));
+                    chain_broken = true;
+                    break;
+                }
+                match &owners[current_block_index] {
+                    Some(owner) => issues.push(format!(
+                        "Block {} is shared by files '{}' and '{}'.",
+                        current_block_index, owner, alias
+                    )),
+                    None => owners[current_block_index] = Some(alias.clone()),
+                }
+                reachable[current_block_index] = true;
+                chain.push(current_block_index);
+                current_block_opt = self.read_next_pointer(current_block_index)?;
+            }
+
+            let expected_blocks = node.stored_len().div_ceil(self.usable_block_size());
+            if !chain_broken && visited.len() != expected_blocks {
+                issues.push(format!(
+                    "File '{}' has {} blocks in its chain but its size of {} bytes needs {}.",
+                    alias,
+                    visited.len(),
+                    node.stored_len(),
+                    expected_blocks
+                ));
+            }
+            if let (false, Some(first_block_index)) = (chain_broken, node.first_block_index) {
+                chains.insert(first_block_index, chain);
+            }
+        }
+        Ok((reachable, chains))
+    }
+
+    /// Rebuilds the free block bitmap from the blocks reachable from used files, freeing
+    /// orphaned blocks and marking referenced blocks as used. Returns the number of
+    /// bitmap entries that changed.
+    pub fn rebuild_bitmap(&mut self) -> Result<usize, FsError> {
+        let reachable = self.reachable_blocks(&mut Vec::new())?;
+        self.apply_reachable_to_bitmap(&reachable)
+    }
+
+    /// Marks exactly the `reachable` blocks as used and persists the bitmap if anything
+    /// changed. Returns the number of bitmap entries that changed.
+    fn apply_reachable_to_bitmap(&mut self, reachable: &[bool]) -> Result<usize, FsError> {
+        let mut changed = 0;
+        for (block_index, is_reachable) in reachable.iter().enumerate() {
+            if self.is_block_free(block_index) == *is_reachable {
+                self.set_block_free(block_index, !is_reachable);
+                changed += 1;
+            }
+        }
+        if changed > 0 {
+            self.write_bitmap_to_disk()?;
+        }
+        Ok(changed)
+    }
+
+    /// Defragments the filesystem, then cuts the free blocks at the end of the data area off
+    /// the volume and shrinks its file to match. Returns the number of bytes the volume
+    /// shrank by. Nothing is cut off if defragmentation cannot finish, as free blocks
+    /// would still be left between files.
+    ///
+    /// The bitmap keeps its room for the blocks cut off, so uploads that need more space
+    /// than is left grow the data area back, up to as many blocks as it was created with.
+    pub fn compact(&mut self) -> Result<usize, FsError> {
+        if !self.defragment()?.complete {
+            return Err(FsError::InvalidArgument(
+                "Defragmentation stopped early for lack of a spare block, so the filesystem \
+                 was not compacted. Free some space and try again."
+                    .to_string(),
+            ));
+        }
+        let last_used_block = (0..self.header.num_data_blocks)
+            .rev()
+            .find(|&block_index| !self.is_block_free(block_index));
+        let old_total_size = self.header.total_size;
+        // A volume keeps at least one data block, unless it never had room for any
+        let num_blocks = last_used_block.map_or(self.data_block_capacity().min(1), |block_index| {
+            block_index + 1
+        });
+        self.resize_data_area(num_blocks)?;
+        Ok(old_total_size.saturating_sub(self.header.total_size))
+    }
+
+    /// Returns the number of data blocks the volume can hold when it is not compacted.
+    fn data_block_capacity(&self) -> usize {
+        self.header.max_data_blocks
+    }
+
+    /// Checks that `num_blocks` blocks are free, first growing a compacted data area back
+    /// towards its capacity if there are too few. A growable volume grows past its
+    /// capacity as far as needed.
+    ///
+    /// The grown layout is written out at once, even inside a transaction, as a rollback
+    /// could not move data blocks back. A transaction must therefore make room before it
+    /// writes any metadata.
+    fn make_room(&mut self, num_blocks: usize) -> Result<(), FsError> {
+        let free_blocks_count = self.free_block_count();
+        if num_blocks <= free_blocks_count {
+            return Ok(());
+        }
+        let pending = self.journal.take();
+        debug_assert!(
+            pending.as_ref().is_none_or(Vec::is_empty),
+            "volume grown after metadata writes in the same transaction"
+        );
+        let grown = self.grow_data_area(num_blocks - free_blocks_count);
+        self.journal = pending;
+        grown?;
+        if num_blocks > self.free_block_count() {
+            return Err(FsError::NotEnoughSpace {
+                needed: num_blocks,
+                available: self.free_block_count(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Adds up to `missing` data blocks, as far as the capacity allows or, for a growable
+    /// volume, as far as needed.
+    fn grow_data_area(&mut self, missing: usize) -> Result<(), FsError> {
+        let wanted = self.header.num_data_blocks + missing;
+        if self.growable && wanted > self.header.max_data_blocks && self.volume_ends_storage()? {
+            if wanted > self.bitmap_room() {
+                // Room for twice as many blocks keeps later growth from moving data again
+                self.widen_bitmap(wanted * 2)?;
+            }
+            self.header.max_data_blocks = wanted;
+        }
+        let capacity = self.data_block_capacity();
+        if self.header.num_data_blocks < capacity {
+            self.resize_data_area(wanted.min(capacity))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of data blocks the on-disk bitmap has room for.
+    fn bitmap_room(&self) -> usize {
+        (self.header.data_blocks_offset - self.header.free_block_bitmap_offset) * 8
+    }
+
+    /// Returns true if nothing but the volume is stored after its start in the storage.
+    fn volume_ends_storage(&self) -> Result<bool, FsError> {
+        let storage_len = self
+            .storage
+            .len()
+            .map_err(|e| FsError::io("Failed to get storage length", e))?;
+        Ok(storage_len <= self.base_offset + self.header.total_size as u64)
+    }
+
+    /// Moves the data area towards the end of the volume so that the bitmap has room for
+    /// `num_blocks` blocks. Every block keeps its index, so no chain or filenode changes.
+    /// Used blocks are copied from the last one back, as their new places overlap the old.
+    ///
+    /// The journal grows with the bitmap so that it still holds a transaction rewriting all
+    /// of it, moving the filenode table and bitmap along with the data area.
+    fn widen_bitmap(&mut self, num_blocks: usize) -> Result<(), FsError> {
+        let bitmap_size_bytes = num_blocks.div_ceil(8);
+        let table_size = self.header.free_block_bitmap_offset - self.header.filenode_table_offset;
+        let journal_size =
+            journal::journal_size(self.header.journal_offset, table_size, bitmap_size_bytes)
+                .max(self.header.journal_size);
+        let new_table_offset = self.header.journal_offset + journal_size;
+        let new_bitmap_offset = new_table_offset + table_size;
+        let new_data_blocks_offset = new_bitmap_offset + bitmap_size_bytes;
+        let shift = new_data_blocks_offset - self.header.data_blocks_offset;
+        info!(
+            "Moving the data area {} bytes back to make room in the bitmap for {} blocks",
+            shift, num_blocks
+        );
+        let new_end = self.base_offset + (self.header.total_size + shift) as u64;
+        self.storage
+            .set_len(new_end)
+            .map_err(|e| FsError::io("Failed to set storage length", e))?;
+
+        let mut block_data_buffer = vec![0u8; self.header.block_size];
+        for block_index in (0..self.header.num_data_blocks).rev() {
+            if self.is_block_free(block_index) {
+                continue;
+            }
+            self.read_block(block_index, &mut block_data_buffer)?;
+            let disk_offset = self.base_offset
+                + (new_data_blocks_offset + block_index * self.header.block_size) as u64;
+            let storage = &mut self.storage;
+            retry_io(self.io_retries, || {
+                storage.write_at(disk_offset, &block_data_buffer)
+            })
+            .map_err(|e| FsError::io(format!("Write failed (move block {})", block_index), e))?;
+        }
+
+        self.header.journal_size = journal_size;
+        self.header.filenode_table_offset = new_table_offset;
+        self.header.free_block_bitmap_offset = new_bitmap_offset;
+        self.header.data_blocks_offset = new_data_blocks_offset;
+        self.header.total_size += shift;
+        self.transaction(|fs| {
+            fs.save_filenodes()?;
+            fs.write_bitmap_to_disk()
+        })?;
+        self.storage
+            .flush()
+            .map_err(|e| FsError::io("Flush failed (widen bitmap)", e))
+    }
+
+    /// Changes the number of data blocks to `num_blocks`, which must not exceed the capacity,
+    /// resizing the volume's file to end after the last block. Blocks cut off must be free.
+    ///
+    /// When growing, the file is extended and the bitmap written before the header, so the
+    /// header never covers blocks whose bitmap bits are stale. When shrinking, the header is
+    /// written before the file is cut. A volume followed by other data in its file is never
+    /// cut short.
+    fn resize_data_area(&mut self, num_blocks: usize) -> Result<(), FsError> {
+        let old_num_blocks = self.header.num_data_blocks;
+        if num_blocks > self.data_block_capacity() {
+            return Err(FsError::InvalidArgument(format!(
+                "The volume only has room for {} data blocks, not {}.",
+                self.data_block_capacity(),
+                num_blocks
+            )));
+        }
+        if (num_blocks..old_num_blocks).any(|block_index| !self.is_block_free(block_index)) {
+            return Err(FsError::InvalidArgument(format!(
+                "Blocks past block {} are still in use.",
+                num_blocks
+            )));
+        }
+
+        let old_end = self.base_offset + self.header.total_size as u64;
+        let new_total_size = self.header.data_blocks_offset + num_blocks * self.header.block_size;
+        let new_end = self.base_offset + new_total_size as u64;
+        let storage_len = self
+            .storage
+            .len()
+            .map_err(|e| FsError::io("Failed to get storage length", e))?;
+
+        let mut free_block_bitmap = all_free_bitmap(num_blocks);
+        for block_index in 0..num_blocks.min(old_num_blocks) {
+            if !self.is_block_free(block_index) {
+                free_block_bitmap[block_index / 64] &= !(1 << (block_index % 64));
+            }
+        }
+        let used_blocks = old_num_blocks - self.free_block_count;
+        self.free_block_bitmap = free_block_bitmap;
+        self.free_block_count = num_blocks - used_blocks;
+        self.header.num_data_blocks = num_blocks;
+        info!(
+            "Resizing the data area from {} to {} blocks",
+            old_num_blocks, num_blocks
+        );
+        self.header.total_size = new_total_size;
+
+        if num_blocks > old_num_blocks {
+            if storage_len < new_end {
+                self.storage
+                    .set_len(new_end)
+                    .map_err(|e| FsError::io("Failed to set storage length", e))?;
+            }
+            self.write_bitmap_to_disk()?;
+            self.save_header()?;
+        } else {
+            // The bitmap keeps its place but covers fewer blocks, so its checksum changes
+            self.write_bitmap_to_disk()?;
+            if storage_len == old_end {
+                self.storage
+                    .set_len(new_end)
+                    .map_err(|e| FsError::io("Failed to set storage length", e))?;
+            }
+        }
+        self.storage
+            .flush()
+            .map_err(|e| FsError::io("Flush failed (resize data area)", e))
+    }
+
+    /// Packs every file into one contiguous run of blocks, files following each other from
+    /// the start of the data area, so that free space ends up as one run at the end.
+    ///
+    /// Blocks are moved one at a time, each move committed before the next starts. Every
+    /// block the run copies into is marked used on disk before the first move, and the
+    /// blocks it leaves are only marked free once the last is done, so the bitmap is
+    /// written twice per run and an interruption can at worst leak blocks, which
+    /// `fsck --repair` reclaims.
+    pub fn defragment(&mut self) -> Result<DefragReport, FsError> {
+        let (mut chains, mut owners) = self.defrag_layout()?;
+        let (moves, report) = self.plan_defrag_moves(chains.clone(), owners.clone());
+        let targets: Vec<usize> = moves.iter().map(|&(_, _, to)| to).collect();
+        for &to in &targets {
+            self.set_block_free(to, false);
+        }
+        self.write_bitmap_blocks(&targets)?;
+        let mut touched = targets;
+        for (file, position, to) in moves {
+            touched.push(self.relocate_block(&mut chains, &mut owners, file, position, to)?);
+        }
+        self.write_bitmap_blocks(&touched)?;
+        self.rebuild_bitmap()?;
+        Ok(report)
+    }
+
+    /// Works out what `defragment` would do without moving any block, returning the
+    /// report it would give.
+    pub fn plan_defragment(&mut self) -> Result<DefragReport, FsError> {
+        let (chains, owners) = self.defrag_layout()?;
+        Ok(self.plan_defrag_moves(chains, owners).1)
+    }
+
+    /// Returns the chains of all used files, ordered by where they start so that files
+    /// near the front move as little as possible, and the (file, position) owning each
+    /// block. Deduplicated files sharing a chain appear once.
+    #[allow(clippy::type_complexity)]
+    fn defrag_layout(
+        &mut self,
+    ) -> Result<(Vec<(usize, Vec<usize>)>, Vec<Option<(usize, usize)>>), FsError> {
+        let mut chains: Vec<(usize, Vec<usize>)> = Vec::new();
+        for index in 0..self.filenodes.len() {
+            if !self.filenodes[index].is_used {
+                continue;
+            }
+            let node = self.filenodes[index].clone();
+            let chain = self.collect_block_chain(&node).map_err(|_| {
+                FsError::Corrupt(format!(
+                    "File '{}' has a broken block chain. Run `fsck --repair` first.",
+                    node.alias_lossy()
+                ))
+            })?;
+            chains.push((index, chain));
+        }
+        chains.sort_by_key(|(_, chain)| chain.first().copied());
+        chains.dedup_by_key(|(_, chain)| chain.first().copied());
+        let mut owners: Vec<Option<(usize, usize)>> = vec![None; self.header.num_data_blocks];
+        for (file, (_, chain)) in chains.iter().enumerate() {
+            for (position, &block_index) in chain.iter().enumerate() {
+                if owners[block_index].replace((file, position)).is_some() {
+                    return Err(FsError::Corrupt(format!(
+                        "Block {} belongs to more than one file. Run `fsck` first.",
+                        block_index
+                    )));
+                }
+            }
+        }
+        Ok((chains, owners))
+    }
+
+    /// Plays defragmentation through on `chains` and `owners` without touching the disk.
+    /// Returns the block moves in the order they must be made, as (file, position in its
+    /// chain, new block), and the report defragmenting would give.
+    fn plan_defrag_moves(
+        &self,
+        mut chains: Vec<(usize, Vec<usize>)>,
+        mut owners: Vec<Option<(usize, usize)>>,
+    ) -> (Vec<(usize, usize, usize)>, DefragReport) {
+        let mut report = DefragReport {
+            complete: true,
+            ..DefragReport::default()
+        };
+        let mut free: Vec<bool> = (0..self.header.num_data_blocks)
+            .map(|block_index| self.is_block_free(block_index))
+            .collect();
+        let mut moves = Vec::new();
+        let mut relocate = |chains: &mut Vec<(usize, Vec<usize>)>,
+                            owners: &mut Vec<Option<(usize, usize)>>,
+                            free: &mut Vec<bool>,
+                            file: usize,
+                            position: usize,
+                            to: usize| {
+            let from = std::mem::replace(&mut chains[file].1[position], to);
+            owners[to] = owners[from].take();
+            free[to] = false;
+            free[from] = true;
+            moves.push((file, position, to));
+        };
+
+        let mut cursor = 0;
+        'files: for file in 0..chains.len() {
+            let num_blocks = chains[file].1.len();
+            let mut moved = false;
+            for position in 0..num_blocks {
+                let target = cursor + position;
+                if chains[file].1[position] == target {
+                    continue;
+                }
+                // Move whatever occupies the target out of the run this file is packed into
+                if let Some((owner_file, owner_position)) = owners[target] {
+                    let spare = (cursor + num_blocks..self.header.num_data_blocks)
+                        .find(|&block_index| free[block_index]);
+                    let Some(spare) = spare else {
+                        report.complete = false;
+                        break 'files;
+                    };
+                    relocate(
+                        &mut chains,
+                        &mut owners,
+                        &mut free,
+                        owner_file,
+                        owner_position,
+                        spare,
+                    );
+                    report.blocks_relocated += 1;
+                }
+                relocate(&mut chains, &mut owners, &mut free, file, position, target);
+                report.blocks_relocated += 1;
+                moved = true;
+            }
+            if moved {
+                report.files_moved += 1;
+            }
+            cursor += num_blocks;
+        }
+        (moves, report)
+    }
+
+    /// Copies block `position` of the chain `chains[file]` to block `to` and points the
+    /// chain, and every file sharing it, at the copy in one transaction. `to` must already
+    /// be marked used on disk. The old block is marked free in memory only, for
+    /// `defragment` to write out, and returned.
+    fn relocate_block(
+        &mut self,
+        chains: &mut [(usize, Vec<usize>)],
+        owners: &mut [Option<(usize, usize)>],
+        file: usize,
+        position: usize,
+        to: usize,
+    ) -> Result<usize, FsError> {
+        let chain = &mut chains[file].1;
+        let from = chain[position];
+        let mut block_data_buffer = vec![0u8; self.header.block_size];
+        self.read_block(from, &mut block_data_buffer)?;
+        self.write_block(to, &block_data_buffer)?;
+        // `to` may have been left by an earlier move of the same run
+        self.set_block_free(to, false);
+
+        // Point the previous block, or the filenode for the first block, at the copy
+        let previous_block = position.checked_sub(1).map(|previous| chain[previous]);
+        self.transaction(|fs| match previous_block {
+            Some(previous_block) => {
+                let pointer_offset = fs.header.data_blocks_offset
+                    + previous_block * fs.header.block_size
+                    + fs.usable_block_size();
+                fs.write_metadata(pointer_offset, to.to_le_bytes().to_vec())
+                    .map_err(|e| FsError::io("Write failed (relocate block)", e))
+            }
+            None => {
+                for sharer in fs.chain_sharers(Some(from)) {
+                    fs.filenodes[sharer].first_block_index = Some(to);
+                    fs.save_single_filenode(sharer)?;
+                }
+                Ok(())
+            }
+        })?;
+        chain[position] = to;
+        owners[to] = owners[from].take();
+
+        self.set_block_free(from, true);
+        Ok(from)
+    }
+
+    /// Repairs the filesystem: chains with an out-of-range or cyclic next pointer are cut
+    /// after the last good block, files are truncated to the bytes their chain reaches,
+    /// refcounts are recounted, and the free block bitmap is rebuilt from the blocks
+    /// reachable from used files.
+    pub fn repair(&mut self) -> Result<RepairReport, FsError> {
+        let mut report = RepairReport::default();
+        let used_indices: Vec<usize> = (0..self.filenodes.len())
+            .filter(|&index| self.filenodes[index].is_used)
+            .collect();
+
+        for index in used_indices {
+            let node = self.filenodes[index].clone();
+            let mut visited = HashSet::new();
+            let mut last_good_block = None;
+            let mut current_block_opt = node.first_block_index;
+            let mut broken = false;
+            while let Some(current_block_index) = current_block_opt {
+                if current_block_index >= self.header.num_data_blocks
+                    || !visited.insert(current_block_index)
+                {
+                    broken = true;
+                    break;
+                }
+                last_good_block = Some(current_block_index);
+                current_block_opt = self.read_next_pointer(current_block_index)?;
+            }
+            let new_size =
+                std::cmp::min(node.stored_len(), visited.len() * self.usable_block_size());
+            if !broken && new_size == node.stored_len() {
+                continue;
+            }
+
+            // End the chain at the last block that was reached
+            if broken {
+                let mut block_data_buffer = vec![0u8; self.header.block_size];
+                match last_good_block {
+                    Some(block_index) => {
+                        self.read_block(block_index, &mut block_data_buffer)?;
+                        block_data_buffer[self.usable_block_size()..self.header.block_size]
+                            .copy_from_slice(&usize::MAX.to_le_bytes());
+                        self.write_block(block_index, &block_data_buffer)?;
+                    }
+                    None => self.filenodes[index].first_block_index = None,
+                }
+            }
+            // The checksum now covers only the bytes that were kept. Compressed contents
+            // cut short can no longer be decompressed, but the chain is consistent again.
+            self.filenodes[index].stored_size = new_size;
+            if !node.compressed {
+                self.filenodes[index].size = new_size;
+            }
+            let truncated_node = self.filenodes[index].clone();
+            self.filenodes[index].checksum = self.stream_chain(&truncated_node, &mut io::sink())?;
+            self.record_change(index)?;
+            self.save_single_filenode(index)?;
+            let alias = node.alias_lossy();
+            report.truncated.push((alias, node.stored_len(), new_size));
+        }
+
+        for sharers in self.miscounted_chains() {
+            self.set_refcounts(&sharers)?;
+        }
+
+        let reachable = self.reachable_blocks(&mut Vec::new())?;
+        let (orphaned, marked_free) = self.bitmap_mismatches(&reachable);
+        report.blocks_reclaimed = orphaned.len();
+        report.blocks_marked_used = marked_free.len();
+        self.apply_reachable_to_bitmap(&reachable)?;
+        Ok(report)
+    }
+
+    /// Returns the files sharing each chain whose files do not all record the number of
+    /// files sharing it as their refcount.
+    fn miscounted_chains(&self) -> Vec<Vec<usize>> {
+        let first_blocks: HashSet<usize> = self
+            .filenodes
+            .iter()
+            .filter(|n| n.is_used)
+            .filter_map(|n| n.first_block_index)
+            .collect();
+        first_blocks
+            .into_iter()
+            .map(|first_block_index| self.chain_sharers(Some(first_block_index)))
+            .filter(|sharers| {
+                sharers
+                    .iter()
+                    .any(|&index| self.filenodes[index].refcount as usize != sharers.len())
+            })
+            .collect()
+    }
+
+    /// Runs all health checks and collects the findings together with suggested fixes.
+    /// Block chains are walked once, and read again only to verify file contents.
+    ///
+    /// With `fix` set, the repairs that cannot lose data are applied: refcounts are set to
+    /// the number of files sharing each chain, and the free block bitmap is rebuilt from
+    /// the chains, which frees orphaned blocks and protects blocks in use that were marked
+    /// free. Broken chains are only reported, as cutting them short loses data; `repair`
+    /// does that.
+    pub fn doctor(&mut self, fix: bool) -> Result<DoctorReport, FsError> {
+        let mut report = DoctorReport::default();
+
+        // Refcounts come from the filenodes alone, so they are put right before the walk
+        let miscounted = self.miscounted_chains();
+        if fix && !miscounted.is_empty() {
+            for sharers in &miscounted {
+                self.set_refcounts(sharers)?;
+            }
+            report.findings.push(format!(
+                "Corrected the refcounts of {} shared chains.",
+                miscounted.len()
+            ));
+        } else if !miscounted.is_empty() {
+            report.suggestions.push(format!(
+                "{} shared chains have wrong refcounts - run `doctor --fix` to correct them.",
+                miscounted.len()
+            ));
+        }
+
+        // Consistency between block chains and the bitmap
+        let mut chain_issues = Vec::new();
+        let (reachable, chains) = self.walk_chains(&mut chain_issues)?;
+        let (orphaned, marked_free) = self.bitmap_mismatches(&reachable);
+        if fix && (!orphaned.is_empty() || !marked_free.is_empty()) {
+            let changed = self.apply_reachable_to_bitmap(&reachable)?;
+            report.findings.push(format!(
+                "Rebuilt free block bitmap ({} blocks corrected).",
+                changed
+            ));
+        } else {
+            if !orphaned.is_empty() {
+                report.suggestions.push(format!(
+                    "{} orphaned blocks - run `doctor --fix` to reclaim them.",
+                    orphaned.len()
+                ));
+            }
+            if !marked_free.is_empty() {
+                report.suggestions.push(format!(
+                    "{} blocks in use are marked free - run `doctor --fix` to protect them.",
+                    marked_free.len()
+                ));
+            }
+        }
+        if !chain_issues.is_empty() {
+            report.suggestions.push(
+                "Some files have broken block chains - run `fsck --repair` to truncate them."
+                    .to_string(),
+            );
+        }
+        report.problems = chain_issues;
+        self.bitmap_issues(&reachable, &mut report.problems);
+        self.symlink_issues(&mut report.problems);
+
+        // Contents of live files against their checksums. Broken chains are reported above.
+        let chain_of = |node: &FileNode| match node.first_block_index {
+            Some(first_block_index) => chains.get(&first_block_index).map(Vec::as_slice),
+            None => Some(&[][..]),
+        };
+        let now = unix_now();
+        let live_files: Vec<FileNode> = self
+            .filenodes
+            .iter()
+            .filter(|n| n.is_used && n.node_type == NodeType::File && !n.is_expired(now))
+            .cloned()
+            .collect();
+        for node in &live_files {
+            let Some(chain) = chain_of(node) else {
+                continue;
+            };
+            if let Err(e) = self.verify_node(node, chain.len()) {
+                report.problems.push(format!(
+                    "File '{}' failed checksum: {}",
+                    node.alias_lossy(),
+                    e
+                ));
+            }
+        }
+
+        // Fragmentation and wasted space in partially filled last blocks
+        let used_nodes: Vec<FileNode> = self
+            .filenodes
+            .iter()
+            .filter(|n| n.is_used)
+            .cloned()
+            .collect();
+        let mut fragmented_files = 0;
+        let mut slack_bytes = 0;
+        for node in &used_nodes {
+            if let Some(chain) = chain_of(node) {
+                if chain.windows(2).any(|pair| pair[1] != pair[0] + 1) {
+                    fragmented_files += 1;
+                }
+                slack_bytes +=
+                    (chain.len() * self.usable_block_size()).saturating_sub(node.stored_len());
+            }
+        }
+        report.findings.push(format!(
+            "{} of {} files are fragmented.",
+            fragmented_files,
+            used_nodes.len()
+        ));
+        report.findings.push(format!(
+            "{} bytes are unused at the end of partially filled blocks.",
+            slack_bytes
+        ));
+        if fragmented_files > 0 {
+            report
+                .suggestions
+                .push("Run `defrag` to make fragmented files contiguous.".to_string());
+        }
+
+        // Filenode table usage
+        report.findings.push(format!(
+            "{} of {} filenodes in use.",
+            used_nodes.len(),
+            self.header.filenode_table_size
+        ));
+        if used_nodes.len() == self.header.filenode_table_size {
+            report.suggestions.push(
+                "The filenode table is full - run `grow-filenodes` to allow more files."
+                    .to_string(),
+            );
+        }
+        let alias_stats = self.alias_space_stats();
+        report.findings.push(format!(
+            "Average alias length is {:.1} bytes; {} bytes of the alias space reserved in filenode slots are unused.",
+            alias_stats.average_alias_len, alias_stats.wasted_bytes
+        ));
+        if alias_stats.wasted_bytes * 2
+            > self.header.filenode_table_size * self.header.max_alias_len
+        {
+            report.suggestions.push(
+                "Most of the reserved alias space is unused; a new filesystem could use a smaller `--max-alias-len`."
+                    .to_string(),
+            );
+        }
+
+        Ok(report)
+    }
+
+    /// Returns the space each unexpired file takes up in data blocks, in filenode order.
+    /// Directories have no blocks and are left out.
+    pub fn disk_usage(&self) -> Vec<DiskUsage> {
+        self.files()
+            .filter(|info| !info.is_directory)
+            .map(|info| {
+                let disk_bytes = info.num_blocks * self.header.block_size;
+                DiskUsage {
+                    size: info.size,
+                    num_blocks: info.num_blocks,
+                    disk_bytes,
+                    slack: disk_bytes - info.stored_size,
+                    shared: info.refcount > 1,
+                    alias: info.alias,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns space statistics computed from the in-memory metadata, without disk reads.
+    /// Expired files are not counted as files, but hold their blocks and filenodes until
+    /// they are purged.
+    pub fn usage(&self) -> UsageStats {
+        let free_blocks = self.free_block_count();
+        let now = unix_now();
+        let used_nodes = self.filenodes.iter().filter(|node| node.is_used);
+        let live_nodes = used_nodes.clone().filter(|node| !node.is_expired(now));
+        UsageStats {
+            total_blocks: self.header.num_data_blocks,
+            used_blocks: self.header.num_data_blocks - free_blocks,
+            free_blocks,
+            total_usable_bytes: self.header.num_data_blocks * self.usable_block_size(),
+            used_bytes: live_nodes.clone().map(|node| node.size).sum(),
+            num_files: live_nodes.count(),
+            free_filenodes: self.header.filenode_table_size - used_nodes.count(),
+        }
+    }
+
+    /// Counts, per file, the transitions between consecutive blocks of its chain that are
+    /// not to the next block on disk, and how the free blocks split into runs. Only the
+    /// next pointers are read. Files with a broken chain are skipped.
+    pub fn fragmentation_report(&mut self) -> Result<FragReport, FsError> {
+        let mut report = FragReport::default();
+        let used_nodes: Vec<FileNode> = self
+            .filenodes
+            .iter()
+            .filter(|node| node.is_used)
+            .cloned()
+            .collect();
+        for node in &used_nodes {
+            let Ok(chain) = self.collect_block_chain(node) else {
+                continue;
+            };
+            let transitions = chain.len().saturating_sub(1);
+            let jumps = chain
+                .windows(2)
+                .filter(|pair| pair[1] != pair[0] + 1)
+                .count();
+            report.total_transitions += transitions;
+            report.non_contiguous_transitions += jumps;
+            let alias = node.alias_lossy();
+            report.files.push((alias, transitions, jumps));
+        }
+
+        let mut run_len = 0;
+        for block_index in 0..=self.header.num_data_blocks {
+            if block_index < self.header.num_data_blocks && self.is_block_free(block_index) {
+                run_len += 1;
+            } else if run_len > 0 {
+                report.free_runs += 1;
+                report.largest_free_run = report.largest_free_run.max(run_len);
+                run_len = 0;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Reports how much of the alias space reserved in the filenode slots is unused.
+    pub fn alias_space_stats(&self) -> AliasSpaceStats {
+        let used_alias_lengths: Vec<usize> = self
+            .filenodes
+            .iter()
+            .filter(|node| node.is_used)
+            .map(|node| node.alias.len())
+            .collect();
+        let total_alias_bytes: usize = used_alias_lengths.iter().sum();
+        let average_alias_len = if used_alias_lengths.is_empty() {
+            0.0
+        } else {
+            total_alias_bytes as f64 / used_alias_lengths.len() as f64
+        };
+        AliasSpaceStats {
+            average_alias_len,
+            wasted_bytes: (self.header.filenode_table_size * self.header.max_alias_len)
+                .saturating_sub(total_alias_bytes),
+        }
+    }
+
+    /// Changes the maximum number of files the filesystem can hold.
+    ///
+    /// Growing the filenode table moves the bitmap and data regions, so this is done by
+    /// reading every file into memory, re-initialising the filesystem with the larger table
+    /// and writing the files back under their original aliases. The filesystem is only
+    /// touched once all files have been read and the new layout is known to fit them.
+    pub fn grow_filenode_table(&mut self, new_max: usize) -> Result<(), FsError> {
+        let current_max = self.header.filenode_table_size;
+        if new_max <= current_max {
+            return Err(FsError::InvalidArgument(format!(
+                "New filenode count ({}) must be larger than the current one ({}).",
+                new_max, current_max
+            )));
+        }
+
+        self.rebuild(&InitOptions {
+            num_filenodes: new_max,
+            fill_byte: self.header.fill_byte,
+            total_size: self.header.total_size,
+            block_size: self.header.block_size,
+            max_alias_len: self.header.max_alias_len,
+        })
+    }
+
+    /// Lays the filesystem out again for `options`, keeping every file, its metadata and
+    /// the deletion history. Files are read into memory, the filesystem is re-initialised
+    /// and the files are written back; nothing is changed unless they fit the new layout.
+    fn rebuild(&mut self, options: &InitOptions) -> Result<(), FsError> {
+        // Check that the existing files still fit into the new data region.
+        let new_header = Self::compute_header(options)?;
+        let blocks_in_use = self.header.num_data_blocks - self.free_block_count();
+        if blocks_in_use > new_header.num_data_blocks {
+            return Err(FsError::NotEnoughSpace {
+                needed: blocks_in_use,
+                available: new_header.num_data_blocks,
+            });
+        }
+
+        // Read every file into memory before touching the filesystem.
+        let generation = self.header.generation;
+        let tombstone_floor = self.header.tombstone_floor;
+        let tombstones: Vec<FileNode> = self
+            .filenodes
+            .iter()
+            .filter(|n| n.is_tombstone())
+            .cloned()
+            .collect();
+        let used_nodes: Vec<FileNode> = self
+            .filenodes
+            .iter()
+            .filter(|n| n.is_used)
+            .cloned()
+            .collect();
+        let mut saved_files: Vec<(FileNode, Option<Vec<u8>>)> =
+            Vec::with_capacity(used_nodes.len());
+        for node in used_nodes {
+            // A chain shared by deduplicated files is only saved once
+            let shared = node.first_block_index.is_some()
+                && saved_files
+                    .iter()
+                    .any(|(saved, _)| saved.first_block_index == node.first_block_index);
+            if shared {
+                saved_files.push((node, None));
+                continue;
+            }
+            // Stored bytes are kept as they are, compressed, encrypted or not
+            let mut data = Vec::with_capacity(node.stored_len());
+            self.stream_chain(&node, &mut data)?;
+            saved_files.push((node, Some(data)));
+        }
+
+        // Re-initialise with the new layout and restore the files.
+        self.reset(options)?;
+        let mut moved_chains: HashMap<Option<usize>, Option<usize>> = HashMap::new();
+        for (node, data) in saved_files {
+            let alias = node.get_alias_str().map_err(|e| {
+                FsError::Corrupt(format!("Invalid alias while rebuilding filesystem: {}", e))
+            })?;
+            let index = match data {
+                Some(data) => self.write_from_reader(
+                    &alias,
+                    &mut data.as_slice(),
+                    data.len(),
+                    node.compressed.then_some(node.size),
+                    node.encryption,
+                    None,
+                )?,
+                None => self
+                    .find_free_filenode_index()
+                    .ok_or(FsError::NoFreeFilenodes)?,
+            };
+
+            // Keep the original metadata, only the block chain has moved.
+            let first_block_index = *moved_chains
+                .entry(node.first_block_index)
+                .or_insert(self.filenodes[index].first_block_index);
+            self.filenodes[index] = node;
+            self.filenodes[index].first_block_index = first_block_index;
+        }
+
+        // Carry over the generation and deletion history.
+        for tombstone in tombstones {
+            if let Some(index) = self
+                .filenodes
+                .iter()
+                .position(|n| !n.is_used && !n.is_tombstone())
+            {
+                self.filenodes[index] = tombstone;
+            }
+        }
+        self.header.generation = generation;
+        self.header.tombstone_floor = tombstone_floor;
+        self.save_header()?;
+        self.save_filenodes()
+    }
+
+    /// Exports files to an archive at `path`. With `since` set, only files changed after
+    /// that generation are included, together with the aliases deleted since then.
+    /// Symlinks are left out. Returns the archive that was written.
+    pub fn export_archive(&mut self, path: &Path, since: Option<u64>) -> Result<Archive, FsError> {
+        let since_generation = since.unwrap_or(0);
+        if since_generation > self.header.generation {
+            return Err(FsError::InvalidArgument(format!(
+                "Generation {} is newer than the filesystem (generation {}).",
+                since_generation, self.header.generation
+            )));
+        }
+        if since.is_some() && since_generation < self.header.tombstone_floor {
+            return Err(FsError::InvalidArgument(format!(
+                "Deletions before generation {} are no longer recorded. Run a full export.",
+                self.header.tombstone_floor
+            )));
+        }
+
+        let changed_nodes: Vec<FileNode> = self
+            .filenodes
+            .iter()
+            .filter(|n| {
+                n.is_used && n.node_type == NodeType::File && n.generation > since_generation
+            })
+            .cloned()
+            .collect();
+        let mut files = Vec::with_capacity(changed_nodes.len());
+        for node in changed_nodes {
+            let mut data = Vec::with_capacity(node.size);
+            self.read_into_writer(&node, &mut data)?;
+            files.push(ArchivedFile {
+                alias: node.get_alias_str().map_err(|e| {
+                    FsError::Corrupt(format!("Invalid alias while exporting: {}", e))
+                })?,
+                expires_at: node.expires_at,
+                content_type: node.content_type(),
+                description: node.description(),
+                created_at: node.created_at,
+                modified_at: node.modified_at,
+                data,
+            });
+        }
+
+        // A tombstone only counts as a deletion if the alias was not re-created later.
+        let mut deleted = Vec::new();
+        if since.is_some() {
+            for node in self.filenodes.iter().filter(|n| n.is_tombstone()) {
+                if node.generation <= since_generation {
+                    continue;
+                }
+                if let Ok(alias) = node.get_alias_str() {
+                    if self.find_filenode_index(&alias).is_none() && !deleted.contains(&alias) {
+                        deleted.push(alias);
+                    }
+                }
+            }
+        }
+
+        let archive = Archive {
+            since_generation,
+            generation: self.header.generation,
+            files,
+            deleted,
+        };
+        let archive_file = File::create(path).map_err(|e| {
+            FsError::io(format!("Failed to create archive '{}'", path.display()), e)
+        })?;
+        bincode::serialize_into(std::io::BufWriter::new(archive_file), &archive)
+            .map_err(|e| FsError::Serialization(format!("Archive serialization failed: {}", e)))?;
+        Ok(archive)
+    }
+
+    /// Applies an archive produced by `export_archive`: deleted aliases are removed and
+    /// every archived file is added with its metadata, replacing any existing file with
+    /// the same alias. A file is only replaced once its new contents are written, so one
+    /// that fails to import is left as it was.
+    /// Returns the number of files written and the number of files deleted.
+    pub fn import_archive(&mut self, path: &Path) -> Result<(usize, usize), FsError> {
+        let archive_file = File::open(path)
+            .map_err(|e| FsError::io(format!("Failed to open archive '{}'", path.display()), e))?;
+        let archive: Archive = bincode::deserialize_from(std::io::BufReader::new(archive_file))
+            .map_err(|e| {
+                FsError::Serialization(format!("Archive deserialization failed: {}", e))
+            })?;
+
+        let mut deleted_count = 0;
+        for alias in &archive.deleted {
+            if self.find_filenode_index(alias).is_some() {
+                self.delete_file(alias)?;
+                deleted_count += 1;
+            }
+        }
+        for file in &archive.files {
+            let content_type = validate_content_type(&file.content_type)?;
+            let description = file.description.as_deref().unwrap_or_default();
+            validate_description(description)?;
+            // An existing file is only freed once its replacement is written and swapped in
+            let existing = self.prepare_alias(&file.alias, true)?;
+            self.make_room(file.data.len().div_ceil(self.usable_block_size()))?;
+            self.transaction(|fs| {
+                if existing.is_none() {
+                    fs.create_parent_dirs(&file.alias)?;
+                }
+                let index = fs.write_from_reader(
+                    &file.alias,
+                    &mut file.data.as_slice(),
+                    file.data.len(),
+                    None,
+                    None,
+                    existing,
+                )?;
+                let filenode = &mut fs.filenodes[index];
+                filenode.expires_at = file.expires_at;
+                filenode.content_type = content_type.as_bytes().to_vec();
+                filenode.description = description.as_bytes().to_vec();
+                filenode.created_at = file.created_at;
+                filenode.modified_at = file.modified_at;
+                fs.save_single_filenode(index)
+            })?;
+        }
+        Ok((archive.files.len(), deleted_count))
+    }
+
+    /// Writes every stored file and directory to a tar archive at `path`, with entries
+    /// named by alias. File contents are streamed from their block chains one block at a
+    /// time. Expired files are left out. Returns the number of entries written.
+    pub fn export_tar(&mut self, path: &Path) -> Result<usize, FsError> {
+        let tar_file = File::create(path).map_err(|e| {
+            FsError::io(format!("Failed to create tarball '{}'", path.display()), e)
+        })?;
+        let mut builder = tar::Builder::new(io::BufWriter::new(tar_file));
+        let now = unix_now();
+        let nodes: Vec<FileNode> = self
+            .filenodes
+            .iter()
+            .filter(|node| node.is_used && !node.is_expired(now))
+            .cloned()
+            .collect();
+
+        for node in &nodes {
+            let alias = node
+                .get_alias_str()
+                .map_err(|e| FsError::Corrupt(format!("Invalid alias while exporting: {}", e)))?;
+            let mut header = tar::Header::new_gnu();
+            header.set_mtime(node.modified_at);
+            let appended = if node.is_dir() {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                builder.append_data(&mut header, format!("{}/", alias), io::empty())
+            } else if node.is_symlink() {
+                // Tar link targets are relative to the directory holding the link
+                let target = self.read_link_target(node)?;
+                let relative_target = "../".repeat(alias.matches('/').count()) + &target;
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_mode(0o777);
+                header.set_size(0);
+                builder.append_link(&mut header, &alias, relative_target)
+            } else {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_mode(0o644);
+                header.set_size(node.size as u64);
+                let reader = self.file_reader(node)?;
+                builder.append_data(&mut header, &alias, reader)
+            };
+            appended
+                .map_err(|e| FsError::io(format!("Failed to add '{}' to tarball", alias), e))?;
+        }
+        builder
+            .into_inner()
+            .and_then(|mut writer| writer.flush())
+            .map_err(|e| {
+                FsError::io(format!("Failed to finish tarball '{}'", path.display()), e)
+            })?;
+        Ok(nodes.len())
+    }
+
+    /// Stores every regular file in the tar archive at `path`, using its entry path as the
+    /// alias. Directory entries are skipped; the directories a file needs are created as
+    /// it is stored. With `overwrite`, files replace existing ones with the same alias.
+    ///
+    /// Files are stored one at a time, so if one fails, e.g. for lack of space, the files
+    /// before it stay imported. Returns the number of files imported.
+    pub fn import_tar(&mut self, path: &Path, overwrite: bool) -> Result<usize, FsError> {
+        let mut imported = 0;
+        self.for_each_tar_file(path, |fs, alias, entry| {
+            let size = entry.size() as usize;
+            let existing = fs.prepare_alias(&alias, overwrite)?;
+            if existing.is_none() {
+                fs.create_parent_dirs(&alias)?;
+            }
+            fs.write_from_reader(&alias, entry, size, None, None, existing)?;
+            imported += 1;
+            Ok(())
+        })?;
+        Ok(imported)
+    }
+
+    /// Works out what `import_tar` would do without storing anything: the files it would
+    /// create and overwrite, and the entries that would make it fail.
+    pub fn plan_import_tar(&mut self, path: &Path, overwrite: bool) -> Result<ImportPlan, FsError> {
+        let mut plan = ImportPlan::default();
+        let usable_block_size = self.usable_block_size();
+        // Aliases created by earlier entries, which later entries would find taken
+        let mut created = HashSet::new();
+        self.for_each_tar_file(path, |fs, alias, entry| {
+            let size = entry.size() as usize;
+            let existing = overwrite.then(|| fs.find_filenode_index(&alias)).flatten();
+            let num_rejected = plan.rejected.len();
+            match existing {
+                Some(index) if fs.filenodes[index].is_dir() => plan
+                    .rejected
+                    .push((alias.clone(), format!("'{}' is a directory.", alias))),
+                Some(_) => plan.overwritten.push((alias, size)),
+                None if created.contains(&alias) => {
+                    if overwrite {
+                        plan.overwritten.push((alias, size));
+                    } else {
+                        let error = FsError::AliasExists(alias.clone());
+                        plan.rejected.push((alias, error.to_string()));
+                    }
+                }
+                None => match fs.validate_new_alias(&alias) {
+                    Ok(()) => {
+                        created.insert(alias.clone());
+                        plan.created.push((alias, size));
+                    }
+                    Err(e) => plan.rejected.push((alias, e.to_string())),
+                },
+            }
+            if plan.rejected.len() == num_rejected {
+                plan.blocks_needed += size.div_ceil(usable_block_size);
+            }
+            Ok(())
+        })?;
+        Ok(plan)
+    }
+
+    /// Calls `f` with the alias and entry of every regular file in the tarball at `path`,
+    /// in archive order, stopping at the first error.
+    fn for_each_tar_file(
+        &mut self,
+        path: &Path,
+        mut f: impl FnMut(
+            &mut Self,
+            String,
+            &mut tar::Entry<io::BufReader<File>>,
+        ) -> Result<(), FsError>,
+    ) -> Result<(), FsError> {
+        let tar_file = File::open(path)
+            .map_err(|e| FsError::io(format!("Failed to open tarball '{}'", path.display()), e))?;
+        let mut archive = tar::Archive::new(io::BufReader::new(tar_file));
+        let entries = archive
+            .entries()
+            .map_err(|e| FsError::io(format!("Failed to read tarball '{}'", path.display()), e))?;
+
+        for entry in entries {
+            let mut entry = entry.map_err(|e| {
+                FsError::io(format!("Failed to read tarball '{}'", path.display()), e)
+            })?;
+            if entry.header().entry_type() != tar::EntryType::Regular {
+                continue;
+            }
+            let entry_path = entry
+                .path()
+                .map_err(|e| FsError::io("Invalid entry path in tarball", e))?;
+            let alias = entry_path
+                .to_str()
+                .ok_or_else(|| {
+                    FsError::InvalidAlias(format!(
+                        "Tarball entry '{}' is not valid UTF-8.",
+                        entry_path.display()
+                    ))
+                })?
+                .trim_start_matches("./")
+                .to_string();
+            f(self, alias, &mut entry)?;
+        }
+        Ok(())
+    }
+
+    /// Returns a reader over the contents of `filenode`. Plain files are streamed from
+    /// their chain; compressed and encrypted files are decoded into memory first.
+    fn file_reader(&mut self, filenode: &FileNode) -> Result<Box<dyn Read + '_>, FsError> {
+        if filenode.compressed || filenode.encryption.is_some() {
+            let mut contents = Vec::with_capacity(filenode.size);
+            self.read_into_writer(filenode, &mut contents)?;
+            return Ok(Box::new(io::Cursor::new(contents)));
+        }
+        Ok(Box::new(ChainReader {
+            alias: filenode.alias_lossy(),
+            usable_block_size: self.usable_block_size(),
+            block: Vec::new(),
+            position: 0,
+            end: 0,
+            remaining: filenode.size,
+            hasher: Crc32::new(),
+            checksum: filenode.checksum,
+            chain: BlockChainIter::new(self, filenode.first_block_index),
+        }))
+    }
+
+    /// Copies the whole filesystem image to a new file at `path`, flushing pending writes
+    /// first. Returns the number of bytes written.
+    pub fn export_to(&mut self, path: &Path) -> Result<u64, FsError> {
+        self.storage
+            .flush()
+            .map_err(|e| FsError::io("Flush failed (export image)", e))?;
+        let mut image_file = File::create(path)
+            .map_err(|e| FsError::io(format!("Failed to create image '{}'", path.display()), e))?;
+
+        let mut buffer = vec![0u8; MEGABYTE];
+        let mut copied = 0;
+        while copied < self.header.total_size {
+            let chunk = &mut buffer[..std::cmp::min(MEGABYTE, self.header.total_size - copied)];
+            self.storage
+                .read_at(self.base_offset + copied as u64, chunk)
+                .map_err(|e| FsError::io("Read failed (export image)", e))?;
+            image_file
+                .write_all(chunk)
+                .map_err(|e| FsError::io(format!("Write failed to '{}'", path.display()), e))?;
+            copied += chunk.len();
+        }
+        image_file
+            .sync_all()
+            .map_err(|e| FsError::io(format!("Sync failed for '{}'", path.display()), e))?;
+        Ok(copied as u64)
+    }
+
+    /// Replaces the filesystem with the image at `path`, as written by `export_to`. The
+    /// image is opened and checked first, so an incompatible or corrupt image leaves the
+    /// current filesystem untouched. Its snapshots are discarded as they describe the old
+    /// contents.
+    pub fn import_from(&mut self, path: &Path) -> Result<(), FsError> {
+        let image_file = File::open(path)
+            .map_err(|e| FsError::io(format!("Failed to open image '{}'", path.display()), e))?;
+        let image = Self::open_volume(Box::new(FileStorage::new(image_file)), None, 0, false)?;
+        // A filesystem embedded at an offset cannot grow past its original end, which an
+        // image in an older layout may need to be upgraded
+        if self.base_offset != 0
+            && image.header.version < FILESYSTEM_VERSION
+            && image.upgrade_options()?.total_size > image.header.total_size
+        {
+            return Err(FsError::InvalidArgument(format!(
+                "Image '{}' is in layout version {} and needs more room to be upgraded than \
+                 the filesystem at offset {} can give it.",
+                path.display(),
+                image.header.version,
+                self.base_offset
+            )));
+        }
+        if self.base_offset != 0 && image.header.total_size > self.header.total_size {
+            return Err(FsError::InvalidArgument(format!(
+                "Image '{}' is {} bytes, larger than the {} bytes the filesystem has at offset {}.",
+                path.display(),
+                image.header.total_size,
+                self.header.total_size,
+                self.base_offset
+            )));
+        }
+
+        self.copy_image_in(image, path)?;
+        if self.header.version < FILESYSTEM_VERSION {
+            self.upgrade()?;
+        }
+
+        if self.path.is_some() {
+            let snapshots_path = self.snapshots_path()?;
+            match std::fs::remove_file(&snapshots_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(FsError::io(
+                        format!("Failed to remove snapshots '{}'", snapshots_path.display()),
+                        e,
+                    ))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the whole of `image`, opened from `path`, over the filesystem and adopts its
+    /// metadata.
+    fn copy_image_in(&mut self, mut image: FileSystemManager, path: &Path) -> Result<(), FsError> {
+        let mut buffer = vec![0u8; MEGABYTE];
+        let mut copied = 0;
+        while copied < image.header.total_size {
+            let chunk = &mut buffer[..std::cmp::min(MEGABYTE, image.header.total_size - copied)];
+            image
+                .storage
+                .read_at(copied as u64, chunk)
+                .map_err(|e| FsError::io(format!("Read failed from '{}'", path.display()), e))?;
+            self.storage
+                .write_at(self.base_offset + copied as u64, chunk)
+                .map_err(|e| FsError::io("Write failed (import image)", e))?;
+            copied += chunk.len();
+        }
+        if self.base_offset == 0 {
+            self.storage
+                .set_len(copied as u64)
+                .map_err(|e| FsError::io("Failed to resize filesystem (import image)", e))?;
+        }
+        self.storage
+            .flush()
+            .map_err(|e| FsError::io("Final flush failed (import image)", e))?;
+
+        self.header = image.header;
+        self.filenodes = image.filenodes;
+        self.slot_checksums = image.slot_checksums;
+        self.free_block_bitmap = image.free_block_bitmap;
+        self.free_block_count = image.free_block_count;
+        Ok(())
+    }
+
+    /// Returns the path of the file the volume's snapshots are kept in, next to the
+    /// filesystem file. Filesystems not stored in a file cannot have snapshots.
+    fn snapshots_path(&self) -> Result<PathBuf, FsError> {
+        let path = self.path.as_ref().ok_or_else(|| {
+            FsError::InvalidArgument("Snapshots need a filesystem stored in a file.".to_string())
+        })?;
+        let mut file_name = path.as_os_str().to_owned();
+        if self.base_offset != 0 {
+            file_name.push(format!(".{}", self.base_offset));
+        }
+        file_name.push(".snapshots");
+        Ok(PathBuf::from(file_name))
+    }
+
+    /// Returns the snapshots of the filesystem, oldest first.
+    pub fn list_snapshots(&self) -> Result<Vec<Snapshot>, FsError> {
+        let path = self.snapshots_path()?;
+        let snapshot_file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(FsError::io(
+                    format!("Failed to open snapshots '{}'", path.display()),
+                    e,
+                ))
+            }
+        };
+        bincode::deserialize_from(io::BufReader::new(snapshot_file))
+            .map_err(|e| FsError::Serialization(format!("Snapshot deserialization failed: {}", e)))
+    }
+
+    /// Replaces the stored snapshots with `snapshots`. They are written to a temporary
+    /// file first, so an interruption leaves the previous snapshots intact.
+    fn save_snapshots(&self, snapshots: &[Snapshot]) -> Result<(), FsError> {
+        let path = self.snapshots_path()?;
+        let mut temp_name = path.as_os_str().to_owned();
+        temp_name.push(".tmp");
+        let temp_path = PathBuf::from(temp_name);
+        let snapshot_file = File::create(&temp_path)
+            .map_err(|e| FsError::io(format!("Failed to create '{}'", temp_path.display()), e))?;
+        let mut writer = io::BufWriter::new(snapshot_file);
+        bincode::serialize_into(&mut writer, snapshots)
+            .map_err(|e| FsError::Serialization(format!("Snapshot serialization failed: {}", e)))?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_all())
+            .and_then(|_| std::fs::rename(&temp_path, &path))
+            .map_err(|e| FsError::io(format!("Failed to write snapshots '{}'", path.display()), e))
+    }
+
+    /// Saves the current file metadata as a snapshot called `name`, which `restore_snapshot`
+    /// can later return the filesystem to. Only the filenode table is captured; data blocks
+    /// are not copied.
+    pub fn snapshot(&mut self, name: &str) -> Result<(), FsError> {
+        if name.is_empty() {
+            return Err(FsError::InvalidArgument(
+                "Snapshot name must not be empty.".to_string(),
+            ));
+        }
+        let mut snapshots = self.list_snapshots()?;
+        if snapshots.iter().any(|snapshot| snapshot.name == name) {
+            return Err(FsError::InvalidArgument(format!(
+                "Snapshot '{}' already exists.",
+                name
+            )));
+        }
+        snapshots.push(Snapshot {
+            name: name.to_string(),
+            created_at: unix_now(),
+            generation: self.header.generation,
+            max_alias_len: self.header.max_alias_len,
+            filenodes: self.filenodes.clone(),
+        });
+        self.save_snapshots(&snapshots)
+    }
+
+    /// Deletes the snapshot called `name`.
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<(), FsError> {
+        let mut snapshots = self.list_snapshots()?;
+        let position = snapshots
+            .iter()
+            .position(|snapshot| snapshot.name == name)
+            .ok_or_else(|| FsError::InvalidArgument(format!("No snapshot called '{}'.", name)))?;
+        snapshots.remove(position);
+        self.save_snapshots(&snapshots)
+    }
+
+    /// Returns the filesystem to the files it held when snapshot `name` was taken. Files
+    /// stored since are dropped and their blocks freed.
+    ///
+    /// Snapshots are metadata-only: blocks freed after the snapshot may since have been
+    /// reused, and appends or truncations edit blocks in place. Every file's contents are
+    /// therefore checked against its checksum first, and nothing is restored if any of
+    /// them changed. The snapshot is kept, so it can be restored again.
+    pub fn restore_snapshot(&mut self, name: &str) -> Result<(), FsError> {
+        let snapshot = self
+            .list_snapshots()?
+            .into_iter()
+            .find(|snapshot| snapshot.name == name)
+            .ok_or_else(|| FsError::InvalidArgument(format!("No snapshot called '{}'.", name)))?;
+        if snapshot.filenodes.len() != self.filenodes.len()
+            || snapshot.max_alias_len != self.header.max_alias_len
+        {
+            return Err(FsError::InvalidArgument(format!(
+                "Snapshot '{}' was taken before the filenode table was resized.",
+                name
+            )));
+        }
+
+        // Each chain, shared by deduplicated files or not, is checked once
+        let mut damaged = Vec::new();
+        let mut checked_chains = HashSet::new();
+        let used_nodes = snapshot.filenodes.iter().filter(|node| node.is_used);
+        for node in used_nodes {
+            let Some(first_block) = node.first_block_index else {
+                continue;
+            };
+            if !checked_chains.insert(first_block) {
+                continue;
+            }
+            if !self
+                .stream_chain(node, &mut io::sink())
+                .is_ok_and(|checksum| checksum == node.checksum)
+            {
+                damaged.push(format!("'{}'", node.alias_lossy()));
+            }
+        }
+        if !damaged.is_empty() {
+            return Err(FsError::Corrupt(format!(
+                "Snapshot '{}' cannot be restored: the blocks of {} have changed since it was taken.",
+                name,
+                damaged.join(", ")
+            )));
+        }
+
+        // Generations move on, so incremental exports pick up every restored file
+        let generation = self.header.generation + 1;
+        let mut filenodes = snapshot.filenodes;
+        for node in filenodes.iter_mut().filter(|node| node.is_used) {
+            node.generation = generation;
+        }
+        self.filenodes = filenodes;
+        self.free_block_bitmap = all_free_bitmap(self.header.num_data_blocks);
+        self.free_block_count = self.header.num_data_blocks;
+        for first_block in checked_chains {
+            let chain = BlockChainIter::indices_only(self, Some(first_block))
+                .map(|block| block.map(|(block_index, _)| block_index))
+                .collect::<Result<Vec<usize>, FsError>>()?;
+            for block_index in chain {
+                self.set_block_free(block_index, false);
+            }
+        }
+        self.header.generation = generation;
+        self.header.tombstone_floor = generation;
+        info!("Restored snapshot '{}'", name);
+
+        self.transaction(|fs| {
+            fs.save_header()?;
+            fs.save_filenodes()?;
+            fs.write_bitmap_to_disk()
+        })
+    }
+
+    /// Creates a filesystem at `path`, uploads `options.num_files` files of
+    /// `options.file_size` bytes into it and deletes them again, timing each phase. The
+    /// file at `path` is overwritten and left behind for the caller to remove.
+    ///
+    /// Allocation is timed by running the block search each upload makes just before it,
+    /// as the search only reads the bitmap and so finds the same blocks twice. A full
+    /// filesystem fails the upload with the usual error.
+    pub fn benchmark(path: &Path, options: &BenchOptions) -> Result<BenchReport, FsError> {
+        let mut manager = Self::init_filesystem_at(path, 0, &options.init)?;
+        manager.set_alloc_strategy(options.alloc_strategy);
+        let blocks_per_file = options.file_size.div_ceil(manager.usable_block_size());
+        let data = vec![0xA5; options.file_size];
+        let aliases: Vec<String> = (0..options.num_files)
+            .map(|i| format!("bench-{}", i))
+            .collect();
+
+        let mut alloc_time = Duration::ZERO;
+        let mut upload_time = Duration::ZERO;
+        for alias in &aliases {
+            let start = Instant::now();
+            std::hint::black_box(
+                manager.find_free_blocks(blocks_per_file, manager.prefers_contiguous()),
+            );
+            alloc_time += start.elapsed();
+            let start = Instant::now();
+            manager.upload_reader(&mut data.as_slice(), alias, &UploadOptions::default())?;
+            upload_time += start.elapsed();
+        }
+
+        let start = Instant::now();
+        for alias in &aliases {
+            manager.delete_file(alias)?;
+        }
+        let delete_time = start.elapsed();
+
+        Ok(BenchReport {
+            num_files: options.num_files,
+            bytes: options.num_files * options.file_size,
+            upload_time,
+            delete_time,
+            alloc_time,
+        })
+    }
+
+    /// Runs the free-block search for `num_blocks` blocks without allocating them.
+    /// Exposed for the benchmarks only.
+    #[doc(hidden)]
+    pub fn bench_find_free_blocks(&self, num_blocks: usize) -> Option<Vec<usize>> {
+        self.find_free_blocks(num_blocks, self.prefers_contiguous())
+    }
+
+    /// Downloads a file like `download_file`, but always through the userspace copy loop
+    /// rather than `sendfile`. Exposed for the benchmarks only.
+    #[doc(hidden)]
+    pub fn bench_download_with_copy_loop(
+        &mut self,
+        alias: &str,
+        local_path_str: &str,
+    ) -> Result<usize, FsError> {
+        self.download(alias, local_path_str, false, &mut |_, _| {})
+    }
+
+    /// Writes the whole filenode table to storage. Exposed for the benchmarks only.
+    #[doc(hidden)]
+    pub fn bench_save_filenodes(&mut self) -> Result<(), FsError> {
+        self.save_filenodes()
+    }
+}
+
+/// Wraps a reader or writer and reports `(bytes_done, total_bytes)` each time the bytes
+/// passed through it complete another block or reach the total.
+struct Progress<'a, T> {
+    inner: T,
+    done: usize,
+    total: usize,
+    block_size: usize,
+    callback: &'a mut dyn FnMut(usize, usize),
+}
+
+impl<'a, T> Progress<'a, T> {
+    fn new(
+        inner: T,
+        total: usize,
+        block_size: usize,
+        callback: &'a mut dyn FnMut(usize, usize),
+    ) -> Self {
+        Progress {
+            inner,
+            done: 0,
+            total,
+            block_size,
+            callback,
+        }
+    }
+
+    fn advance(&mut self, count: usize) {
+        let before = self.done;
+        self.done += count;
+        if count > 0
+            && (self.done / self.block_size > before / self.block_size || self.done == self.total)
+        {
+            (self.callback)(self.done, self.total);
+        }
+    }
+}
+
+impl<T: Read> Read for Progress<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.advance(count);
+        Ok(count)
+    }
+}
+
+impl<T: Write> Write for Progress<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.advance(count);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Walks a block chain from its first block, following the next pointers on disk, and
+/// yields each block's index with its full contents, next pointer included. The walk ends
+/// at the end-of-chain sentinel. A block outside the data area, or one the chain has
+/// already visited, is yielded as a `Corrupt` error and ends the walk.
+struct BlockChainIter<'a> {
+    manager: &'a mut FileSystemManager,
+    next_block: Option<usize>,
+    visited: HashSet<usize>,
+    read_contents: bool, // Otherwise only next pointers are read and contents are empty
+    looped: bool,
+}
+
+impl<'a> BlockChainIter<'a> {
+    fn new(manager: &'a mut FileSystemManager, first_block: Option<usize>) -> Self {
+        BlockChainIter {
+            manager,
+            next_block: first_block,
+            visited: HashSet::new(),
+            read_contents: true,
+            looped: false,
+        }
+    }
+
+    /// Walks the chain reading only the next pointers, yielding empty block contents.
+    fn indices_only(manager: &'a mut FileSystemManager, first_block: Option<usize>) -> Self {
+        BlockChainIter {
+            read_contents: false,
+            ..Self::new(manager, first_block)
+        }
+    }
+
+    /// Returns true if the walk ended because the chain came back to a visited block.
+    fn looped(&self) -> bool {
+        self.looped
+    }
+}
+
+impl Iterator for BlockChainIter<'_> {
+    type Item = Result<(usize, Vec<u8>), FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let block_index = self.next_block.take()?;
+        if block_index >= self.manager.header.num_data_blocks {
+            warn!(
+                "Block chain points outside the data area, at block {}",
+                block_index
+            );
+            return Some(Err(FsError::Corrupt(format!(
+                "Invalid block chain at block {}. Corrupt.",
+                block_index
+            ))));
+        }
+        if !self.visited.insert(block_index) {
+            warn!("Block chain loops back to block {}", block_index);
+            self.looped = true;
+            return Some(Err(FsError::Corrupt(format!(
+                "Cycle in block chain at block {}. Corrupt.",
+                block_index
+            ))));
+        }
+
+        if !self.read_contents {
+            return Some(
+                self.manager
+                    .read_next_pointer(block_index)
+                    .map(|next_block| {
+                        self.next_block = next_block;
+                        (block_index, Vec::new())
+                    }),
+            );
+        }
+        let mut block_data = vec![0u8; self.manager.header.block_size];
+        if let Err(e) = self.manager.read_block(block_index, &mut block_data) {
+            return Some(Err(e));
+        }
+        let usable_block_size = self.manager.usable_block_size();
+        let mut next_block_ptr_bytes = [0u8; NEXT_BLOCK_POINTER_SIZE];
+        next_block_ptr_bytes.copy_from_slice(&block_data[usable_block_size..]);
+        let next_block_index = usize::from_le_bytes(next_block_ptr_bytes);
+        self.next_block = (next_block_index != usize::MAX).then_some(next_block_index);
+        Some(Ok((block_index, block_data)))
+    }
+}
+
+/// Streams the contents of a plain (neither compressed nor encrypted) file, one block at a
+/// time. The checksum is verified when the last block is loaded, before its bytes are
+/// handed out, so a reader never sees the end of a corrupt file.
+struct ChainReader<'a> {
+    chain: BlockChainIter<'a>,
+    alias: String,
+    usable_block_size: usize,
+    block: Vec<u8>,   // The block being handed out
+    position: usize,  // Next byte of `block` to hand out
+    end: usize,       // End of the file's bytes in `block`
+    remaining: usize, // Bytes of the file not loaded yet
+    hasher: Crc32,
+    checksum: u32, // Checksum stored for the file
+}
+
+impl ChainReader<'_> {
+    /// Loads the next block of the chain into `block`.
+    fn load_next_block(&mut self) -> Result<(), FsError> {
+        self.block = match self.chain.next() {
+            Some(block) => block?.1,
+            None => {
+                return Err(FsError::Corrupt(format!(
+                    "Block chain of file '{}' ends {} bytes early. Corrupt.",
+                    self.alias, self.remaining
+                )))
+            }
+        };
+        self.position = 0;
+        self.end = std::cmp::min(self.remaining, self.usable_block_size);
+        self.remaining -= self.end;
+        self.hasher.update(&self.block[..self.end]);
+
+        if self.remaining == 0 {
+            let checksum = self.hasher.clone().finalize();
+            if checksum != self.checksum {
+                warn!("Checksum mismatch for '{}'", self.alias);
+                return Err(FsError::Corrupt(format!(
+                    "Checksum mismatch for file '{}': stored {:08x}, computed {:08x}. Corrupt.",
+                    self.alias, self.checksum, checksum
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for ChainReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.end {
+            if self.remaining == 0 {
+                return Ok(0);
+            }
+            self.load_next_block().map_err(|e| match e {
+                FsError::Io(error) => error,
+                other => io::Error::new(io::ErrorKind::InvalidData, other.to_string()),
+            })?;
+        }
+        let count = std::cmp::min(buf.len(), self.end - self.position);
+        buf[..count].copy_from_slice(&self.block[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+/// Returns the number of single-character edits needed to turn `a` into `b`.
+fn levenshtein_distance(a: &str, b: &str) -> usize {
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut previous_row: Vec<usize> = (0..=b_chars.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current_row = vec![i + 1; b_chars.len() + 1];
+        for (j, b_char) in b_chars.iter().enumerate() {
+            let substitution_cost = if a_char == *b_char { 0 } else { 1 };
+            current_row[j + 1] = (previous_row[j] + substitution_cost)
+                .min(previous_row[j + 1] + 1)
+                .min(current_row[j] + 1);
+        }
+        previous_row = current_row;
+    }
+    previous_row[b_chars.len()]
+}
+
+/// Returns true if `text` matches the glob `pattern`, where `*` matches any run of
+/// characters and `?` matches exactly one.
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it is currently matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the last `*` swallow one more character and retry
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Derives the AES-256 key for `password` with Argon2.
+fn derive_key(password: &str, salt: &[u8]) -> Result<[u8; 32], FsError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e| FsError::InvalidArgument(format!("Key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+/// Encrypts `data` in place under a fresh salt and nonce, returning what is needed to
+/// decrypt it again.
+fn encrypt_contents(password: &str, data: &mut [u8]) -> Result<Encryption, FsError> {
+    let mut encryption = Encryption::default();
+    OsRng.fill_bytes(&mut encryption.salt);
+    let key = derive_key(password, &encryption.salt)?;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let tag = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+        .encrypt_in_place_detached(&nonce, b"", data)
+        .map_err(|_| FsError::InvalidArgument("Encryption failed.".to_string()))?;
+    encryption.nonce.copy_from_slice(&nonce);
+    encryption.tag.copy_from_slice(&tag);
+    Ok(encryption)
+}
+
+/// Decrypts `data` in place. Returns false if the password is wrong or the ciphertext or
+/// its parameters were modified.
+fn decrypt_contents(
+    password: &str,
+    encryption: &Encryption,
+    data: &mut [u8],
+) -> Result<bool, FsError> {
+    let key = derive_key(password, &encryption.salt)?;
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+        .decrypt_in_place_detached(
+            Nonce::from_slice(&encryption.nonce),
+            b"",
+            data,
+            Tag::from_slice(&encryption.tag),
+        )
+        .is_ok())
+}
+
+/// Reads up to `len` bytes from `reader`, fewer only if it ends first.
+fn read_chunk<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u8>, FsError> {
+    let mut chunk = Vec::with_capacity(len);
+    reader
+        .take(len as u64)
+        .read_to_end(&mut chunk)
+        .map_err(|e| FsError::io("Read failed from source", e))?;
+    Ok(chunk)
+}
+
+/// Runs `op`, retrying up to `retries` more times with exponential backoff while it fails
+/// with a transient error. Permanent errors are returned immediately.
+fn retry_io<T>(retries: u32, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                thread::sleep(Duration::from_millis(10 << attempt.min(10)));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether `error` may succeed if the same operation is tried again.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Returns the MIME type usually meant by the extension of `alias`, or
+/// `application/octet-stream` for unknown extensions.
+pub fn guess_content_type(alias: &str) -> &'static str {
+    let file_name = alias.rsplit('/').next().unwrap_or_default();
+    let extension = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => extension.to_ascii_lowercase(),
+        _ => return DEFAULT_CONTENT_TYPE,
+    };
+    match extension.as_str() {
+        "txt" | "log" => "text/plain",
+        "md" => "text/markdown",
+        "csv" => "text/csv",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "zst" => "application/zstd",
+        "wasm" => "application/wasm",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "ico" => "image/vnd.microsoft.icon",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "ogg" => "audio/ogg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => DEFAULT_CONTENT_TYPE,
+    }
+}
+
+/// Checks that `content_type` looks like a MIME type (`type/subtype`, optionally with
+/// parameters) and fits in a filenode, returning it unchanged.
+fn validate_content_type(content_type: &str) -> Result<&str, FsError> {
+    if content_type.len() > MAX_CONTENT_TYPE_LEN {
+        return Err(FsError::InvalidArgument(format!(
+            "Content type '{}' is {} bytes long, but at most {} bytes are allowed.",
+            content_type,
+            content_type.len(),
+            MAX_CONTENT_TYPE_LEN
+        )));
+    }
+    let valid = content_type
+        .split_once('/')
+        .is_some_and(|(kind, subtype)| !kind.is_empty() && !subtype.is_empty())
+        && content_type
+            .chars()
+            .all(|c| c.is_ascii_graphic() || c == ' ');
+    if !valid {
+        return Err(FsError::InvalidArgument(format!(
+            "'{}' is not a valid content type. Expected something like 'text/plain'.",
+            content_type
+        )));
+    }
+    Ok(content_type)
+}
+
+/// Checks that `description` fits in a filenode and has no control characters, which
+/// would break listings.
+fn validate_description(description: &str) -> Result<(), FsError> {
+    if description.len() > MAX_DESCRIPTION_LEN {
+        return Err(FsError::InvalidArgument(format!(
+            "Note is {} bytes long, but at most {} bytes are allowed.",
+            description.len(),
+            MAX_DESCRIPTION_LEN
+        )));
+    }
+    if let Some(c) = description.chars().find(|c| c.is_control()) {
+        return Err(FsError::InvalidArgument(format!(
+            "Note contains the control character {:?}, which is not allowed.",
+            c
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the checksum stored in the header for a filenode table whose slots have the
+/// given CRC32s. It covers the node count as well, so a table cut short is caught.
+fn filenode_table_checksum(slot_checksums: &[u32]) -> u32 {
+    let mut hasher = Crc32::new();
+    hasher.update(&(slot_checksums.len() as u64).to_le_bytes());
+    for checksum in slot_checksums {
+        hasher.update(&checksum.to_le_bytes());
+    }
+    hasher.finalize()
+}
+
+/// Returns an in-memory bitmap with all `num_blocks` blocks free. Bits past the last
+/// block stay clear so they are never handed out.
+fn all_free_bitmap(num_blocks: usize) -> Vec<u64> {
+    let mut words = vec![u64::MAX; num_blocks.div_ceil(64)];
+    if !num_blocks.is_multiple_of(64) {
+        if let Some(last) = words.last_mut() {
+            *last = (1 << (num_blocks % 64)) - 1;
+        }
+    }
+    words
+}
+
+/// Exchanges the block chains of two filenodes together with the fields describing what
+/// the chains hold, leaving the fields that belong to the alias.
+fn swap_contents(a: &mut FileNode, b: &mut FileNode) {
+    std::mem::swap(&mut a.first_block_index, &mut b.first_block_index);
+    std::mem::swap(&mut a.size, &mut b.size);
+    std::mem::swap(&mut a.checksum, &mut b.checksum);
+    std::mem::swap(&mut a.modified_at, &mut b.modified_at);
+    std::mem::swap(&mut a.compressed, &mut b.compressed);
+    std::mem::swap(&mut a.stored_size, &mut b.stored_size);
+    std::mem::swap(&mut a.encryption, &mut b.encryption);
+    std::mem::swap(&mut a.content_hash, &mut b.content_hash);
+    std::mem::swap(&mut a.refcount, &mut b.refcount);
+    std::mem::swap(&mut a.content_type, &mut b.content_type);
+}
+
+/// Takes an exclusive lock on the filesystem file at `path` for a manager that writes it.
+/// The lock is released when the file is closed.
+fn lock_exclusive(file: &File, path: &Path) -> Result<(), FsError> {
+    file.try_lock().map_err(|e| {
+        FsError::io(
+            format!("Failed to lock {}", path.display()),
+            io::Error::from(e),
+        )
+    })
+}
+
+/// Filenode table and bitmap of a volume as read from disk.
+struct Tables {
+    filenodes: Vec<FileNode>,
+    slot_checksums: Vec<u32>,
+    free_block_bitmap: Vec<u64>,
+    free_block_count: usize,
+}
+
+/// Decodes an on-disk bitmap of `num_blocks` blocks, where a set bit marks a used block,
+/// into the in-memory bitmap and its number of free blocks.
+fn bitmap_from_disk(disk_bitmap_bytes: &[u8], num_blocks: usize) -> (Vec<u64>, usize) {
+    let mut free_block_bitmap = all_free_bitmap(num_blocks);
+    let mut free_block_count = num_blocks;
+    for i in 0..num_blocks {
+        if (disk_bitmap_bytes[i / 8] >> (i % 8)) & 1 != 0 {
+            free_block_bitmap[i / 64] &= !(1 << (i % 64));
+            free_block_count -= 1;
+        }
+    }
+    (free_block_bitmap, free_block_count)
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn get_filesystem_manager() -> Result<FileSystemManager, FsError> {
+    get_filesystem_manager_at(Path::new(FILESYSTEM_FILENAME))
+}
+
+/// Opens the filesystem stored in the file at `path`, initialising it if the file is missing.
+pub fn get_filesystem_manager_at(path: &Path) -> Result<FileSystemManager, FsError> {
+    if !path.exists() {
+        return FileSystemManager::init_filesystem_at(path, 0, &InitOptions::default());
+    }
+    FileSystemManager::open_at(path, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_structs::USABLE_BLOCK_SIZE;
+    use crate::storage::MemStorage;
+
+    /// A filesystem small enough to fill, with blocks holding 504 bytes each.
+    fn small_options() -> InitOptions {
+        InitOptions {
+            num_filenodes: 16,
+            total_size: 64 * 1024,
+            block_size: 512,
+            ..InitOptions::default()
+        }
+    }
+
+    fn new_fs() -> FileSystemManager {
+        FileSystemManager::init_in_storage(Box::new(MemStorage::new()), &small_options()).unwrap()
+    }
+
+    /// Bytes that differ from block to block, so misplaced blocks are noticed.
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    fn store(fs: &mut FileSystemManager, alias: &str, data: &[u8]) {
+        fs.write_file(alias, &mut &data[..], data.len()).unwrap();
+    }
+
+    fn contents(fs: &mut FileSystemManager, alias: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        fs.read_file(alias, &mut out).unwrap();
+        out
+    }
+
+    /// Returns the whole image behind `fs`.
+    fn read_image(fs: &mut FileSystemManager) -> Vec<u8> {
+        let mut image = vec![0u8; fs.storage.len().unwrap() as usize];
+        fs.storage.read_at(0, &mut image).unwrap();
+        image
+    }
+
+    /// Returns a path in the temporary directory unique to this process and `name`.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("fs-ops-test-{}-{}", std::process::id(), name))
+    }
+
+    /// Flips the lowest bit of byte `offset` of data block `block_index`.
+    fn flip_data_byte(fs: &mut FileSystemManager, block_index: usize, offset: usize) {
+        let position =
+            (fs.header.data_blocks_offset + block_index * fs.header.block_size + offset) as u64;
+        let mut byte = [0u8];
+        fs.storage.read_at(position, &mut byte).unwrap();
+        fs.storage.write_at(position, &[byte[0] ^ 1]).unwrap();
+    }
+
+    /// Overwrites the next pointer stored at the end of data block `block_index`.
+    fn set_next_pointer(fs: &mut FileSystemManager, block_index: usize, next: usize) {
+        let offset = fs.header.data_blocks_offset
+            + block_index * fs.header.block_size
+            + fs.usable_block_size();
+        fs.storage
+            .write_at(offset as u64, &next.to_le_bytes())
+            .unwrap();
+    }
+
+    /// In-memory storage whose writes at or past `fail_from` fail with `kind` while
+    /// `failures` is above zero, each failure counting it down.
+    struct FaultyStorage {
+        inner: MemStorage,
+        fail_from: u64,
+        kind: io::ErrorKind,
+        failures: std::rc::Rc<std::cell::Cell<usize>>,
+    }
+
+    impl Storage for FaultyStorage {
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+            self.inner.read_at(offset, buf)
+        }
+
+        fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+            if offset >= self.fail_from && self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err(io::Error::from(self.kind));
+            }
+            self.inner.write_at(offset, data)
+        }
+
+        fn len(&self) -> io::Result<u64> {
+            self.inner.len()
+        }
+
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            self.inner.set_len(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Returns a filesystem of `small_options` whose writes from data block `block_index`
+    /// on fail with `kind` as many times as the returned counter says, initially none.
+    fn faulty_fs(
+        block_index: usize,
+        kind: io::ErrorKind,
+    ) -> (FileSystemManager, std::rc::Rc<std::cell::Cell<usize>>) {
+        let header = FileSystemManager::compute_header(&small_options()).unwrap();
+        let failures = std::rc::Rc::new(std::cell::Cell::new(0));
+        let storage = FaultyStorage {
+            inner: MemStorage::new(),
+            fail_from: (header.data_blocks_offset + block_index * header.block_size) as u64,
+            kind,
+            failures: failures.clone(),
+        };
+        let fs = FileSystemManager::init_in_storage(Box::new(storage), &small_options()).unwrap();
+        (fs, failures)
+    }
+
+    #[test]
+    fn upload_and_read_back_round_trips() {
+        let mut fs = new_fs();
+        let block = fs.usable_block_size();
+        for (i, len) in [0, 1, block - 1, block, block + 1, 5 * block + 17]
+            .into_iter()
+            .enumerate()
+        {
+            let alias = format!("file{}", i);
+            let data = pattern(len);
+            store(&mut fs, &alias, &data);
+            assert_eq!(contents(&mut fs, &alias), data, "{} bytes", len);
+            assert_eq!(fs.get_file_info(&alias).unwrap().size, len);
+        }
+    }
+
+    #[test]
+    fn upload_reader_round_trips_through_a_reopened_image() {
+        let mut fs = new_fs();
+        let data = pattern(3000);
+        fs.upload_reader(&mut &data[..], "a", &UploadOptions::default())
+            .unwrap();
+        let image = MemStorage::from_vec(read_image(&mut fs));
+        let mut reopened = FileSystemManager::open_storage(Box::new(image)).unwrap();
+        assert_eq!(contents(&mut reopened, "a"), data);
+    }
+
+    #[test]
+    fn deleted_files_free_their_blocks_for_reuse() {
+        let mut fs = new_fs();
+        let free_before = fs.free_block_count();
+        store(&mut fs, "a", &pattern(2000));
+        let blocks = fs.block_chain("a").unwrap();
+        assert_eq!(fs.free_block_count(), free_before - blocks.len());
+
+        fs.delete_file("a").unwrap();
+        assert!(!fs.exists("a"));
+        assert_eq!(fs.free_block_count(), free_before);
+
+        let data = pattern(1900);
+        store(&mut fs, "b", &data);
+        assert_eq!(fs.block_chain("b").unwrap(), blocks);
+        assert_eq!(contents(&mut fs, "b"), data);
+    }
+
+    #[test]
+    fn upload_larger_than_free_space_fails_cleanly() {
+        let mut fs = new_fs();
+        let free_before = fs.free_block_count();
+        let too_big = pattern((free_before + 1) * fs.usable_block_size());
+        match fs.write_file("big", &mut &too_big[..], too_big.len()) {
+            Err(FsError::NotEnoughSpace { needed, available }) => {
+                assert_eq!((needed, available), (free_before + 1, free_before));
+            }
+            other => panic!("expected NotEnoughSpace, got {:?}", other.map(|_| ())),
+        }
+        assert!(!fs.exists("big"));
+        assert_eq!(fs.free_block_count(), free_before);
+
+        // Exactly the free space still fits
+        let fits = pattern(free_before * fs.usable_block_size());
+        store(&mut fs, "fits", &fits);
+        assert_eq!(fs.free_block_count(), 0);
+    }
+
+    #[test]
+    fn upload_failing_to_write_a_block_leaves_the_space_free() {
+        let (mut fs, failures) = faulty_fs(2, io::ErrorKind::Other);
+        let free_before = fs.free_block_count();
+        let bitmap_before = fs.free_block_bitmap.clone();
+        let local = temp_path("faulty-upload");
+        std::fs::write(&local, pattern(3000)).unwrap();
+
+        failures.set(usize::MAX);
+        let uploaded = fs.upload_file(local.to_str().unwrap(), "dir/a", &UploadOptions::default());
+        assert!(matches!(uploaded, Err(FsError::Io(_))));
+        failures.set(0);
+        assert!(!fs.exists("dir/a"));
+        assert!(!fs.exists("dir"));
+        assert_eq!(fs.free_block_count(), free_before);
+        assert_eq!(fs.free_block_bitmap, bitmap_before);
+        assert!(fs.check_integrity().unwrap().is_empty());
+        std::fs::remove_file(&local).unwrap();
+    }
+
+    #[test]
+    fn reader_failing_after_the_first_block_leaves_the_space_free() {
+        /// Yields one block of data, then fails.
+        struct FailingReader(Vec<u8>);
+
+        impl Read for FailingReader {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() {
+                    return Err(io::Error::other("source went away"));
+                }
+                let len = buf.len().min(self.0.len());
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0.drain(..len);
+                Ok(len)
+            }
+        }
+
+        let mut fs = new_fs();
+        let free_before = fs.free_block_count();
+        let bitmap_before = fs.free_block_bitmap.clone();
+        let block = fs.usable_block_size();
+        let mut reader = FailingReader(pattern(block));
+        let written = fs.write_file("a", &mut reader, 4 * block);
+        assert!(matches!(written, Err(FsError::Io(_))));
+        assert!(!fs.exists("a"));
+        assert_eq!(fs.free_block_count(), free_before);
+        assert_eq!(fs.free_block_bitmap, bitmap_before);
+        assert!(fs.check_integrity().unwrap().is_empty());
+    }
+
+    #[test]
+    fn transient_write_errors_are_retried() {
+        let (mut fs, failures) = faulty_fs(0, io::ErrorKind::Interrupted);
+        let data = pattern(1500);
+        failures.set(1);
+        assert!(matches!(
+            fs.write_file("a", &mut &data[..], data.len()),
+            Err(FsError::Io(_))
+        ));
+        assert!(!fs.exists("a"));
+
+        fs.set_io_retries(3);
+        failures.set(3);
+        store(&mut fs, "a", &data);
+        assert_eq!(failures.get(), 0);
+        assert_eq!(contents(&mut fs, "a"), data);
+
+        // Permanent errors are not retried
+        let (mut fs, failures) = faulty_fs(0, io::ErrorKind::PermissionDenied);
+        fs.set_io_retries(3);
+        failures.set(2);
+        assert!(fs.write_file("a", &mut &data[..], data.len()).is_err());
+        assert_eq!(failures.get(), 1);
+    }
+
+    #[test]
+    fn chain_iterator_yields_blocks_and_stops_at_corruption() {
+        let mut fs = new_fs();
+        let usable = fs.usable_block_size();
+        let data = pattern(3 * usable);
+        store(&mut fs, "a", &data);
+        let first = fs.filenodes[fs.find_filenode_index("a").unwrap()].first_block_index;
+
+        let blocks: Vec<(usize, Vec<u8>)> = BlockChainIter::new(&mut fs, first)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            blocks.iter().map(|(index, _)| *index).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        for (i, (_, block)) in blocks.iter().enumerate() {
+            assert_eq!(block.len(), fs.header.block_size);
+            assert_eq!(&block[..usable], &data[i * usable..(i + 1) * usable]);
+        }
+        let indices: Vec<usize> = BlockChainIter::indices_only(&mut fs, first)
+            .map(|block| block.unwrap().0)
+            .collect();
+        assert_eq!(indices, [0, 1, 2]);
+        assert_eq!(BlockChainIter::new(&mut fs, None).count(), 0);
+
+        // A loop is yielded as one error after the blocks before it, then the walk ends
+        set_next_pointer(&mut fs, 2, 1);
+        let mut chain = BlockChainIter::indices_only(&mut fs, first);
+        let walked: Vec<_> = chain.by_ref().collect();
+        assert_eq!(walked.len(), 4);
+        assert!(matches!(walked[3], Err(FsError::Corrupt(_))));
+        assert!(chain.looped());
+        // So is a pointer past the data area
+        let past_the_end = fs.header.num_data_blocks;
+        set_next_pointer(&mut fs, 0, past_the_end);
+        let walked: Vec<_> = BlockChainIter::new(&mut fs, first).collect();
+        assert_eq!(walked.len(), 2);
+        assert!(matches!(walked[1], Err(FsError::Corrupt(_))));
+    }
+
+    #[test]
+    fn chain_cycle_is_reported_as_corrupt() {
+        let mut fs = new_fs();
+        let data = pattern(3 * fs.usable_block_size());
+        store(&mut fs, "loop", &data);
+        store(&mut fs, "other", &pattern(100));
+        let chain = fs.block_chain("loop").unwrap();
+        set_next_pointer(&mut fs, chain[1], chain[0]);
+
+        assert!(matches!(fs.block_chain("loop"), Err(FsError::Corrupt(_))));
+        let mut out = Vec::new();
+        assert!(matches!(
+            fs.read_file("loop", &mut out),
+            Err(FsError::Corrupt(_))
+        ));
+        assert!(matches!(fs.verify_file("loop"), Err(FsError::Corrupt(_))));
+        // Deleting must not free blocks reached by going round the loop
+        let free_before = fs.free_block_count();
+        assert!(matches!(fs.delete_file("loop"), Err(FsError::Corrupt(_))));
+        assert!(fs.exists("loop"));
+        assert_eq!(fs.free_block_count(), free_before);
+        assert_eq!(contents(&mut fs, "other"), pattern(100));
+    }
+
+    #[test]
+    fn image_with_another_block_size_is_opened_not_wiped() {
+        let path = temp_path("block-size.dat");
+        let data = pattern(1500);
+        {
+            let mut fs = FileSystemManager::init_filesystem_at(&path, 0, &small_options()).unwrap();
+            store(&mut fs, "kept", &data);
+        }
+        assert_ne!(small_options().block_size, BLOCK_SIZE);
+
+        let mut fs = get_filesystem_manager_at(&path).unwrap();
+        assert_eq!(fs.header.block_size, small_options().block_size);
+        assert_eq!(contents(&mut fs, "kept"), data);
+        drop(fs);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn image_of_unknown_version_is_refused_and_left_alone() {
+        let path = temp_path("version.dat");
+        {
+            let mut fs = FileSystemManager::init_filesystem_at(&path, 0, &small_options()).unwrap();
+            store(&mut fs, "kept", &pattern(100));
+        }
+        let mut image = std::fs::read(&path).unwrap();
+        // Layouts between the baseline and the oldest upgradable one are refused too
+        for version in [FILESYSTEM_VERSION + 1, OLDEST_UPGRADABLE_VERSION - 1] {
+            image[..4].copy_from_slice(&version.to_le_bytes());
+            std::fs::write(&path, &image).unwrap();
+
+            match get_filesystem_manager_at(&path) {
+                Err(FsError::Incompatible {
+                    field: "version",
+                    found,
+                    ..
+                }) => assert_eq!(found, version as usize),
+                other => panic!("expected Incompatible, got {:?}", other.map(|_| ())),
+            }
+            assert_eq!(std::fs::read(&path).unwrap(), image);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn flipped_data_byte_fails_the_checksum() {
+        let mut fs = new_fs();
+        let data = pattern(2000);
+        store(&mut fs, "a", &data);
+        let chain = fs.block_chain("a").unwrap();
+        flip_data_byte(&mut fs, chain[2], 10);
+
+        let mut out = Vec::new();
+        assert!(matches!(
+            fs.read_file("a", &mut out),
+            Err(FsError::Corrupt(_))
+        ));
+        assert!(matches!(fs.verify_file("a"), Err(FsError::Corrupt(_))));
+        flip_data_byte(&mut fs, chain[2], 10);
+        assert_eq!(contents(&mut fs, "a"), data);
+    }
+
+    #[test]
+    fn flipped_data_byte_fails_a_download_to_a_file() {
+        let path = temp_path("checksum.dat");
+        let local = temp_path("checksum.out");
+        let mut fs = FileSystemManager::init_filesystem_at(&path, 0, &small_options()).unwrap();
+        let data = pattern(2000);
+        store(&mut fs, "a", &data);
+        assert_eq!(
+            fs.download_file("a", local.to_str().unwrap()).unwrap(),
+            data.len()
+        );
+        assert_eq!(std::fs::read(&local).unwrap(), data);
+
+        let chain = fs.block_chain("a").unwrap();
+        flip_data_byte(&mut fs, chain[1], 0);
+        // The corrupt copy is removed, whichever way the contents were copied
+        assert!(matches!(
+            fs.download_file("a", local.to_str().unwrap()),
+            Err(FsError::Corrupt(_))
+        ));
+        assert!(!local.exists());
+        assert!(matches!(
+            fs.bench_download_with_copy_loop("a", local.to_str().unwrap()),
+            Err(FsError::Corrupt(_))
+        ));
+        assert!(!local.exists());
+        drop(fs);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn exists_for_present_absent_and_deleted_aliases() {
+        let mut fs = new_fs();
+        store(&mut fs, "present", b"here");
+        store(&mut fs, "deleted", b"gone");
+        fs.delete_file("deleted").unwrap();
+
+        assert!(fs.exists("present"));
+        assert!(!fs.exists("absent"));
+        // The deleted file's alias is kept as a tombstone but no longer exists
+        assert!(!fs.exists("deleted"));
+    }
+
+    #[test]
+    fn expired_files_are_left_out_of_listings() {
+        let mut fs = new_fs();
+        store(&mut fs, "live", b"here");
+        let expiring = UploadOptions {
+            ttl: Some(0),
+            ..UploadOptions::default()
+        };
+        fs.upload_reader(&mut &pattern(600)[..], "expired", &expiring)
+            .unwrap();
+
+        let aliases: Vec<String> = fs.files().map(|info| info.alias).collect();
+        assert_eq!(aliases, ["live"]);
+        assert_eq!(fs.list_file_infos().len(), 1);
+        assert_eq!(fs.list_files("").unwrap(), ["live (4 bytes)"]);
+        let found = fs.find_files("*", false, SizeFilter::default()).unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(matches!(
+            fs.get_file_info("expired"),
+            Err(FsError::AliasNotFound(_))
+        ));
+        assert_eq!(fs.disk_usage().len(), 1);
+        let usage = fs.usage();
+        assert_eq!((usage.num_files, usage.used_bytes), (1, 4));
+        // Its blocks and filenode stay taken until it is purged
+        assert_eq!(usage.used_blocks, 3);
+        assert_eq!(usage.free_filenodes, 14);
+    }
+
+    #[test]
+    fn file_iterator_counts_every_live_entry() {
+        let mut fs = new_fs();
+        assert_eq!(fs.files().count(), 0);
+        for i in 0..5 {
+            store(&mut fs, &format!("dir/f{}", i), &pattern(i * 300));
+        }
+        fs.link("dir/f1", "link").unwrap();
+        // Five files, their directory and the link
+        assert_eq!(fs.files().count(), 7);
+        assert_eq!(fs.files().count(), fs.list_file_infos().len());
+        let total: usize = fs
+            .files()
+            .filter(|info| !info.is_directory && !info.is_symlink)
+            .map(|info| info.size)
+            .sum();
+        assert_eq!(total, (0..5).map(|i| i * 300).sum::<usize>());
+
+        fs.delete_file("dir/f3").unwrap();
+        assert_eq!(fs.files().count(), 6);
+        assert!(fs.files().all(|info| info.alias != "dir/f3"));
+        assert_eq!(fs.files().take(2).count(), 2);
+    }
+
+    #[test]
+    fn swapped_aliases_serve_each_others_contents() {
+        let mut fs = new_fs();
+        let long = pattern(1500);
+        let note = |text: &str| UploadOptions {
+            description: Some(text.to_string()),
+            ..UploadOptions::default()
+        };
+        fs.upload_reader(&mut &b"short"[..], "a.txt", &note("note of a"))
+            .unwrap();
+        fs.upload_reader(&mut &long[..], "b.bin", &note("note of b"))
+            .unwrap();
+        fs.link("a.txt", "link").unwrap();
+
+        fs.swap_aliases("a.txt", "b.bin").unwrap();
+        assert_eq!(contents(&mut fs, "a.txt"), long);
+        assert_eq!(contents(&mut fs, "b.bin"), b"short");
+        let info = fs.get_file_info("a.txt").unwrap();
+        assert_eq!(info.size, long.len());
+        assert_eq!(info.description.as_deref(), Some("note of a"));
+        assert_eq!(
+            info.content_type.as_deref(),
+            Some("application/octet-stream")
+        );
+        assert_eq!(
+            fs.get_file_info("b.bin").unwrap().content_type.as_deref(),
+            Some("text/plain")
+        );
+        assert!(fs.check_integrity().unwrap().is_empty());
+
+        assert!(matches!(
+            fs.swap_aliases("link", "b.bin"),
+            Err(FsError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn encrypted_file_needs_the_right_password() {
+        let mut fs = new_fs();
+        let data = pattern(1200);
+        let options = UploadOptions {
+            password: Some("correct horse".to_string()),
+            ..UploadOptions::default()
+        };
+        fs.upload_reader(&mut &data[..], "secret", &options)
+            .unwrap();
+        assert!(fs.get_file_info("secret").unwrap().encrypted);
+
+        let mut out = Vec::new();
+        assert!(matches!(
+            fs.read_file("secret", &mut out),
+            Err(FsError::InvalidArgument(_))
+        ));
+        fs.set_password(Some("wrong".to_string()));
+        assert!(matches!(
+            fs.read_file("secret", &mut out),
+            Err(FsError::BadPassword(_))
+        ));
+        fs.set_password(Some("correct horse".to_string()));
+        assert_eq!(contents(&mut fs, "secret"), data);
+    }
+
+    #[test]
+    fn tampered_encrypted_file_is_detected() {
+        let mut fs = new_fs();
+        fs.set_password(Some("pw".to_string()));
+        let options = UploadOptions {
+            password: Some("pw".to_string()),
+            ..UploadOptions::default()
+        };
+        fs.upload_reader(&mut &pattern(800)[..], "secret", &options)
+            .unwrap();
+        let chain = fs.block_chain("secret").unwrap();
+        flip_data_byte(&mut fs, chain[0], 5);
+
+        let mut out = Vec::new();
+        assert!(matches!(
+            fs.read_file("secret", &mut out),
+            Err(FsError::Corrupt(_))
+        ));
+        // With the checksum forged to match, the authentication tag still catches it
+        let index = fs.filenode_index("secret").unwrap();
+        let node = fs.filenodes[index].clone();
+        fs.filenodes[index].checksum = fs.stream_chain(&node, &mut io::sink()).unwrap();
+        assert!(matches!(
+            fs.read_file("secret", &mut out),
+            Err(FsError::BadPassword(_))
+        ));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn export_modify_import_restores_the_exported_state() {
+        let path = temp_path("import.dat");
+        let backup = temp_path("import.img");
+        let mut fs = FileSystemManager::init_filesystem_at(&path, 0, &small_options()).unwrap();
+        store(&mut fs, "a", &pattern(1000));
+        store(&mut fs, "b", &pattern(10));
+        fs.export_to(&backup).unwrap();
+
+        fs.delete_file("a").unwrap();
+        store(&mut fs, "c", &pattern(3000));
+        fs.snapshot("after").unwrap();
+        // The open filesystem holds an exclusive lock, which keeps readers and writers out
+        assert!(matches!(
+            FileSystemManager::open_read_only(&path),
+            Err(FsError::Io(_))
+        ));
+        assert!(matches!(
+            FileSystemManager::open_at(&path, 0),
+            Err(FsError::Io(_))
+        ));
+        fs.import_from(&backup).unwrap();
+
+        let check = |fs: &mut FileSystemManager| {
+            assert_eq!(contents(fs, "a"), pattern(1000));
+            assert_eq!(contents(fs, "b"), pattern(10));
+            assert!(!fs.exists("c"));
+            assert!(fs.list_snapshots().unwrap().is_empty());
+            assert!(fs.check_integrity().unwrap().is_empty());
+        };
+        check(&mut fs);
+        drop(fs);
+        check(&mut FileSystemManager::open_at(&path, 0).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+    }
+
+    #[test]
+    fn volume_at_an_offset_round_trips_and_keeps_the_prefix() {
+        let path = temp_path("offset.dat");
+        let prefix = pattern(1000);
+        std::fs::write(&path, &prefix).unwrap();
+        let data = pattern(3000);
+        {
+            let mut fs =
+                FileSystemManager::init_filesystem_at(&path, 1000, &small_options()).unwrap();
+            store(&mut fs, "dir/a", &data);
+        }
+
+        let file = std::fs::read(&path).unwrap();
+        assert_eq!(&file[..1000], &prefix[..]);
+        assert_eq!(file.len(), 1000 + small_options().total_size);
+        let mut reopened = FileSystemManager::open_at(&path, 1000).unwrap();
+        assert_eq!(contents(&mut reopened, "dir/a"), data);
+        assert!(reopened.check_integrity().unwrap().is_empty());
+        drop(reopened);
+        // The volume is not found at the start of the file
+        assert!(FileSystemManager::open_at(&path, 0).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn image_larger_than_an_embedded_volume_is_refused() {
+        let path = temp_path("embedded.dat");
+        let backup = temp_path("embedded.img");
+        let larger = InitOptions {
+            total_size: 2 * small_options().total_size,
+            ..small_options()
+        };
+        let mut source =
+            FileSystemManager::init_in_storage(Box::new(MemStorage::new()), &larger).unwrap();
+        store(&mut source, "a", &pattern(100));
+        std::fs::write(&backup, read_image(&mut source)).unwrap();
+
+        let mut fs = FileSystemManager::init_filesystem_at(&path, 4096, &small_options()).unwrap();
+        store(&mut fs, "kept", &pattern(100));
+        assert!(matches!(
+            fs.import_from(&backup),
+            Err(FsError::InvalidArgument(_))
+        ));
+        assert_eq!(contents(&mut fs, "kept"), pattern(100));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+    }
+
+    /// Runs `op` as a transaction that is interrupted once `record` bytes of its journal
+    /// record and the first `applied` of its writes have reached the disk. Returns the
+    /// image as the interruption left it.
+    fn interrupted(
+        fs: &mut FileSystemManager,
+        op: impl FnOnce(&mut FileSystemManager) -> Result<(), FsError>,
+        record: impl FnOnce(usize) -> usize,
+        applied: usize,
+    ) -> Vec<u8> {
+        fs.journal = Some(Vec::new());
+        op(fs).unwrap();
+        let writes = fs.journal.take().unwrap();
+        let writes = journal::coalesce(fs.storage.as_mut(), 0, &writes).unwrap();
+        let encoded = journal::encode_record(&writes).unwrap();
+        assert!(encoded.len() <= fs.header.journal_size);
+        let journal_offset = fs.header.journal_offset as u64;
+        fs.storage
+            .write_at(journal_offset, &encoded[..record(encoded.len())])
+            .unwrap();
+        journal::apply(fs.storage.as_mut(), 0, &writes[..applied]).unwrap();
+        read_image(fs)
+    }
+
+    #[test]
+    fn journaled_delete_is_finished_after_a_crash() {
+        for applied in [0, 1] {
+            let mut fs = new_fs();
+            let free_before = fs.free_block_count();
+            store(&mut fs, "a", &pattern(2000));
+            store(&mut fs, "b", &pattern(700));
+            let image = interrupted(&mut fs, |fs| fs.remove_file("a", false), |len| len, applied);
+
+            let mut recovered =
+                FileSystemManager::open_storage(Box::new(MemStorage::from_vec(image))).unwrap();
+            assert!(!recovered.exists("a"));
+            assert_eq!(contents(&mut recovered, "b"), pattern(700));
+            assert_eq!(
+                recovered.free_block_count(),
+                free_before - recovered.block_chain("b").unwrap().len()
+            );
+            assert!(recovered.check_integrity().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn torn_journal_record_is_discarded_after_a_crash() {
+        let mut fs = new_fs();
+        store(&mut fs, "a", &pattern(2000));
+        let free_before = fs.free_block_count();
+        let image = interrupted(&mut fs, |fs| fs.remove_file("a", false), |len| len / 2, 0);
+
+        let mut recovered =
+            FileSystemManager::open_storage(Box::new(MemStorage::from_vec(image))).unwrap();
+        assert_eq!(contents(&mut recovered, "a"), pattern(2000));
+        assert_eq!(recovered.free_block_count(), free_before);
+        assert!(recovered.check_integrity().unwrap().is_empty());
+    }
+
+    #[test]
+    fn growable_volume_grows_when_space_runs_out() {
+        let mut fs = new_fs();
+        let existing = pattern(1000);
+        store(&mut fs, "existing", &existing);
+        let too_big = pattern((fs.free_block_count() + 20) * fs.usable_block_size());
+        assert!(matches!(
+            fs.write_file("big", &mut &too_big[..], too_big.len()),
+            Err(FsError::NotEnoughSpace { .. })
+        ));
+        let total_size = fs.header.total_size;
+
+        fs.set_growable(true);
+        store(&mut fs, "big", &too_big);
+        assert!(fs.header.total_size > total_size);
+        assert_eq!(fs.storage.len().unwrap() as usize, fs.header.total_size);
+        let image = MemStorage::from_vec(read_image(&mut fs));
+        let mut reopened = FileSystemManager::open_storage(Box::new(image)).unwrap();
+        assert_eq!(contents(&mut reopened, "existing"), existing);
+        assert_eq!(contents(&mut reopened, "big"), too_big);
+        assert!(reopened.check_integrity().unwrap().is_empty());
+    }
+
+    #[test]
+    fn clearing_leaves_an_empty_volume_of_the_same_size() {
+        let mut fs = new_fs();
+        let free_when_empty = fs.free_block_count();
+        let total_size = fs.header.total_size;
+        store(&mut fs, "dir/sub/a", &pattern(3000));
+        store(&mut fs, "b", &pattern(10));
+        fs.link("b", "dir/link").unwrap();
+
+        assert_eq!(fs.clear_all().unwrap(), 5);
+        assert_eq!(fs.files().count(), 0);
+        assert_eq!(fs.free_block_count(), free_when_empty);
+        assert_eq!(fs.header.total_size, total_size);
+        let image = MemStorage::from_vec(read_image(&mut fs));
+        let mut reopened = FileSystemManager::open_storage(Box::new(image)).unwrap();
+        assert_eq!(reopened.files().count(), 0);
+        assert_eq!(reopened.free_block_count(), free_when_empty);
+        assert!(reopened.check_integrity().unwrap().is_empty());
+        // Every filenode is free again
+        for i in 0..16 {
+            store(&mut reopened, &format!("f{}", i), b"x");
+        }
+    }
+
+    #[test]
+    fn clearing_a_grown_volume_is_journaled() {
+        let mut fs = new_fs();
+        fs.set_growable(true);
+        // Far more than the volume was created with, so its bitmap is widened
+        store(&mut fs, "big", &pattern(256 * 1024));
+        for index in 0..14 {
+            store(&mut fs, &format!("dir/{}", index), &pattern(600));
+        }
+        assert!(fs.header.num_data_blocks > small_options().total_size / 512);
+        let image = interrupted(&mut fs, |fs| fs.clear_all().map(|_| ()), |len| len, 0);
+
+        let mut recovered =
+            FileSystemManager::open_storage(Box::new(MemStorage::from_vec(image))).unwrap();
+        assert_eq!(recovered.files().count(), 0);
+        assert_eq!(
+            recovered.free_block_count(),
+            recovered.header.num_data_blocks
+        );
+        assert!(recovered.check_integrity().unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_transaction_is_rolled_back() {
+        let mut fs = new_fs();
+        store(&mut fs, "a", &pattern(2000));
+        let free_before = fs.free_block_count();
+        let image = read_image(&mut fs);
+
+        let result = fs.transaction(|fs| {
+            fs.remove_file("a", false)?;
+            Err::<(), _>(FsError::InvalidArgument("stop".to_string()))
+        });
+        assert!(matches!(result, Err(FsError::InvalidArgument(_))));
+        assert_eq!(read_image(&mut fs), image);
+        assert_eq!(contents(&mut fs, "a"), pattern(2000));
+        assert_eq!(fs.free_block_count(), free_before);
+        assert!(fs.check_integrity().unwrap().is_empty());
+    }
+
+    #[test]
+    fn copy_of_a_multi_block_file_is_independent() {
+        let mut fs = new_fs();
+        let data = pattern(20 * fs.usable_block_size() + 33);
+        store(&mut fs, "src", &data);
+        let free_before = fs.free_block_count();
+
+        fs.copy_file("src", "dst").unwrap();
+        let src_chain = fs.block_chain("src").unwrap();
+        let dst_chain = fs.block_chain("dst").unwrap();
+        assert_eq!(dst_chain.len(), 21);
+        assert!(dst_chain.iter().all(|block| !src_chain.contains(block)));
+        assert_eq!(fs.free_block_count(), free_before - 21);
+        assert_eq!(contents(&mut fs, "dst"), data);
+
+        fs.delete_file("src").unwrap();
+        assert_eq!(contents(&mut fs, "dst"), data);
+        assert!(fs.check_integrity().unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_copy_leaves_no_directories_or_blocks_behind() {
+        let mut fs = new_fs();
+        store(&mut fs, "src", &pattern(2000));
+        for index in 0..14 {
+            store(&mut fs, &format!("f{}", index), b"x");
+        }
+        let free_before = fs.free_block_count();
+        let image = read_image(&mut fs);
+
+        // The directory takes the last filenode, leaving none for the copy
+        assert!(matches!(
+            fs.copy_file("src", "dir/copy"),
+            Err(FsError::NoFreeFilenodes)
+        ));
+        assert!(!fs.exists("dir"));
+        assert_eq!(fs.free_block_count(), free_before);
+        assert_eq!(
+            read_image(&mut fs)[..fs.header.data_blocks_offset],
+            image[..fs.header.data_blocks_offset]
+        );
+        assert!(fs.check_integrity().unwrap().is_empty());
+
+        fs.remove_file("f0", false).unwrap();
+        fs.remove_file("f1", false).unwrap();
+        fs.copy_file("src", "dir/copy").unwrap();
+        assert_eq!(contents(&mut fs, "dir/copy"), pattern(2000));
+    }
+
+    #[test]
+    fn archive_import_keeps_metadata_and_replaces_files_in_place() {
+        let mut source = new_fs();
+        let options = UploadOptions {
+            content_type: Some("text/x-log".to_string()),
+            description: Some("nightly".to_string()),
+            ..UploadOptions::default()
+        };
+        source
+            .upload_reader(&mut &pattern(1500)[..], "logs/a", &options)
+            .unwrap();
+        let index = source.find_filenode_index("logs/a").unwrap();
+        source.filenodes[index].created_at = 1_000;
+        source.filenodes[index].modified_at = 2_000;
+        let path = temp_path("metadata.archive");
+        source.export_archive(&path, None).unwrap();
+
+        let mut target = new_fs();
+        store(&mut target, "logs/a", &pattern(300));
+        assert_eq!(target.import_archive(&path).unwrap(), (1, 0));
+        assert_eq!(contents(&mut target, "logs/a"), pattern(1500));
+        let info = target.get_file_info("logs/a").unwrap();
+        assert_eq!(info.content_type.as_deref(), Some("text/x-log"));
+        assert_eq!(info.description.as_deref(), Some("nightly"));
+        let index = target.find_filenode_index("logs/a").unwrap();
+        assert_eq!(target.filenodes[index].created_at, 1_000);
+        assert_eq!(target.filenodes[index].modified_at, 2_000);
+        assert!(target.check_integrity().unwrap().is_empty());
+
+        // A replacement that does not fit leaves the file it would replace alone
+        let mut full = new_fs();
+        store(&mut full, "logs/a", &pattern(300));
+        let free = full.free_block_count();
+        store(&mut full, "filler", &pattern((free - 1) * (512 - 8)));
+        assert!(full.import_archive(&path).is_err());
+        assert_eq!(contents(&mut full, "logs/a"), pattern(300));
+        assert!(full.check_integrity().unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compacted_file_shrinks_and_reopens() {
+        let path = temp_path("compact.dat");
+        let mut fs = FileSystemManager::init_filesystem_at(&path, 0, &small_options()).unwrap();
+        let first = pattern(1200);
+        let second = pattern(700);
+        store(&mut fs, "gap", &pattern(2000));
+        store(&mut fs, "dir/first", &first);
+        store(&mut fs, "second", &second);
+        fs.delete_file("gap").unwrap();
+        let size_before = std::fs::metadata(&path).unwrap().len() as usize;
+
+        let saved = fs.compact().unwrap();
+        assert!(saved > 0);
+        drop(fs);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len() as usize,
+            size_before - saved
+        );
+        let mut reopened = FileSystemManager::open_at(&path, 0).unwrap();
+        assert_eq!(reopened.free_block_count(), 0);
+        assert_eq!(contents(&mut reopened, "dir/first"), first);
+        assert_eq!(contents(&mut reopened, "second"), second);
+        assert!(reopened.check_integrity().unwrap().is_empty());
+        drop(reopened);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compact_needs_a_complete_defragmentation() {
+        let mut fs = new_fs();
+        let num_blocks = fs.header.num_data_blocks;
+        let usable = fs.usable_block_size();
+        store(&mut fs, "x", b"x");
+        store(&mut fs, "big", &pattern((num_blocks - 3) * usable));
+        store(&mut fs, "spare", b"s");
+        fs.delete_file("x").unwrap();
+        // Split between the first and last block, with no spare block to move through
+        store(&mut fs, "y", &pattern(usable + 1));
+        assert_eq!(fs.block_chain("y").unwrap(), vec![0, num_blocks - 1]);
+
+        let total_size = fs.header.total_size;
+        assert!(matches!(fs.compact(), Err(FsError::InvalidArgument(_))));
+        assert_eq!(fs.header.total_size, total_size);
+        assert_eq!(contents(&mut fs, "y"), pattern(usable + 1));
+        assert!(fs.check_integrity().unwrap().is_empty());
+
+        fs.delete_file("big").unwrap();
+        assert!(fs.compact().unwrap() >= (num_blocks - 3) * 512);
+        assert_eq!(fs.header.num_data_blocks, 3);
+        let image = read_image(&mut fs);
+        let mut reopened =
+            FileSystemManager::open_storage(Box::new(MemStorage::from_vec(image))).unwrap();
+        assert_eq!(contents(&mut reopened, "y"), pattern(usable + 1));
+        assert_eq!(contents(&mut reopened, "spare"), b"s");
+        assert_eq!(reopened.free_block_count(), 0);
+        assert!(reopened.check_integrity().unwrap().is_empty());
+    }
+
+    #[test]
+    fn doctor_fix_corrects_refcounts_and_frees_orphaned_blocks() {
+        let mut fs = new_fs();
+        let options = UploadOptions {
+            dedup: true,
+            ..UploadOptions::default()
+        };
+        for alias in ["a", "b"] {
+            fs.upload_reader(&mut &pattern(1200)[..], alias, &options)
+                .unwrap();
+        }
+        let index = fs.find_filenode_index("a").unwrap();
+        fs.filenodes[index].refcount = 5;
+        fs.save_single_filenode(index).unwrap();
+        let orphan = fs.header.num_data_blocks - 1;
+        fs.set_block_free(orphan, false);
+        fs.write_bitmap_to_disk().unwrap();
+
+        let report = fs.doctor(false).unwrap();
+        assert!(!report.problems.is_empty());
+        assert!(report.suggestions.iter().any(|s| s.contains("refcounts")));
+        assert!(report.suggestions.iter().any(|s| s.contains("orphaned")));
+        assert_eq!(fs.filenodes[index].refcount, 5);
+
+        let report = fs.doctor(true).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!(fs.filenodes[index].refcount, 2);
+        assert!(fs.is_block_free(orphan));
+        let image = read_image(&mut fs);
+        let mut reopened =
+            FileSystemManager::open_storage(Box::new(MemStorage::from_vec(image))).unwrap();
+        assert!(reopened.check_integrity().unwrap().is_empty());
+        assert_eq!(contents(&mut reopened, "b"), pattern(1200));
+    }
+
+    #[test]
+    fn truncating_frees_the_blocks_past_the_new_end() {
+        let mut fs = new_fs();
+        let block = fs.usable_block_size();
+        let data = pattern(3 * block);
+        store(&mut fs, "a", &data);
+        let chain = fs.block_chain("a").unwrap();
+        let free_before = fs.free_block_count();
+
+        fs.truncate_file("a", 100).unwrap();
+        assert_eq!(fs.block_chain("a").unwrap(), &chain[..1]);
+        assert_eq!(fs.free_block_count(), free_before + 2);
+        for &freed in &chain[1..] {
+            assert!(fs.is_block_free(freed));
+        }
+        assert_eq!(contents(&mut fs, "a"), &data[..100]);
+        assert!(fs.check_integrity().unwrap().is_empty());
+
+        // Growing is refused and truncating to nothing frees the last block
+        assert!(matches!(
+            fs.truncate_file("a", 101),
+            Err(FsError::InvalidArgument(_))
+        ));
+        fs.truncate_file("a", 0).unwrap();
+        assert_eq!(fs.free_block_count(), free_before + 3);
+        assert!(contents(&mut fs, "a").is_empty());
+    }
+
+    #[test]
+    fn append_refuses_expired_files_and_sizes_short_of_the_chain() {
+        let mut fs = new_fs();
+        let expiring = UploadOptions {
+            ttl: Some(0),
+            ..UploadOptions::default()
+        };
+        fs.upload_reader(&mut &pattern(600)[..], "expired", &expiring)
+            .unwrap();
+        assert!(matches!(
+            fs.append_file("expired", &mut &b"more"[..]),
+            Err(FsError::AliasNotFound(_))
+        ));
+
+        store(&mut fs, "a", &pattern(1200));
+        let index = fs.find_filenode_index("a").unwrap();
+        fs.filenodes[index].size = 10;
+        let free = fs.free_block_count();
+        assert!(matches!(
+            fs.append_file("a", &mut &b"more"[..]),
+            Err(FsError::Corrupt(_))
+        ));
+        assert_eq!(fs.free_block_count(), free);
+        assert_eq!(fs.filenodes[index].size, 10);
+    }
+
+    #[test]
+    fn grown_filenode_table_keeps_files_readable_after_a_reopen() {
+        let mut fs = new_fs();
+        let first = pattern(1500);
+        let second = pattern(40);
+        store(&mut fs, "dir/first", &first);
+        store(&mut fs, "second", &second);
+
+        fs.grow_filenode_table(32).unwrap();
+        assert!(matches!(
+            fs.grow_filenode_table(32),
+            Err(FsError::InvalidArgument(_))
+        ));
+        let image = MemStorage::from_vec(read_image(&mut fs));
+        let mut reopened = FileSystemManager::open_storage(Box::new(image)).unwrap();
+        assert_eq!(reopened.header.filenode_table_size, 32);
+        assert_eq!(contents(&mut reopened, "dir/first"), first);
+        assert_eq!(contents(&mut reopened, "second"), second);
+        assert!(reopened.check_integrity().unwrap().is_empty());
+
+        // The extra filenodes are usable
+        for i in 0..20 {
+            store(&mut reopened, &format!("more{}", i), b"x");
+        }
+        assert_eq!(contents(&mut reopened, "more19"), b"x");
+    }
+
+    #[test]
+    fn unused_block_tail_holds_the_fill_byte() {
+        let options = InitOptions {
+            fill_byte: 0xa5,
+            ..small_options()
+        };
+        let mut fs =
+            FileSystemManager::init_in_storage(Box::new(MemStorage::new()), &options).unwrap();
+        let data = pattern(fs.usable_block_size() + 10);
+        store(&mut fs, "a", &data);
+        let last = *fs.block_chain("a").unwrap().last().unwrap();
+
+        let image = read_image(&mut fs);
+        let start = fs.header.data_blocks_offset + last * fs.header.block_size;
+        let tail = &image[start + 10..start + fs.usable_block_size()];
+        assert!(tail.iter().all(|&byte| byte == 0xa5));
+        assert_eq!(contents(&mut fs, "a"), data);
+
+        // The fill byte is kept across a reopen
+        let mut reopened =
+            FileSystemManager::open_storage(Box::new(MemStorage::from_vec(image))).unwrap();
+        assert_eq!(reopened.header.fill_byte, 0xa5);
+        assert_eq!(contents(&mut reopened, "a"), data);
+    }
+
+    #[test]
+    fn layout_regions_are_contiguous_and_cover_the_file() {
+        let mut fs = new_fs();
+        store(&mut fs, "a", &pattern(100));
+        let layout = fs.layout().unwrap();
+        let regions = [
+            layout.header,
+            layout.journal,
+            layout.filenode_table,
+            layout.free_block_bitmap,
+            layout.data_blocks,
+            layout.unused_tail,
+        ];
+        assert_eq!(regions[0].offset, 0);
+        for pair in regions.windows(2) {
+            assert_eq!(pair[0].offset + pair[0].len, pair[1].offset);
+        }
+        let total: usize = regions.iter().map(|region| region.len).sum();
+        assert_eq!(total, fs.storage.len().unwrap() as usize);
+        assert!(layout.filenode_table_serialized_len <= layout.filenode_table.len);
+    }
+
+    #[test]
+    fn close_misspellings_suggest_the_stored_alias() {
+        let mut fs = new_fs();
+        store(&mut fs, "report.txt", b"r");
+        store(&mut fs, "notes.md", b"n");
+        assert_eq!(
+            fs.suggest_alias("reprot.txt").as_deref(),
+            Some("report.txt")
+        );
+        assert_eq!(fs.suggest_alias("note.md").as_deref(), Some("notes.md"));
+        // Existing aliases and distant names get no suggestion
+        assert_eq!(fs.suggest_alias("report.txt"), None);
+        assert_eq!(fs.suggest_alias("something-else"), None);
+    }
+
+    #[test]
+    fn glob_patterns_match_wildcards_and_literals() {
+        let matches = |pattern: &str, text: &str| {
+            let pattern: Vec<char> = pattern.chars().collect();
+            let text: Vec<char> = text.chars().collect();
+            glob_match(&pattern, &text)
+        };
+        assert!(matches("*.txt", "notes.txt"));
+        assert!(matches("*.txt", ".txt"));
+        assert!(!matches("*.txt", "notes.txt.bak"));
+        assert!(matches("a*b*c", "aXXbYYbc"));
+        assert!(matches("file?.log", "file1.log"));
+        assert!(!matches("file?.log", "file.log"));
+        assert!(!matches("file?.log", "file12.log"));
+        assert!(matches("exact", "exact"));
+        assert!(!matches("exact", "exactly"));
+        assert!(matches("*", ""));
+        assert!(!matches("", "a"));
+
+        let mut fs = new_fs();
+        store(&mut fs, "dir/a.txt", b"a");
+        store(&mut fs, "b.TXT", b"b");
+        let found = |fs: &FileSystemManager, pattern: &str, ignore_case: bool| -> Vec<String> {
+            let files = fs.find_files(pattern, ignore_case, SizeFilter::default());
+            files.unwrap().into_iter().map(|info| info.alias).collect()
+        };
+        assert_eq!(found(&fs, "*.txt", false), ["dir/a.txt"]);
+        assert_eq!(found(&fs, "*.txt", true), ["dir/a.txt", "b.TXT"]);
+    }
+
+    #[test]
+    fn aliases_with_control_characters_are_rejected() {
+        let mut fs = new_fs();
+        store(&mut fs, "plain", b"p");
+        let free_before = fs.free_block_count();
+        for alias in ["a\0b", "a\nb", "tab\there", "dir/\u{7f}"] {
+            let written = fs.write_file(alias, &mut &b"data"[..], 4);
+            assert!(
+                matches!(written, Err(FsError::InvalidAlias(_))),
+                "{:?}",
+                alias
+            );
+            assert!(matches!(
+                fs.rename_file("plain", alias),
+                Err(FsError::InvalidAlias(_))
+            ));
+        }
+        assert_eq!(fs.free_block_count(), free_before);
+        assert_eq!(fs.files().count(), 1);
+        // Non-ASCII printable characters are fine
+        store(&mut fs, "café", b"c");
+    }
+
+    #[test]
+    fn deduplicated_uploads_share_blocks_until_deleted() {
+        let mut fs = new_fs();
+        let dedup = UploadOptions {
+            dedup: true,
+            ..UploadOptions::default()
+        };
+        let data = pattern(2000);
+        fs.upload_reader(&mut &data[..], "a", &dedup).unwrap();
+        let free_after_first = fs.free_block_count();
+
+        // A hit shares the chain and writes no blocks
+        fs.upload_reader(&mut &data[..], "b", &dedup).unwrap();
+        assert_eq!(fs.free_block_count(), free_after_first);
+        assert_eq!(fs.block_chain("b").unwrap(), fs.block_chain("a").unwrap());
+        assert_eq!(fs.get_file_info("a").unwrap().refcount, 2);
+        assert_eq!(fs.get_file_info("b").unwrap().refcount, 2);
+
+        // A miss gets its own blocks
+        let other = pattern(2001);
+        fs.upload_reader(&mut &other[..], "c", &dedup).unwrap();
+        assert!(fs.free_block_count() < free_after_first);
+        assert_eq!(fs.get_file_info("c").unwrap().refcount, 1);
+
+        // Deleting one sharer keeps the blocks for the other
+        let free_before_delete = fs.free_block_count();
+        fs.delete_file("a").unwrap();
+        assert_eq!(fs.free_block_count(), free_before_delete);
+        assert_eq!(fs.get_file_info("b").unwrap().refcount, 1);
+        assert_eq!(contents(&mut fs, "b"), data);
+        fs.delete_file("b").unwrap();
+        assert_eq!(fs.free_block_count(), free_before_delete + 4);
+        assert!(fs.check_integrity().unwrap().is_empty());
+    }
+
+    #[test]
+    fn duplicates_are_grouped_by_contents() {
+        let mut fs = new_fs();
+        let data = pattern(1200);
+        store(&mut fs, "x/one", &data);
+        store(&mut fs, "two", &data);
+        store(&mut fs, "three", &data);
+        // Same size, different contents
+        let mut changed = data.clone();
+        changed[1100] ^= 1;
+        store(&mut fs, "near", &changed);
+        store(&mut fs, "empty1", b"");
+        store(&mut fs, "empty2", b"");
+        store(&mut fs, "unique", &pattern(10));
+
+        let free_before = fs.free_block_count();
+        assert_eq!(
+            fs.find_duplicates().unwrap(),
+            [vec!["empty1", "empty2"], vec!["three", "two", "x/one"],]
+        );
+        // Reporting changes nothing
+        assert_eq!(fs.free_block_count(), free_before);
+        assert_eq!(fs.get_file_info("two").unwrap().refcount, 1);
+    }
+
+    #[test]
+    fn ranges_across_block_boundaries_and_at_the_end() {
+        let mut fs = new_fs();
+        let block = fs.usable_block_size();
+        let data = pattern(3 * block + 50);
+        store(&mut fs, "a", &data);
+
+        let across = fs.read_range("a", block - 10, 20).unwrap();
+        assert_eq!(across, &data[block - 10..block + 10]);
+        let two_boundaries = fs.read_range("a", block - 1, block + 2).unwrap();
+        assert_eq!(two_boundaries, &data[block - 1..2 * block + 1]);
+        // Ranges running past the end are clamped, and the end itself is empty
+        let tail = fs.read_range("a", data.len() - 30, 100).unwrap();
+        assert_eq!(tail, &data[data.len() - 30..]);
+        assert!(fs.read_range("a", data.len(), 10).unwrap().is_empty());
+        assert!(matches!(
+            fs.read_range("a", data.len() + 1, 10),
+            Err(FsError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn grep_finds_lines_spanning_block_boundaries() {
+        let mut fs = new_fs();
+        let block = fs.usable_block_size();
+        // Filler lines up to just before the first boundary, then a matching line across it
+        let mut text = Vec::new();
+        while text.len() < block - 8 {
+            text.extend_from_slice(b"filler\n");
+        }
+        let filler_lines = text.len() / 7;
+        text.extend_from_slice(b"the needle spans blocks\n");
+        while text.len() < 2 * block + 100 {
+            text.extend_from_slice(b"filler\n");
+        }
+        text.extend_from_slice(b"last needle");
+        store(&mut fs, "a", &text);
+
+        let found = fs
+            .grep_file("a", &LinePattern::Substring(b"needle".to_vec()))
+            .unwrap();
+        let total_lines = text.split(|&byte| byte == b'\n').count();
+        assert_eq!(
+            found,
+            [
+                (filler_lines + 1, b"the needle spans blocks".to_vec()),
+                (total_lines, b"last needle".to_vec()),
+            ]
+        );
+        let regex = LinePattern::regex("^the .* blocks$").unwrap();
+        assert_eq!(fs.grep_file("a", &regex).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn grep_for_files_with_matches_stops_at_the_first_hit() {
+        let mut fs = new_fs();
+        let block = fs.usable_block_size();
+        let mut data = vec![b'.'; 4 * block];
+        data[10..16].copy_from_slice(b"needle");
+        data[3 * block..3 * block + 6].copy_from_slice(b"needle");
+        // A match spanning the boundary between the first two blocks
+        data[block - 3..block + 3].copy_from_slice(b"needle");
+        store(&mut fs, "a", &data);
+        store(&mut fs, "b", b"no match here");
+        assert_eq!(fs.grep(b"needle", false).unwrap(), [("a".to_string(), 3)]);
+
+        // Breaking the chain after the first block shows the rest is never read
+        let chain = fs.block_chain("a").unwrap();
+        set_next_pointer(&mut fs, chain[0], usize::MAX - 1);
+        assert_eq!(fs.grep(b"needle", true).unwrap(), [("a".to_string(), 1)]);
+        assert!(fs.grep(b"needle", false).is_err());
+    }
+
+    #[test]
+    fn tar_export_holds_every_file_and_directory() {
+        let mut fs = new_fs();
+        let big = pattern(3000);
+        store(&mut fs, "dir/big", &big);
+        store(&mut fs, "small", b"small");
+        let tarball = temp_path("export.tar");
+        assert_eq!(fs.export_tar(&tarball).unwrap(), 3);
+
+        let mut archive = tar::Archive::new(File::open(&tarball).unwrap());
+        let mut entries = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            entries.push((path, entry.header().entry_type().is_dir(), data));
+        }
+        assert_eq!(
+            entries,
+            [
+                ("dir/".to_string(), true, Vec::new()),
+                ("dir/big".to_string(), false, big),
+                ("small".to_string(), false, b"small".to_vec()),
+            ]
+        );
+        std::fs::remove_file(&tarball).unwrap();
+    }
+
+    #[test]
+    fn tar_import_round_trips_an_export() {
+        let mut source = new_fs();
+        let big = pattern(3000);
+        store(&mut source, "dir/sub/big", &big);
+        store(&mut source, "small", b"small");
+        let tarball = temp_path("round-trip.tar");
+        source.export_tar(&tarball).unwrap();
+
+        let mut fs = new_fs();
+        store(&mut fs, "small", b"old");
+        assert!(matches!(
+            fs.import_tar(&tarball, false),
+            Err(FsError::AliasExists(_))
+        ));
+        // Files before the clash stay imported
+        assert_eq!(contents(&mut fs, "dir/sub/big"), big);
+        assert_eq!(contents(&mut fs, "small"), b"old");
+
+        let free_before = fs.free_block_count();
+        assert_eq!(fs.import_tar(&tarball, true).unwrap(), 2);
+        assert_eq!(fs.free_block_count(), free_before);
+        assert_eq!(contents(&mut fs, "dir/sub/big"), big);
+        assert_eq!(contents(&mut fs, "small"), b"small");
+        assert!(fs.get_file_info("dir/sub").unwrap().is_directory);
+        assert!(fs.check_integrity().unwrap().is_empty());
+        std::fs::remove_file(&tarball).unwrap();
+    }
+
+    #[test]
+    fn transfer_moves_a_file_between_images() {
+        let mut source = new_fs();
+        let larger_blocks = InitOptions {
+            block_size: 1024,
+            ..small_options()
+        };
+        let mut target =
+            FileSystemManager::init_in_storage(Box::new(MemStorage::new()), &larger_blocks)
+                .unwrap();
+        let data = pattern(5000);
+        let compressible = vec![b'z'; 5000];
+        store(&mut source, "dir/plain", &data);
+        let compress = UploadOptions {
+            compress: true,
+            ..UploadOptions::default()
+        };
+        source
+            .upload_reader(&mut &compressible[..], "packed", &compress)
+            .unwrap();
+        let free_before = source.free_block_count();
+
+        source.transfer_to(&mut target, "dir/plain", false).unwrap();
+        assert_eq!(contents(&mut source, "dir/plain"), data);
+        assert_eq!(contents(&mut target, "dir/plain"), data);
+        assert!(matches!(
+            source.transfer_to(&mut target, "dir/plain", false),
+            Err(FsError::AliasExists(_))
+        ));
 
-        let metadata = file
-            .metadata()
-            .map_err(|e| format!("Failed to get metadata for {}: {}", FILESYSTEM_FILENAME, e))?;
-        if metadata.len() < FILESYSTEM_SIZE as u64 {
-            file.set_len(FILESYSTEM_SIZE as u64)
-                .map_err(|e| format!("Failed to set length for {}: {}", FILESYSTEM_FILENAME, e))?;
-        }
+        source.transfer_to(&mut target, "packed", true).unwrap();
+        assert!(!source.exists("packed"));
+        assert!(source.free_block_count() > free_before);
+        assert!(target.get_file_info("packed").unwrap().compressed);
+        assert_eq!(contents(&mut target, "packed"), compressible);
+        assert!(target.check_integrity().unwrap().is_empty());
+        assert!(source.check_integrity().unwrap().is_empty());
+    }
 
-        let header_size: usize = std::mem::size_of::<Header>();
-        let num_filenodes: usize = 100; // Max number of files
+    #[test]
+    fn snapshot_restores_deleted_files_unless_their_blocks_changed() {
+        let path = temp_path("snapshot.dat");
+        let mut fs = FileSystemManager::init_filesystem_at(&path, 0, &small_options()).unwrap();
+        let snapshots = fs.snapshots_path().unwrap();
+        let data = pattern(1500);
+        store(&mut fs, "dir/a", &data);
+        fs.snapshot("before").unwrap();
+        let free_at_snapshot = fs.free_block_count();
 
-        // Calculate the actual on-disk size of the serialized Vec<FileNode>
-        // Bincode stores length of vector as prefix (u64), and then the serialised vector.
-        let serialized_filenode_table_bytes: usize =
-            std::mem::size_of::<u64>() + (num_filenodes * std::mem::size_of::<FileNode>());
+        store(&mut fs, "later", b"later");
+        fs.delete_file("dir/a").unwrap();
+        fs.restore_snapshot("before").unwrap();
+        assert_eq!(contents(&mut fs, "dir/a"), data);
+        assert!(!fs.exists("later"));
+        assert_eq!(fs.free_block_count(), free_at_snapshot);
+        assert!(fs.check_integrity().unwrap().is_empty());
 
-        // Calculate tentative offsets to determine the number of data blocks and bitmap size.
-        let tentative_data_blocks_offset_for_calc: usize =
-            header_size + serialized_filenode_table_bytes;
-        let tentative_num_data_blocks_for_calc: usize =
-            (FILESYSTEM_SIZE.saturating_sub(tentative_data_blocks_offset_for_calc)) / BLOCK_SIZE;
-        let bitmap_size_bytes: usize = (tentative_num_data_blocks_for_calc + 7) / 8;
+        // Once the freed blocks are reused, restoring is refused and changes nothing
+        fs.delete_file("dir/a").unwrap();
+        store(&mut fs, "reuse", &pattern(1500 + 7)[7..]);
+        assert!(fs.restore_snapshot("before").is_err());
+        assert!(!fs.exists("dir/a"));
+        assert_eq!(contents(&mut fs, "reuse"), &pattern(1500 + 7)[7..]);
+        assert_eq!(fs.list_snapshots().unwrap().len(), 1);
 
-        // Calculate actual offsets based on the above calculations.
-        let actual_filenode_table_offset: usize = header_size;
-        let actual_free_block_bitmap_offset: usize =
-            actual_filenode_table_offset + serialized_filenode_table_bytes;
-        let actual_data_blocks_offset: usize = actual_free_block_bitmap_offset + bitmap_size_bytes;
+        drop(fs);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&snapshots).unwrap();
+    }
 
-        let actual_num_data_blocks: usize = if FILESYSTEM_SIZE > actual_data_blocks_offset {
-            (FILESYSTEM_SIZE - actual_data_blocks_offset) / BLOCK_SIZE
-        } else {
-            0
-        };
+    /// Returns a filesystem whose free space is a run of 3 blocks at 1, one of 5 at 5
+    /// and the rest from block 11 on.
+    fn fragmented_fs(strategy: AllocStrategy) -> FileSystemManager {
+        let mut fs = new_fs();
+        fs.set_alloc_strategy(strategy);
+        let usable = fs.usable_block_size();
+        for (alias, blocks) in [("a", 1), ("hole3", 3), ("b", 1), ("hole5", 5), ("c", 1)] {
+            store(&mut fs, alias, &pattern(blocks * usable));
+        }
+        fs.delete_file("hole3").unwrap();
+        fs.delete_file("hole5").unwrap();
+        fs
+    }
 
-        if actual_num_data_blocks == 0 && FILESYSTEM_SIZE > BLOCK_SIZE {
-            return Err(
-                "Calculated zero data blocks. Filesystem size or offsets might be misconfigured."
-                    .to_string(),
-            );
+    #[test]
+    fn contiguous_allocation_picks_the_smallest_run_that_fits() {
+        let mut fs = fragmented_fs(AllocStrategy::Contiguous);
+        let usable = fs.usable_block_size();
+        store(&mut fs, "four", &pattern(4 * usable));
+        assert_eq!(fs.block_chain("four").unwrap(), [5, 6, 7, 8]);
+        store(&mut fs, "three", &pattern(3 * usable));
+        assert_eq!(fs.block_chain("three").unwrap(), [1, 2, 3]);
+        // Nothing left is large enough for two blocks but the tail
+        store(&mut fs, "two", &pattern(2 * usable));
+        assert_eq!(fs.block_chain("two").unwrap(), [11, 12]);
+        assert_eq!(contents(&mut fs, "four"), pattern(4 * usable));
+
+        let mut first_fit = fragmented_fs(AllocStrategy::FirstFit);
+        store(&mut first_fit, "four", &pattern(4 * usable));
+        assert_eq!(first_fit.block_chain("four").unwrap(), [1, 2, 3, 5]);
+    }
+
+    #[test]
+    fn known_size_upload_reserves_one_contiguous_run() {
+        let mut fs = fragmented_fs(AllocStrategy::Contiguous);
+        let usable = fs.usable_block_size();
+        let free_before = fs.free_block_count();
+        let data = pattern(10 * usable - 1);
+        store(&mut fs, "ten", &data);
+        assert_eq!(fs.block_chain("ten").unwrap(), (11..21).collect::<Vec<_>>());
+        assert_eq!(fs.free_block_count(), free_before - 10);
+        assert!((11..21).all(|block| !fs.is_block_free(block)));
+        assert!((1..4).chain(5..10).all(|block| fs.is_block_free(block)));
+        assert_eq!(contents(&mut fs, "ten"), data);
+    }
+
+    /// Runs the same uploads, overwrite, truncation and deletion on a new filesystem file
+    /// and returns its bytes, mapping the file into memory first if `mmap` is set.
+    fn image_after_edits(name: &str, mmap: bool) -> Vec<u8> {
+        let path = temp_path(name);
+        let mut fs = FileSystemManager::init_filesystem_at(&path, 0, &small_options()).unwrap();
+        if mmap {
+            fs.use_mmap().unwrap();
         }
+        store(&mut fs, "dir/a", &pattern(3000));
+        store(&mut fs, "b", &pattern(700));
+        let overwrite = UploadOptions {
+            overwrite: true,
+            ..UploadOptions::default()
+        };
+        fs.upload_reader(&mut &pattern(1200)[..], "b", &overwrite)
+            .unwrap();
+        fs.truncate_file("dir/a", 1000).unwrap();
+        fs.delete_file("b").unwrap();
+        assert_eq!(contents(&mut fs, "dir/a"), &pattern(3000)[..1000]);
+        drop(fs);
+        let image = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        image
+    }
 
-        // Creates the header with the calculated offsets and sizes.
-        let header: Header = Header {
-            version: 1,
-            total_size: FILESYSTEM_SIZE,
-            block_size: BLOCK_SIZE,
-            filenode_table_offset: actual_filenode_table_offset,
-            filenode_table_size: num_filenodes,
-            free_block_bitmap_offset: actual_free_block_bitmap_offset,
-            data_blocks_offset: actual_data_blocks_offset,
-            num_data_blocks: actual_num_data_blocks,
+    #[test]
+    fn mapped_and_plain_file_storage_write_the_same_image() {
+        // Timestamps are in whole seconds, so retry if the runs straddle a second boundary
+        let (plain, mapped) = loop {
+            let started = unix_now();
+            let plain = image_after_edits("plain.dat", false);
+            let mapped = image_after_edits("mapped.dat", true);
+            if unix_now() == started {
+                break (plain, mapped);
+            }
         };
+        assert_eq!(plain.len(), mapped.len());
+        assert!(plain == mapped, "images differ");
+    }
 
-        // Write the header to the beginning of the file.
-        file.seek(SeekFrom::Start(0))
-            .map_err(|e| format!("Seek failed (header): {}", e))?;
-        bincode::serialize_into(&mut file, &header)
-            .map_err(|e| format!("Header serialization failed: {}", e))?;
+    #[test]
+    fn move_clobbers_the_destination_and_onto_itself_does_nothing() {
+        let mut fs = new_fs();
+        let src = pattern(1500);
+        store(&mut fs, "src", &src);
+        store(&mut fs, "dst", &pattern(2000));
+        let dst_blocks = fs.block_chain("dst").unwrap();
+        let free_before = fs.free_block_count();
 
-        // Initialise filenodes (all empty/unused)
-        let filenodes: Vec<FileNode> = vec![FileNode::new(); num_filenodes];
-        file.seek(SeekFrom::Start(header.filenode_table_offset as u64))
-            .map_err(|e| format!("Seek failed (filenodes): {}", e))?;
+        assert!(matches!(
+            fs.move_file("src", "dst", false),
+            Err(FsError::AliasExists(_))
+        ));
+        fs.move_file("src", "dst", true).unwrap();
+        assert!(!fs.exists("src"));
+        assert_eq!(contents(&mut fs, "dst"), src);
+        assert_eq!(fs.free_block_count(), free_before + dst_blocks.len());
+        assert!(dst_blocks.iter().all(|&block| fs.is_block_free(block)));
 
-        // Serialise the entire Vec<FileNode>.
-        bincode::serialize_into(&mut file, &filenodes)
-            .map_err(|e| format!("Filenodes serialization failed: {}", e))?;
+        let image = read_image(&mut fs);
+        fs.move_file("dst", "dst", true).unwrap();
+        assert_eq!(read_image(&mut fs), image);
+        assert_eq!(contents(&mut fs, "dst"), src);
+        assert!(fs.check_integrity().unwrap().is_empty());
+    }
 
-        // Write the free block bitmap (initially all blocks are free).
-        let free_block_bitmap: Vec<bool> = vec![true; header.num_data_blocks];
-        let disk_bitmap_bytes: Vec<u8> = vec![0; bitmap_size_bytes];
-        file.seek(SeekFrom::Start(header.free_block_bitmap_offset as u64))
-            .map_err(|e| format!("Seek failed (bitmap): {}", e))?;
-        file.write_all(&disk_bitmap_bytes)
-            .map_err(|e| format!("Bitmap write failed: {}", e))?;
+    #[test]
+    fn dry_run_delete_reports_the_blocks_without_freeing_them() {
+        let mut fs = new_fs();
+        let dedup = UploadOptions {
+            dedup: true,
+            ..UploadOptions::default()
+        };
+        store(&mut fs, "a", &pattern(1500));
+        fs.upload_reader(&mut &pattern(1500)[..], "shared", &dedup)
+            .unwrap();
+        fs.upload_reader(&mut &pattern(1500)[..], "shared2", &dedup)
+            .unwrap();
+        store(&mut fs, "b", &pattern(100));
+        let image = read_image(&mut fs);
+        let bitmap = fs.free_block_bitmap.clone();
 
-        // Flush the file to ensure all data is written.
-        file.flush()
-            .map_err(|e| format!("Failed to flush after init: {}", e))?;
+        let plan = fs.plan_delete("b").unwrap();
+        assert_eq!(plan.blocks_freed, fs.block_chain("b").unwrap());
+        assert!(plan.shared_with.is_empty());
+        let plan = fs.plan_delete("shared").unwrap();
+        assert!(plan.blocks_freed.is_empty());
+        assert_eq!(plan.shared_with, ["a", "shared2"]);
+        assert!(matches!(
+            fs.plan_delete("missing"),
+            Err(FsError::AliasNotFound(_))
+        ));
 
-        Ok(FileSystemManager {
-            file,
-            header,
-            filenodes,
-            free_block_bitmap,
-        })
+        assert_eq!(fs.get_file_info("shared").unwrap().refcount, 3);
+        assert_eq!(fs.free_block_bitmap, bitmap);
+        assert_eq!(read_image(&mut fs), image);
     }
 
-    fn find_free_filenode_index(&self) -> Option<usize> {
-        self.filenodes.iter().position(|node| !node.is_used)
-    }
+    #[test]
+    fn partial_bitmap_write_leaves_other_bytes_alone() {
+        let mut fs = new_fs();
+        let bitmap_offset = fs.header.free_block_bitmap_offset;
+        let bitmap_len = fs.header.num_data_blocks.div_ceil(8);
+        let before = read_image(&mut fs)[bitmap_offset..bitmap_offset + bitmap_len].to_vec();
 
-    fn find_free_blocks(&self, num_blocks_needed: usize) -> Option<Vec<usize>> {
-        let mut free_blocks_indices = Vec::new();
-        for (index, is_free) in self.free_block_bitmap.iter().enumerate() {
-            if *is_free {
-                free_blocks_indices.push(index);
-                if free_blocks_indices.len() == num_blocks_needed {
-                    return Some(free_blocks_indices);
-                }
-            }
-        }
-        None
+        fs.set_block_free(3, false);
+        fs.set_block_free(20, false);
+        fs.set_block_free(21, false);
+        fs.write_bitmap_range(20..22).unwrap();
+        let after = read_image(&mut fs)[bitmap_offset..bitmap_offset + bitmap_len].to_vec();
+        assert_eq!(after[2], before[2] | 0b0011_0000);
+        // Block 3 is only marked used in memory
+        assert_eq!(after[..2], before[..2]);
+        assert_eq!(after[3..], before[3..]);
     }
 
-    /// Writes the entire filenode table to disk.
-    fn save_filenodes(&mut self) -> Result<(), String> {
+    #[test]
+    fn note_survives_a_reload_and_can_be_cleared() {
+        let mut fs = new_fs();
+        let with_note = UploadOptions {
+            description: Some("first draft".to_string()),
+            ..UploadOptions::default()
+        };
+        fs.upload_reader(&mut &b"text"[..], "a", &with_note)
+            .unwrap();
+        let info = fs.get_file_info("a").unwrap();
+        assert_eq!(info.description.as_deref(), Some("first draft"));
+        fs.set_description("a", Some("final — reviewed")).unwrap();
+        assert!(matches!(
+            fs.set_description("a", Some("two\nlines")),
+            Err(FsError::InvalidArgument(_))
+        ));
 
-        // Seek to the beginning of the filenode table.
-        self.file
-            .seek(SeekFrom::Start(self.header.filenode_table_offset as u64))
-            .map_err(|e| format!("Seek failed (write_all_filenodes): {}", e))?;
+        let image = MemStorage::from_vec(read_image(&mut fs));
+        let mut reopened = FileSystemManager::open_storage(Box::new(image)).unwrap();
+        let info = reopened.get_file_info("a").unwrap();
+        assert_eq!(info.description.as_deref(), Some("final — reviewed"));
 
-        // Serialise the entire Vec<FileNode> to the file.
-        bincode::serialize_into(&mut self.file, &self.filenodes)
-            .map_err(|e| format!("Serialize failed (write_all_filenodes): {}", e))?;
+        reopened.set_description("a", None).unwrap();
+        assert_eq!(reopened.get_file_info("a").unwrap().description, None);
+        let image = MemStorage::from_vec(read_image(&mut reopened));
+        let mut reopened = FileSystemManager::open_storage(Box::new(image)).unwrap();
+        assert_eq!(reopened.get_file_info("a").unwrap().description, None);
+    }
 
-        // Flush the file to ensure all data is written.
-        self.file
-            .flush()
-            .map_err(|e| format!("Flush failed (write_all_filenodes): {}", e))
+    #[test]
+    fn content_type_survives_a_reload() {
+        let mut fs = new_fs();
+        let typed = UploadOptions {
+            content_type: Some("application/x-custom".to_string()),
+            ..UploadOptions::default()
+        };
+        fs.upload_reader(&mut &b"{}"[..], "data.bin", &typed)
+            .unwrap();
+        fs.upload_reader(&mut &b"# Title"[..], "notes.md", &UploadOptions::default())
+            .unwrap();
+        let invalid = UploadOptions {
+            content_type: Some("not a type".to_string()),
+            ..UploadOptions::default()
+        };
+        assert!(matches!(
+            fs.upload_reader(&mut &b"x"[..], "bad", &invalid),
+            Err(FsError::InvalidArgument(_))
+        ));
+
+        let image = MemStorage::from_vec(read_image(&mut fs));
+        let mut reopened = FileSystemManager::open_storage(Box::new(image)).unwrap();
+        let content_type =
+            |fs: &mut FileSystemManager, alias: &str| fs.get_file_info(alias).unwrap().content_type;
+        assert_eq!(
+            content_type(&mut reopened, "data.bin").as_deref(),
+            Some("application/x-custom")
+        );
+        assert_eq!(
+            content_type(&mut reopened, "notes.md").as_deref(),
+            Some("text/markdown")
+        );
+        assert!(!reopened.exists("bad"));
     }
 
-    /// Writes the free block bitmap to disk.
-    fn write_bitmap_to_disk(&mut self) -> Result<(), String> {
-        // Calculate the size of the bitmap in bytes.
-        let bitmap_size_bytes: usize = (self.header.num_data_blocks + 7) / 8;
+    #[test]
+    fn alias_space_waste_counts_every_unused_reserved_byte() {
+        let mut fs = new_fs();
+        let reserved = 16 * fs.header.max_alias_len;
+        let empty = fs.alias_space_stats();
+        assert_eq!(empty.average_alias_len, 0.0);
+        assert_eq!(empty.wasted_bytes, reserved);
 
-        // Create a byte array to represent the bitmap.
-        let mut disk_bitmap_bytes: Vec<u8> = vec![0; bitmap_size_bytes];
+        store(&mut fs, "ab", b"1");
+        store(&mut fs, "dir/wxyz", b"2");
+        // "dir" is stored too: 2 + 8 + 3 bytes over three entries
+        let stats = fs.alias_space_stats();
+        assert_eq!(stats.wasted_bytes, reserved - 13);
+        assert!((stats.average_alias_len - 13.0 / 3.0).abs() < 1e-9);
 
-        // Set the bits in the byte array based on the free block bitmap.
-        for i in 0..self.header.num_data_blocks {
-            if !self.free_block_bitmap[i] {
-                disk_bitmap_bytes[i / 8] |= 1 << (i % 8);
-            }
-        }
+        fs.delete_file("ab").unwrap();
+        assert_eq!(fs.alias_space_stats().wasted_bytes, reserved - 11);
+        let report = fs.doctor(false).unwrap();
+        assert!(report
+            .suggestions
+            .iter()
+            .any(|suggestion| suggestion.contains("--max-alias-len")));
+    }
 
-        // Seek to the offset for the free block bitmap in the file.
-        self.file
-            .seek(SeekFrom::Start(self.header.free_block_bitmap_offset as u64))
-            .map_err(|e| format!("Seek failed (write_bitmap): {}", e))?;
+    #[test]
+    fn bitmap_words_split_at_block_64() {
+        assert!(all_free_bitmap(0).is_empty());
+        assert_eq!(all_free_bitmap(63), [u64::MAX >> 1]);
+        assert_eq!(all_free_bitmap(64), [u64::MAX]);
+        assert_eq!(all_free_bitmap(65), [u64::MAX, 1]);
 
-        // Write the bitmap to the file.
-        self.file
-            .write_all(&disk_bitmap_bytes)
-            .map_err(|e| format!("Write failed (write_bitmap): {}", e))?;
+        let mut fs = new_fs();
+        assert!(fs.header.num_data_blocks > 66);
+        let usable = fs.usable_block_size();
+        store(&mut fs, "first", &pattern(63 * usable));
+        store(&mut fs, "across", &pattern(2 * usable));
+        assert_eq!(fs.block_chain("across").unwrap(), [63, 64]);
+        assert_eq!(fs.free_block_bitmap[0], 0);
+        assert_eq!(fs.free_block_bitmap[1] & 1, 0);
+        assert!(fs.is_block_free(65));
 
-        // Flush the file to ensure all data is written.
-        self.file
-            .flush()
-            .map_err(|e| format!("Flush failed (write_bitmap): {}", e))
+        let image = MemStorage::from_vec(read_image(&mut fs));
+        let mut reopened = FileSystemManager::open_storage(Box::new(image)).unwrap();
+        assert_eq!(reopened.free_block_bitmap, fs.free_block_bitmap);
+        reopened.delete_file("across").unwrap();
+        assert!(reopened.is_block_free(63) && reopened.is_block_free(64));
+        assert_eq!(reopened.free_block_bitmap[0], 1 << 63);
+        assert_eq!(contents(&mut reopened, "first"), pattern(63 * usable));
     }
 
-    /// Uploads a file from the local filesystem to the virtual filesystem.
-    pub fn upload_file(&mut self, local_path_str: &str, alias: &str) -> Result<(), String> {
-        // Check if the alias is valid
-        if alias.is_empty() || alias.len() > MAX_FILENAME_LENGTH {
-            return Err(format!(
-                "Alias length must be 1-{} chars.",
-                MAX_FILENAME_LENGTH
-            ));
+    #[test]
+    fn single_filenode_write_leaves_the_other_slots_alone() {
+        let mut fs = new_fs();
+        for alias in ["a", "b", "c"] {
+            store(&mut fs, alias, alias.as_bytes());
         }
+        let index = fs.find_filenode_index("b").unwrap();
+        let slot_len = fs.filenode_slot(index).unwrap().len();
+        let slot_start =
+            fs.header.filenode_table_offset + std::mem::size_of::<u64>() + index * slot_len;
+        let table = fs.header.filenode_table_offset..fs.header.free_block_bitmap_offset;
+        let before = read_image(&mut fs);
 
-        // Check if the alias already exists
-        for node in self.filenodes.iter().filter(|n| n.is_used) {
-            if node.get_alias_str().map_or(false, |a| a == alias) {
-                return Err(format!("File with alias '{}' already exists.", alias));
-            }
-        }
+        fs.filenodes[index].description = b"changed".to_vec();
+        fs.save_single_filenode(index).unwrap();
+        let after = read_image(&mut fs);
+        assert_ne!(
+            after[slot_start..slot_start + slot_len],
+            before[slot_start..slot_start + slot_len]
+        );
+        assert_eq!(
+            after[table.start..slot_start],
+            before[table.start..slot_start]
+        );
+        assert_eq!(
+            after[slot_start + slot_len..table.end],
+            before[slot_start + slot_len..table.end]
+        );
+        // The data region is untouched and the table still loads
+        assert_eq!(after[table.end..], before[table.end..]);
+        let mut reopened =
+            FileSystemManager::open_storage(Box::new(MemStorage::from_vec(after))).unwrap();
+        let info = reopened.get_file_info("b").unwrap();
+        assert_eq!(info.description.as_deref(), Some("changed"));
+        assert_eq!(contents(&mut reopened, "c"), b"c");
+    }
 
-        // Check if the local file exists and is a file
-        let local_path = Path::new(local_path_str);
-        if !local_path.exists() {
-            return Err(format!("Local file '{}' does not exist.", local_path_str));
-        }
-        if !local_path.is_file() {
-            return Err(format!("'{}' is not a file.", local_path_str));
-        }
+    #[test]
+    fn shredded_blocks_are_zeroed_and_plain_deletes_are_not() {
+        let mut fs = new_fs();
+        let block_size = fs.header.block_size;
+        let block_bytes = |fs: &mut FileSystemManager, block: usize| {
+            let start = fs.header.data_blocks_offset + block * block_size;
+            read_image(fs)[start..start + block_size].to_vec()
+        };
+        store(&mut fs, "secret", &pattern(1500));
+        store(&mut fs, "kept", &pattern(1500));
+        let secret_blocks = fs.block_chain("secret").unwrap();
+        let kept_blocks = fs.block_chain("kept").unwrap();
 
-        // Check if the local file is empty
-        let file_size: usize = local_path
-            .metadata()
-            .map_err(|e| format!("Metadata failed for '{}': {}", local_path_str, e))?
-            .len() as usize;
-        if file_size == 0 {
-            return Err("Cannot upload empty file.".to_string());
+        fs.shred_file("secret").unwrap();
+        for &block in &secret_blocks {
+            assert!(fs.is_block_free(block));
+            assert!(block_bytes(&mut fs, block).iter().all(|&byte| byte == 0));
         }
+        fs.delete_file("kept").unwrap();
+        assert!(block_bytes(&mut fs, kept_blocks[0])
+            .iter()
+            .any(|&byte| byte != 0));
+        assert!(fs.check_integrity().unwrap().is_empty());
+    }
 
-        // Check if there is enough space in the filesystem
-        let free_blocks_count: usize = self.free_block_bitmap.iter().filter(|&free| *free).count();
-        if file_size > free_blocks_count * USABLE_BLOCK_SIZE {
-            return Err(format!(
-                "Not enough total space. File size: {}, Available space: approx {} bytes.",
-                file_size,
-                free_blocks_count * USABLE_BLOCK_SIZE
-            ));
+    #[test]
+    fn nested_paths_create_and_list_their_directories() {
+        let mut fs = new_fs();
+        store(&mut fs, "a/b/c/deep", b"deep");
+        store(&mut fs, "a/top", b"top");
+        for dir in ["a", "a/b", "a/b/c"] {
+            assert!(fs.get_file_info(dir).unwrap().is_directory, "{}", dir);
         }
+        assert_eq!(fs.list_files("").unwrap(), ["a/"]);
+        assert_eq!(fs.list_files("a").unwrap(), ["a/b/", "a/top (3 bytes)"]);
+        assert_eq!(fs.list_files("a/b/c").unwrap(), ["a/b/c/deep (4 bytes)"]);
+        assert!(matches!(
+            fs.list_files("a/top"),
+            Err(FsError::InvalidArgument(_))
+        ));
 
-        // Find a free filenode and free blocks
-        let filenode_index = self
-            .find_free_filenode_index()
-            .ok_or("No free filenodes available.".to_string())?;
-        let num_blocks_needed = (file_size + USABLE_BLOCK_SIZE - 1) / USABLE_BLOCK_SIZE;
-        if num_blocks_needed == 0 && file_size > 0 {
-            return Err(
-                "Calculated zero blocks for a non-empty file (internal error).".to_string(),
-            );
+        // A file cannot hold entries, and directories must be emptied before deletion
+        assert!(matches!(
+            fs.write_file("a/top/x", &mut &b"x"[..], 1),
+            Err(FsError::InvalidAlias(_))
+        ));
+        assert!(matches!(
+            fs.delete_file("a/b"),
+            Err(FsError::InvalidArgument(_))
+        ));
+        fs.delete_file("a/b/c/deep").unwrap();
+        fs.delete_file("a/b/c").unwrap();
+        assert_eq!(fs.list_files("a/b").unwrap(), Vec::<String>::new());
+        assert_eq!(contents(&mut fs, "a/top"), b"top");
+    }
+
+    #[test]
+    fn progress_is_reported_once_per_block() {
+        let mut fs = new_fs();
+        let data = pattern(5 * fs.usable_block_size() + 17);
+        let local = temp_path("progress-upload");
+        let downloaded = temp_path("progress-download");
+        std::fs::write(&local, &data).unwrap();
+
+        let mut uploads = Vec::new();
+        fs.upload_file_with_progress(
+            local.to_str().unwrap(),
+            "a",
+            &UploadOptions::default(),
+            |done, total| uploads.push((done, total)),
+        )
+        .unwrap();
+        let mut downloads = Vec::new();
+        fs.download_file_with_progress("a", downloaded.to_str().unwrap(), |done, total| {
+            downloads.push((done, total))
+        })
+        .unwrap();
+
+        for calls in [&uploads, &downloads] {
+            assert_eq!(calls.len(), 6, "{:?}", calls);
+            assert!(calls.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            assert!(calls.iter().all(|&(_, total)| total == data.len()));
+            assert_eq!(calls.last().unwrap().0, data.len());
         }
-        if num_blocks_needed > free_blocks_count {
-            return Err(format!(
-                "Not enough free blocks. Needed: {}, Available: {}.",
-                num_blocks_needed, free_blocks_count
+        std::fs::remove_file(&local).unwrap();
+        std::fs::remove_file(&downloaded).unwrap();
+    }
+
+    #[test]
+    fn multibyte_aliases_are_kept_whole_up_to_the_byte_limit() {
+        let mut fs = new_fs();
+        assert_eq!(fs.header.max_alias_len, 255);
+        let accented = "é".repeat(127) + "a";
+        let emoji = "🦀".repeat(63) + "abc";
+        assert_eq!((accented.len(), emoji.len()), (255, 255));
+        store(&mut fs, &accented, b"accented");
+        store(&mut fs, &emoji, b"emoji");
+        // One more character is refused rather than cut in the middle of a character
+        for too_long in ["é".repeat(128), "🦀".repeat(64)] {
+            assert!(matches!(
+                fs.write_file(&too_long, &mut &b"x"[..], 1),
+                Err(FsError::InvalidAlias(_))
             ));
         }
 
-        // Find free blocks
-        let block_indices = self.find_free_blocks(num_blocks_needed).ok_or(format!(
-            "Could not find {} free blocks.",
-            num_blocks_needed
-        ))?;
-
-        // Mark the blocks as used
-        let mut local_file = File::open(local_path)
-            .map_err(|e| format!("Failed to open local file '{}': {}", local_path_str, e))?;
-        let mut read_buffer = vec![0u8; USABLE_BLOCK_SIZE];
-        let mut bytes_remaining_to_write = file_size;
+        let image = MemStorage::from_vec(read_image(&mut fs));
+        let mut reopened = FileSystemManager::open_storage(Box::new(image)).unwrap();
+        let mut aliases: Vec<String> = reopened.files().map(|info| info.alias).collect();
+        aliases.sort();
+        let mut expected = vec![accented.clone(), emoji.clone()];
+        expected.sort();
+        assert_eq!(aliases, expected);
+        assert_eq!(contents(&mut reopened, &accented), b"accented");
+        assert_eq!(contents(&mut reopened, &emoji), b"emoji");
+    }
 
-        // Read from the local file and write to the filesystem
-        for i in 0..num_blocks_needed {
+    #[test]
+    fn header_of_any_encoded_size_round_trips() {
+        let mut fs = new_fs();
+        store(&mut fs, "a", &pattern(100));
+        let size = |header: &Header| bincode::serialized_size(header).unwrap() as usize;
+        assert!(size(&fs.header) < Header::max_serialized_size());
 
-            // Read data for the current block
-            let current_fs_block_index = block_indices[i];
-            let bytes_to_read_this_iteration =
-                std::cmp::min(bytes_remaining_to_write, USABLE_BLOCK_SIZE);
-            let mut block_data_buffer = vec![0u8; BLOCK_SIZE];
-            local_file
-                .read_exact(&mut read_buffer[0..bytes_to_read_this_iteration])
-                .map_err(|e| format!("Read failed from local file: {}", e))?;
-            block_data_buffer[0..bytes_to_read_this_iteration]
-                .copy_from_slice(&read_buffer[0..bytes_to_read_this_iteration]);
+        // A cached Merkle root makes the header as large as it gets
+        let root = fs.merkle_root().unwrap();
+        assert_eq!(size(&fs.header), Header::max_serialized_size());
+        assert!(Header::max_serialized_size() <= fs.header.journal_offset);
 
-            // If this is not the last block, set the next block pointer to the next block index
-            if i < num_blocks_needed - 1 {
-                let next_fs_block_index = block_indices[i + 1];
-                block_data_buffer[USABLE_BLOCK_SIZE..BLOCK_SIZE]
-                    .copy_from_slice(&next_fs_block_index.to_le_bytes());
-            } else {
-                block_data_buffer[USABLE_BLOCK_SIZE..BLOCK_SIZE]
-                    .copy_from_slice(&usize::MAX.to_le_bytes());
-            }
+        let image = read_image(&mut fs);
+        let decoded: Header = bincode::deserialize(&image[..fs.header.journal_offset]).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", fs.header));
+        let mut reopened =
+            FileSystemManager::open_storage(Box::new(MemStorage::from_vec(image))).unwrap();
+        assert_eq!(format!("{:?}", reopened.header), format!("{:?}", fs.header));
+        assert_eq!(reopened.header.merkle_root, Some(root));
+        assert_eq!(contents(&mut reopened, "a"), pattern(100));
+    }
 
-            // Write the block data to the filesystem
-            let disk_offset = self.header.data_blocks_offset + current_fs_block_index * BLOCK_SIZE;
-            self.file
-                .seek(SeekFrom::Start(disk_offset as u64))
-                .map_err(|e| {
-                    format!("Seek failed (data block {}): {}", current_fs_block_index, e)
-                })?;
-            self.file.write_all(&block_data_buffer).map_err(|e| {
-                format!(
-                    "Write failed (data block {}): {}",
-                    current_fs_block_index, e
-                )
-            })?;
+    #[test]
+    fn block_chain_follows_the_blocks_allocated() {
+        let mut fs = fragmented_fs(AllocStrategy::FirstFit);
+        let usable = fs.usable_block_size();
+        let free_before = fs.free_block_count();
+        store(&mut fs, "split", &pattern(3 * usable + 1));
+        let chain = fs.block_chain("split").unwrap();
+        assert_eq!(chain, [1, 2, 3, 5]);
+        assert_eq!(fs.get_file_info("split").unwrap().num_blocks, chain.len());
+        assert_eq!(fs.free_block_count(), free_before - chain.len());
 
-            // Mark the block as used in the bitmap
-            self.free_block_bitmap[current_fs_block_index] = false;
-            bytes_remaining_to_write -= bytes_to_read_this_iteration;
+        // The next pointers on disk link the blocks in that order
+        let image = read_image(&mut fs);
+        let next_pointer = |block: usize| {
+            let offset = fs.header.data_blocks_offset + block * fs.header.block_size + usable;
+            usize::from_le_bytes(image[offset..offset + 8].try_into().unwrap())
+        };
+        for pair in chain.windows(2) {
+            assert_eq!(next_pointer(pair[0]), pair[1]);
         }
+        assert_eq!(next_pointer(5), usize::MAX);
 
-        if bytes_remaining_to_write != 0 {
-            return Err(format!(
-                "Write error: {} bytes remaining unexpectedly.",
-                bytes_remaining_to_write
-            ));
-        }
+        store(&mut fs, "empty", b"");
+        assert!(fs.block_chain("empty").unwrap().is_empty());
+        assert!(matches!(
+            fs.block_chain("missing"),
+            Err(FsError::AliasNotFound(_))
+        ));
+    }
 
-        // Update the filenode with the alias and size
-        let filenode = &mut self.filenodes[filenode_index];
-        filenode.alias_len = alias.len() as u8;
-        filenode.alias[0..alias.len()].copy_from_slice(alias.as_bytes());
-        filenode.size = file_size;
-        filenode.first_block_index = Some(block_indices[0]);
-        filenode.is_used = true;
+    #[test]
+    fn configured_alias_limit_is_kept_and_enforced() {
+        let short = InitOptions {
+            max_alias_len: 8,
+            ..small_options()
+        };
+        let mut fs =
+            FileSystemManager::init_in_storage(Box::new(MemStorage::new()), &short).unwrap();
+        store(&mut fs, "x", b"1");
+        store(&mut fs, "12345678", b"8");
+        store(&mut fs, "dir/é12", b"7");
+        let rejected = |fs: &mut FileSystemManager, alias: &str| {
+            matches!(
+                fs.write_file(alias, &mut &b"x"[..], 1),
+                Err(FsError::InvalidAlias(_))
+            )
+        };
+        assert!(rejected(&mut fs, "123456789"));
+        assert!(rejected(&mut fs, "dir/é123"));
 
-        // Save the filenode and bitmap to disk and flush the file
-        self.save_filenodes()?;
-        self.write_bitmap_to_disk()?;
-        self.file
-            .flush()
-            .map_err(|e| format!("Final flush failed (upload): {}", e))?;
-        Ok(())
-    }
+        // Smaller slots leave more room for data
+        let default_slots = FileSystemManager::compute_header(&small_options()).unwrap();
+        let table_len =
+            |header: &Header| header.free_block_bitmap_offset - header.filenode_table_offset;
+        assert!(table_len(&fs.header) < table_len(&default_slots));
+        assert!(fs.header.num_data_blocks >= default_slots.num_data_blocks);
 
-    /// Downloads a file from the virtual filesystem to the local filesystem.
-    pub fn download_file(&mut self, alias: &str, local_path_str: &str) -> Result<(), String> {
-        // Find the filenode by alias (immutable borrow first)
-        let filenode_to_download = self
-            .filenodes
-            .iter()
-            .find(|node| node.is_used && node.get_alias_str().map_or(false, |a| a == alias))
-            .cloned(); // Clone the found filenode to avoid borrowing issues with self.file
+        let image = MemStorage::from_vec(read_image(&mut fs));
+        let mut reopened = FileSystemManager::open_storage(Box::new(image)).unwrap();
+        assert_eq!(reopened.header.max_alias_len, 8);
+        assert_eq!(contents(&mut reopened, "12345678"), b"8");
+        assert!(rejected(&mut reopened, "123456789"));
+        assert!(matches!(
+            FileSystemManager::compute_header(&InitOptions {
+                max_alias_len: 0,
+                ..small_options()
+            }),
+            Err(FsError::InvalidArgument(_))
+        ));
+    }
 
-        // Check if the filenode exists
-        let filenode =
-            filenode_to_download.ok_or(format!("File with alias '{}' not found.", alias))?;
+    #[test]
+    fn volume_without_room_for_a_data_block_is_refused() {
+        let with_size = |total_size| InitOptions {
+            total_size,
+            ..small_options()
+        };
+        // The header and filenode table alone leave nothing for the journal or data
+        let table_end = Header::max_serialized_size()
+            + std::mem::size_of::<u64>()
+            + 16 * FileNode::slot_size(small_options().max_alias_len);
+        for total_size in [0, 512, table_end] {
+            assert!(
+                matches!(
+                    FileSystemManager::compute_header(&with_size(total_size)),
+                    Err(FsError::InvalidArgument(_))
+                ),
+                "{} bytes",
+                total_size
+            );
+        }
+        let path = temp_path("no-data-blocks.dat");
+        assert!(FileSystemManager::init_filesystem_at(&path, 0, &with_size(table_end)).is_err());
+        assert!(!path.exists());
 
-        // Check if the local path is valid
-        let mut local_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(local_path_str)
-            .map_err(|e| {
-                format!(
-                    "Failed to open/create local file '{}': {}",
-                    local_path_str, e
-                )
-            })?;
-        
-        // Calculate the number of bytes to download and the starting block index
-        let mut bytes_to_download = filenode.size;
-        let mut current_block_opt = filenode.first_block_index;
-        let mut block_data_buffer = vec![0u8; BLOCK_SIZE];
+        // The smallest volume that works has exactly one data block
+        let smallest = (table_end..)
+            .find(|&size| FileSystemManager::compute_header(&with_size(size)).is_ok())
+            .unwrap();
+        let header = FileSystemManager::compute_header(&with_size(smallest)).unwrap();
+        assert_eq!(header.num_data_blocks, 1);
+        let mut fs =
+            FileSystemManager::init_in_storage(Box::new(MemStorage::new()), &with_size(smallest))
+                .unwrap();
+        let one_block = pattern(fs.usable_block_size());
+        store(&mut fs, "one", &one_block);
+        assert_eq!(fs.free_block_count(), 0);
 
-        // Read the blocks from the filesystem and write to the local file
-        while let Some(current_block_index) = current_block_opt {
-            
-            // Check if there are no more bytes to download
-            if bytes_to_download == 0 {
-                break;
-            }
+        // An image claiming no data blocks is refused on open instead of read
+        fs.header.num_data_blocks = 0;
+        fs.save_header().unwrap();
+        let image = MemStorage::from_vec(read_image(&mut fs));
+        assert!(FileSystemManager::open_storage(Box::new(image)).is_err());
+    }
 
-            // Check if the block index is valid
-            if current_block_index >= self.header.num_data_blocks {
-                return Err(format!(
-                    "Invalid block index {} for file '{}'. Corrupt.",
-                    current_block_index, alias
-                ));
-            }
+    #[test]
+    fn disk_usage_counts_a_block_for_one_byte_over_the_boundary() {
+        let mut fs = new_fs();
+        let usable = fs.usable_block_size();
+        store(&mut fs, "exact", &pattern(2 * usable));
+        store(&mut fs, "over", &pattern(2 * usable + 1));
+        store(&mut fs, "empty", b"");
+        store(&mut fs, "dir/tiny", b"t");
 
-            // Read the block data from the filesystem
-            let disk_offset = self.header.data_blocks_offset + current_block_index * BLOCK_SIZE;
-            self.file
-                .seek(SeekFrom::Start(disk_offset as u64))
-                .map_err(|e| {
-                    format!(
-                        "Seek failed (download block {}): {}",
-                        current_block_index, e
-                    )
-                })?;
-            self.file.read_exact(&mut block_data_buffer).map_err(|e| {
-                format!(
-                    "Read failed (download block {}): {}",
-                    current_block_index, e
+        let usage = fs.disk_usage();
+        let rows: Vec<(&str, usize, usize, usize)> = usage
+            .iter()
+            .map(|row| {
+                (
+                    row.alias.as_str(),
+                    row.num_blocks,
+                    row.disk_bytes,
+                    row.slack,
                 )
-            })?;
-
-            // Write the block data to the local file
-            let bytes_in_this_block = std::cmp::min(bytes_to_download, USABLE_BLOCK_SIZE);
-            local_file
-                .write_all(&block_data_buffer[0..bytes_in_this_block])
-                .map_err(|e| format!("Write failed to local file '{}': {}", local_path_str, e))?;
-            bytes_to_download -= bytes_in_this_block;
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                ("exact", 2, 1024, 16),
+                ("over", 3, 1536, 1536 - (2 * usable + 1)),
+                ("empty", 0, 0, 0),
+                ("dir/tiny", 1, 512, 511),
+            ]
+        );
+        for row in &usage {
+            assert_eq!(row.disk_bytes, row.size + row.slack);
+            assert_eq!(row.num_blocks, fs.block_chain(&row.alias).unwrap().len());
+        }
+    }
 
-            if bytes_to_download == 0 {
-                break;
-            }
+    #[test]
+    fn transfers_return_the_bytes_of_the_contents() {
+        let mut fs = new_fs();
+        let local = temp_path("counts-upload");
+        let downloaded = temp_path("counts-download");
+        let data = vec![b'a'; 4000];
+        std::fs::write(&local, &data).unwrap();
+        let compress = UploadOptions {
+            compress: true,
+            ..UploadOptions::default()
+        };
 
-            // Get the next block index from the block data
-            let mut next_block_ptr_bytes = [0u8; NEXT_BLOCK_POINTER_SIZE];
-            next_block_ptr_bytes.copy_from_slice(&block_data_buffer[USABLE_BLOCK_SIZE..BLOCK_SIZE]);
-            let next_block_index = usize::from_le_bytes(next_block_ptr_bytes);
-            current_block_opt = if next_block_index == usize::MAX {
-                None
-            } else {
-                Some(next_block_index)
-            };
+        // Compressed files still count the bytes of their contents
+        for (alias, options) in [("plain", UploadOptions::default()), ("packed", compress)] {
+            let uploaded = fs.upload_file(local.to_str().unwrap(), alias, &options);
+            assert_eq!(uploaded.unwrap(), 4000);
+            let written = fs.download_file(alias, downloaded.to_str().unwrap());
+            assert_eq!(written.unwrap(), 4000);
+            assert_eq!(fs.read_file(alias, &mut io::sink()).unwrap(), 4000);
         }
+        let read = fs.upload_reader(&mut &data[..10], "short", &UploadOptions::default());
+        assert_eq!(read.unwrap(), 10);
+        std::fs::write(&local, b"").unwrap();
+        let empty = fs.upload_file(local.to_str().unwrap(), "empty", &UploadOptions::default());
+        assert_eq!(empty.unwrap(), 0);
+        assert_eq!(
+            fs.download_file("empty", downloaded.to_str().unwrap())
+                .unwrap(),
+            0
+        );
+        std::fs::remove_file(&local).unwrap();
+        std::fs::remove_file(&downloaded).unwrap();
+    }
 
-        // Check if the download was incomplete
-        if bytes_to_download != 0 {
-            return Err(format!(
-                "File download incomplete for '{}'. {} bytes remaining. Corrupt.",
-                alias, bytes_to_download
-            ));
-        }
+    #[test]
+    fn symlink_resolves_to_its_target() {
+        let mut fs = new_fs();
+        store(&mut fs, "target", b"needle in a haystack");
+        fs.link("target", "link").unwrap();
 
-        // Flush the local file to ensure all data is written
-        local_file
-            .flush()
-            .map_err(|e| format!("Flush failed for local file '{}': {}", local_path_str, e))?;
-        Ok(())
+        assert_eq!(contents(&mut fs, "link"), b"needle in a haystack");
+        assert_eq!(fs.link_target("link").unwrap(), "target");
+        let info = fs.get_file_info("link").unwrap();
+        assert_eq!(info.size, 20);
+        assert!(info.is_symlink);
+        // Links to links are refused, so chains cannot loop
+        assert!(fs.link("link", "link2").is_err());
+        // Search reads the target once, not again through the link
+        assert_eq!(
+            fs.grep(b"needle", false).unwrap(),
+            vec![("target".to_string(), 1)]
+        );
     }
 
-    /// Lists all files in the filesystem.
-    pub fn list_files(&self) -> Result<Vec<String>, String> {
-        let mut active_files = Vec::new();
-        for filenode in &self.filenodes {
-            // Check if the filenode is used
-            if filenode.is_used {
-                match filenode.get_alias_str() {
-                    Ok(alias_str) => {
-                        // Add the alias and size to the list of active files
-                        active_files.push(format!("{} ({} bytes)", alias_str, filenode.size))
-                    }
-                    Err(_) => active_files.push(format!(
-                        "[Error reading alias for filenode, size: {}]",
-                        filenode.size
-                    )),
-                }
-            }
+    #[test]
+    fn symlink_to_a_deleted_target_is_dangling() {
+        let mut fs = new_fs();
+        store(&mut fs, "target", b"contents");
+        fs.link("target", "link").unwrap();
+        fs.delete_file("target").unwrap();
+
+        let mut out = Vec::new();
+        assert!(matches!(
+            fs.read_file("link", &mut out),
+            Err(FsError::InvalidArgument(_))
+        ));
+        let issues = fs.check_integrity().unwrap();
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].contains("dangling"), "{}", issues[0]);
+    }
+
+    #[test]
+    fn corrupted_metadata_is_refused_on_open() {
+        let mut fs = new_fs();
+        store(&mut fs, "a", &pattern(1000));
+        let image = read_image(&mut fs);
+        // A byte inside the first filenode slot, and the first byte of the bitmap
+        let slot_byte = fs.header.filenode_table_offset + 8 + 20;
+        let bitmap_byte = fs.header.free_block_bitmap_offset;
+        for offset in [slot_byte, bitmap_byte] {
+            let mut damaged = image.clone();
+            damaged[offset] ^= 0x40;
+            let opened = FileSystemManager::open_storage(Box::new(MemStorage::from_vec(damaged)));
+            assert!(
+                matches!(opened, Err(FsError::Corrupt(_))),
+                "byte {} not detected",
+                offset
+            );
         }
-        Ok(active_files)
+        assert!(FileSystemManager::open_storage(Box::new(MemStorage::from_vec(image))).is_ok());
     }
 
-    /// Deletes a file from the filesystem.
-    pub fn delete_file(&mut self, alias: &str) -> Result<(), String> {
-        // Check if the alias is valid
-        let filenode_index_opt = self
-            .filenodes
-            .iter()
-            .position(|node| node.is_used && node.get_alias_str().map_or(false, |a| a == alias));
-        let filenode_index = filenode_index_opt
-            .ok_or(format!("File with alias '{}' not found to delete.", alias))?;
+    /// Rewrites the filenode table of `fs` in the smaller slots of layout `version`, as
+    /// an older build would have written it, and returns the resulting image.
+    fn downgrade_image(fs: &mut FileSystemManager, version: u32) -> Vec<u8> {
+        let slot_size = FileNode::slot_size_for_version(version, fs.header.max_alias_len);
+        // Version 18 only enlarged the journal, so slots last changed in version 17
+        let missing_fields = 17u32.saturating_sub(version) as usize;
+        let mut table = (fs.filenodes.len() as u64).to_le_bytes().to_vec();
+        let mut slot_checksums = Vec::new();
+        for node in &fs.filenodes {
+            // The fields added since are empty, so they are the trailing length prefixes
+            let mut slot = bincode::serialize(node).unwrap();
+            assert!(slot.ends_with(&vec![0u8; 8 * missing_fields]));
+            slot.truncate(slot.len() - 8 * missing_fields);
+            slot.resize(slot_size, 0);
+            slot_checksums.push(crc32fast::hash(&slot));
+            table.extend_from_slice(&slot);
+        }
+        table.resize(
+            fs.header.free_block_bitmap_offset - fs.header.filenode_table_offset,
+            0,
+        );
+        let table_offset = fs.header.filenode_table_offset as u64;
+        fs.storage.write_at(table_offset, &table).unwrap();
+        fs.header.version = version;
+        fs.header.filenode_table_checksum = filenode_table_checksum(&slot_checksums);
+        fs.save_header().unwrap();
+        read_image(fs)
+    }
 
-        // Calculate the number of blocks to free
-        let mut blocks_to_free = Vec::new();
-        let mut current_block_opt = self.filenodes[filenode_index].first_block_index;
-        let mut block_data_buffer = vec![0u8; BLOCK_SIZE];
-
-        // Traverse the linked list of blocks and free them
-        while let Some(current_block_idx) = current_block_opt {
-            // Check if the block index is valid
-            if current_block_idx >= self.header.num_data_blocks {
-                eprintln!(
-                    "Warning: Invalid block index {} for file '{}'. Corrupt.",
-                    current_block_idx, alias
-                );
-                break;
+    #[test]
+    fn older_layouts_are_upgraded_on_open() {
+        for version in OLDEST_UPGRADABLE_VERSION..FILESYSTEM_VERSION {
+            let mut fs = new_fs();
+            store(&mut fs, "small", &pattern(10));
+            store(&mut fs, "dir/large", &pattern(3000));
+            for node in &mut fs.filenodes {
+                node.content_type.clear();
+                node.description.clear();
             }
+            let image = downgrade_image(&mut fs, version);
 
-            // Mark the block as free in the bitmap
-            blocks_to_free.push(current_block_idx);
-            let disk_offset = self.header.data_blocks_offset + current_block_idx * BLOCK_SIZE;
-            self.file
-                .seek(SeekFrom::Start(disk_offset as u64))
-                .map_err(|e| format!("Seek (delete block {}): {}", current_block_idx, e))?;
-            self.file
-                .read_exact(&mut block_data_buffer)
-                .map_err(|e| format!("Read (delete block {}): {}", current_block_idx, e))?;
-
-            // Get the next block index from the block data
-            let mut next_block_ptr_bytes = [0u8; NEXT_BLOCK_POINTER_SIZE];
-            next_block_ptr_bytes.copy_from_slice(&block_data_buffer[USABLE_BLOCK_SIZE..BLOCK_SIZE]);
-            let next_block_index = usize::from_le_bytes(next_block_ptr_bytes);
-            current_block_opt = if next_block_index == usize::MAX {
-                None
-            } else {
-                Some(next_block_index)
-            };
+            let mut upgraded =
+                FileSystemManager::open_storage(Box::new(MemStorage::from_vec(image))).unwrap();
+            assert_eq!(upgraded.header.version, FILESYSTEM_VERSION);
+            assert_eq!(contents(&mut upgraded, "small"), pattern(10));
+            assert_eq!(contents(&mut upgraded, "dir/large"), pattern(3000));
+            let info = upgraded.get_file_info("dir/large").unwrap();
+            assert_eq!(info.content_type.as_deref(), Some(DEFAULT_CONTENT_TYPE));
+            assert_eq!(info.description, None);
+            assert!(upgraded.check_integrity().unwrap().is_empty());
+
+            // The upgrade is written back, so the image now opens in the current layout
+            let image = read_image(&mut upgraded);
+            let reopened =
+                FileSystemManager::open_storage(Box::new(MemStorage::from_vec(image))).unwrap();
+            assert_eq!(reopened.header.version, FILESYSTEM_VERSION);
         }
+    }
 
-        // Mark the blocks as free in the bitmap
-        for block_idx in &blocks_to_free {
-            if *block_idx < self.free_block_bitmap.len() {
-                self.free_block_bitmap[*block_idx] = true;
-            } else {
-                eprintln!(
-                    "Warning: Tried to free out-of-bounds block {} for '{}'.",
-                    block_idx, alias
-                );
+    /// Returns a 1 MiB image in the baseline layout holding `files`, laid out as the
+    /// original release did: a header of 64 bytes, 100 variable-length filenodes in room
+    /// for 288 bytes each and 4 KiB blocks. Each file's chain runs backwards through the
+    /// blocks, and a deleted file leaves an unused node behind.
+    fn baseline_image(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let num_filenodes = 100;
+        let bitmap_offset = 64 + 8 + num_filenodes * 288;
+        let num_data_blocks = (FILESYSTEM_SIZE - bitmap_offset) / BLOCK_SIZE;
+        let data_blocks_offset = bitmap_offset + num_data_blocks.div_ceil(8);
+        let mut image = bincode::serialize(&BaselineHeader {
+            version: BASELINE_VERSION,
+            total_size: FILESYSTEM_SIZE,
+            block_size: BLOCK_SIZE,
+            filenode_table_offset: 64,
+            filenode_table_size: num_filenodes,
+            free_block_bitmap_offset: bitmap_offset,
+            data_blocks_offset,
+            num_data_blocks,
+        })
+        .unwrap();
+        image.resize(FILESYSTEM_SIZE, 0);
+
+        let mut table = (num_filenodes as u64).to_le_bytes().to_vec();
+        let mut next_block = 0;
+        for (alias, data) in files {
+            let num_blocks = data.len().div_ceil(USABLE_BLOCK_SIZE);
+            let blocks: Vec<usize> = (next_block..next_block + num_blocks).rev().collect();
+            next_block += num_blocks;
+            for (i, chunk) in data.chunks(USABLE_BLOCK_SIZE).enumerate() {
+                let start = data_blocks_offset + blocks[i] * BLOCK_SIZE;
+                image[start..start + chunk.len()].copy_from_slice(chunk);
+                let next = blocks.get(i + 1).copied().unwrap_or(usize::MAX);
+                image[start + USABLE_BLOCK_SIZE..start + BLOCK_SIZE]
+                    .copy_from_slice(&next.to_le_bytes());
+                image[bitmap_offset + blocks[i] / 8] |= 1 << (blocks[i] % 8);
             }
+            let mut padded_alias = [0u8; MAX_FILENAME_LENGTH];
+            padded_alias[..alias.len()].copy_from_slice(alias.as_bytes());
+            table.extend_from_slice(&padded_alias);
+            table.push(alias.len() as u8);
+            table.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            table.push(1);
+            table.extend_from_slice(&(blocks[0] as u64).to_le_bytes());
+            table.push(1);
+            // A deleted file in the original release only cleared the in-use flag
+            table.extend_from_slice(&[b'x'; MAX_FILENAME_LENGTH]);
+            table.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
         }
+        let empty_nodes = num_filenodes - 2 * files.len();
+        for _ in 0..empty_nodes {
+            table.extend_from_slice(&[0u8; MAX_FILENAME_LENGTH + 11]);
+        }
+        image[64..64 + table.len()].copy_from_slice(&table);
+        image
+    }
 
-        // Clear the filenode data
-        let filenode = &mut self.filenodes[filenode_index];
-        filenode.is_used = false;
-        filenode.size = 0;
-        filenode.first_block_index = None;
-        filenode.alias = [0; MAX_FILENAME_LENGTH]; // Clear alias
-        filenode.alias_len = 0;
+    #[test]
+    fn baseline_image_is_upgraded_on_open() {
+        let big = pattern(3 * USABLE_BLOCK_SIZE + 100);
+        let image = baseline_image(&[("notes.txt", b"hello"), ("big.bin", &big)]);
 
-        // Save the updated filenode and bitmap to disk and flush the file
-        self.save_filenodes()?;
-        self.write_bitmap_to_disk()?;
-        self.file
-            .flush()
-            .map_err(|e| format!("Final flush failed (delete): {}", e))?;
-        Ok(())
-    }
-}
+        let mut fs =
+            FileSystemManager::open_storage(Box::new(MemStorage::from_vec(image))).unwrap();
+        assert_eq!(fs.header.version, FILESYSTEM_VERSION);
+        assert!(fs.header.num_data_blocks >= (FILESYSTEM_SIZE - 28872) / BLOCK_SIZE);
+        assert_eq!(contents(&mut fs, "notes.txt"), b"hello");
+        assert_eq!(contents(&mut fs, "big.bin"), big);
+        assert!(fs.exists("notes.txt") && !fs.exists("xxx"));
+        assert_eq!(fs.list_files("").unwrap().len(), 2);
+        assert!(fs.check_integrity().unwrap().is_empty());
+        fs.verify_file("big.bin").unwrap();
 
-pub fn get_filesystem_manager() -> Result<FileSystemManager, String> {
-    if !Path::new(FILESYSTEM_FILENAME).exists() {
-        return FileSystemManager::init_filesystem();
+        let image = read_image(&mut fs);
+        let mut reopened =
+            FileSystemManager::open_storage(Box::new(MemStorage::from_vec(image))).unwrap();
+        assert_eq!(reopened.header.version, FILESYSTEM_VERSION);
+        assert_eq!(contents(&mut reopened, "big.bin"), big);
     }
 
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(FILESYSTEM_FILENAME)
-        .map_err(|e| format!("Failed to open {}: {}", FILESYSTEM_FILENAME, e))?;
+    #[test]
+    fn older_image_opened_read_only_is_left_alone() {
+        let path = temp_path("baseline-read-only.dat");
+        let image = baseline_image(&[("notes.txt", b"hello")]);
+        std::fs::write(&path, &image).unwrap();
 
-    let mut header_data = vec![0u8; std::mem::size_of::<Header>()];
-    file.read_exact(&mut header_data)
-        .map_err(|e| format!("Failed to read header data: {}", e))?;
-    let header: Header = bincode::deserialize(&header_data)
-        .map_err(|e| format!("Failed to deserialize header: {}", e))?;
+        let mut fs = FileSystemManager::open_read_only(&path).unwrap();
+        assert_eq!(fs.header.version, BASELINE_VERSION);
+        assert_eq!(contents(&mut fs, "notes.txt"), b"hello");
+        drop(fs);
+        assert_eq!(std::fs::read(&path).unwrap(), image);
 
-    if header.total_size != FILESYSTEM_SIZE
-        || header.block_size != BLOCK_SIZE
-        || header.version != 1
-    {
-        eprintln!("Filesystem header mismatch or incompatible version. Re-initializing.");
-        return FileSystemManager::init_filesystem();
+        // Importing it as a backup upgrades the copy, not the image
+        let target = temp_path("baseline-import.dat");
+        let mut fs = FileSystemManager::init_filesystem_at(&target, 0, &small_options()).unwrap();
+        fs.import_from(&path).unwrap();
+        assert_eq!(fs.header.version, FILESYSTEM_VERSION);
+        assert_eq!(contents(&mut fs, "notes.txt"), b"hello");
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+        drop(fs);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&target).unwrap();
     }
 
-    file.seek(SeekFrom::Start(header.filenode_table_offset as u64))
-        .map_err(|e| format!("Seek failed (load filenodes): {}", e))?;
-    let filenodes: Vec<FileNode> = bincode::deserialize_from(&mut file)
-        .map_err(|e| format!("Deserialize from stream failed (load filenodes): {}", e))?;
+    #[test]
+    fn older_image_followed_by_other_data_is_not_upgraded() {
+        let path = temp_path("baseline-embedded.dat");
+        let mut image = baseline_image(&[("notes.txt", b"hello")]);
+        image.extend_from_slice(b"trailing data that must survive");
+        std::fs::write(&path, &image).unwrap();
 
-    if filenodes.len() != header.filenode_table_size {
-        return Err(format!(
-            "Filenode count mismatch after deserialize. Header: {}, Actual: {}. Re-initializing.",
-            header.filenode_table_size,
-            filenodes.len()
-        ));
+        match FileSystemManager::open_at(&path, 0) {
+            Err(FsError::InvalidArgument(_)) => {}
+            other => panic!("expected InvalidArgument, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+        std::fs::remove_file(&path).unwrap();
     }
 
-    let bitmap_size_bytes = (header.num_data_blocks + 7) / 8;
-    let mut disk_bitmap_bytes = vec![0u8; bitmap_size_bytes];
-    file.seek(SeekFrom::Start(header.free_block_bitmap_offset as u64))
-        .map_err(|e| format!("Seek failed (load bitmap): {}", e))?;
-    file.read_exact(&mut disk_bitmap_bytes)
-        .map_err(|e| format!("Read failed (load bitmap): {}", e))?;
+    /// Returns a filesystem whose cached free block count claims two more blocks than
+    /// its bitmap has, and how many blocks are really free.
+    fn drifted_fs() -> (FileSystemManager, usize) {
+        let mut fs = new_fs();
+        store(&mut fs, "a", &pattern(1000));
+        let free = fs.free_block_count();
+        fs.free_block_count += 2;
+        (fs, free)
+    }
 
-    let mut free_block_bitmap = vec![true; header.num_data_blocks];
-    for i in 0..header.num_data_blocks {
-        if (disk_bitmap_bytes[i / 8] >> (i % 8)) & 1 != 0 {
-            free_block_bitmap[i] = false;
-        }
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "drifted from the bitmap (")]
+    fn free_count_drift_stops_debug_builds() {
+        let (mut fs, free) = drifted_fs();
+        let _ = fs.reserve_blocks(free + 1, false);
     }
 
-    Ok(FileSystemManager {
-        file,
-        header,
-        filenodes,
-        free_block_bitmap,
-    })
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn free_count_drift_is_reported_as_corrupt() {
+        let (mut fs, free) = drifted_fs();
+        assert!(matches!(
+            fs.reserve_blocks(free + 1, false),
+            Err(FsError::Corrupt(_))
+        ));
+    }
 }
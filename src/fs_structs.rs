@@ -3,11 +3,18 @@ use serde::{Deserialize, Serialize};
 
 pub const KILOBYTE: usize = 1024;
 pub const MEGABYTE: usize = 1024 * KILOBYTE;
-pub const FILESYSTEM_SIZE: usize = MEGABYTE; // 1 MB
-pub const BLOCK_SIZE: usize = 4 * KILOBYTE; // 4 KB
+pub const FILESYSTEM_SIZE: usize = MEGABYTE; // Default size of a new filesystem: 1 MB
+pub const BLOCK_SIZE: usize = 4 * KILOBYTE; // Default block size of a new filesystem: 4 KB
 pub const NEXT_BLOCK_POINTER_SIZE: usize = std::mem::size_of::<usize>();
-pub const USABLE_BLOCK_SIZE: usize = BLOCK_SIZE - NEXT_BLOCK_POINTER_SIZE;
-pub const MAX_FILENAME_LENGTH: usize = 255; // Max length for file alias
+pub const USABLE_BLOCK_SIZE: usize = BLOCK_SIZE - NEXT_BLOCK_POINTER_SIZE; // For the default block size
+pub const MAX_FILENAME_LENGTH: usize = 255; // Default max alias length in bytes of a new volume
+pub const FILESYSTEM_VERSION: u32 = 18; // Bumped whenever the on-disk layout changes
+pub const OLDEST_UPGRADABLE_VERSION: u32 = 15; // Older layouts cannot be opened, bar the baseline
+pub const BASELINE_VERSION: u32 = 1; // The original layout, which can still be upgraded
+pub const DEFAULT_FILENODE_COUNT: usize = 100; // Max number of files on a fresh volume
+pub const MAX_CONTENT_TYPE_LEN: usize = 64; // Longest MIME type a filenode slot has room for
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+pub const MAX_DESCRIPTION_LEN: usize = 256; // Longest note in bytes a filenode slot has room for
 
 // Placeholder for Header structure
 #[derive(Serialize, Deserialize, Debug)]
@@ -20,33 +27,337 @@ pub struct Header {
     pub free_block_bitmap_offset: usize,
     pub data_blocks_offset: usize,
     pub num_data_blocks: usize,
+    pub fill_byte: u8,   // Fills the unused tail of data blocks (0 on older images)
+    pub generation: u64, // Incremented on every change to the filenode table
+    pub tombstone_floor: u64, // Deletions at or before this generation are no longer recorded
+    pub merkle_root: Option<[u8; 32]>, // Cached volume Merkle root
+    pub merkle_generation: u64, // Generation at which `merkle_root` was computed
+    pub max_alias_len: usize, // Longest alias in bytes that a filenode slot has room for
+    pub max_data_blocks: usize, // Data blocks the volume was created with and can grow back to
+    pub journal_offset: usize, // Start of the metadata journal, right after the header
+    pub journal_size: usize,
+    pub filenode_table_checksum: u32, // CRC32 over the node count and each slot's CRC32
+    pub bitmap_checksum: u32,         // CRC32 of the on-disk free block bitmap
 }
 
-use serde_big_array::BigArray;
+impl Header {
+    /// Returns the largest number of bytes a serialized header can take up. This much space
+    /// is reserved for it before the filenode table.
+    pub fn max_serialized_size() -> usize {
+        let largest = Header {
+            version: 0,
+            total_size: 0,
+            block_size: 0,
+            filenode_table_offset: 0,
+            filenode_table_size: 0,
+            free_block_bitmap_offset: 0,
+            data_blocks_offset: 0,
+            num_data_blocks: 0,
+            fill_byte: 0,
+            generation: 0,
+            tombstone_floor: 0,
+            merkle_root: Some([0; 32]),
+            merkle_generation: 0,
+            max_alias_len: 0,
+            max_data_blocks: 0,
+            journal_offset: 0,
+            journal_size: 0,
+            filenode_table_checksum: 0,
+            bitmap_checksum: 0,
+        };
+        bincode::serialized_size(&largest).expect("header is serializable") as usize
+    }
+}
+
+/// Header of a volume in the baseline layout (`BASELINE_VERSION`), which has none of the
+/// fields added since and no journal or checksums.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BaselineHeader {
+    pub version: u32,
+    pub total_size: usize,
+    pub block_size: usize,
+    pub filenode_table_offset: usize,
+    pub filenode_table_size: usize, // Number of filenodes
+    pub free_block_bitmap_offset: usize,
+    pub data_blocks_offset: usize,
+    pub num_data_blocks: usize,
+}
 
 /// FileNode structure
+///
+/// Aliases are slash-separated paths such as `docs/report.txt`. A directory is a filenode
+/// of type `NodeType::Dir` without any blocks; its children are the entries whose alias
+/// continues its own with a slash and one more component.
+///
+/// The alias is stored with its length, so a node only takes the bytes its alias needs. On
+/// disk every node gets a slot with room for the longest alias the volume allows, so that
+/// a node can be rewritten in place.
+///
+/// Every file owns a chain of whole data blocks starting at `first_block_index`. The unused
+/// tail of a file's last block is padded with the fill byte, so each file starts and ends
+/// on a block boundary. Blocks are only shared by deduplicated files with identical stored
+/// bytes: these point at the same first block and each records the number of files sharing
+/// the chain in `refcount`. `check_integrity` reports any other block reachable from more
+/// than one file.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileNode {
-    #[serde(with = "BigArray")]
-    pub alias: [u8; MAX_FILENAME_LENGTH],
-    pub alias_len: u8, // Actual length of the alias
+    pub alias: Vec<u8>, // UTF-8 bytes of the alias
     pub size: usize,
+    #[serde(with = "fixed_option")]
     pub first_block_index: Option<usize>, // Index of the first data block
     pub is_used: bool,
+    #[serde(with = "fixed_option")]
+    pub expires_at: Option<u64>, // Unix time after which the file is considered expired
+    pub generation: u64, // Generation of the last change (or deletion, for tombstones)
+    pub checksum: u32,   // CRC32 of the file's contents
+    pub created_at: u64, // Unix time at which the file was first stored
+    pub modified_at: u64, // Unix time at which the contents were last written
+    pub compressed: bool, // Whether the block chain holds zstd-compressed contents
+    pub stored_size: usize, // Bytes held in the block chain
+    #[serde(with = "fixed_option")]
+    pub encryption: Option<Encryption>, // Set if the block chain holds AES-256-GCM ciphertext
+    pub content_hash: [u8; 32], // SHA-256 of the bytes held in the block chain
+    pub refcount: u32,   // Number of files sharing the block chain, this one included
+    pub node_type: NodeType,
+    pub content_type: Vec<u8>, // MIME type in ASCII; empty means DEFAULT_CONTENT_TYPE
+    pub description: Vec<u8>,  // UTF-8 note about the file; empty if there is none
+}
+
+/// Kind of entry held by a filenode.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NodeType {
+    #[default]
+    File,
+    Dir,
+    /// A link whose block chain holds the alias of the file it points to.
+    Symlink,
+}
+
+/// Parameters needed to decrypt a file's contents, apart from the password.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Encryption {
+    pub salt: [u8; 16],  // Argon2 salt the key was derived with
+    pub nonce: [u8; 12], // AES-GCM nonce
+    pub tag: [u8; 16],   // AES-GCM authentication tag
+}
+
+/// Serializes an optional integer as a presence flag followed by the value, so that every
+/// filenode has the same encoded size and can be rewritten in place.
+mod fixed_option {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<T, S>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Copy + Default + Serialize,
+        S: Serializer,
+    {
+        (value.is_some(), value.unwrap_or_default()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let (present, value) = <(bool, T)>::deserialize(deserializer)?;
+        Ok(present.then_some(value))
+    }
+}
+
+impl Default for FileNode {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FileNode {
     pub fn new() -> Self {
         FileNode {
-            alias: [0; MAX_FILENAME_LENGTH],
-            alias_len: 0,
+            alias: Vec::new(),
             size: 0,
             first_block_index: None,
             is_used: false,
+            expires_at: None,
+            generation: 0,
+            checksum: 0,
+            created_at: 0,
+            modified_at: 0,
+            compressed: false,
+            stored_size: 0,
+            encryption: None,
+            content_hash: [0; 32],
+            refcount: 0,
+            node_type: NodeType::File,
+            content_type: Vec::new(),
+            description: Vec::new(),
+        }
+    }
+
+    /// Returns the number of bytes held in the file's block chain. This is the size of the
+    /// contents unless they are compressed.
+    pub fn stored_len(&self) -> usize {
+        if self.compressed {
+            self.stored_size
+        } else {
+            self.size
+        }
+    }
+
+    /// Returns true if this node is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.node_type == NodeType::Dir
+    }
+
+    /// Returns true if this node is a symbolic link to another alias.
+    pub fn is_symlink(&self) -> bool {
+        self.node_type == NodeType::Symlink
+    }
+
+    /// Returns true if this unused node still records the alias of a deleted file.
+    pub fn is_tombstone(&self) -> bool {
+        !self.is_used && !self.alias.is_empty()
+    }
+
+    /// Stores `alias` in the node. The caller must check that its UTF-8 encoding fits in
+    /// the volume's maximum alias length first; the alias is never cut short.
+    pub fn set_alias(&mut self, alias: &str) {
+        self.alias = alias.as_bytes().to_vec();
+    }
+
+    /// Returns the MIME type of the file's contents.
+    pub fn content_type(&self) -> String {
+        if self.content_type.is_empty() {
+            DEFAULT_CONTENT_TYPE.to_string()
+        } else {
+            String::from_utf8_lossy(&self.content_type).into_owned()
+        }
+    }
+
+    /// Returns the note about the file, if it has one.
+    pub fn description(&self) -> Option<String> {
+        (!self.description.is_empty())
+            .then(|| String::from_utf8_lossy(&self.description).into_owned())
+    }
+
+    /// Returns the size in bytes of an on-disk filenode slot with room for an alias of
+    /// `max_alias_len` bytes, the longest content type and the longest description.
+    pub fn slot_size(max_alias_len: usize) -> usize {
+        let empty = bincode::serialized_size(&FileNode::new()).expect("filenode is serializable");
+        empty as usize + max_alias_len + MAX_CONTENT_TYPE_LEN + MAX_DESCRIPTION_LEN
+    }
+
+    /// Returns the size of a filenode slot in a volume of the given layout `version`.
+    /// Each field added since takes its length prefix and its room out of the slot.
+    pub fn slot_size_for_version(version: u32, max_alias_len: usize) -> usize {
+        let mut slot_size = Self::slot_size(max_alias_len);
+        if version < 17 {
+            slot_size -= MAX_DESCRIPTION_LEN + 8;
+        }
+        if version < 16 {
+            slot_size -= MAX_CONTENT_TYPE_LEN + 8;
+        }
+        slot_size
+    }
+
+    /// Decodes the filenode in a slot written by a volume of the given layout `version`.
+    pub fn from_slot(slot: &[u8], version: u32) -> bincode::Result<FileNode> {
+        // Older nodes end before the fields added since, content type (16) and description
+        // (17). The zero padding after them, topped up in case the alias filled the slot,
+        // decodes as empty values for those fields.
+        let missing_fields = 17u32.saturating_sub(version) as usize;
+        if missing_fields == 0 {
+            return bincode::deserialize(slot);
         }
+        let mut bytes = slot.to_vec();
+        bytes.resize(slot.len() + 8 * missing_fields, 0);
+        bincode::deserialize(&bytes)
+    }
+
+    /// Reads a filenode as the baseline layout encoded it: the alias padded to 255 bytes,
+    /// its length, the size, the optional first block and the in-use flag. Nodes of that
+    /// layout vary in length, so they are read one after the other from `reader`.
+    pub fn read_baseline<R: std::io::Read>(reader: &mut R) -> std::io::Result<FileNode> {
+        let mut alias = [0u8; MAX_FILENAME_LENGTH];
+        reader.read_exact(&mut alias)?;
+        let mut alias_len = [0u8; 1];
+        reader.read_exact(&mut alias_len)?;
+        let mut size = [0u8; 8];
+        reader.read_exact(&mut size)?;
+        let mut tag = [0u8; 1];
+        reader.read_exact(&mut tag)?;
+        let first_block_index = match tag[0] {
+            0 => None,
+            1 => {
+                let mut index = [0u8; 8];
+                reader.read_exact(&mut index)?;
+                Some(u64::from_le_bytes(index) as usize)
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "invalid first block tag",
+                ))
+            }
+        };
+        let mut is_used = [0u8; 1];
+        reader.read_exact(&mut is_used)?;
+
+        let mut node = FileNode::new();
+        if is_used[0] != 0 {
+            node.alias = alias[..alias_len[0] as usize].to_vec();
+            node.size = u64::from_le_bytes(size) as usize;
+            node.stored_size = node.size;
+            node.first_block_index = first_block_index;
+            node.is_used = true;
+            node.refcount = 1;
+        }
+        Ok(node)
+    }
+
+    /// Returns true if the file has an expiry time that is not after `now`.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
     }
 
     pub fn get_alias_str(&self) -> Result<String, std::string::FromUtf8Error> {
-        String::from_utf8(self.alias[0..self.alias_len as usize].to_vec())
+        String::from_utf8(self.alias.clone())
+    }
+
+    /// Returns the alias for display, with any invalid UTF-8 from a corrupt node replaced
+    /// by U+FFFD. Use `get_alias_str` where the exact alias matters.
+    pub fn alias_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.alias).into_owned()
     }
 }
+
+/// Archive of files exported from the filesystem, either complete or incremental.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Archive {
+    pub since_generation: u64, // 0 for a full export
+    pub generation: u64,       // Generation of the filesystem at export time
+    pub files: Vec<ArchivedFile>,
+    pub deleted: Vec<String>, // Aliases deleted since `since_generation`
+}
+
+/// A single file stored in an `Archive`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchivedFile {
+    pub alias: String,
+    pub expires_at: Option<u64>,
+    pub content_type: String,
+    pub description: Option<String>,
+    pub created_at: u64,  // Unix time
+    pub modified_at: u64, // Unix time
+    pub data: Vec<u8>,
+}
+
+/// File metadata captured by `FileSystemManager::snapshot`. Data blocks are not copied,
+/// so a snapshot can only be restored while the blocks of its files are unchanged.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub name: String,
+    pub created_at: u64, // Unix time at which the snapshot was taken
+    pub generation: u64, // Generation of the filesystem at that time
+    pub max_alias_len: usize,
+    pub filenodes: Vec<FileNode>,
+}
@@ -0,0 +1,172 @@
+// Write-ahead journal that makes metadata updates crash-consistent.
+//
+// A transaction's writes to the header, filenode table and bitmap are first stored as one
+// record in the journal region. Only once the record is on disk are they applied to their
+// home locations, after which the record is cleared. A record found when a volume is
+// opened was complete, as it passed its checksum, and is applied again; a torn record
+// never had any of its writes applied and is discarded.
+
+use crate::fs_error::FsError;
+use crate::storage::Storage;
+use crc32fast::Hasher as Crc32;
+use log::warn;
+use std::io;
+
+const MAGIC: [u8; 4] = *b"FSJ1";
+/// Bytes before the payload: the magic, the payload length (u64) and its CRC32.
+const RECORD_HEADER_SIZE: usize = 16;
+/// Bytes bincode adds to the payload for each write: its offset and length as u64s.
+const WRITE_FRAMING: usize = 16;
+/// Next pointers in data blocks a transaction may write besides the metadata.
+const POINTERS_PER_RECORD: usize = 2;
+
+/// A metadata write of `bytes` at `offset` within the volume.
+pub(crate) type JournalWrite = (usize, Vec<u8>);
+
+/// Returns the size of the journal region for a volume whose header, filenode table and
+/// bitmap take the given number of bytes. This holds a record that rewrites all of them,
+/// the largest transaction there can be, once its writes are merged by `coalesce`.
+pub(crate) fn journal_size(header_size: usize, table_size: usize, bitmap_size: usize) -> usize {
+    // bincode stores the list length as a u64. Each write after the first is preceded by
+    // a gap of more than its framing, which the sizes below do not need to hold
+    let framing = RECORD_HEADER_SIZE + 8 + WRITE_FRAMING;
+    let pointers = POINTERS_PER_RECORD * (WRITE_FRAMING + std::mem::size_of::<usize>());
+    framing + header_size + table_size + bitmap_size + pointers
+}
+
+/// Merges `writes` into as few writes as possible, so that a record takes no more room
+/// than the bytes it changes. Writes that overlap, touch or are separated by no more than
+/// a write's framing are joined, the bytes between them being read from `storage`; where
+/// writes overlap, the later one wins, as it would when they are applied in order.
+pub(crate) fn coalesce(
+    storage: &mut dyn Storage,
+    base_offset: u64,
+    writes: &[JournalWrite],
+) -> io::Result<Vec<JournalWrite>> {
+    let mut spans: Vec<(usize, usize)> = writes
+        .iter()
+        .map(|(offset, bytes)| (*offset, offset + bytes.len()))
+        .collect();
+    spans.sort_unstable();
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in spans {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 + WRITE_FRAMING => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    let mut coalesced = Vec::with_capacity(merged.len());
+    for (start, end) in merged {
+        let mut bytes = vec![0u8; end - start];
+        storage.read_at(base_offset + start as u64, &mut bytes)?;
+        coalesced.push((start, bytes));
+    }
+    for (offset, bytes) in writes {
+        let index = coalesced.partition_point(|(start, _)| start <= offset) - 1;
+        let (start, region) = &mut coalesced[index];
+        region[offset - *start..offset - *start + bytes.len()].copy_from_slice(bytes);
+    }
+    Ok(coalesced)
+}
+
+/// Encodes `writes` as a journal record, checksummed so a torn record is recognised.
+pub(crate) fn encode_record(writes: &[JournalWrite]) -> Result<Vec<u8>, FsError> {
+    let payload = bincode::serialize(writes)
+        .map_err(|e| FsError::Serialization(format!("Serialize failed (journal): {}", e)))?;
+    let mut hasher = Crc32::new();
+    hasher.update(&payload);
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    record.extend_from_slice(&MAGIC);
+    record.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    record.extend_from_slice(&hasher.finalize().to_le_bytes());
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+/// Marks the journal at `journal_offset` as empty.
+pub(crate) fn clear(
+    storage: &mut dyn Storage,
+    base_offset: u64,
+    journal_offset: usize,
+) -> Result<(), FsError> {
+    storage
+        .write_at(base_offset + journal_offset as u64, &[0; MAGIC.len()])
+        .and_then(|_| storage.flush())
+        .map_err(|e| FsError::io("Write failed (clear journal)", e))
+}
+
+/// Applies `writes` to the volume starting at `base_offset` and flushes them.
+pub(crate) fn apply(
+    storage: &mut dyn Storage,
+    base_offset: u64,
+    writes: &[JournalWrite],
+) -> Result<(), FsError> {
+    for (offset, bytes) in writes {
+        storage
+            .write_at(base_offset + *offset as u64, bytes)
+            .map_err(|e| FsError::io(format!("Write failed (apply journal at {})", offset), e))?;
+    }
+    storage
+        .flush()
+        .map_err(|e| FsError::io("Flush failed (apply journal)", e))
+}
+
+/// Finishes or discards a transaction left in the journal region by an interrupted
+/// update, then clears the journal. Returns true if a complete record was applied, in
+/// which case the metadata has changed and must be read again.
+pub(crate) fn recover(
+    storage: &mut dyn Storage,
+    base_offset: u64,
+    journal_offset: usize,
+    journal_size: usize,
+) -> Result<bool, FsError> {
+    if journal_size < RECORD_HEADER_SIZE {
+        return Ok(false);
+    }
+    let mut record_header = [0u8; RECORD_HEADER_SIZE];
+    storage
+        .read_at(base_offset + journal_offset as u64, &mut record_header)
+        .map_err(|e| FsError::io("Read failed (journal)", e))?;
+    if record_header[..MAGIC.len()] != MAGIC {
+        return Ok(false);
+    }
+
+    let payload_len = u64::from_le_bytes(record_header[4..12].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(record_header[12..16].try_into().unwrap());
+    let writes = if payload_len <= journal_size - RECORD_HEADER_SIZE {
+        let mut payload = vec![0u8; payload_len];
+        storage
+            .read_at(
+                base_offset + (journal_offset + RECORD_HEADER_SIZE) as u64,
+                &mut payload,
+            )
+            .map_err(|e| FsError::io("Read failed (journal)", e))?;
+        let mut hasher = Crc32::new();
+        hasher.update(&payload);
+        if hasher.finalize() == checksum {
+            bincode::deserialize::<Vec<JournalWrite>>(&payload).ok()
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+
+    let replayed = match writes {
+        Some(writes) => {
+            warn!(
+                "Replaying {} metadata writes of an interrupted update from the journal",
+                writes.len()
+            );
+            apply(storage, base_offset, &writes)?;
+            true
+        }
+        None => {
+            warn!("Discarding an incomplete update found in the journal");
+            false
+        }
+    };
+    clear(storage, base_offset, journal_offset)?;
+    Ok(replayed)
+}
@@ -0,0 +1,8 @@
+// Library interface to the filesystem, shared by the command-line tool.
+
+pub mod fs_error;
+pub mod fs_ops;
+pub mod fs_structs;
+mod journal;
+pub mod server;
+pub mod storage;
@@ -1,119 +1,2103 @@
-mod fs_ops;
-mod fs_structs;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use filesystem::fs_error::FsError;
+use filesystem::fs_ops::{
+    self, get_filesystem_manager_at, AllocStrategy, BenchOptions, DiskUsage, FileInfo,
+    FileSystemManager, InitOptions, LinePattern, SizeFilter, SortBy, UploadOptions,
+};
+use filesystem::fs_structs::{
+    BLOCK_SIZE, FILESYSTEM_SIZE, KILOBYTE, MAX_FILENAME_LENGTH, MEGABYTE,
+};
+use filesystem::server;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use clap::Parser;
-use fs_ops::{get_filesystem_manager, FileSystemManager};
+/// Set by `--quiet` to leave out success messages; command output and errors are kept.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Prints a message confirming that a command succeeded, unless `--quiet` was given.
+macro_rules! report {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
 
 #[derive(Parser, Debug)]
-#[clap(
-    name = "filesystem",
-    version = "0.1.0",
-    about = "A simple filesystem"
-)]
+#[clap(name = "filesystem", version = "0.1.0", about = "A simple filesystem")]
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+    /// Retry block reads and writes this many times after a transient I/O error
+    #[clap(long, global = true, default_value_t = 0)]
+    io_retries: u32,
+    /// How blocks are chosen for new files: first-fit or contiguous
+    #[clap(long, global = true, default_value = "first-fit")]
+    alloc: AllocStrategy,
+    /// Grow the filesystem file with more data blocks instead of running out of space
+    #[clap(long, global = true)]
+    grow: bool,
+    /// Access the filesystem file through a memory mapping, falling back to regular I/O
+    #[clap(long, global = true)]
+    mmap: bool,
+    /// Password that uploaded files are encrypted with and encrypted files are read with
+    #[clap(long, global = true)]
+    password: Option<String>,
+    /// File holding the filesystem
+    #[clap(long, global = true, default_value = fs_ops::FILESYSTEM_FILENAME)]
+    fs_file: PathBuf,
+    /// Log block allocations, bitmap writes and detected corruption to stderr
+    #[clap(long, short, global = true)]
+    verbose: bool,
+    /// Leave out messages confirming that a command succeeded. Errors are still printed
+    #[clap(long, short, global = true)]
+    quiet: bool,
+    /// Re-initialise an incompatible filesystem image instead of refusing to open it,
+    /// destroying its contents
+    #[clap(long, global = true)]
+    force_init: bool,
+}
+
+/// Output format of the `list` command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ListFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+impl std::str::FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ListFormat::Text),
+            "json" => Ok(ListFormat::Json),
+            "csv" => Ok(ListFormat::Csv),
+            _ => Err(format!("Unknown format '{}'. Use text, json or csv.", s)),
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
 enum Commands {
     /// Upload a local file to the filesystem
     Upload {
-        /// Path to the local file to upload
+        /// Path to the local file to upload, or `-` to read from standard input
         #[clap(long, short)]
-        path: String, 
+        path: String,
         /// Alias for the file in the filesystem
         #[clap(long, short)]
         alias: String,
+        /// Number of seconds after which the file expires
+        #[clap(long)]
+        ttl: Option<u64>,
+        /// Overwrite the file if the alias already exists
+        #[clap(long, short)]
+        force: bool,
+        /// Store the file zstd-compressed if that saves space
+        #[clap(long)]
+        compress: bool,
+        /// Share the blocks of an identical file already stored instead of writing new ones
+        #[clap(long)]
+        dedup: bool,
+        /// MIME type of the file, e.g. `text/plain`. Guessed from the alias's extension
+        /// if not given
+        #[clap(long = "type")]
+        content_type: Option<String>,
+        /// Short note to attach to the file
+        #[clap(long)]
+        note: Option<String>,
+    },
+    /// Append the contents of a local file to a stored file
+    Append {
+        /// Alias of the file in the filesystem
+        #[clap(long, short)]
+        alias: String,
+        /// Path to the local file whose contents to append
+        #[clap(long, short)]
+        path: String,
+    },
+    /// Shrink a stored file to its first bytes
+    Truncate {
+        /// Alias of the file in the filesystem
+        #[clap(long, short)]
+        alias: String,
+        /// Number of bytes to keep
+        #[clap(long, short)]
+        size: usize,
     },
     /// Download a file from the filesystem to the local system
     Download {
         /// Alias of the file in the filesystem
         #[clap(long, short)]
         alias: String,
-        /// Path to save the downloaded file locally
+        /// Path to save the downloaded file locally, or `-` to write to standard output
         #[clap(long, short)]
         path: String,
     },
+    /// Print the contents of a file to standard output
+    Cat {
+        /// Alias of the file in the filesystem
+        #[clap(long, short)]
+        alias: String,
+    },
+    /// Print the first bytes of a file to standard output
+    Head {
+        /// Alias of the file in the filesystem
+        #[clap(long, short)]
+        alias: String,
+        /// Number of bytes to print
+        #[clap(long, short, default_value_t = 256)]
+        bytes: usize,
+    },
+    /// Print the last bytes of a file to standard output
+    Tail {
+        /// Alias of the file in the filesystem
+        #[clap(long, short)]
+        alias: String,
+        /// Number of bytes to print
+        #[clap(long, short, default_value_t = 256)]
+        bytes: usize,
+    },
+    /// Check a file's block chain and checksum without downloading it
+    Verify {
+        /// Alias of the file in the filesystem
+        #[clap(long, short)]
+        alias: String,
+    },
     /// List files stored in the filesystem
-    List,
+    List {
+        /// Directory whose entries to list; every entry is listed if omitted
+        path: Option<String>,
+        /// Show the size and timestamps of each file
+        #[clap(long, short)]
+        long: bool,
+        /// Order files by name, size or none (filenode order)
+        #[clap(long, default_value = "none")]
+        sort: SortBy,
+        /// Reverse the sort order
+        #[clap(long, short)]
+        reverse: bool,
+        /// Show entries as an indented tree
+        #[clap(long, short)]
+        tree: bool,
+        /// List only directories
+        #[clap(long, conflicts_with = "files_only")]
+        dirs_only: bool,
+        /// List only files
+        #[clap(long)]
+        files_only: bool,
+        /// Output format: text, json or csv
+        #[clap(long, default_value = "text")]
+        format: ListFormat,
+    },
+    /// Create a directory, along with any missing parent directories
+    Mkdir {
+        /// Path of the directory
+        path: String,
+    },
+    /// List files whose alias matches a glob pattern (`*` and `?` wildcards)
+    Find {
+        /// Pattern to match aliases against
+        #[clap(default_value = "*")]
+        pattern: String,
+        /// Match letters regardless of case
+        #[clap(long, short)]
+        ignore_case: bool,
+        /// Only files larger than this size, e.g. 1500, 10k or 1M
+        #[clap(long, value_parser = parse_size)]
+        larger_than: Option<usize>,
+        /// Only files smaller than this size, e.g. 1500, 10k or 1M
+        #[clap(long, value_parser = parse_size)]
+        smaller_than: Option<usize>,
+    },
+    /// Check whether a file exists, exiting with status 1 if it does not
+    Exists {
+        /// Alias of the file in the filesystem
+        #[clap(long, short)]
+        alias: String,
+    },
     /// Delete a file from the filesystem
     Delete {
         #[clap(long, short)]
         alias: String, // Alias of the file to delete
+        /// Overwrite the file's blocks with zeros before freeing them
+        #[clap(long)]
+        secure: bool,
+        /// Report what would be deleted without changing anything
+        #[clap(long)]
+        dry_run: bool,
     },
     /// Initialise or re-initialise the filesystem (for testing/reset)
-    Init,
+    Init {
+        /// Byte used to fill unused space at the end of data blocks
+        #[clap(long, default_value_t = 0)]
+        fill_byte: u8,
+        /// Total size of the filesystem in bytes
+        #[clap(long, default_value_t = FILESYSTEM_SIZE)]
+        size: usize,
+        /// Size of each data block in bytes
+        #[clap(long, default_value_t = BLOCK_SIZE)]
+        block_size: usize,
+        /// Longest alias allowed, in bytes of UTF-8
+        #[clap(long, default_value_t = MAX_FILENAME_LENGTH)]
+        max_alias_len: usize,
+    },
+    /// Delete every file, keeping the filesystem's size and settings
+    #[clap(alias = "wipe")]
+    Clear {
+        /// Overwrite the blocks in use with zeros before freeing them
+        #[clap(long)]
+        secure: bool,
+    },
+    /// Copy a stored file to a new alias
+    Copy {
+        /// Alias of the file to copy
+        #[clap(long, short)]
+        src: String,
+        /// Alias for the copy
+        #[clap(long, short)]
+        dst: String,
+    },
+    /// Copy a stored file into another filesystem image under the same alias
+    Transfer {
+        /// Alias of the file to transfer
+        #[clap(long, short)]
+        alias: String,
+        /// Path of the filesystem image to transfer the file to
+        #[clap(long, short)]
+        to: PathBuf,
+        /// Delete the file from this filesystem once it has been transferred
+        #[clap(long = "move")]
+        remove: bool,
+    },
+    /// Swap the contents of two stored files, keeping their aliases
+    Swap {
+        /// First alias
+        #[clap(long)]
+        a: String,
+        /// Second alias
+        #[clap(long)]
+        b: String,
+    },
+    /// Export files to an archive, optionally only those changed since a generation
+    ExportArchive {
+        /// Path of the archive to create
+        #[clap(long, short)]
+        path: String,
+        /// Only include changes made after this generation
+        #[clap(long)]
+        since: Option<u64>,
+    },
+    /// Apply an archive created by `export-archive`
+    ImportArchive {
+        /// Path of the archive to import
+        #[clap(long, short)]
+        path: String,
+    },
+    /// Write every stored file to a tar archive
+    ExportTar {
+        /// Path of the tarball to create
+        #[clap(long, short)]
+        path: String,
+    },
+    /// Store every file from a tar archive, named by its path in the archive
+    ImportTar {
+        /// Path of the tarball to import
+        #[clap(long, short)]
+        path: String,
+        /// Overwrite files whose alias already exists
+        #[clap(long, short)]
+        force: bool,
+        /// Report what would be imported without changing anything
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Copy the whole filesystem image to a file, as a backup
+    Export {
+        /// Path of the image file to create
+        #[clap(long, short)]
+        path: String,
+    },
+    /// Replace the filesystem with an image created by `export`
+    Import {
+        /// Path of the image file to restore
+        #[clap(long, short)]
+        path: String,
+    },
+    /// Show the metadata of a stored file
+    Info {
+        /// Alias of the file
+        #[clap(long, short)]
+        alias: String,
+        /// Print the metadata as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Show where a stored file's blocks are: its filenode and its chain of data blocks
+    Stats {
+        /// Alias of the file
+        #[clap(long, short)]
+        alias: String,
+    },
+    /// Attach a short note to a file, or remove it
+    Annotate {
+        /// Alias of the file
+        #[clap(long, short)]
+        alias: String,
+        /// Note to attach, replacing any previous one
+        #[clap(long, short, required_unless_present = "clear")]
+        note: Option<String>,
+        /// Remove the file's note
+        #[clap(long, conflicts_with = "note")]
+        clear: bool,
+    },
+    /// Rename a file in the filesystem
+    Rename {
+        /// Current alias of the file
+        #[clap(long, short)]
+        old_alias: String,
+        /// New alias for the file
+        #[clap(long, short)]
+        new_alias: String,
+    },
+    /// Move a file to a new alias, replacing any file already stored there
+    #[clap(alias = "mv")]
+    Move {
+        /// Alias of the file to move
+        #[clap(long, short)]
+        src: String,
+        /// Alias to move it to
+        #[clap(long, short)]
+        dst: String,
+    },
+    /// Create a symbolic link to a stored file
+    Link {
+        /// Alias of the file the link points to
+        #[clap(long, short)]
+        target: String,
+        /// Alias of the new link
+        #[clap(long, short)]
+        name: String,
+    },
+    /// Show how full the filesystem is
+    Df,
+    /// Show the blocks and bytes each file takes up on disk, including slack
+    Du,
+    /// Show how fragmented files and free space are
+    Frag,
+    /// Delete all files whose time to live has expired
+    Purge,
+    /// List groups of files with identical contents
+    Dupes,
+    /// Search the contents of every file for a pattern, or print the matching lines of one file
+    Grep {
+        /// Text to search for
+        pattern: String,
+        /// Only print the aliases of matching files, stopping at each file's first match
+        #[clap(long = "files-with-matches", short = 'l', conflicts_with = "alias")]
+        files_with_matches: bool,
+        /// Search only this file and print its matching lines
+        #[clap(long, short)]
+        alias: Option<String>,
+        /// Treat the pattern as a regular expression
+        #[clap(long, short = 'E', requires = "alias")]
+        regex: bool,
+        /// Print the line number before each matching line
+        #[clap(long = "line-number", short = 'n', requires = "alias")]
+        line_number: bool,
+    },
+    /// Check the filesystem for corruption
+    Fsck {
+        /// Truncate broken block chains and rebuild the free block bitmap
+        #[clap(long)]
+        repair: bool,
+    },
+    /// Move file blocks so every file is contiguous and free space is one run at the end
+    Defrag {
+        /// Report how many blocks would move without moving them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Defragment, then shrink the filesystem file to the blocks in use. Uploads grow it back as needed
+    Compact,
+    /// Save the current file metadata under a name. Data blocks are not copied
+    Snapshot {
+        /// Name of the snapshot
+        #[clap(long, short)]
+        name: String,
+        /// Delete the snapshot instead of taking it
+        #[clap(long)]
+        delete: bool,
+    },
+    /// Return the filesystem to the files it held when a snapshot was taken
+    Restore {
+        /// Name of the snapshot
+        #[clap(long, short)]
+        name: String,
+    },
+    /// List the snapshots of the filesystem
+    Snapshots,
+    /// Run all health checks and suggest fixes
+    Doctor {
+        /// Correct refcounts and rebuild the free block bitmap, freeing orphaned blocks
+        #[clap(long)]
+        fix: bool,
+    },
+    /// Print the Merkle root summarising the content of all files
+    Root,
+    /// Show the byte ranges of the on-disk regions
+    Layout {
+        /// Print the layout as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// [Expert] Print a hex dump of a physical data block
+    RawRead {
+        /// Index of the data block
+        #[clap(long, short)]
+        block: usize,
+    },
+    /// [Expert] Overwrite the start of a physical data block with raw bytes
+    RawWrite {
+        /// Index of the data block
+        #[clap(long, short)]
+        block: usize,
+        /// Bytes to write, as hexadecimal
+        #[clap(long)]
+        hex: String,
+        /// Confirm that the block may be overwritten
+        #[clap(long)]
+        force: bool,
+    },
+    /// Keep the filesystem open and read commands from stdin until `exit`
+    Shell,
+    /// Apply the operations listed in a manifest file, one per line: `upload <path> <alias>`,
+    /// `delete <alias>` or `rename <old> <new>`. Blank lines and lines starting with `#`
+    /// are skipped
+    Batch {
+        /// Path of the manifest file
+        #[clap(long, short)]
+        manifest: PathBuf,
+        /// Carry on with the remaining operations after one fails
+        #[clap(long, short)]
+        keep_going: bool,
+    },
+    /// Increase the maximum number of files the filesystem can hold, keeping existing files
+    GrowFilenodes {
+        /// New maximum number of files
+        #[clap(long, short)]
+        count: usize,
+    },
+    /// Serve the stored files over HTTP until interrupted. The filesystem is opened
+    /// read-only
+    Serve {
+        /// Address to listen on
+        #[clap(long, short, default_value = "127.0.0.1:8080")]
+        addr: String,
+    },
+    /// Time uploading and deleting many small files in a throwaway filesystem
+    #[clap(hide = true)]
+    Bench {
+        /// Number of files to upload
+        #[clap(long, short, default_value_t = 1000)]
+        files: usize,
+        /// Size of each file, with an optional k, M or G suffix
+        #[clap(long, default_value = "1k", value_parser = parse_size)]
+        file_size: usize,
+        /// Total size of the throwaway filesystem, with an optional k, M or G suffix
+        #[clap(long, default_value = "64M", value_parser = parse_size)]
+        size: usize,
+        /// Size of each data block in bytes
+        #[clap(long, default_value_t = BLOCK_SIZE)]
+        block_size: usize,
+    },
 }
 
 fn main() {
     let cli: Cli = Cli::parse();
+    // Warnings are always shown; RUST_LOG can ask for more, as can --verbose
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"));
+    if cli.verbose {
+        logger.filter_level(log::LevelFilter::Debug);
+    }
+    logger.init();
+    QUIET.store(cli.quiet, Ordering::Relaxed);
 
     match cli.command {
-        Commands::Init => match FileSystemManager::init_filesystem() {
-            Ok(_) => println!(
-                "Filesystem initialised successfully at '{}'.",
-                fs_ops::FILESYSTEM_FILENAME
-            ),
-            Err(e) => eprintln!("Error initialising filesystem: {}", e),
-        },
-        Commands::Upload { path, alias } => {
-            // fs_manager_result is consumed or re-assigned here
-            let fs_manager_result_for_upload = get_filesystem_manager(); // Renamed and made immutable
-            match fs_manager_result_for_upload {
-                Ok(mut manager) => match manager.upload_file(&path, &alias) {
-                    Ok(_) => println!("File '{}' uploaded successfully as '{}'.", path, alias),
-                    Err(e) => eprintln!("Error uploading file: {}", e),
+        Commands::Init {
+            fill_byte,
+            size,
+            block_size,
+            max_alias_len,
+        } => {
+            let options = InitOptions {
+                fill_byte,
+                total_size: size,
+                block_size,
+                max_alias_len,
+                ..InitOptions::default()
+            };
+            if init_filesystem(&cli.fs_file, &options).is_none() {
+                std::process::exit(1);
+            }
+        }
+        Commands::Bench {
+            files,
+            file_size,
+            size,
+            block_size,
+        } => {
+            let options = BenchOptions {
+                init: InitOptions {
+                    num_filenodes: files.max(1),
+                    total_size: size,
+                    block_size,
+                    ..InitOptions::default()
                 },
-                Err(e) => eprintln!("Failed to access filesystem: {}", e),
+                num_files: files,
+                file_size,
+                alloc_strategy: cli.alloc,
+            };
+            if !run_bench(&options) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Serve { addr } => {
+            if !run_server(&cli.fs_file, &addr, cli.password) {
+                std::process::exit(1);
+            }
+        }
+        command => {
+            let manager = match get_filesystem_manager_at(&cli.fs_file) {
+                Err(FsError::Incompatible { .. }) if cli.force_init => {
+                    init_filesystem(&cli.fs_file, &InitOptions::default())
+                }
+                Ok(manager) => Some(manager),
+                Err(e @ FsError::Incompatible { .. }) => {
+                    eprintln!(
+                        "Failed to access filesystem: {} Use --force-init to re-initialise it, destroying its contents.",
+                        e
+                    );
+                    None
+                }
+                Err(e) => {
+                    eprintln!("Failed to access filesystem: {}", e);
+                    None
+                }
+            };
+            let Some(mut manager) = manager else {
+                std::process::exit(1);
+            };
+            let settings = ManagerSettings {
+                io_retries: cli.io_retries,
+                alloc: cli.alloc,
+                grow: cli.grow,
+                mmap: cli.mmap,
+                password: cli.password,
+            };
+            settings.apply(&mut manager);
+            if let Commands::Shell = command {
+                run_shell(&mut manager, &settings);
+            } else if !run_command(&mut manager, command) {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// The global flags that configure an open filesystem rather than choose which one to open.
+struct ManagerSettings {
+    io_retries: u32,
+    alloc: AllocStrategy,
+    grow: bool,
+    mmap: bool,
+    password: Option<String>,
+}
+
+impl ManagerSettings {
+    fn apply(&self, manager: &mut FileSystemManager) {
+        manager.set_io_retries(self.io_retries);
+        manager.set_alloc_strategy(self.alloc);
+        manager.set_growable(self.grow);
+        if self.mmap {
+            if let Err(e) = manager.use_mmap() {
+                eprintln!("Warning: {} Using regular file I/O instead.", e);
+            }
+        }
+        manager.set_password(self.password.clone());
+    }
+}
+
+/// Initialises a fresh filesystem in the file at `path`, printing the outcome.
+fn init_filesystem(path: &Path, options: &InitOptions) -> Option<FileSystemManager> {
+    match FileSystemManager::init_filesystem_at(path, 0, options) {
+        Ok(manager) => {
+            report!(
+                "Filesystem initialised successfully at '{}'.",
+                path.display()
+            );
+            Some(manager)
+        }
+        Err(e) => {
+            eprintln!("Error initialising filesystem: {}", e);
+            None
+        }
+    }
+}
+
+/// Runs a single command against an open filesystem.
+/// Returns false if the command found serious problems that should fail the process.
+fn run_command(manager: &mut FileSystemManager, command: Commands) -> bool {
+    match command {
+        Commands::Init {
+            fill_byte,
+            size,
+            block_size,
+            max_alias_len,
+        } => {
+            let options = InitOptions {
+                fill_byte,
+                total_size: size,
+                block_size,
+                max_alias_len,
+                ..InitOptions::default()
+            };
+            let Some(path) = manager.path().map(Path::to_path_buf) else {
+                eprintln!("Error initialising filesystem: it is not stored in a file.");
+                return false;
+            };
+            // The open filesystem is reset in place, as it holds the lock on the file
+            match manager.reinitialise(&options) {
+                Ok(()) => report!(
+                    "Filesystem initialised successfully at '{}'.",
+                    path.display()
+                ),
+                Err(e) => {
+                    eprintln!("Error initialising filesystem: {}", e);
+                    return false;
+                }
+            }
+        }
+        Commands::Upload {
+            path,
+            alias,
+            ttl,
+            force,
+            compress,
+            dedup,
+            content_type,
+            note,
+        } => {
+            let options = UploadOptions {
+                ttl,
+                overwrite: force,
+                compress,
+                password: manager.password().map(str::to_string),
+                dedup,
+                content_type,
+                description: note,
+            };
+            if path == "-" {
+                let mut stdin = std::io::stdin().lock();
+                match manager.upload_reader(&mut stdin, &alias, &options) {
+                    Ok(size) => report!("{} bytes from stdin uploaded as '{}'.", size, alias),
+                    Err(e) => {
+                        eprintln!("Error uploading file: {}", e);
+                        return false;
+                    }
+                }
+            } else {
+                match manager.upload_file_with_progress(
+                    &path,
+                    &alias,
+                    &options,
+                    progress("Uploading"),
+                ) {
+                    Ok(size) => report!(
+                        "File '{}' uploaded successfully as '{}' ({} bytes).",
+                        path,
+                        alias,
+                        size
+                    ),
+                    Err(e) => {
+                        eprintln!("Error uploading file: {}", e);
+                        return false;
+                    }
+                }
+            }
+        }
+        Commands::Append { alias, path } => match std::fs::File::open(&path) {
+            Ok(mut local_file) => match manager.append_file(&alias, &mut local_file) {
+                Ok(_) => report!("File '{}' appended to '{}'.", path, alias),
+                Err(e) => {
+                    eprintln!("Error appending file: {}{}", e, did_you_mean(manager, &e));
+                    return false;
+                }
+            },
+            Err(e) => {
+                eprintln!("Error opening local file '{}': {}", path, e);
+                return false;
+            }
+        },
+        Commands::Truncate { alias, size } => match manager.truncate_file(&alias, size) {
+            Ok(_) => report!("File '{}' truncated to {} bytes.", alias, size),
+            Err(e) => {
+                eprintln!("Error truncating file: {}{}", e, did_you_mean(manager, &e));
+                return false;
+            }
+        },
+        Commands::Download { alias, path } if path == "-" => {
+            // Only the file's bytes go to stdout so the output can be piped
+            let mut stdout = std::io::stdout().lock();
+            let result = manager.read_file(&alias, &mut stdout);
+            let _ = stdout.flush();
+            if let Err(e) = result {
+                eprintln!("Error downloading file: {}{}", e, did_you_mean(manager, &e));
+                return false;
             }
         }
         Commands::Download { alias, path } => {
-            let fs_manager_result_for_download = get_filesystem_manager();
-            match fs_manager_result_for_download {
-                Ok(mut manager) => {
-                    match manager.download_file(&alias, &path) {
-                        Ok(_) => {
-                            println!("File '{}' downloaded successfully to '{}'.", alias, path)
+            match manager.download_file_with_progress(&alias, &path, progress("Downloading")) {
+                Ok(size) => report!(
+                    "File '{}' downloaded successfully to '{}' ({} bytes).",
+                    alias,
+                    path,
+                    size
+                ),
+                Err(e) => {
+                    eprintln!("Error downloading file: {}{}", e, did_you_mean(manager, &e));
+                    return false;
+                }
+            }
+        }
+        Commands::Cat { alias } => {
+            let mut stdout = std::io::stdout().lock();
+            let result = manager.read_file(&alias, &mut stdout);
+            let _ = stdout.flush();
+            if let Err(e) = result {
+                eprintln!("Error reading file: {}{}", e, did_you_mean(manager, &e));
+                return false;
+            }
+        }
+        Commands::Head { alias, bytes } => {
+            let range = manager.read_range(&alias, 0, bytes);
+            return print_range(manager, range);
+        }
+        Commands::Tail { alias, bytes } => {
+            let range = manager.get_file_info(&alias).and_then(|info| {
+                manager.read_range(&alias, info.size.saturating_sub(bytes), bytes)
+            });
+            return print_range(manager, range);
+        }
+        Commands::Verify { alias } => match manager.verify_file(&alias) {
+            Ok(()) => report!("OK"),
+            Err(e) => {
+                eprintln!("Error verifying file: {}{}", e, did_you_mean(manager, &e));
+                return false;
+            }
+        },
+        Commands::List {
+            path,
+            long,
+            sort,
+            reverse,
+            tree,
+            dirs_only,
+            files_only,
+            format,
+        } => {
+            let listing = match &path {
+                Some(path) => manager.list_dir(path, sort, reverse),
+                None => manager.list_files_sorted(sort, reverse),
+            };
+            let listing = listing.map(|mut files| {
+                files.retain(|info| {
+                    (!dirs_only || info.is_directory) && (!files_only || !info.is_directory)
+                });
+                if tree {
+                    files.sort_by(|a, b| a.alias.split('/').cmp(b.alias.split('/')));
+                }
+                files
+            });
+            match listing {
+                Ok(files) if format == ListFormat::Json => {
+                    match serde_json::to_string_pretty(&files) {
+                        Ok(text) => println!("{}", text),
+                        Err(e) => {
+                            eprintln!("Error formatting file list: {}", e);
+                            return false;
+                        }
+                    }
+                }
+                Ok(files) if format == ListFormat::Csv => print_csv(&files),
+                Ok(files) if files.is_empty() => println!("Filesystem is empty."),
+                Ok(files) if long => {
+                    for info in files {
+                        let size = if info.is_directory {
+                            "-".to_string()
+                        } else {
+                            info.size.to_string()
+                        };
+                        println!(
+                            "{:>10}  {}  {}  {:<24}  {}{}{}",
+                            size,
+                            format_timestamp(info.created_at),
+                            format_timestamp(info.modified_at),
+                            info.content_type.as_deref().unwrap_or("-"),
+                            entry_label(&info, tree),
+                            link_suffix(manager, &info),
+                            info.description
+                                .as_ref()
+                                .map(|note| format!("  # {}", note))
+                                .unwrap_or_default()
+                        );
+                    }
+                }
+                Ok(files) => {
+                    println!("Files in filesystem:");
+                    for info in files {
+                        if info.is_directory {
+                            println!("- {}", entry_label(&info, tree));
+                        } else if info.is_symlink {
+                            let suffix = link_suffix(manager, &info);
+                            println!("- {}{}", entry_label(&info, tree), suffix);
+                        } else {
+                            println!("- {} ({} bytes)", entry_label(&info, tree), info.size);
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error listing files: {}", e);
+                    return false;
+                }
+            }
+        }
+        Commands::Mkdir { path } => match manager.mkdir(&path) {
+            Ok(_) => report!("Directory '{}' created.", path),
+            Err(e) => {
+                eprintln!("Error creating directory: {}", e);
+                return false;
+            }
+        },
+        Commands::Find {
+            pattern,
+            ignore_case,
+            larger_than,
+            smaller_than,
+        } => match manager.find_files(
+            &pattern,
+            ignore_case,
+            SizeFilter {
+                larger_than,
+                smaller_than,
+            },
+        ) {
+            Ok(files) if files.is_empty() => println!("No files match '{}'.", pattern),
+            Ok(files) => {
+                for info in files {
+                    println!("- {} ({} bytes)", info.alias, info.size);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error finding files: {}", e);
+                return false;
+            }
+        },
+        Commands::Exists { alias } => {
+            if !manager.exists(&alias) {
+                report!("File '{}' does not exist.", alias);
+                return false;
+            }
+            report!("File '{}' exists.", alias);
+        }
+        Commands::Delete {
+            alias,
+            secure,
+            dry_run: true,
+        } => match manager.plan_delete(&alias) {
+            Ok(plan) => {
+                println!(
+                    "Would delete '{}' and free {} blocks{}.",
+                    alias,
+                    plan.blocks_freed.len(),
+                    if secure && !plan.blocks_freed.is_empty() {
+                        ", zeroing them first"
+                    } else {
+                        ""
+                    }
+                );
+                if !plan.shared_with.is_empty() {
+                    println!(
+                        "Its blocks are shared with {} and would be kept.",
+                        plan.shared_with
+                            .iter()
+                            .map(|a| format!("'{}'", a))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                }
+            }
+            Err(e) => {
+                eprintln!("Error deleting file: {}{}", e, did_you_mean(manager, &e));
+                return false;
+            }
+        },
+        Commands::Delete { alias, secure, .. } => {
+            let result = if secure {
+                manager.shred_file(&alias)
+            } else {
+                manager.delete_file(&alias)
+            };
+            match result {
+                Ok(_) => report!("File '{}' deleted successfully.", alias),
+                Err(e) => {
+                    eprintln!("Error deleting file: {}{}", e, did_you_mean(manager, &e));
+                    return false;
+                }
+            }
+        }
+        Commands::Clear { secure } => {
+            let result = if secure {
+                manager.shred_all()
+            } else {
+                manager.clear_all()
+            };
+            match result {
+                Ok(count) => report!("Filesystem cleared: {} entries deleted.", count),
+                Err(e) => {
+                    eprintln!("Error clearing filesystem: {}", e);
+                    return false;
+                }
+            }
+        }
+        Commands::Copy { src, dst } => match manager.copy_file(&src, &dst) {
+            Ok(_) => report!("File '{}' copied successfully to '{}'.", src, dst),
+            Err(e) => {
+                eprintln!("Error copying file: {}{}", e, did_you_mean(manager, &e));
+                return false;
+            }
+        },
+        Commands::Transfer { alias, to, remove } => match FileSystemManager::open_at(&to, 0) {
+            Ok(mut other) => match manager.transfer_to(&mut other, &alias, remove) {
+                Ok(_) if remove => {
+                    report!("File '{}' moved to '{}'.", alias, to.display())
+                }
+                Ok(_) => report!("File '{}' copied to '{}'.", alias, to.display()),
+                Err(e) => {
+                    eprintln!(
+                        "Error transferring file: {}{}",
+                        e,
+                        did_you_mean(manager, &e)
+                    );
+                    return false;
+                }
+            },
+            Err(e) => {
+                eprintln!("Error opening '{}': {}", to.display(), e);
+                return false;
+            }
+        },
+        Commands::Swap { a, b } => match manager.swap_aliases(&a, &b) {
+            Ok(_) => report!("Files '{}' and '{}' swapped successfully.", a, b),
+            Err(e) => {
+                eprintln!("Error swapping files: {}", e);
+                return false;
+            }
+        },
+        Commands::ExportArchive { path, since } => {
+            match manager.export_archive(Path::new(&path), since) {
+                Ok(archive) => report!(
+                    "Exported {} files and {} deletions to '{}' (generation {}).",
+                    archive.files.len(),
+                    archive.deleted.len(),
+                    path,
+                    archive.generation
+                ),
+                Err(e) => {
+                    eprintln!("Error exporting archive: {}", e);
+                    return false;
+                }
+            }
+        }
+        Commands::ImportArchive { path } => match manager.import_archive(Path::new(&path)) {
+            Ok((written, deleted)) => report!(
+                "Imported {} files and applied {} deletions from '{}'.",
+                written,
+                deleted,
+                path
+            ),
+            Err(e) => {
+                eprintln!("Error importing archive: {}", e);
+                return false;
+            }
+        },
+        Commands::ExportTar { path } => match manager.export_tar(Path::new(&path)) {
+            Ok(count) => report!("Exported {} entries to '{}'.", count, path),
+            Err(e) => {
+                eprintln!("Error exporting tarball: {}", e);
+                return false;
+            }
+        },
+        Commands::ImportTar {
+            path,
+            force,
+            dry_run: true,
+        } => match manager.plan_import_tar(Path::new(&path), force) {
+            Ok(plan) => {
+                for (alias, size) in &plan.created {
+                    println!("Would create '{}' ({} bytes)", alias, size);
+                }
+                for (alias, size) in &plan.overwritten {
+                    println!("Would overwrite '{}' ({} bytes)", alias, size);
+                }
+                for (alias, reason) in &plan.rejected {
+                    println!("Would fail on '{}': {}", alias, reason);
+                }
+                println!(
+                    "Would import {} files ({} new, {} overwritten) using {} blocks; {} entries rejected.",
+                    plan.created.len() + plan.overwritten.len(),
+                    plan.created.len(),
+                    plan.overwritten.len(),
+                    plan.blocks_needed,
+                    plan.rejected.len()
+                );
+                if !plan.rejected.is_empty() {
+                    return false;
+                }
+            }
+            Err(e) => {
+                eprintln!("Error importing tarball: {}", e);
+                return false;
+            }
+        },
+        Commands::ImportTar { path, force, .. } => {
+            match manager.import_tar(Path::new(&path), force) {
+                Ok(count) => report!("Imported {} files from '{}'.", count, path),
+                Err(e) => {
+                    eprintln!("Error importing tarball: {}", e);
+                    return false;
+                }
+            }
+        }
+        Commands::Export { path } => match manager.export_to(Path::new(&path)) {
+            Ok(bytes) => report!("Exported filesystem image ({} bytes) to '{}'.", bytes, path),
+            Err(e) => {
+                eprintln!("Error exporting image: {}", e);
+                return false;
+            }
+        },
+        Commands::Import { path } => match manager.import_from(Path::new(&path)) {
+            Ok(()) => report!("Filesystem restored from '{}'.", path),
+            Err(e) => {
+                eprintln!("Error importing image: {}", e);
+                return false;
+            }
+        },
+        Commands::Info { alias, json } => match manager.get_file_info(&alias) {
+            Ok(info) if json => match serde_json::to_string_pretty(&info) {
+                Ok(text) => println!("{}", text),
+                Err(e) => {
+                    eprintln!("Error formatting file info: {}", e);
+                    return false;
+                }
+            },
+            Ok(info) => {
+                println!("Alias:       {}", info.alias);
+                if info.is_directory {
+                    println!("Type:        directory");
+                }
+                if let Ok(target) = manager.link_target(&info.alias) {
+                    println!("Links to:    {}", target);
+                }
+                println!("Size:        {} bytes", info.size);
+                if let Some(content_type) = &info.content_type {
+                    println!("MIME type:   {}", content_type);
+                }
+                if let Some(note) = &info.description {
+                    println!("Note:        {}", note);
+                }
+                println!("Blocks:      {}", info.num_blocks);
+                if info.compressed {
+                    println!("Stored:      {} bytes (compressed)", info.stored_size);
+                }
+                if info.encrypted {
+                    println!("Encrypted:   yes");
+                }
+                if info.refcount > 1 {
+                    println!("Shared by:   {} files (deduplicated)", info.refcount);
+                }
+                match info.first_block_index {
+                    Some(block) => println!("First block: {}", block),
+                    None => println!("First block: none"),
+                }
+                println!("Created:     {}", format_timestamp(info.created_at));
+                println!("Modified:    {}", format_timestamp(info.modified_at));
+                if let Some(expires_at) = info.expires_at {
+                    println!("Expires at:  {}", expires_at);
+                }
+            }
+            Err(e) => {
+                eprintln!(
+                    "Error reading file info: {}{}",
+                    e,
+                    did_you_mean(manager, &e)
+                );
+                return false;
+            }
+        },
+        Commands::Annotate { alias, note, .. } => {
+            match manager.set_description(&alias, note.as_deref()) {
+                Ok(()) if note.as_deref().is_some_and(|note| !note.is_empty()) => {
+                    report!("Note added to '{}'.", alias)
+                }
+                Ok(()) => report!("Note removed from '{}'.", alias),
+                Err(e) => {
+                    eprintln!("Error annotating file: {}{}", e, did_you_mean(manager, &e));
+                    return false;
+                }
+            }
+        }
+        Commands::Rename {
+            old_alias,
+            new_alias,
+        } => match manager.rename_file(&old_alias, &new_alias) {
+            Ok(()) => report!("File '{}' renamed to '{}'.", old_alias, new_alias),
+            Err(e) => {
+                eprintln!("Error renaming file: {}", e);
+                return false;
+            }
+        },
+        Commands::Move { src, dst } => match manager.move_file(&src, &dst, true) {
+            Ok(()) => report!("File '{}' moved to '{}'.", src, dst),
+            Err(e) => {
+                eprintln!("Error moving file: {}{}", e, did_you_mean(manager, &e));
+                return false;
+            }
+        },
+        Commands::Link { target, name } => match manager.link(&target, &name) {
+            Ok(_) => report!("Link '{}' -> '{}' created.", name, target),
+            Err(e) => {
+                eprintln!("Error creating link: {}{}", e, did_you_mean(manager, &e));
+                return false;
+            }
+        },
+        Commands::Du => {
+            let usage = manager.disk_usage();
+            if usage.is_empty() {
+                println!("Filesystem is empty.");
+            } else {
+                print_disk_usage(&usage);
+            }
+        }
+        Commands::Df => {
+            let usage = manager.usage();
+            println!(
+                "Blocks:    {} used, {} free, {} total",
+                usage.used_blocks, usage.free_blocks, usage.total_blocks
+            );
+            println!(
+                "Bytes:     {} used of {} usable ({:.1}%)",
+                usage.used_bytes,
+                usage.total_usable_bytes,
+                usage.used_bytes as f64 * 100.0 / usage.total_usable_bytes.max(1) as f64
+            );
+            println!(
+                "Files:     {} stored, {} more can be added",
+                usage.num_files, usage.free_filenodes
+            );
+        }
+        Commands::Stats { alias } => {
+            let chain = manager.block_chain(&alias).and_then(|chain| {
+                let info = manager.get_file_info(&alias)?;
+                Ok((info, chain))
+            });
+            match chain {
+                Ok((info, chain)) => {
+                    println!("Alias:       {}", info.alias);
+                    if let Some(index) = manager.filenode_index(&alias) {
+                        println!("Filenode:    {}", index);
+                    }
+                    println!("Size:        {} bytes", info.size);
+                    println!("Blocks:      {}", chain.len());
+                    // Jumps to a block that does not follow the previous one are marked
+                    let mut layout = String::new();
+                    for (i, block) in chain.iter().enumerate() {
+                        if i > 0 {
+                            let contiguous = chain[i - 1] + 1 == *block;
+                            layout.push_str(if contiguous { " " } else { " -> " });
                         }
-                        Err(e) => eprintln!("Error downloading file: {}", e),
+                        layout.push_str(&block.to_string());
+                    }
+                    if layout.is_empty() {
+                        layout.push_str("none");
+                    }
+                    println!("Chain:       {}", layout);
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Error reading block chain: {}{}",
+                        e,
+                        did_you_mean(manager, &e)
+                    );
+                    return false;
+                }
+            }
+        }
+        Commands::Frag => match manager.fragmentation_report() {
+            Ok(report) => {
+                for (alias, transitions, jumps) in &report.files {
+                    if *jumps > 0 {
+                        println!(
+                            "{}: {} of {} block transitions jump",
+                            alias, jumps, transitions
+                        );
+                    }
+                }
+                println!(
+                    "Files:      {} of {} fragmented",
+                    report.fragmented_files(),
+                    report.files.len()
+                );
+                println!(
+                    "Jumps:      {} of {} block transitions ({:.1}%)",
+                    report.non_contiguous_transitions,
+                    report.total_transitions,
+                    report.percentage()
+                );
+                println!(
+                    "Free space: {} runs, largest {} blocks",
+                    report.free_runs, report.largest_free_run
+                );
+            }
+            Err(e) => {
+                eprintln!("Error measuring fragmentation: {}", e);
+                return false;
+            }
+        },
+        Commands::Dupes => match manager.find_duplicates() {
+            Ok(groups) if groups.is_empty() => println!("No duplicate files."),
+            Ok(groups) => {
+                for (i, group) in groups.iter().enumerate() {
+                    if i > 0 {
+                        println!();
+                    }
+                    for alias in group {
+                        println!("{}", alias);
                     }
                 }
-                Err(e) => eprintln!("Failed to access filesystem: {}", e),
-            }
-        }
-        Commands::List => {
-            let fs_manager_result_for_list = get_filesystem_manager();
-            match fs_manager_result_for_list {
-                // Use the fresh instance
-                Ok(manager) => {
-                    // manager can be immutable as list_files takes &self
-                    match manager.list_files() {
-                        Ok(files) => {
-                            if files.is_empty() {
-                                println!("Filesystem is empty.");
-                            } else {
-                                println!("Files in filesystem:");
-                                for file_info in files {
-                                    println!("- {}", file_info);
-                                }
-                            }
+            }
+            Err(e) => {
+                eprintln!("Error finding duplicates: {}", e);
+                return false;
+            }
+        },
+        Commands::Purge => match manager.purge_expired() {
+            Ok(purged) => {
+                for alias in &purged {
+                    report!("Purged expired file '{}'.", alias);
+                }
+                report!("{} expired files purged.", purged.len());
+            }
+            Err(e) => {
+                eprintln!("Error purging expired files: {}", e);
+                return false;
+            }
+        },
+        Commands::Grep {
+            pattern,
+            alias: Some(alias),
+            regex,
+            line_number,
+            ..
+        } => {
+            let pattern = if regex {
+                LinePattern::regex(&pattern)
+            } else {
+                Ok(LinePattern::Substring(pattern.into_bytes()))
+            };
+            match pattern.and_then(|pattern| manager.grep_file(&alias, &pattern)) {
+                Ok(lines) => {
+                    // Lines are written as stored, even if they are not valid UTF-8
+                    let mut stdout = std::io::stdout().lock();
+                    for (number, line) in &lines {
+                        if line_number {
+                            let _ = write!(stdout, "{}:", number);
                         }
-                        Err(e) => eprintln!("Error listing files: {}", e),
+                        let _ = stdout.write_all(line);
+                        let _ = stdout.write_all(b"\n");
                     }
+                    let _ = stdout.flush();
+                }
+                Err(e) => {
+                    eprintln!("Error searching file: {}{}", e, did_you_mean(manager, &e));
+                    return false;
                 }
-                Err(e) => eprintln!("Failed to access filesystem: {}", e),
             }
         }
-        Commands::Delete { alias } => {
-            let fs_manager_result_for_delete = get_filesystem_manager();
-            match fs_manager_result_for_delete {
-                Ok(mut manager) => match manager.delete_file(&alias) {
-                    Ok(_) => println!("File '{}' deleted successfully.", alias),
-                    Err(e) => eprintln!("Error deleting file: {}", e),
+        Commands::Grep {
+            pattern,
+            files_with_matches,
+            ..
+        } => match manager.grep(pattern.as_bytes(), files_with_matches) {
+            Ok(matches) => {
+                for (alias, count) in &matches {
+                    if files_with_matches {
+                        println!("{}", alias);
+                    } else {
+                        println!("{}: {} matches", alias, count);
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("Error searching files: {}", e);
+                return false;
+            }
+        },
+        Commands::Fsck { repair: true } => match manager.repair() {
+            Ok(report) => {
+                for (alias, old_size, new_size) in &report.truncated {
+                    report!(
+                        "Truncated '{}' from {} to {} bytes to match its block chain.",
+                        alias,
+                        old_size,
+                        new_size
+                    );
+                }
+                report!(
+                    "Reclaimed {} orphaned blocks; marked {} blocks in use.",
+                    report.blocks_reclaimed,
+                    report.blocks_marked_used
+                );
+                return run_command(manager, Commands::Fsck { repair: false });
+            }
+            Err(e) => {
+                eprintln!("Error repairing filesystem: {}", e);
+                return false;
+            }
+        },
+        Commands::Fsck { repair: false } => match manager.check_integrity() {
+            Ok(issues) if issues.is_empty() => report!("No problems found."),
+            Ok(issues) => {
+                for issue in &issues {
+                    println!("{}", issue);
+                }
+                println!("{} problems found.", issues.len());
+                return false;
+            }
+            Err(e) => {
+                eprintln!("Error checking filesystem: {}", e);
+                return false;
+            }
+        },
+        Commands::Defrag { dry_run: true } => match manager.plan_defragment() {
+            Ok(report) => {
+                println!(
+                    "Would relocate {} blocks of {} files.",
+                    report.blocks_relocated, report.files_moved
+                );
+                if !report.complete {
+                    println!("Would stop early: no spare block is free to move blocks through.");
+                    return false;
+                }
+            }
+            Err(e) => {
+                eprintln!("Error defragmenting filesystem: {}", e);
+                return false;
+            }
+        },
+        Commands::Defrag { .. } => match manager.defragment() {
+            Ok(report) => {
+                report!(
+                    "Relocated {} blocks of {} files.",
+                    report.blocks_relocated,
+                    report.files_moved
+                );
+                if !report.complete {
+                    println!("Stopped early: no spare block is free to move blocks through.");
+                    return false;
+                }
+            }
+            Err(e) => {
+                eprintln!("Error defragmenting filesystem: {}", e);
+                return false;
+            }
+        },
+        Commands::Compact => match manager.compact() {
+            Ok(saved) => report!("Filesystem compacted: {} bytes smaller.", saved),
+            Err(e) => {
+                eprintln!("Error compacting filesystem: {}", e);
+                return false;
+            }
+        },
+        Commands::Snapshot { name, delete: true } => match manager.delete_snapshot(&name) {
+            Ok(_) => report!("Snapshot '{}' deleted.", name),
+            Err(e) => {
+                eprintln!("Error deleting snapshot: {}", e);
+                return false;
+            }
+        },
+        Commands::Snapshot { name, .. } => match manager.snapshot(&name) {
+            Ok(_) => report!("Snapshot '{}' taken.", name),
+            Err(e) => {
+                eprintln!("Error taking snapshot: {}", e);
+                return false;
+            }
+        },
+        Commands::Restore { name } => match manager.restore_snapshot(&name) {
+            Ok(_) => report!("Snapshot '{}' restored.", name),
+            Err(e) => {
+                eprintln!("Error restoring snapshot: {}", e);
+                return false;
+            }
+        },
+        Commands::Snapshots => match manager.list_snapshots() {
+            Ok(snapshots) if snapshots.is_empty() => println!("No snapshots."),
+            Ok(snapshots) => {
+                for snapshot in snapshots {
+                    let files = snapshot
+                        .filenodes
+                        .iter()
+                        .filter(|node| node.is_used && !node.is_dir())
+                        .count();
+                    println!(
+                        "{}  {}  generation {}, {} files",
+                        snapshot.name,
+                        format_timestamp(snapshot.created_at),
+                        snapshot.generation,
+                        files
+                    );
+                }
+            }
+            Err(e) => {
+                eprintln!("Error listing snapshots: {}", e);
+                return false;
+            }
+        },
+        Commands::Doctor { fix } => match manager.doctor(fix) {
+            Ok(report) => {
+                for finding in &report.findings {
+                    println!("{}", finding);
+                }
+                for problem in &report.problems {
+                    println!("PROBLEM: {}", problem);
+                }
+                for suggestion in &report.suggestions {
+                    println!("Suggestion: {}", suggestion);
+                }
+                if !report.problems.is_empty() {
+                    return false;
+                }
+                report!("No problems found.");
+            }
+            Err(e) => {
+                eprintln!("Error running health checks: {}", e);
+                return false;
+            }
+        },
+        Commands::Root => match manager.merkle_root() {
+            Ok(root) => println!(
+                "{}",
+                root.iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>()
+            ),
+            Err(e) => {
+                eprintln!("Error computing Merkle root: {}", e);
+                return false;
+            }
+        },
+        Commands::Layout { json } => match manager.layout() {
+            Ok(layout) if json => match serde_json::to_string_pretty(&layout) {
+                Ok(text) => println!("{}", text),
+                Err(e) => {
+                    eprintln!("Error formatting layout: {}", e);
+                    return false;
+                }
+            },
+            Ok(layout) => {
+                let regions = [
+                    ("header", layout.header),
+                    ("journal", layout.journal),
+                    ("filenode table", layout.filenode_table),
+                    ("free block bitmap", layout.free_block_bitmap),
+                    ("data blocks", layout.data_blocks),
+                    ("unused tail", layout.unused_tail),
+                ];
+                for (name, region) in regions {
+                    println!(
+                        "{:<18} offset {:>8}  length {:>8}",
+                        name, region.offset, region.len
+                    );
+                }
+                println!(
+                    "Filenode table uses {} of {} reserved bytes.",
+                    layout.filenode_table_serialized_len, layout.filenode_table.len
+                );
+            }
+            Err(e) => {
+                eprintln!("Error reading layout: {}", e);
+                return false;
+            }
+        },
+        Commands::RawRead { block } => match manager.read_block_raw(block) {
+            Ok(data) => {
+                for (line_index, chunk) in data.chunks(16).enumerate() {
+                    let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+                    let ascii: String = chunk
+                        .iter()
+                        .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+                        .collect();
+                    println!("{:08x}  {:<47}  {}", line_index * 16, hex.join(" "), ascii);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error reading block: {}", e);
+                return false;
+            }
+        },
+        Commands::RawWrite { block, hex, force } => {
+            if !force {
+                eprintln!("Refusing to overwrite block {} without --force.", block);
+                return false;
+            }
+            match parse_hex(&hex) {
+                Ok(data) => match manager.write_block_raw(block, &data) {
+                    Ok(_) => report!("Wrote {} bytes to block {}.", data.len(), block),
+                    Err(e) => {
+                        eprintln!("Error writing block: {}", e);
+                        return false;
+                    }
                 },
-                Err(e) => eprintln!("Failed to access filesystem: {}", e),
+                Err(e) => {
+                    eprintln!("Error parsing hex: {}", e);
+                    return false;
+                }
             }
         }
+        Commands::Shell => {
+            eprintln!("Already in a shell.");
+            return false;
+        }
+        Commands::Batch {
+            manifest,
+            keep_going,
+        } => return run_batch(manager, &manifest, keep_going),
+        Commands::GrowFilenodes { count } => match manager.grow_filenode_table(count) {
+            Ok(_) => report!("Filenode table grown to {} entries.", count),
+            Err(e) => {
+                eprintln!("Error growing filenode table: {}", e);
+                return false;
+            }
+        },
+        Commands::Bench { .. } => {
+            eprintln!("Error: bench runs in its own filesystem and cannot be used here.");
+            return false;
+        }
+        Commands::Serve { .. } => {
+            eprintln!("Error: serve opens the filesystem read-only and cannot be used here.");
+            return false;
+        }
+    }
+    true
+}
+
+/// Opens the filesystem at `path` read-only and serves its files over HTTP on `addr`.
+/// Only returns if the server cannot start or stops accepting connections.
+fn run_server(path: &Path, addr: &str, password: Option<String>) -> bool {
+    let mut manager = match FileSystemManager::open_read_only(path) {
+        Ok(manager) => manager,
+        Err(e) => {
+            eprintln!("Failed to access filesystem: {}", e);
+            return false;
+        }
+    };
+    manager.set_password(password);
+    let listener = match std::net::TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Error listening on {}: {}", addr, e);
+            return false;
+        }
+    };
+    match listener.local_addr() {
+        Ok(local_addr) => report!("Serving '{}' on http://{}/", path.display(), local_addr),
+        Err(_) => report!("Serving '{}' on http://{}/", path.display(), addr),
+    }
+    if let Err(e) = server::serve(&mut manager, &listener) {
+        eprintln!("Error serving files: {}", e);
+        return false;
+    }
+    true
+}
+
+/// Runs the upload and delete benchmark in a temporary filesystem and prints the timings.
+fn run_bench(options: &BenchOptions) -> bool {
+    let path = std::env::temp_dir().join(format!("filesystem-bench-{}.dat", std::process::id()));
+    let result = FileSystemManager::benchmark(&path, options);
+    let _ = std::fs::remove_file(&path);
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Error running benchmark: {}", e);
+            return false;
+        }
+    };
+
+    let seconds = |duration: std::time::Duration| duration.as_secs_f64().max(f64::EPSILON);
+    let upload_secs = seconds(report.upload_time);
+    println!(
+        "Uploaded {} files ({} bytes) in {:.3}s: {:.0} files/s, {:.2} MiB/s",
+        report.num_files,
+        report.bytes,
+        upload_secs,
+        report.num_files as f64 / upload_secs,
+        report.bytes as f64 / MEGABYTE as f64 / upload_secs
+    );
+    println!(
+        "  allocation {:.3}s ({:.1}%), I/O {:.3}s ({:.1}%)",
+        report.alloc_time.as_secs_f64(),
+        100.0 * report.alloc_time.as_secs_f64() / upload_secs,
+        report.io_time().as_secs_f64(),
+        100.0 * report.io_time().as_secs_f64() / upload_secs
+    );
+    let delete_secs = seconds(report.delete_time);
+    println!(
+        "Deleted {} files in {:.3}s: {:.0} files/s",
+        report.num_files,
+        delete_secs,
+        report.num_files as f64 / delete_secs
+    );
+    true
+}
+
+/// Returns a " Did you mean ...?" hint when `error` is about an alias that looks like a
+/// typo of a stored alias.
+fn did_you_mean(manager: &FileSystemManager, error: &FsError) -> String {
+    let FsError::AliasNotFound(alias) = error else {
+        return String::new();
+    };
+    match manager.suggest_alias(alias) {
+        Some(suggestion) => format!(" Did you mean '{}'?", suggestion),
+        None => String::new(),
+    }
+}
+
+/// Returns a progress callback that shows the percentage done on standard error, or one
+/// that does nothing when standard error is not a terminal.
+fn progress(label: &'static str) -> impl FnMut(usize, usize) {
+    let show = std::io::stderr().is_terminal();
+    move |done, total| {
+        if !show {
+            return;
+        }
+        let percent = (done * 100).checked_div(total).unwrap_or(100);
+        eprint!("\r{}: {}%", label, percent);
+        if done >= total {
+            eprintln!();
+        }
+    }
+}
+
+/// Writes bytes read from a file to standard output, or reports why they could not be read.
+/// Returns false in the latter case.
+fn print_range(manager: &FileSystemManager, range: Result<Vec<u8>, FsError>) -> bool {
+    match range {
+        Ok(bytes) => {
+            let mut stdout = std::io::stdout().lock();
+            let _ = stdout.write_all(&bytes);
+            let _ = stdout.flush();
+            true
+        }
+        Err(e) => {
+            eprintln!("Error reading file: {}{}", e, did_you_mean(manager, &e));
+            false
+        }
+    }
+}
+
+/// Prints the space each file takes up on disk, followed by the totals.
+fn print_disk_usage(usage: &[DiskUsage]) {
+    println!(
+        "{:>10}  {:>6}  {:>10}  {:>8}  Alias",
+        "Size", "Blocks", "On disk", "Slack"
+    );
+    for file in usage {
+        println!(
+            "{:>10}  {:>6}  {:>10}  {:>8}  {}{}",
+            file.size,
+            file.num_blocks,
+            file.disk_bytes,
+            file.slack,
+            file.alias,
+            if file.shared { " (shared)" } else { "" }
+        );
+    }
+    println!(
+        "{:>10}  {:>6}  {:>10}  {:>8}  total",
+        usage.iter().map(|file| file.size).sum::<usize>(),
+        usage.iter().map(|file| file.num_blocks).sum::<usize>(),
+        usage.iter().map(|file| file.disk_bytes).sum::<usize>(),
+        usage.iter().map(|file| file.slack).sum::<usize>()
+    );
+}
+
+/// Prints file metadata as CSV with a header row. Aliases are quoted when needed.
+fn print_csv(files: &[FileInfo]) {
+    println!("alias,size,num_blocks,first_block_index,expires_at,created_at,modified_at,compressed,stored_size,encrypted,refcount,is_directory");
+    let optional = |value: Option<u64>| value.map(|v| v.to_string()).unwrap_or_default();
+    for info in files {
+        let alias = if info.alias.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", info.alias.replace('"', "\"\""))
+        } else {
+            info.alias.clone()
+        };
+        println!(
+            "{},{},{},{},{},{},{},{},{},{},{},{}",
+            alias,
+            info.size,
+            info.num_blocks,
+            optional(info.first_block_index.map(|block| block as u64)),
+            optional(info.expires_at),
+            info.created_at,
+            info.modified_at,
+            info.compressed,
+            info.stored_size,
+            info.encrypted,
+            info.refcount,
+            info.is_directory
+        );
+    }
+}
+
+/// Returns how a listed entry is shown: its full alias, or in a tree its last path
+/// component indented by depth. Directory names end in a slash.
+fn entry_label(info: &FileInfo, tree: bool) -> String {
+    let name = if tree {
+        let depth = info.alias.matches('/').count();
+        let last = info.alias.rsplit('/').next().unwrap_or_default();
+        format!("{}{}", "  ".repeat(depth), last)
+    } else {
+        info.alias.clone()
+    };
+    if info.is_directory {
+        format!("{}/", name)
+    } else {
+        name
+    }
+}
+
+/// Returns ` -> target` for a symlink, naming the alias it points to, and nothing for
+/// other entries.
+fn link_suffix(manager: &mut FileSystemManager, info: &FileInfo) -> String {
+    if !info.is_symlink {
+        return String::new();
+    }
+    match manager.link_target(&info.alias) {
+        Ok(target) => format!(" -> {}", target),
+        Err(_) => " -> ?".to_string(),
+    }
+}
+
+/// Formats Unix seconds as a UTC date and time, e.g. `2024-03-01 12:00:00`.
+fn format_timestamp(secs: u64) -> String {
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let days = (secs / 86_400) as i64;
+    let secs_of_day = secs % 86_400;
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Parses a byte count with an optional k, M or G suffix (powers of 1024), e.g. `10k`.
+fn parse_size(text: &str) -> Result<usize, String> {
+    let (digits, multiplier) = match text.char_indices().last() {
+        Some((index, suffix)) if suffix.is_ascii_alphabetic() => {
+            let multiplier = match suffix.to_ascii_lowercase() {
+                'k' => KILOBYTE,
+                'm' => MEGABYTE,
+                'g' => 1024 * MEGABYTE,
+                _ => return Err(format!("Unknown size suffix '{}'. Use k, M or G.", suffix)),
+            };
+            (&text[..index], multiplier)
+        }
+        _ => (text, 1),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|count| count.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid size '{}'.", text))
+}
+
+/// Parses a string of hexadecimal byte pairs, ignoring whitespace.
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = hex.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("Hex string must have an even number of digits.".to_string());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let byte: String = pair.iter().collect();
+            u8::from_str_radix(&byte, 16).map_err(|_| format!("Invalid hex byte '{}'.", byte))
+        })
+        .collect()
+}
+
+/// Reads commands from stdin and runs them against the already open filesystem until
+/// `exit`, `quit` or end of input. Each line uses the same syntax as the command line,
+/// except that global flags are only accepted when starting the shell. `settings` are
+/// applied again to a filesystem re-created with `init`.
+fn run_shell(manager: &mut FileSystemManager, settings: &ManagerSettings) {
+    let stdin = std::io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut lines = stdin.lock().lines();
+
+    loop {
+        if interactive {
+            print!("fs> ");
+            let _ = std::io::stdout().flush();
+        }
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => {
+                eprintln!("Error reading command: {}", e);
+                break;
+            }
+            None => break,
+        };
+
+        let words = match split_words(&line) {
+            Ok(words) => words,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        match words.first().map(String::as_str) {
+            None => continue,
+            Some("exit") | Some("quit") => break,
+            _ => {}
+        }
+
+        let args = std::iter::once("filesystem".to_string()).chain(words);
+        let parsed = Cli::command()
+            .try_get_matches_from(args)
+            .and_then(|matches| Cli::from_arg_matches(&matches).map(|cli| (matches, cli)));
+        let (matches, cli) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                let _ = e.print();
+                continue;
+            }
+        };
+        // The filesystem is already open and configured, so these would be ignored
+        let command = Cli::command();
+        let global_flag = command.get_arguments().find(|arg| {
+            arg.is_global_set()
+                && matches.value_source(arg.get_id().as_str())
+                    == Some(clap::parser::ValueSource::CommandLine)
+        });
+        if let Some(arg) = global_flag {
+            eprintln!(
+                "--{} cannot be used in the shell. Give it when starting the shell instead.",
+                arg.get_long().unwrap_or_default()
+            );
+            continue;
+        }
+
+        let is_init = matches!(cli.command, Commands::Init { .. });
+        if run_command(manager, cli.command) && is_init {
+            settings.apply(manager);
+        }
+    }
+}
+
+/// One operation of a batch manifest.
+enum BatchOp {
+    Upload {
+        path: String,
+        alias: String,
+    },
+    Delete {
+        alias: String,
+    },
+    Rename {
+        old_alias: String,
+        new_alias: String,
+    },
+}
+
+impl std::fmt::Display for BatchOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatchOp::Upload { path, alias } => write!(f, "upload '{}' as '{}'", path, alias),
+            BatchOp::Delete { alias } => write!(f, "delete '{}'", alias),
+            BatchOp::Rename {
+                old_alias,
+                new_alias,
+            } => write!(f, "rename '{}' to '{}'", old_alias, new_alias),
+        }
+    }
+}
+
+/// Parses one manifest line, returning None for blank lines and comments.
+fn parse_batch_line(line: &str) -> Result<Option<BatchOp>, String> {
+    if line.trim_start().starts_with('#') {
+        return Ok(None);
+    }
+    let words = split_words(line)?;
+    let op = match words.as_slice() {
+        [] => return Ok(None),
+        [op, path, alias] if op == "upload" => BatchOp::Upload {
+            path: path.clone(),
+            alias: alias.clone(),
+        },
+        [op, alias] if op == "delete" => BatchOp::Delete {
+            alias: alias.clone(),
+        },
+        [op, old_alias, new_alias] if op == "rename" => BatchOp::Rename {
+            old_alias: old_alias.clone(),
+            new_alias: new_alias.clone(),
+        },
+        [op, ..] if ["upload", "delete", "rename"].contains(&op.as_str()) => {
+            return Err(format!("wrong number of arguments for '{}'", op));
+        }
+        [op, ..] => return Err(format!("unknown operation '{}'", op)),
+    };
+    Ok(Some(op))
+}
+
+/// Applies the operations in the manifest at `path` in order, reporting each one, then
+/// prints a summary. The whole manifest is parsed first, so a malformed line means nothing
+/// is applied. Unless `keep_going` is set, the first failed operation stops the batch.
+/// Returns false if any line was malformed or any operation failed.
+fn run_batch(manager: &mut FileSystemManager, path: &Path, keep_going: bool) -> bool {
+    let manifest = match std::fs::read_to_string(path) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            eprintln!("Error reading manifest '{}': {}", path.display(), e);
+            return false;
+        }
+    };
+    let mut ops = Vec::new();
+    let mut malformed = 0;
+    for (number, line) in manifest.lines().enumerate() {
+        match parse_batch_line(line) {
+            Ok(Some(op)) => ops.push((number + 1, op)),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("Line {}: {}", number + 1, e);
+                malformed += 1;
+            }
+        }
+    }
+    if malformed > 0 {
+        eprintln!(
+            "Error: {} malformed line(s) in '{}'. Nothing was applied.",
+            malformed,
+            path.display()
+        );
+        return false;
+    }
+
+    let (mut succeeded, mut failed) = (0, 0);
+    for (number, op) in &ops {
+        let result = match op {
+            BatchOp::Upload { path, alias } => {
+                let options = UploadOptions {
+                    password: manager.password().map(str::to_string),
+                    ..UploadOptions::default()
+                };
+                manager.upload_file(path, alias, &options).map(|_| ())
+            }
+            BatchOp::Delete { alias } => manager.delete_file(alias),
+            BatchOp::Rename {
+                old_alias,
+                new_alias,
+            } => manager.rename_file(old_alias, new_alias),
+        };
+        match result {
+            Ok(()) => {
+                report!("Line {}: {}: ok", number, op);
+                succeeded += 1;
+            }
+            Err(e) => {
+                eprintln!(
+                    "Line {}: {}: {}{}",
+                    number,
+                    op,
+                    e,
+                    did_you_mean(manager, &e)
+                );
+                failed += 1;
+                if !keep_going {
+                    break;
+                }
+            }
+        }
+    }
+    report!(
+        "Batch finished: {} succeeded, {} failed, {} skipped.",
+        succeeded,
+        failed,
+        ops.len() - succeeded - failed
+    );
+    failed == 0
+}
+
+/// Splits a shell line into words, honouring single and double quotes.
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+
+    for c in line.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => current.push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                in_word = true;
+            }
+            None if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            None => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err("Unterminated quote.".to_string());
+    }
+    if in_word {
+        words.push(current);
     }
+    Ok(words)
 }
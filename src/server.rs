@@ -0,0 +1,219 @@
+// Minimal HTTP/1.1 server for browsing and downloading stored files.
+//
+// Requests are handled one at a time on the calling thread and every connection is closed
+// after its response, so no request parsing beyond the request line is needed.
+
+use crate::fs_error::FsError;
+use crate::fs_ops::FileSystemManager;
+use log::{info, warn};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Longest request line or header line accepted, in bytes.
+const MAX_LINE_LEN: usize = 8 * 1024;
+/// How long a read from or write to a client may stall before its connection is dropped,
+/// so one idle client cannot hold up the requests queued behind it.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Serves the files of `manager` to connections accepted on `listener` until accepting
+/// fails. `GET /` lists the stored files as links and `GET /<alias>` returns a file's
+/// contents. A failed request is logged and does not stop the server.
+pub fn serve(manager: &mut FileSystemManager, listener: &TcpListener) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        if let Err(e) = handle_connection(manager, stream) {
+            warn!("Failed to answer request: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Reads one request from `stream` and writes the response.
+fn handle_connection(manager: &mut FileSystemManager, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut out = io::BufWriter::new(stream);
+
+    let request_line = read_line(&mut reader)?;
+    // Headers are not used, but must be read before the connection is closed
+    while !read_line(&mut reader)?.is_empty() {}
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return send_error(&mut out, 400, "Bad Request", "Malformed request line."),
+    };
+    if method != "GET" && method != "HEAD" {
+        return send_error(
+            &mut out,
+            405,
+            "Method Not Allowed",
+            "Only GET is supported.",
+        );
+    }
+    let head_only = method == "HEAD";
+    let path = target.split('?').next().unwrap_or_default();
+    let Some(alias) = percent_decode(path.trim_start_matches('/')) else {
+        return send_error(&mut out, 400, "Bad Request", "Malformed path.");
+    };
+    info!("{} {}", method, target);
+
+    if alias.is_empty() {
+        let body = file_list_html(manager);
+        send_head(&mut out, 200, "OK", "text/html; charset=utf-8", body.len())?;
+        if !head_only {
+            out.write_all(body.as_bytes())?;
+        }
+        return out.flush();
+    }
+
+    // The file is looked up once, so it cannot expire or go missing after the status line
+    let filenode = match manager.resolve_node(&alias) {
+        Ok(filenode) if filenode.is_dir() => {
+            return send_error(
+                &mut out,
+                404,
+                "Not Found",
+                "Directories cannot be downloaded.",
+            )
+        }
+        Ok(filenode) => filenode,
+        Err(e) => return send_fs_error(&mut out, &e),
+    };
+    let content_type = filenode.content_type();
+
+    // Encrypted files are decrypted whole before any of them is written, so reading them
+    // first lets a missing or wrong password be answered with an error status
+    if filenode.encryption.is_some() {
+        if manager.password().is_none() {
+            let message = format!("File '{}' is encrypted and no password is set.", alias);
+            return send_error(&mut out, 403, "Forbidden", &message);
+        }
+        let mut contents = Vec::with_capacity(filenode.size);
+        if let Err(e) = manager.read_node(&filenode, &mut contents) {
+            return send_fs_error(&mut out, &e);
+        }
+        send_head(&mut out, 200, "OK", &content_type, contents.len())?;
+        if !head_only {
+            out.write_all(&contents)?;
+        }
+        return out.flush();
+    }
+
+    send_head(&mut out, 200, "OK", &content_type, filenode.size)?;
+    if !head_only {
+        // The status line is already out, so a failure can only cut the body short
+        manager
+            .read_node(&filenode, &mut out)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+    }
+    out.flush()
+}
+
+/// Reads one CRLF-terminated line, without the line ending.
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_LEN as u64 + 2)
+        .read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\n") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request line too long or connection closed",
+        ));
+    }
+    let line = String::from_utf8(line)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "request is not UTF-8"))?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn send_head(
+    out: &mut impl Write,
+    status: u16,
+    reason: &str,
+    content_type: &str,
+    content_length: usize,
+) -> io::Result<()> {
+    write!(
+        out,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, reason, content_type, content_length
+    )
+}
+
+fn send_error(out: &mut impl Write, status: u16, reason: &str, message: &str) -> io::Result<()> {
+    let body = format!("{}\n", message);
+    send_head(out, status, reason, "text/plain; charset=utf-8", body.len())?;
+    out.write_all(body.as_bytes())?;
+    out.flush()
+}
+
+/// Answers with the status that matches why a file could not be served.
+fn send_fs_error(out: &mut impl Write, error: &FsError) -> io::Result<()> {
+    let (status, reason) = match error {
+        FsError::AliasNotFound(_) => (404, "Not Found"),
+        FsError::InvalidArgument(_) | FsError::InvalidAlias(_) => (400, "Bad Request"),
+        FsError::BadPassword(_) => (403, "Forbidden"),
+        _ => (500, "Internal Server Error"),
+    };
+    send_error(out, status, reason, &error.to_string())
+}
+
+/// Returns an HTML page linking to every unexpired file.
+fn file_list_html(manager: &FileSystemManager) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Files</title></head>\n<body><ul>\n",
+    );
+    for info in manager.files().filter(|info| !info.is_directory) {
+        html.push_str(&format!(
+            "<li><a href=\"/{}\">{}</a> ({} bytes)</li>\n",
+            percent_encode(&info.alias),
+            html_escape(&info.alias),
+            info.size
+        ));
+    }
+    html.push_str("</ul></body></html>\n");
+    html
+}
+
+/// Decodes `%XX` escapes in a URL path. Returns None for a malformed escape or if the
+/// result is not UTF-8.
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Escapes every byte of `alias` that is not safe in a URL path. Slashes are kept.
+fn percent_encode(alias: &str) -> String {
+    alias
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,241 @@
+// Byte storage backends that hold a filesystem image.
+
+use memmap2::MmapMut;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Random-access bytes that a filesystem image is stored in.
+pub trait Storage {
+    /// Fills `buf` with the bytes starting at `offset`. Fails with `UnexpectedEof` if the
+    /// storage ends first.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Writes `data` starting at `offset`, growing the storage if needed.
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Returns the current length of the storage in bytes.
+    fn len(&self) -> io::Result<u64>;
+
+    /// Returns true if the storage holds no bytes.
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Truncates or zero-extends the storage to `len` bytes.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Pushes buffered writes to the underlying medium.
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Returns the file behind the storage, if any, for system calls such as `sendfile`.
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+/// Reads storage sequentially from a starting offset, for decoders that take a reader.
+pub(crate) struct StorageReader<'a> {
+    storage: &'a mut dyn Storage,
+    offset: u64,
+}
+
+impl<'a> StorageReader<'a> {
+    pub(crate) fn new(storage: &'a mut dyn Storage, offset: u64) -> Self {
+        StorageReader { storage, offset }
+    }
+
+    /// Returns the offset of the next byte to be read.
+    pub(crate) fn position(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl Read for StorageReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.storage.read_at(self.offset, buf)?;
+        self.offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+}
+
+/// Storage in a regular file on disk.
+pub struct FileStorage {
+    file: File,
+}
+
+impl FileStorage {
+    pub fn new(file: File) -> Self {
+        FileStorage { file }
+    }
+
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+}
+
+impl Storage for FileStorage {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        Some(&self.file)
+    }
+}
+
+/// Storage in a memory-mapped file, so block reads and writes are plain copies instead of
+/// a seek and a system call each. Resizing the file maps it again.
+pub struct MmapStorage {
+    file: File,
+    map: Option<MmapMut>, // None while the file is empty, as empty files cannot be mapped
+}
+
+impl MmapStorage {
+    /// Maps `file`, which must be open for reading and writing.
+    pub fn new(file: File) -> io::Result<Self> {
+        let mut storage = MmapStorage { file, map: None };
+        storage.remap()?;
+        Ok(storage)
+    }
+
+    fn remap(&mut self) -> io::Result<()> {
+        self.map = None;
+        if self.file.metadata()?.len() > 0 {
+            // SAFETY: the file is only changed through this mapping or `set_len`, which
+            // drops the mapping first. Other processes must not truncate it while mapped.
+            self.map = Some(unsafe { MmapMut::map_mut(&self.file)? });
+        }
+        Ok(())
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.map.as_deref().unwrap_or_default()
+    }
+}
+
+impl Storage for MmapStorage {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        let source = start
+            .checked_add(buf.len())
+            .and_then(|end| self.bytes().get(start..end))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(source);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = offset + data.len() as u64;
+        if self.len()? < end {
+            self.set_len(end)?;
+        }
+        if let Some(map) = self.map.as_mut() {
+            let start = offset as usize;
+            map[start..start + data.len()].copy_from_slice(data);
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.bytes().len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.flush()?;
+        self.map = None;
+        self.file.set_len(len)?;
+        self.remap()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.map {
+            Some(map) => map.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        Some(&self.file)
+    }
+}
+
+/// Storage held entirely in memory, for tests and throwaway filesystems.
+#[derive(Debug, Clone, Default)]
+pub struct MemStorage {
+    data: Vec<u8>,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wraps an existing image, e.g. one read from disk.
+    pub fn from_vec(data: Vec<u8>) -> Self {
+        MemStorage { data }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Storage for MemStorage {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        let source = start
+            .checked_add(buf.len())
+            .and_then(|end| self.data.get(start..end))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(source);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let start = usize::try_from(offset)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "offset out of range"))?;
+        let end = start + data.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let len = usize::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "length out of range"))?;
+        self.data.resize(len, 0);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+// Runs the command-line tool against filesystems in temporary directories.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// Creates an empty directory unique to this process and `name` to run the tool in.
+fn work_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fs-cli-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn filesystem(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_filesystem"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn download_to_stdout_writes_raw_bytes() {
+    let dir = work_dir("stdout");
+    // Every byte value, including NUL, CR and LF, followed by invalid UTF-8
+    let mut data: Vec<u8> = (0..=255).collect();
+    data.extend_from_slice(b"\r\n\n\xff\xfe");
+    std::fs::write(dir.join("binary"), &data).unwrap();
+
+    let upload = filesystem(&dir, &["upload", "--path", "binary", "--alias", "bin"]);
+    assert!(upload.status.success());
+    let download = filesystem(&dir, &["download", "--alias", "bin", "--path", "-"]);
+    assert!(download.status.success());
+    assert_eq!(download.stdout, data);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn delete_exit_status_reports_failure() {
+    let dir = work_dir("exit-status");
+    std::fs::write(dir.join("local"), b"contents").unwrap();
+    assert!(
+        filesystem(&dir, &["upload", "--path", "local", "--alias", "a"])
+            .status
+            .success()
+    );
+
+    let deleted = filesystem(&dir, &["delete", "--alias", "a"]);
+    assert_eq!(deleted.status.code(), Some(0));
+    let missing = filesystem(&dir, &["delete", "--alias", "a"]);
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(!missing.stderr.is_empty());
+
+    // --quiet leaves out the confirmation but not the error
+    assert!(
+        filesystem(&dir, &["upload", "--path", "local", "--alias", "b"])
+            .status
+            .success()
+    );
+    let quiet = filesystem(&dir, &["--quiet", "delete", "--alias", "b"]);
+    assert_eq!(quiet.status.code(), Some(0));
+    assert!(quiet.stdout.is_empty());
+    let quiet_missing = filesystem(&dir, &["--quiet", "delete", "--alias", "b"]);
+    assert_eq!(quiet_missing.status.code(), Some(1));
+    assert!(!quiet_missing.stderr.is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn head_and_tail_print_the_ends_of_a_file() {
+    let dir = work_dir("head-tail");
+    // Long enough to span several blocks
+    let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+    std::fs::write(dir.join("local"), &data).unwrap();
+    assert!(
+        filesystem(&dir, &["upload", "--path", "local", "--alias", "a"])
+            .status
+            .success()
+    );
+
+    let head = filesystem(&dir, &["head", "--alias", "a", "--bytes", "5000"]);
+    assert!(head.status.success());
+    assert_eq!(head.stdout, &data[..5000]);
+    let tail = filesystem(&dir, &["tail", "--alias", "a", "--bytes", "100"]);
+    assert!(tail.status.success());
+    assert_eq!(tail.stdout, &data[data.len() - 100..]);
+    // More bytes than the file holds print the whole file
+    let whole = filesystem(&dir, &["tail", "--alias", "a", "--bytes", "20000"]);
+    assert_eq!(whole.stdout, data);
+
+    let missing = filesystem(&dir, &["head", "--alias", "b"]);
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(missing.stdout.is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn list_can_show_only_directories_or_only_files() {
+    let dir = work_dir("list-kinds");
+    std::fs::write(dir.join("local"), b"contents").unwrap();
+    for alias in ["docs/a.txt", "docs/old/b.txt", "top"] {
+        assert!(
+            filesystem(&dir, &["upload", "--path", "local", "--alias", alias])
+                .status
+                .success()
+        );
+    }
+    assert!(filesystem(&dir, &["mkdir", "empty"]).status.success());
+
+    let listed = |args: &[&str]| -> Vec<String> {
+        let output = filesystem(&dir, args);
+        assert!(output.status.success());
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.strip_prefix("- "))
+            .map(|entry| entry.split(" (").next().unwrap().to_string())
+            .collect()
+    };
+    let mut dirs = listed(&["list", "--dirs-only"]);
+    dirs.sort();
+    assert_eq!(dirs, ["docs/", "docs/old/", "empty/"]);
+    let mut files = listed(&["list", "--files-only"]);
+    files.sort();
+    assert_eq!(files, ["docs/a.txt", "docs/old/b.txt", "top"]);
+    assert_eq!(listed(&["list", "docs", "--files-only"]), ["docs/a.txt"]);
+
+    // The two filters cannot be combined
+    let both = filesystem(&dir, &["list", "--dirs-only", "--files-only"]);
+    assert!(!both.status.success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn json_listing_and_info_parse() {
+    let dir = work_dir("json");
+    std::fs::write(dir.join("local"), b"twelve bytes").unwrap();
+    // Quotes, backslashes and non-ASCII characters must be escaped correctly
+    let alias = "dir/say \"hi\" \\ café.txt";
+    assert!(
+        filesystem(&dir, &["upload", "--path", "local", "--alias", alias])
+            .status
+            .success()
+    );
+
+    let list = filesystem(&dir, &["list", "--format", "json"]);
+    assert!(list.status.success());
+    let entries: serde_json::Value = serde_json::from_slice(&list.stdout).unwrap();
+    let entries = entries.as_array().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["alias"], "dir");
+    assert_eq!(entries[0]["is_directory"], true);
+    assert_eq!(entries[1]["alias"], alias);
+    assert_eq!(entries[1]["size"], 12);
+
+    let info = filesystem(&dir, &["info", "--alias", alias, "--json"]);
+    assert!(info.status.success());
+    let info: serde_json::Value = serde_json::from_slice(&info.stdout).unwrap();
+    assert_eq!(info["alias"], alias);
+    assert_eq!(info["content_type"], "text/plain");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn upload_reads_stdin_for_a_dash_path() {
+    let dir = work_dir("stdin");
+    let data: Vec<u8> = (0..5000).map(|i| (i * 13 % 256) as u8).collect();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_filesystem"))
+        .current_dir(&dir)
+        .args(["upload", "--path", "-", "--alias", "piped"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&data).unwrap();
+    let upload = child.wait_with_output().unwrap();
+    assert!(upload.status.success());
+    assert_eq!(
+        String::from_utf8(upload.stdout).unwrap().trim(),
+        "5000 bytes from stdin uploaded as 'piped'."
+    );
+
+    let download = filesystem(&dir, &["download", "--alias", "piped", "--path", "-"]);
+    assert!(download.status.success());
+    assert_eq!(download.stdout, data);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Returns the aliases of the files stored in the filesystem in `dir`, sorted.
+fn stored_files(dir: &Path) -> Vec<String> {
+    let list = filesystem(dir, &["list", "--files-only", "--format", "json"]);
+    assert!(list.status.success());
+    let entries: serde_json::Value = serde_json::from_slice(&list.stdout).unwrap();
+    let mut aliases: Vec<String> = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["alias"].as_str().unwrap().to_string())
+        .collect();
+    aliases.sort();
+    aliases
+}
+
+#[test]
+fn batch_applies_a_mixed_manifest_in_order() {
+    let dir = work_dir("batch");
+    std::fs::write(dir.join("one"), b"first").unwrap();
+    std::fs::write(dir.join("two"), b"second").unwrap();
+    std::fs::write(
+        dir.join("manifest"),
+        "# set up\n\
+         upload one a\n\
+         upload two 'with space'\n\
+         \n\
+         rename a docs/renamed\n\
+         delete missing\n\
+         delete \"with space\"\n\
+         upload two b\n",
+    )
+    .unwrap();
+
+    // The failed delete stops the batch unless told to keep going
+    let stopped = filesystem(&dir, &["batch", "--manifest", "manifest"]);
+    assert_eq!(stopped.status.code(), Some(1));
+    assert_eq!(stored_files(&dir), ["docs/renamed", "with space"]);
+    let summary = String::from_utf8(stopped.stdout).unwrap();
+    assert!(
+        summary.contains("3 succeeded, 1 failed, 2 skipped"),
+        "{}",
+        summary
+    );
+
+    std::fs::remove_file(dir.join("myfs.dat")).unwrap();
+    let kept_going = filesystem(&dir, &["batch", "--manifest", "manifest", "--keep-going"]);
+    assert_eq!(kept_going.status.code(), Some(1));
+    assert_eq!(stored_files(&dir), ["b", "docs/renamed"]);
+    let download = filesystem(
+        &dir,
+        &["download", "--alias", "docs/renamed", "--path", "-"],
+    );
+    assert_eq!(download.stdout, b"first");
+
+    // A malformed line means nothing is applied
+    std::fs::write(dir.join("bad"), "delete b\nupload only-one-arg\n").unwrap();
+    let malformed = filesystem(&dir, &["batch", "--manifest", "bad"]);
+    assert_eq!(malformed.status.code(), Some(1));
+    assert_eq!(stored_files(&dir), ["b", "docs/renamed"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,108 @@
+// Serves a filesystem over HTTP on an ephemeral port and fetches files back from it.
+
+use filesystem::fs_ops::{FileSystemManager, InitOptions, UploadOptions};
+use filesystem::server;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+
+/// Stores `files` in a new filesystem and serves it read-only from a background thread.
+/// Returns the address it listens on and the path of its image.
+fn serve_files(name: &str, files: &[(&str, &[u8], UploadOptions)]) -> (SocketAddr, PathBuf) {
+    let path = std::env::temp_dir().join(format!("fs-server-test-{}-{}", std::process::id(), name));
+    let mut manager =
+        FileSystemManager::init_filesystem_at(&path, 0, &InitOptions::default()).unwrap();
+    for (alias, data, options) in files {
+        manager
+            .upload_reader(&mut &data[..], alias, options)
+            .unwrap();
+    }
+    drop(manager);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let served_path = path.clone();
+    std::thread::spawn(move || {
+        let mut manager = FileSystemManager::open_read_only(&served_path).unwrap();
+        server::serve(&mut manager, &listener).unwrap();
+    });
+    (addr, path)
+}
+
+/// Sends `GET target` and returns the status line, the headers and the body.
+fn get(addr: SocketAddr, target: &str) -> (String, String, Vec<u8>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let head_end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8(response[..head_end].to_vec()).unwrap();
+    let (status, headers) = head.split_once("\r\n").unwrap();
+    (
+        status.to_string(),
+        headers.to_string(),
+        response[head_end + 4..].to_vec(),
+    )
+}
+
+#[test]
+fn served_file_is_fetched_back_byte_for_byte() {
+    let data: Vec<u8> = (0..20_000u32).map(|i| (i * 31 % 256) as u8).collect();
+    let (addr, path) = serve_files(
+        "fetch",
+        &[("dir/data.bin", &data, UploadOptions::default())],
+    );
+
+    let (status, headers, body) = get(addr, "/dir/data.bin");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(headers.contains(&format!("Content-Length: {}", data.len())));
+    assert_eq!(body, data);
+
+    let (status, _, body) = get(addr, "/");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(String::from_utf8(body)
+        .unwrap()
+        .contains("href=\"/dir/data.bin\""));
+
+    let (status, _, _) = get(addr, "/missing");
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn encrypted_file_without_password_is_forbidden() {
+    let options = UploadOptions {
+        password: Some("pw".to_string()),
+        ..UploadOptions::default()
+    };
+    let (addr, path) = serve_files("encrypted", &[("secret", b"hidden", options)]);
+
+    let (status, headers, _) = get(addr, "/secret");
+    assert_eq!(status, "HTTP/1.1 403 Forbidden");
+    assert!(headers.contains("Content-Type: text/plain"));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn expired_file_is_not_served_or_listed() {
+    let expired = UploadOptions {
+        ttl: Some(0),
+        ..UploadOptions::default()
+    };
+    let (addr, path) = serve_files(
+        "expired",
+        &[
+            ("live", b"here", UploadOptions::default()),
+            ("stale", b"gone", expired),
+        ],
+    );
+
+    let (status, _, body) = get(addr, "/stale");
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+    assert!(!body.starts_with(b"gone"));
+    let (_, _, body) = get(addr, "/");
+    let listing = String::from_utf8(body).unwrap();
+    assert!(listing.contains("href=\"/live\""));
+    assert!(!listing.contains("stale"));
+    std::fs::remove_file(&path).unwrap();
+}